@@ -0,0 +1,281 @@
+// Line codecs shared by the line-based problems, and the error type every
+// problem's codecs report with.
+//
+// A `ProtocolError` is either the peer breaking the protocol, in a way the
+// protocol's own error type `P` describes, or the connection failing. It
+// converts to an `io::Error` for callers that need one: an IO error as it
+// was, and a protocol error as one of kind InvalidData with it as the cause.
+//
+// The line codecs both wrap tokio-util's `LinesCodec`, with a maximum line
+// length so a client can't make a server buffer without limit:
+// `BytesLinesCodec` yields each line as bytes, for handing to another decoder,
+// and `AsciiLinesCodec` yields it as an `AsciiString`, rejecting lines with
+// anything else in them. Lines end in '\n', with any '\r' before it dropped,
+// and a last line without one is still yielded when the stream ends. Lines
+// have to be UTF-8 either way; one that isn't is an `Io` error of kind
+// InvalidData. Errors are a `LineError`, a `ProtocolError` of
+// `LineViolation`s.
+//
+// With the testkit feature, `testkit` has helpers for other crates to test
+// their own codecs with.
+use ascii::AsciiString;
+use bytes::BytesMut;
+use std::io;
+use thiserror::Error;
+use tokio_util::codec::{Decoder, LinesCodec, LinesCodecError};
+
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+
+#[derive(Debug, Error)]
+pub enum ProtocolError<P> {
+    #[error(transparent)]
+    Protocol(P),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl<P> ProtocolError<P> {
+    // How the peer broke the protocol, if it did
+    pub fn protocol(&self) -> Option<&P> {
+        match self {
+            ProtocolError::Protocol(e) => Some(e),
+            ProtocolError::Io(_) => None,
+        }
+    }
+}
+
+impl<P: std::error::Error + Send + Sync + 'static> From<ProtocolError<P>> for io::Error {
+    fn from(e: ProtocolError<P>) -> Self {
+        match e {
+            ProtocolError::Protocol(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+            ProtocolError::Io(e) => e,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LineViolation {
+    // The line went on past the maximum length. The rest of it is skipped,
+    // so decoding can carry on from the next line.
+    #[error("Max line length exceeded")]
+    TooLong,
+    // The line had a non-ASCII byte at this position
+    #[error("Invalid ASCII character at position {0}")]
+    NotAscii(usize),
+}
+
+pub type LineError = ProtocolError<LineViolation>;
+
+impl From<LineViolation> for LineError {
+    fn from(e: LineViolation) -> Self {
+        ProtocolError::Protocol(e)
+    }
+}
+
+impl From<LinesCodecError> for LineError {
+    fn from(e: LinesCodecError) -> Self {
+        match e {
+            LinesCodecError::MaxLineLengthExceeded => LineViolation::TooLong.into(),
+            LinesCodecError::Io(e) => ProtocolError::Io(e),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BytesLinesCodec(LinesCodec);
+
+impl BytesLinesCodec {
+    pub fn new(max_length: usize) -> Self {
+        BytesLinesCodec(LinesCodec::new_with_max_length(max_length))
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.0.max_length()
+    }
+}
+
+impl Decoder for BytesLinesCodec {
+    type Item = BytesMut;
+    type Error = LineError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(self.0.decode(buf)?.map(|line| line.as_bytes().into()))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(self.0.decode_eof(buf)?.map(|line| line.as_bytes().into()))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct AsciiLinesCodec(LinesCodec);
+
+impl AsciiLinesCodec {
+    pub fn new(max_length: usize) -> Self {
+        AsciiLinesCodec(LinesCodec::new_with_max_length(max_length))
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.0.max_length()
+    }
+}
+
+fn ascii(line: Option<String>) -> Result<Option<AsciiString>, LineError> {
+    line.map(AsciiString::from_ascii)
+        .transpose()
+        .map_err(|e| LineViolation::NotAscii(e.ascii_error().valid_up_to()).into())
+}
+
+impl Decoder for AsciiLinesCodec {
+    type Item = AsciiString;
+    type Error = LineError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        ascii(self.0.decode(buf)?)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        ascii(self.0.decode_eof(buf)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit;
+
+    fn buf(bytes: &[u8]) -> BytesMut {
+        BytesMut::from(bytes)
+    }
+
+    #[test]
+    fn bytes_splits_lines() {
+        let mut codec = BytesLinesCodec::new(64);
+        let mut input = buf(b"one\r\ntwo\nthr");
+        assert_eq!(codec.decode(&mut input).unwrap().unwrap(), "one");
+        assert_eq!(codec.decode(&mut input).unwrap().unwrap(), "two");
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        input.extend_from_slice(b"ee\n");
+        assert_eq!(codec.decode(&mut input).unwrap().unwrap(), "three");
+    }
+
+    #[test]
+    fn bytes_yields_an_unterminated_last_line() {
+        let mut codec = BytesLinesCodec::new(64);
+        let mut input = buf(b"last");
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        assert_eq!(codec.decode_eof(&mut input).unwrap().unwrap(), "last");
+        assert!(codec.decode_eof(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn bytes_passes_non_ascii_text_through() {
+        let mut codec = BytesLinesCodec::new(64);
+        let mut input = buf("caf\u{e9}\n".as_bytes());
+        assert_eq!(
+            codec.decode(&mut input).unwrap().unwrap(),
+            "caf\u{e9}".as_bytes()
+        );
+    }
+
+    #[test]
+    fn too_long_lines_are_skipped() {
+        let mut codec = BytesLinesCodec::new(4);
+        assert_eq!(codec.max_length(), 4);
+        let mut input = buf(b"toolong\nok\n");
+        let e = codec.decode(&mut input).unwrap_err();
+        assert_eq!(e.protocol(), Some(&LineViolation::TooLong));
+        assert_eq!(codec.decode(&mut input).unwrap().unwrap(), "ok");
+    }
+
+    #[test]
+    fn a_line_of_exactly_the_maximum_is_fine() {
+        let mut codec = AsciiLinesCodec::new(4);
+        let mut input = buf(b"four\n");
+        assert_eq!(codec.decode(&mut input).unwrap().unwrap(), "four");
+    }
+
+    #[test]
+    fn ascii_splits_lines() {
+        let mut codec = AsciiLinesCodec::new(64);
+        let mut input = buf(b"hello\r\nworld");
+        assert_eq!(codec.decode(&mut input).unwrap().unwrap(), "hello");
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        assert_eq!(codec.decode_eof(&mut input).unwrap().unwrap(), "world");
+    }
+
+    #[test]
+    fn ascii_rejects_other_bytes_with_their_position() {
+        let mut codec = AsciiLinesCodec::new(64);
+        let mut input = buf("ab\u{e9}\nnext\n".as_bytes());
+        let e = codec.decode(&mut input).unwrap_err();
+        assert_eq!(e.protocol(), Some(&LineViolation::NotAscii(2)));
+        assert_eq!(codec.decode(&mut input).unwrap().unwrap(), "next");
+    }
+
+    #[test]
+    fn converts_from_lines_codec_errors() {
+        assert!(matches!(
+            LineError::from(LinesCodecError::MaxLineLengthExceeded),
+            ProtocolError::Protocol(LineViolation::TooLong)
+        ));
+        let io = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        match LineError::from(LinesCodecError::Io(io)) {
+            ProtocolError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn converts_to_io_errors() {
+        let e = io::Error::from(LineError::from(LineViolation::NotAscii(3)));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "Invalid ASCII character at position 3");
+        // The violation is kept as the cause
+        let cause = e.into_inner().unwrap().downcast::<LineViolation>().unwrap();
+        assert_eq!(*cause, LineViolation::NotAscii(3));
+        let e = io::Error::from(LineError::Io(io::ErrorKind::UnexpectedEof.into()));
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = io::Error::from(LineError::from(LineViolation::TooLong));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "Max line length exceeded");
+    }
+
+    #[test]
+    fn lines_decode_however_they_arrive() {
+        let input = b"one\r\ntwo\n\nthree";
+        let lines = testkit::assert_splits_agree(|| BytesLinesCodec::new(8), input).unwrap();
+        assert_eq!(lines, ["one", "two", "", "three"]);
+        let lines = testkit::assert_splits_agree(|| AsciiLinesCodec::new(8), input).unwrap();
+        assert_eq!(lines, ["one", "two", "", "three"]);
+    }
+
+    #[test]
+    fn errors_are_the_same_however_lines_arrive() {
+        let input = "ok\nfar too long\ncaf\u{e9}\n".as_bytes();
+        assert!(testkit::assert_splits_agree(|| BytesLinesCodec::new(8), input).is_err());
+        assert!(testkit::assert_splits_agree(|| AsciiLinesCodec::new(8), input).is_err());
+    }
+
+    #[test]
+    fn a_line_cut_off_by_the_end_of_the_stream_is_still_yielded() {
+        // Unlike binary frames, a partial line is a line
+        for len in 1..5 {
+            let prefix = &b"line\n"[..len];
+            let lines = testkit::decode_bytewise(&mut BytesLinesCodec::new(8), prefix).unwrap();
+            assert_eq!(lines, [prefix]);
+            let lines = testkit::decode_bytewise(&mut AsciiLinesCodec::new(8), prefix).unwrap();
+            assert_eq!(lines, [std::str::from_utf8(prefix).unwrap()]);
+        }
+    }
+
+    #[test]
+    fn lines_round_trip() {
+        for line in ["", "hello", "with spaces\tand tabs"] {
+            let encoded = format!("{}\n", line);
+            let decoded = testkit::round_trip(|| AsciiLinesCodec::new(64), encoded.as_bytes());
+            assert_eq!(decoded, line);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Helpers for testing codecs.
+//
+// A decoder has to give the same answer however its input is split up by the
+// network, so these feed it the input whole, a byte at a time and split in
+// two at every point, and check the results agree. Input cut off mid-frame
+// should be an error when the stream ends, not a frame or silence, and an
+// encoded frame should decode back to what was encoded. Items and errors are
+// compared by their Debug output, as not all of them implement PartialEq.
+use bytes::BytesMut;
+use std::fmt::Debug;
+use tokio_util::codec::Decoder;
+
+// Feed `codec` the chunks in order, decoding all it can after each, then end
+// the stream
+pub fn decode_chunks<'a, D: Decoder>(
+    codec: &mut D,
+    chunks: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<Vec<D::Item>, D::Error> {
+    let mut buf = BytesMut::new();
+    let mut items = Vec::new();
+    for chunk in chunks {
+        buf.extend_from_slice(chunk);
+        while let Some(item) = codec.decode(&mut buf)? {
+            items.push(item);
+        }
+    }
+    while let Some(item) = codec.decode_eof(&mut buf)? {
+        items.push(item);
+    }
+    Ok(items)
+}
+
+pub fn decode_bytewise<D: Decoder>(codec: &mut D, input: &[u8]) -> Result<Vec<D::Item>, D::Error> {
+    decode_chunks(codec, input.chunks(1))
+}
+
+fn outcome<T: Debug, E: Debug>(result: Result<Vec<T>, E>) -> String {
+    format!("{:?}", result)
+}
+
+// Check a fresh codec from `make` decodes `input` the same fed whole, a byte
+// at a time and split anywhere, and return what it decoded
+pub fn assert_splits_agree<D>(make: impl Fn() -> D, input: &[u8]) -> Result<Vec<D::Item>, D::Error>
+where
+    D: Decoder,
+    D::Item: Debug,
+    D::Error: Debug,
+{
+    let whole = outcome(decode_chunks(&mut make(), [input]));
+    assert_eq!(
+        outcome(decode_bytewise(&mut make(), input)),
+        whole,
+        "fed a byte at a time"
+    );
+    for at in 0..=input.len() {
+        let (front, back) = input.split_at(at);
+        assert_eq!(
+            outcome(decode_chunks(&mut make(), [front, back])),
+            whole,
+            "split at {}",
+            at
+        );
+    }
+    decode_chunks(&mut make(), [input])
+}
+
+// Check every non-empty proper prefix of `frame` is an error once the stream
+// ends, fed whole or a byte at a time
+pub fn assert_truncations_fail<D>(make: impl Fn() -> D, frame: &[u8])
+where
+    D: Decoder,
+    D::Item: Debug,
+{
+    for len in 1..frame.len() {
+        let prefix = &frame[..len];
+        let items = decode_chunks(&mut make(), [prefix]);
+        assert!(
+            items.is_err(),
+            "cut off after {} bytes: {:?}",
+            len,
+            items.ok()
+        );
+        let items = decode_bytewise(&mut make(), prefix);
+        assert!(
+            items.is_err(),
+            "cut off after {} bytes: {:?}",
+            len,
+            items.ok()
+        );
+    }
+}
+
+// Decode `encoded`, however it's split, as exactly one item, for comparing
+// with what was encoded
+pub fn round_trip<D>(make: impl Fn() -> D, encoded: &[u8]) -> D::Item
+where
+    D: Decoder,
+    D::Item: Debug,
+    D::Error: Debug,
+{
+    let mut items = assert_splits_agree(make, encoded).expect("encoded frame didn't decode");
+    assert_eq!(items.len(), 1, "decoded {:?}", items);
+    items.pop().unwrap()
+}
+
+// The same for a datagram format, where each message is parsed whole: every
+// non-empty proper prefix of `datagram` has to be rejected, and the whole of
+// it parsed, returning the message
+pub fn datagram_round_trip<T: Debug>(parse: impl Fn(&[u8]) -> Option<T>, datagram: &[u8]) -> T {
+    for len in 1..datagram.len() {
+        let message = parse(&datagram[..len]);
+        assert!(
+            message.is_none(),
+            "cut off after {} bytes: {:?}",
+            len,
+            message
+        );
+    }
+    parse(datagram).expect("encoded datagram didn't parse")
+}
@@ -0,0 +1,553 @@
+// Accept loop with admission control.
+//
+// Accepted sockets go into a bounded queue and are only handed to a new task
+// once there is room in the connection budget (MAX_CONNECTIONS), so a
+// connection flood ends up waiting in the queue and then being rejected
+// instead of spawning tasks without limit. A problem can give a busy message
+// in its own protocol, which a rejected connection is sent before it is
+// closed. With ACCEPT_WHEN_FULL=pause, the loop instead stops accepting while
+// the queue is full, and new connections wait in the listen backlog.
+//
+// With a reap policy (REAP_POLICY=idle or oldest), a connection waiting for a
+// slot, or an accept failing for lack of file descriptors, makes room by
+// closing one of the sessions that has been idle for REAP_MIN_IDLE_MILLIS
+// (default 1000): the one idle longest, or the one open longest. Sessions
+// busier than that are never reaped, so under real load the queue still
+// fills up and rejects as before.
+//
+// MAX_CONNECTIONS_PER_IP caps the connections from any one address; see
+// per_ip.rs. The budget, the per-IP counts, the connection hooks and the
+// backoff after a failed accept are shared with the problem's other
+// listeners; see admission.rs.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::admission::{out_of_descriptors, Admission};
+use crate::handler::{ConnectionHandler, Context};
+use crate::hooks::DisconnectReason;
+use crate::listeners::Listeners;
+use crate::metrics::{Counter, Scope};
+use crate::per_ip::IpSlot;
+use crate::tasks::Task;
+use crate::throughput::{Floor, Guarded, Meter};
+
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_ACCEPT_QUEUE_LEN: usize = 128;
+const DEFAULT_REAP_MIN_IDLE_MILLIS: u64 = 1000;
+// How long a cancelled connection gets to clean up before it is aborted
+const CANCEL_GRACE: Duration = Duration::from_secs(1);
+// Time for a reaped session to close its socket before accepting again
+const REAP_SETTLE: Duration = Duration::from_millis(100);
+
+// Which session to close when the budget is exhausted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReapPolicy {
+    // Close none, new connections wait or are rejected
+    Never,
+    LongestIdle,
+    Oldest,
+}
+
+impl FromStr for ReapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::Never),
+            "idle" => Ok(Self::LongestIdle),
+            "oldest" => Ok(Self::Oldest),
+            _ => Err(format!("unknown reap policy {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ReapPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Never => write!(f, "none"),
+            Self::LongestIdle => write!(f, "idle"),
+            Self::Oldest => write!(f, "oldest"),
+        }
+    }
+}
+
+// What to do with new connections when the accept queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FullPolicy {
+    // Accept and close them, after the busy message if there is one
+    Reject,
+    // Leave them in the listen backlog until there is room
+    Pause,
+}
+
+impl FromStr for FullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "pause" => Ok(Self::Pause),
+            _ => Err(format!("unknown accept policy {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FullPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reject => write!(f, "reject"),
+            Self::Pause => write!(f, "pause"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AcceptLimits {
+    // Connections being served at the same time
+    pub max_connections: usize,
+    // Accepted connections waiting for a free slot before new ones are rejected
+    pub queue_len: usize,
+    pub when_full: FullPolicy,
+    // Sent to a connection rejected for lack of room, in the problem's protocol
+    pub busy_message: Option<&'static [u8]>,
+    pub reap: ReapPolicy,
+    // Sessions busier than this are never reaped
+    pub reap_min_idle: Duration,
+    // Connections open at once from one source address; see per_ip.rs
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl AcceptLimits {
+    pub fn from_env() -> Self {
+        Self::from_env_reaping(ReapPolicy::Never)
+    }
+
+    // Limits for a server that reaps with `policy` unless REAP_POLICY says
+    // otherwise
+    pub fn from_env_reaping(policy: ReapPolicy) -> Self {
+        AcceptLimits {
+            max_connections: crate::env::var_or("MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS),
+            queue_len: crate::env::var_or("ACCEPT_QUEUE_LEN", DEFAULT_ACCEPT_QUEUE_LEN),
+            when_full: crate::env::var_or("ACCEPT_WHEN_FULL", FullPolicy::Reject),
+            busy_message: None,
+            reap: crate::env::var_or("REAP_POLICY", policy),
+            reap_min_idle: Duration::from_millis(crate::env::var_or(
+                "REAP_MIN_IDLE_MILLIS",
+                DEFAULT_REAP_MIN_IDLE_MILLIS,
+            )),
+            max_connections_per_ip: crate::env::var("MAX_CONNECTIONS_PER_IP"),
+        }
+    }
+
+    pub fn busy_message(self, message: &'static [u8]) -> Self {
+        AcceptLimits {
+            busy_message: Some(message),
+            ..self
+        }
+    }
+}
+
+// Open sessions that can be reaped, by connection
+struct Sessions {
+    policy: ReapPolicy,
+    min_idle: Duration,
+    open: Mutex<BTreeMap<u64, (SocketAddr, Task, CancellationToken)>>,
+    reaped: Counter,
+}
+
+impl Sessions {
+    fn open(&self, id: u64, peer: SocketAddr, task: Task, reap: CancellationToken) {
+        if self.policy != ReapPolicy::Never {
+            self.open
+                .lock()
+                .unwrap_or_else(|e| panic!("Error locking open sessions: {}", e))
+                .insert(id, (peer, task, reap));
+        }
+    }
+
+    fn close(&self, id: u64) {
+        self.open
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking open sessions: {}", e))
+            .remove(&id);
+    }
+
+    // Close one session the policy picks, returning whether there was one
+    fn reap(&self) -> bool {
+        let mut open = self
+            .open
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking open sessions: {}", e));
+        let idle = open
+            .iter()
+            .filter(|(_, (_, task, _))| task.idle() >= self.min_idle);
+        let victim = match self.policy {
+            ReapPolicy::Never => None,
+            ReapPolicy::LongestIdle => idle.max_by_key(|(_, (_, task, _))| task.idle()),
+            ReapPolicy::Oldest => idle.max_by_key(|(_, (_, task, _))| task.age()),
+        };
+        let Some((peer, task, reap)) = victim.map(|(id, _)| *id).and_then(|id| open.remove(&id))
+        else {
+            return false;
+        };
+        crate::info!(
+            "Reaping connection from {:?}, idle for {:.1}s, to make room",
+            peer,
+            task.idle().as_secs_f64()
+        );
+        self.reaped.inc();
+        reap.cancel();
+        true
+    }
+}
+
+// Ask a connection's handler to wind down, and abort it if it hasn't within
+// CANCEL_GRACE
+async fn stop(
+    connection: &mut JoinHandle<()>,
+    cancel: &CancellationToken,
+) -> Result<(), JoinError> {
+    cancel.cancel();
+    match tokio::time::timeout(CANCEL_GRACE, &mut *connection).await {
+        Ok(finished) => finished,
+        Err(_) => {
+            connection.abort();
+            connection.await
+        }
+    }
+}
+
+// Serve `listener` until `shutdown` is cancelled or another process takes it
+// over, then wait for the connections already accepted, on any of the
+// problem's listeners
+pub async fn run_acceptor<H: ConnectionHandler>(
+    listener: impl Into<Listeners>,
+    scope: &Scope,
+    admission: Admission,
+    shutdown: CancellationToken,
+    handler: H,
+) {
+    let listener = listener.into();
+    let limits = admission.limits();
+    crate::tuning::check_open_files(limits.max_connections + limits.queue_len);
+    crate::dry_run::finish();
+    let rejected = admission.rejected();
+    let too_slow = scope.counter(
+        "connections_too_slow_total",
+        "Connections closed for staying under the minimum throughput",
+    );
+    let panicked = admission.panicked();
+    let sessions = Arc::new(Sessions {
+        policy: limits.reap,
+        min_idle: limits.reap_min_idle,
+        open: Mutex::new(BTreeMap::new()),
+        reaped: scope.counter(
+            "connections_reaped_total",
+            "Idle connections closed to make room for new ones",
+        ),
+    });
+    crate::report::watch_accept_errors(
+        scope,
+        vec![rejected.clone(), too_slow.clone(), panicked.clone()],
+        admission.accept_errors(),
+    );
+    let active = admission.active();
+    crate::agent_check::spawn_from_env(active.clone(), limits.max_connections);
+    crate::summary::register(scope);
+    crate::tasks::spawn_from_env();
+    #[cfg(unix)]
+    crate::uds::spawn_from_env(handler.clone(), admission.clone(), shutdown.clone());
+
+    let floor = Floor::from_env();
+    crate::env::print_config();
+    let scope = scope.clone();
+    let (queue_tx, mut queue_rx) =
+        mpsc::channel::<(TcpStream, SocketAddr, Instant, Option<IpSlot>)>(limits.queue_len.max(1));
+    let budget = admission.budget();
+    let mut draining = crate::handover::draining();
+    // Parent of every connection's token, cancelled when draining takes too long
+    let connections = CancellationToken::new();
+
+    let serving_budget = budget.clone();
+    let serving_connections = connections.clone();
+    let serving_sessions = sessions.clone();
+    let active_gauge = active.clone();
+    let serving = tokio::spawn(async move {
+        let mut next_id = 0u64;
+        loop {
+            let reaping = serving_sessions.policy != ReapPolicy::Never;
+            // Without reaping, connections stay queued until there's a slot.
+            // With it, one waiting for a slot is what makes room.
+            let permit = if reaping {
+                None
+            } else {
+                match serving_budget.clone().acquire_owned().await {
+                    Ok(p) => Some(p),
+                    Err(_) => return,
+                }
+            };
+            let (socket, addr, accepted_at, ip_slot) = match queue_rx.recv().await {
+                Some(s) => s,
+                None => return,
+            };
+            let permit = match permit {
+                Some(p) => p,
+                None => match serving_budget.clone().try_acquire_owned() {
+                    Ok(p) => p,
+                    Err(_) => {
+                        let mut retry = tokio::time::interval(
+                            (serving_sessions.min_idle / 4).max(Duration::from_millis(10)),
+                        );
+                        loop {
+                            tokio::select! {
+                                p = serving_budget.clone().acquire_owned() => match p {
+                                    Ok(p) => break p,
+                                    Err(_) => return,
+                                },
+                                _ = retry.tick() => {
+                                    if serving_sessions.reap() {
+                                        // Its slot comes back when it finishes
+                                        retry.reset_after(CANCEL_GRACE);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                },
+            };
+            let id = next_id;
+            next_id += 1;
+            let meter = floor.map(|_| Arc::new(Meter::default()));
+            let ctx = Context::new("tcp");
+            ctx.task.set_problem(scope.problem());
+            let stream = Guarded::new(socket, meter.clone(), ctx.task.clone());
+            let active = active.clone();
+            let scope = scope.clone();
+            let too_slow = too_slow.clone();
+            let panicked = panicked.clone();
+            let handler = handler.clone();
+            let cancel = serving_connections.child_token();
+            let reap = CancellationToken::new();
+            serving_sessions.open(id, addr, ctx.task.clone(), reap.clone());
+            let sessions = serving_sessions.clone();
+            let ctx = Context {
+                cancel: cancel.clone(),
+                ..ctx
+            };
+            active.inc();
+            tokio::spawn(async move {
+                // Run the handler as its own task so a panic in it still
+                // releases the slot and can be reported with the peer address
+                let mut connection = crate::handler::spawn(&handler, stream, Some(addr), ctx);
+                let watch = async {
+                    match (&meter, floor) {
+                        (Some(meter), Some(floor)) => meter.too_slow(floor).await,
+                        _ => std::future::pending().await,
+                    }
+                };
+                let mut slow = false;
+                let mut reaped = false;
+                let finished = tokio::select! {
+                    finished = &mut connection => finished,
+                    _ = watch => {
+                        crate::info!("Connection from {:?} too slow, closing", addr);
+                        too_slow.inc();
+                        slow = true;
+                        stop(&mut connection, &cancel).await
+                    }
+                    _ = reap.cancelled() => {
+                        reaped = true;
+                        stop(&mut connection, &cancel).await
+                    }
+                };
+                sessions.close(id);
+                let reason = match finished {
+                    Err(e) if e.is_panic() => {
+                        panicked.inc();
+                        crate::report::report_panic(&scope, &addr.to_string(), &*e.into_panic());
+                        DisconnectReason::Panicked
+                    }
+                    _ if slow => DisconnectReason::TooSlow,
+                    _ if reaped => DisconnectReason::Reaped,
+                    _ => DisconnectReason::Closed,
+                };
+                crate::debug!("Connection from {:?} finished", addr);
+                crate::hooks::disconnect(addr, reason, accepted_at.elapsed());
+                active.dec();
+                drop(ip_slot);
+                drop(permit);
+            });
+        }
+    });
+
+    let mut failed_accepts = 0;
+    loop {
+        // When pausing, room in the queue comes first
+        let room = match limits.when_full {
+            FullPolicy::Reject => None,
+            FullPolicy::Pause => tokio::select! {
+                room = queue_tx.reserve() => match room {
+                    Ok(room) => Some(room),
+                    Err(_) => break,
+                },
+                _ = draining.wait_for(|d| *d) => break,
+                _ = shutdown.cancelled() => break,
+            },
+        };
+        let accepted_socket = tokio::select! {
+            accepted_socket = listener.accept() => accepted_socket,
+            _ = draining.wait_for(|d| *d) => break,
+            _ = shutdown.cancelled() => break,
+        };
+        match accepted_socket {
+            Ok((socket, addr)) => {
+                failed_accepts = 0;
+                crate::debug!("Accepted connection from {:?}", addr);
+                let accepted_at = Instant::now();
+                let Some(ip_slot) = admission.check(addr, accepted_at) else {
+                    continue;
+                };
+                let queued = (socket, addr, accepted_at, ip_slot);
+                if let Some(room) = room {
+                    room.send(queued);
+                } else if let Err(mpsc::error::TrySendError::Full((socket, addr, accepted_at, _))) =
+                    queue_tx.try_send(queued)
+                {
+                    crate::info!("Accept queue full, rejecting connection from {:?}", addr);
+                    if let Some(message) = limits.busy_message {
+                        // A fresh socket has room for it, so this won't block
+                        socket.try_write(message).unwrap_or(0);
+                    }
+                    rejected.inc();
+                    crate::hooks::disconnect(
+                        addr,
+                        DisconnectReason::QueueFull,
+                        accepted_at.elapsed(),
+                    );
+                }
+            }
+            Err(e) => {
+                let mut delay = admission.accept_failed("connection", &e, &mut failed_accepts);
+                if delay.is_zero() {
+                    continue;
+                }
+                if out_of_descriptors(&e) && sessions.policy != ReapPolicy::Never && sessions.reap()
+                {
+                    delay = delay.max(REAP_SETTLE);
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = draining.wait_for(|d| *d) => break,
+                    _ = shutdown.cancelled() => break,
+                }
+            }
+        }
+    }
+
+    // Shutting down or handed over: serve what was already accepted. After a
+    // handover, connections finish in their own time unless that takes too
+    // long or a shutdown comes along; on a shutdown they are told to wind
+    // down straight away, and get SHUTDOWN_DRAIN_SECS to do it.
+    drop(listener);
+    drop(queue_tx);
+    // Connections still queued are served as slots come free, so all are
+    // done once the serving task has finished and every slot is back
+    let all = limits.max_connections as u32;
+    let mut finished = tokio::spawn(async move {
+        serving.await.unwrap_or(());
+        budget.acquire_many(all).await.map(drop).unwrap_or(());
+    });
+    let drained = !shutdown.is_cancelled()
+        && tokio::select! {
+            drained = tokio::time::timeout(
+                crate::handover::drain_timeout(),
+                &mut finished,
+            ) => drained.is_ok(),
+            _ = shutdown.cancelled() => false,
+        };
+    if !drained {
+        if active_gauge.get() > 0 {
+            crate::info!("Cancelling {} connections still open", active_gauge.get());
+        }
+        // Before waiting on anything, as with every slot taken nothing
+        // queued gets served until a connection finishes
+        connections.cancel();
+        let patience = if shutdown.is_cancelled() {
+            crate::shutdown::drain_period()
+        } else {
+            CANCEL_GRACE
+        };
+        if tokio::time::timeout(patience, &mut finished).await.is_err() {
+            crate::warn!(
+                "Stopped waiting for {} connections to finish",
+                active_gauge.get()
+            );
+        }
+    }
+    crate::summary::write_from_env();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::ByteStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    // Holds every connection until told to wind down, counting those it got
+    #[derive(Clone, Default)]
+    struct Hold(Arc<AtomicUsize>);
+
+    impl ConnectionHandler for Hold {
+        async fn handle<S: ByteStream>(&self, _: S, _: Option<SocketAddr>, ctx: Context) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ctx.cancel.cancelled().await
+        }
+    }
+
+    #[tokio::test]
+    async fn shuts_down_with_every_slot_taken() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = AcceptLimits {
+            max_connections: 2,
+            queue_len: 4,
+            when_full: FullPolicy::Reject,
+            busy_message: None,
+            reap: ReapPolicy::Never,
+            reap_min_idle: Duration::from_secs(1),
+            max_connections_per_ip: None,
+        };
+        let shutdown = CancellationToken::new();
+        let scope = Scope::new("accept-test", addr.port());
+        let admission = Admission::new(&scope, limits);
+        let hold = Hold::default();
+        let acceptor = tokio::spawn({
+            let (shutdown, hold) = (shutdown.clone(), hold.clone());
+            async move { run_acceptor(listener, &scope, admission, shutdown, hold).await }
+        });
+
+        // Two in the slots and one queued behind them
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        while hold.0.load(Ordering::SeqCst) < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(3), acceptor)
+            .await
+            .expect("shutdown hung with every slot taken")
+            .unwrap();
+    }
+}
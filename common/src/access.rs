@@ -0,0 +1,317 @@
+// IP allow and deny lists, checked for every accepted connection.
+//
+// ACCESS_LIST_FILE names a file of "allow <cidr>" and "deny <cidr>" lines
+// (bare addresses are single-host ranges, '#' starts a comment). A range with
+// bits set past its prefix length, like 10.0.0.1/8, is taken for a typo and
+// refused rather than widened. A connection from a denied range is refused,
+// and if there are any allow lines, so is one from outside all of them.
+// Sending the process SIGHUP reloads the file, so a misbehaving scanner can be
+// shut out without a restart; a file that no longer parses is reported and
+// the previous lists are kept.
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Once, RwLock};
+
+use crate::hooks::ConnectionHook;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+// `ip` with everything past the first `prefix` bits cleared
+fn masked(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.network.is_ipv4() && masked(ip, self.prefix) == self.network
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let parsed =
+            IpAddr::from_str(addr).map_err(|e| format!("invalid address {:?}: {}", addr, e))?;
+        let max = if parsed.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("invalid prefix length {:?}", p))?,
+            None => max,
+        };
+        // An IPv4-mapped range is the IPv4 range it maps, as long as it
+        // doesn't reach past the mapped addresses
+        let (network, prefix) = match parsed.to_canonical() {
+            IpAddr::V4(v4) if parsed.is_ipv6() => match prefix.checked_sub(96) {
+                Some(prefix) => (IpAddr::V4(v4), prefix),
+                None => {
+                    return Err(format!(
+                        "prefix length {} reaches past the IPv4-mapped addresses",
+                        prefix
+                    ))
+                }
+            },
+            network => (network, prefix),
+        };
+        if masked(network, prefix) != network {
+            return Err(format!("{:?} has bits set past its prefix length", s));
+        }
+        Ok(Cidr { network, prefix })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut list = AccessList::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (action, range) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: expected \"allow|deny <cidr>\"", i + 1))?;
+            let range = range
+                .trim()
+                .parse()
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            match action {
+                "allow" => list.allow.push(range),
+                "deny" => list.deny.push(range),
+                other => return Err(format!("line {}: unknown action {:?}", i + 1, other)),
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|r| r.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|r| r.contains(ip))
+    }
+}
+
+struct AccessHook {
+    list: Arc<RwLock<AccessList>>,
+}
+
+impl ConnectionHook for AccessHook {
+    fn on_connect(&self, peer: SocketAddr) -> bool {
+        let admitted = self
+            .list
+            .read()
+            .unwrap_or_else(|e| panic!("Error locking access list: {}", e))
+            .admits(peer.ip());
+        if !admitted {
+            crate::info!("{} is not allowed to connect", peer.ip());
+        }
+        admitted
+    }
+}
+
+pub(crate) fn load(path: &str) -> Result<AccessList, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    AccessList::parse(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Enforce ACCESS_LIST_FILE, if it is set, reloading it on SIGHUP. Only the
+// first call in a process does anything.
+pub fn spawn_from_env() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        let Some(path) = crate::env::var::<String>("ACCESS_LIST_FILE") else {
+            return;
+        };
+        let list = crate::report::startup("load access list", load(&path));
+        let list = Arc::new(RwLock::new(list));
+        crate::hooks::register(AccessHook { list: list.clone() });
+        #[cfg(unix)]
+        tokio::spawn(reload_on_hangup(path, list));
+    });
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(path: String, list: Arc<RwLock<AccessList>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            crate::warn!(
+                "Couldn't listen for SIGHUP, access list won't reload: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match load(&path) {
+            Ok(reloaded) => {
+                crate::info!("Reloaded access list from {}", path);
+                *list
+                    .write()
+                    .unwrap_or_else(|e| panic!("Error locking access list: {}", e)) = reloaded;
+            }
+            Err(e) => {
+                crate::warn!("Keeping the previous access list: {}", e);
+                crate::report::report(
+                    "config",
+                    &format!("Couldn't reload access list: {}", e),
+                    &[],
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges_contain_their_addresses() {
+        let range = cidr("10.1.0.0/16");
+        assert!(range.contains(ip("10.1.0.0")));
+        assert!(range.contains(ip("10.1.255.255")));
+        assert!(!range.contains(ip("10.2.0.0")));
+        assert!(!range.contains(ip("10.0.255.255")));
+        // IPv4-mapped addresses are the IPv4 addresses they map
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("::ffff:10.2.2.3")));
+    }
+
+    #[test]
+    fn ipv6_ranges_contain_their_addresses() {
+        let range = cidr("2001:db8::/32");
+        assert!(range.contains(ip("2001:db8::1")));
+        assert!(range.contains(ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!range.contains(ip("2001:db9::")));
+        // Nor does a family contain the other's addresses
+        assert!(!range.contains(ip("32.1.13.184")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn zero_prefixes_contain_the_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(cidr("0.0.0.0/0").contains(ip("0.0.0.0")));
+        assert!(cidr("::/0").contains(ip("ffff::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn full_prefixes_and_bare_addresses_are_single_hosts() {
+        for range in ["192.0.2.7/32", "192.0.2.7"] {
+            assert!(cidr(range).contains(ip("192.0.2.7")));
+            assert!(!cidr(range).contains(ip("192.0.2.6")));
+            assert!(!cidr(range).contains(ip("192.0.2.8")));
+        }
+        for range in ["2001:db8::7/128", "2001:db8::7"] {
+            assert!(cidr(range).contains(ip("2001:db8::7")));
+            assert!(!cidr(range).contains(ip("2001:db8::6")));
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped_ranges_are_their_ipv4_ranges() {
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("::ffff:10.0.0.1"), cidr("10.0.0.1/32"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.9.9.9")));
+        assert!("::ffff:0.0.0.0/95".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn malformed_ranges_are_rejected() {
+        for range in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "10.0.0.256",
+            "example.com",
+            "",
+            // Host bits set
+            "10.0.0.1/8",
+            "10.0.0.1/31",
+            "2001:db8::1/64",
+            "::ffff:10.0.0.1/104",
+        ] {
+            assert!(range.parse::<Cidr>().is_err(), "{:?} parsed", range);
+        }
+    }
+
+    #[test]
+    fn denying_wins_over_allowing() {
+        let list = AccessList::parse(
+            "allow 10.0.0.0/8\n\
+             deny 10.1.0.0/16 # except this one\n\
+             allow 10.1.2.3\n",
+        )
+        .unwrap();
+        assert!(list.admits(ip("10.2.0.1")));
+        assert!(!list.admits(ip("10.1.0.1")));
+        assert!(!list.admits(ip("10.1.2.3")));
+        // Allow lines shut out everything else
+        assert!(!list.admits(ip("192.0.2.1")));
+        assert!(!list.admits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn deny_lines_alone_admit_everything_else() {
+        let list =
+            AccessList::parse("# scanners\n\ndeny 192.0.2.0/24\ndeny 2001:db8::/32\n").unwrap();
+        assert!(!list.admits(ip("192.0.2.200")));
+        assert!(!list.admits(ip("::ffff:192.0.2.200")));
+        assert!(!list.admits(ip("2001:db8::1")));
+        assert!(list.admits(ip("192.0.3.1")));
+        assert!(list.admits(ip("2001:db9::1")));
+        assert!(AccessList::parse("").unwrap().admits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn bad_lines_are_reported_by_number() {
+        let e = AccessList::parse("allow 10.0.0.0/8\npermit 10.0.0.0/8\n").unwrap_err();
+        assert!(e.starts_with("line 2:"), "{}", e);
+        let e = AccessList::parse("deny\n").unwrap_err();
+        assert!(e.starts_with("line 1:"), "{}", e);
+        let e = AccessList::parse("\n\ndeny 10.0.0.0/33\n").unwrap_err();
+        assert!(e.starts_with("line 3:"), "{}", e);
+    }
+}
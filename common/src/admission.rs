@@ -0,0 +1,291 @@
+// Admission shared by every listener of a problem.
+//
+// The TCP accept loop queues connections before serving them; the other
+// listeners (QUIC, WebSocket, LRCP, ISL and the Unix socket) serve theirs
+// straight away. Either way a connection is let in here: the connection
+// hooks, and so the access lists, see it, it takes a slot in its address's
+// MAX_CONNECTIONS_PER_IP, and it takes one in the MAX_CONNECTIONS budget all
+// of the problem's listeners share. A listener without a queue closes a
+// connection that finds the budget spent. Unix socket connections have no
+// address, so only the budget applies to them.
+//
+// A failed accept that isn't about the one connection being accepted (running
+// out of file descriptors or memory, say) would fail again straight away, so
+// every listener backs off before the next one: exponentially, from
+// ACCEPT_BACKOFF_INITIAL to ACCEPT_BACKOFF_MAX, until a connection is
+// accepted again.
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::accept::AcceptLimits;
+use crate::handler::{self, ByteStream, ConnectionHandler, Context};
+use crate::hooks::DisconnectReason;
+use crate::metrics::{Counter, Gauge, Scope};
+use crate::per_ip::{IpSlot, PerIpLimit};
+use crate::retry::Backoff;
+
+const ACCEPT_BACKOFF_INITIAL: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct Admission(Arc<Inner>);
+
+struct Inner {
+    scope: Scope,
+    limits: AcceptLimits,
+    budget: Arc<Semaphore>,
+    per_ip: Option<Arc<PerIpLimit>>,
+    backoff: Backoff,
+    accepted: Counter,
+    rejected: Counter,
+    refused: Counter,
+    over_ip_limit: Counter,
+    panicked: Counter,
+    accept_errors: Counter,
+    active: Gauge,
+}
+
+// A connection let in by a listener without a queue. It holds its slots until
+// dropped, and then tells the hooks it is gone.
+pub struct Admitted {
+    admission: Admission,
+    peer: Option<SocketAddr>,
+    accepted_at: Instant,
+    reason: DisconnectReason,
+    _ip_slot: Option<IpSlot>,
+    _permit: OwnedSemaphorePermit,
+}
+
+// Accept failures that closing a connection can fix (EMFILE, ENFILE)
+pub(crate) fn out_of_descriptors(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(23 | 24))
+}
+
+// Accept failures that only concern the connection being accepted, so the
+// next accept can go ahead at once
+fn connection_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        ConnectionRefused | ConnectionAborted | ConnectionReset
+    )
+}
+
+impl Admission {
+    pub fn new(scope: &Scope, limits: AcceptLimits) -> Self {
+        // The access lists are a hook, so in place before anything connects
+        crate::access::spawn_from_env();
+        Admission(Arc::new(Inner {
+            scope: scope.clone(),
+            limits,
+            budget: Arc::new(Semaphore::new(limits.max_connections)),
+            per_ip: limits
+                .max_connections_per_ip
+                .map(|max| PerIpLimit::new(max, scope)),
+            backoff: Backoff::new(ACCEPT_BACKOFF_INITIAL, ACCEPT_BACKOFF_MAX),
+            accepted: scope.counter("connections_accepted_total", "Connections accepted"),
+            rejected: scope.counter(
+                "connections_rejected_total",
+                "Connections closed because the accept queue was full",
+            ),
+            refused: scope.counter(
+                "connections_refused_total",
+                "Connections closed because a connection hook refused them",
+            ),
+            over_ip_limit: scope.counter(
+                "connections_over_ip_limit_total",
+                "Connections closed because their address had too many open",
+            ),
+            panicked: scope.counter(
+                "connections_panicked_total",
+                "Connections whose handler panicked",
+            ),
+            accept_errors: scope.counter("accept_errors_total", "Failed accept calls"),
+            active: scope.gauge("connections_active", "Connections being served"),
+        }))
+    }
+
+    pub fn limits(&self) -> AcceptLimits {
+        self.0.limits
+    }
+
+    pub(crate) fn budget(&self) -> Arc<Semaphore> {
+        self.0.budget.clone()
+    }
+
+    pub(crate) fn active(&self) -> Gauge {
+        self.0.active.clone()
+    }
+
+    pub(crate) fn rejected(&self) -> Counter {
+        self.0.rejected.clone()
+    }
+
+    pub(crate) fn panicked(&self) -> Counter {
+        self.0.panicked.clone()
+    }
+
+    pub(crate) fn accept_errors(&self) -> Counter {
+        self.0.accept_errors.clone()
+    }
+
+    // Ask the hooks about a connection from `peer`, then take a slot for its
+    // address. None if it was turned away; otherwise the slot, if there's a
+    // per-IP limit.
+    pub(crate) fn check(&self, peer: SocketAddr, accepted_at: Instant) -> Option<Option<IpSlot>> {
+        self.0.accepted.inc();
+        if !crate::hooks::connect(peer) {
+            crate::info!("Connection from {:?} refused by a hook", peer);
+            self.0.refused.inc();
+            crate::hooks::disconnect(peer, DisconnectReason::Refused, accepted_at.elapsed());
+            return None;
+        }
+        let Some(per_ip) = &self.0.per_ip else {
+            return Some(None);
+        };
+        match per_ip.acquire(peer.ip()) {
+            Some(slot) => Some(Some(slot)),
+            None => {
+                crate::info!("Too many connections from {}, closing", peer.ip());
+                self.0.over_ip_limit.inc();
+                crate::hooks::disconnect(peer, DisconnectReason::PerIpLimit, accepted_at.elapsed());
+                None
+            }
+        }
+    }
+
+    // Let in a connection from `peer` if there's room for it
+    pub fn admit(&self, peer: Option<SocketAddr>) -> Option<Admitted> {
+        let accepted_at = Instant::now();
+        let ip_slot = match peer {
+            Some(peer) => self.check(peer, accepted_at)?,
+            None => {
+                self.0.accepted.inc();
+                None
+            }
+        };
+        let Ok(permit) = self.0.budget.clone().try_acquire_owned() else {
+            crate::info!("No room for another connection, closing {:?}", peer);
+            self.0.rejected.inc();
+            if let Some(peer) = peer {
+                crate::hooks::disconnect(peer, DisconnectReason::QueueFull, accepted_at.elapsed());
+            }
+            return None;
+        };
+        self.0.active.inc();
+        Some(Admitted {
+            admission: self.clone(),
+            peer,
+            accepted_at,
+            reason: DisconnectReason::Closed,
+            _ip_slot: ip_slot,
+            _permit: permit,
+        })
+    }
+
+    // Count and log a failed accept on `what`, and say how long to wait
+    // before the next one. `failures` counts them in a row; the caller sets
+    // it back to 0 when an accept succeeds.
+    pub fn accept_failed(&self, what: &str, e: &std::io::Error, failures: &mut u32) -> Duration {
+        crate::warn!("Couldn't accept {}: {:?}", what, e);
+        self.0.accept_errors.inc();
+        if connection_error(e) {
+            return Duration::ZERO;
+        }
+        *failures += 1;
+        self.0.backoff.delay(*failures)
+    }
+}
+
+impl Admitted {
+    // Serve the connection with `handler` in its own task, keeping its slots
+    // until the handler is done. The task returned finishes once they are
+    // given back.
+    pub fn spawn<H: ConnectionHandler, S: ByteStream>(
+        mut self,
+        handler: &H,
+        stream: S,
+        ctx: Context,
+    ) -> JoinHandle<()> {
+        ctx.task.set_problem(self.admission.0.scope.problem());
+        let connection = handler::spawn(handler, stream, self.peer, ctx);
+        tokio::spawn(async move {
+            let reason = match connection.await {
+                Err(e) if e.is_panic() => {
+                    self.admission.0.panicked.inc();
+                    let peer = self
+                        .peer
+                        .map_or_else(|| "local".to_owned(), |p| p.to_string());
+                    crate::report::report_panic(&self.admission.0.scope, &peer, &*e.into_panic());
+                    DisconnectReason::Panicked
+                }
+                _ => DisconnectReason::Closed,
+            };
+            self.reason = reason;
+            drop(self);
+        })
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.admission.0.active.dec();
+        if let Some(peer) = self.peer {
+            crate::hooks::disconnect(peer, self.reason, self.accepted_at.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(limits: AcceptLimits) -> Admission {
+        Admission::new(&Scope::new("admission-test", 0), limits)
+    }
+
+    #[test]
+    fn a_full_budget_turns_connections_away_until_one_leaves() {
+        let admission = admission(AcceptLimits {
+            max_connections: 1,
+            ..AcceptLimits::from_env()
+        });
+        let first = admission.admit(None).unwrap();
+        assert!(admission.admit(None).is_none());
+        drop(first);
+        assert!(admission.admit(None).is_some());
+    }
+
+    #[test]
+    fn counts_connections_per_address() {
+        let admission = admission(AcceptLimits {
+            max_connections_per_ip: Some(1),
+            ..AcceptLimits::from_env()
+        });
+        let alice: SocketAddr = "192.0.2.1:1000".parse().unwrap();
+        let bob: SocketAddr = "192.0.2.2:1000".parse().unwrap();
+        let first = admission.admit(Some(alice)).unwrap();
+        assert!(admission.admit(Some(alice)).is_none());
+        assert!(admission.admit(Some(bob)).is_some());
+        drop(first);
+        assert!(admission.admit(Some(alice)).is_some());
+    }
+
+    #[test]
+    fn backs_off_unless_the_failure_was_the_connections_own() {
+        let admission = admission(AcceptLimits::from_env());
+        let mut failures = 0;
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            admission.accept_failed("test", &reset, &mut failures),
+            Duration::ZERO
+        );
+        assert_eq!(failures, 0);
+        let emfile = std::io::Error::from_raw_os_error(24);
+        assert!(admission.accept_failed("test", &emfile, &mut failures) > Duration::ZERO);
+        assert_eq!(failures, 1);
+    }
+}
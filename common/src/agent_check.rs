@@ -0,0 +1,87 @@
+// HAProxy agent-check responder.
+//
+// When AGENT_CHECK_PORT is set, every connection to it gets a single line
+// describing how loaded the server is, in the format HAProxy's `agent-check`
+// expects: the share of free connection slots as a weight, or `drain` once the
+// connection limit is reached, or once the listener has been handed over to
+// another process, so the proxy stops sending new clients.
+use std::net::SocketAddr;
+use std::sync::Once;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+use crate::metrics::Gauge;
+
+fn status(active: i64, limit: usize, draining: bool) -> String {
+    let limit = limit.max(1) as i64;
+    let free = (limit - active).max(0);
+    if free == 0 || draining {
+        return "drain\n".to_owned();
+    }
+    // HAProxy treats a weight of 0% as drain, so never go below 1%
+    let weight = (100 * free / limit).max(1);
+    format!("ready up {}%\n", weight)
+}
+
+pub async fn run_agent_check(addr: SocketAddr, active: Gauge, limit: usize) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            crate::error!("Couldn't start agent-check listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    crate::info!("Answering HAProxy agent checks on {}", addr);
+    let draining = crate::handover::draining();
+
+    loop {
+        match listener.accept().await {
+            Ok((mut socket, _addr)) => {
+                let reply = status(active.get(), limit, *draining.borrow());
+                tokio::spawn(async move {
+                    socket.write_all(reply.as_bytes()).await.unwrap_or(());
+                });
+            }
+            Err(e) => crate::warn!("Couldn't accept agent-check connection: {:?}", e),
+        }
+    }
+}
+
+// Start the agent-check responder on AGENT_CHECK_PORT in the background, if
+// set. Only the first call in a process does anything, so with several
+// problems in one process it reports on the first to start.
+pub fn spawn_from_env(active: Gauge, limit: usize) {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        if let Some(port) = crate::env::var::<u16>("AGENT_CHECK_PORT") {
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            tokio::spawn(run_agent_check(addr, active, limit));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighs_by_free_connection_slots() {
+        assert_eq!(status(0, 100, false), "ready up 100%\n");
+        assert_eq!(status(75, 100, false), "ready up 25%\n");
+        // Never down to 0%, which HAProxy would take for drain
+        assert_eq!(status(999, 1000, false), "ready up 1%\n");
+    }
+
+    #[test]
+    fn drains_when_full() {
+        assert_eq!(status(100, 100, false), "drain\n");
+        assert_eq!(status(150, 100, false), "drain\n");
+        // No limit to speak of
+        assert_eq!(status(1, 0, false), "drain\n");
+    }
+
+    #[test]
+    fn drains_once_handed_over() {
+        assert_eq!(status(0, 100, true), "drain\n");
+    }
+}
@@ -0,0 +1,100 @@
+// Global allocator selection and memory usage reporting.
+//
+// Enabling the `jemalloc` or `mimalloc` feature of a problem crate replaces the
+// system allocator for the whole binary. Setting ALLOC_STATS_SECS makes the
+// server print its memory usage at that interval, which is the only way to
+// watch it during a stress run since there is no metrics endpoint.
+use std::time::Duration;
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("features `jemalloc` and `mimalloc` are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[derive(Debug)]
+pub struct AllocStats {
+    // Bytes handed out to the program, only known when using jemalloc
+    pub allocated: Option<usize>,
+    // Bytes of physical memory mapped by the process
+    pub resident: Option<usize>,
+}
+
+pub fn allocator_name() -> &'static str {
+    if cfg!(feature = "jemalloc") {
+        "jemalloc"
+    } else if cfg!(feature = "mimalloc") {
+        "mimalloc"
+    } else {
+        "system"
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+pub fn stats() -> AllocStats {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches its statistics until the epoch is advanced
+    if epoch::advance().is_err() {
+        return AllocStats {
+            allocated: None,
+            resident: None,
+        };
+    }
+    AllocStats {
+        allocated: stats::allocated::read().ok(),
+        resident: stats::resident::read().ok(),
+    }
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn stats() -> AllocStats {
+    AllocStats {
+        allocated: None,
+        resident: proc_resident_bytes(),
+    }
+}
+
+// Resident set size as reported by /proc/self/statm (in pages)
+#[cfg(not(feature = "jemalloc"))]
+fn proc_resident_bytes() -> Option<usize> {
+    const PAGE_SIZE: usize = 4096;
+
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * PAGE_SIZE)
+}
+
+fn format_bytes(bytes: Option<usize>) -> String {
+    match bytes {
+        Some(b) => format!("{:.1} MiB", b as f64 / (1024. * 1024.)),
+        None => "n/a".to_owned(),
+    }
+}
+
+// Spawn a task printing memory usage every ALLOC_STATS_SECS seconds, if set
+pub fn spawn_stats_reporter() {
+    let secs: u64 = match crate::env::var("ALLOC_STATS_SECS") {
+        Some(secs) if secs > 0 => secs,
+        _ => return,
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            let s = stats();
+            crate::info!(
+                "Memory usage ({}): allocated {}, resident {}",
+                allocator_name(),
+                format_bytes(s.allocated),
+                format_bytes(s.resident)
+            );
+        }
+    });
+}
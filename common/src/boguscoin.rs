@@ -0,0 +1,102 @@
+// Boguscoin address rewriting, for the problem 5 proxy and chat-side filters.
+//
+// A Boguscoin address is a `7` followed by alphanumeric characters, 26 to 35
+// characters long in total, standing on its own: it must start the line or
+// follow a space, and end the line or be followed by a space. Anything else
+// touching it (punctuation, a longer run of alphanumerics, ...) means it's
+// not an address and is left alone.
+use std::borrow::Cow;
+
+// Where every rewritten address ends up pointing
+pub const TONY_ADDRESS: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+
+const MIN_LEN: usize = 26;
+const MAX_LEN: usize = 35;
+
+pub fn is_address(word: &str) -> bool {
+    (MIN_LEN..=MAX_LEN).contains(&word.len())
+        && word.starts_with('7')
+        && word.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+// Replace every address in `line` (without its newline) with `replacement`.
+// Splitting and joining on single spaces keeps runs of spaces as they were.
+pub fn rewrite<'a>(line: &'a str, replacement: &str) -> Cow<'a, str> {
+    if !line.split(' ').any(is_address) {
+        return Cow::Borrowed(line);
+    }
+    let words: Vec<&str> = line
+        .split(' ')
+        .map(|word| if is_address(word) { replacement } else { word })
+        .collect();
+    Cow::Owned(words.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R: &str = "RRR";
+
+    // A candidate address `len` characters long
+    fn address(len: usize) -> String {
+        format!("7{}", "a".repeat(len - 1))
+    }
+
+    #[test]
+    fn lengths() {
+        assert!(!is_address(&address(25)));
+        assert!(is_address(&address(26)));
+        assert!(is_address(&address(35)));
+        assert!(!is_address(&address(36)));
+    }
+
+    #[test]
+    fn must_start_with_a_seven() {
+        assert!(!is_address(&format!("8{}", "a".repeat(27))));
+        assert!(!is_address(&format!("a7{}", "a".repeat(27))));
+        assert!(is_address(&format!("77{}", "a".repeat(27))));
+    }
+
+    #[test]
+    fn neighbours_must_be_spaces() {
+        let a = address(30);
+        for line in [
+            format!("{}!", a),
+            format!("-{}", a),
+            format!("x{}", a),
+            // One alphanumeric more makes it too long
+            format!("{}x", address(35)),
+            format!("({}) ", a),
+            format!("{},{}", a, a),
+        ] {
+            assert_eq!(rewrite(&line, R), line);
+        }
+    }
+
+    #[test]
+    fn at_the_start_and_end_of_a_line() {
+        let a = address(30);
+        assert_eq!(rewrite(&a, R), R);
+        assert_eq!(rewrite(&format!("{} pay", a), R), "RRR pay");
+        assert_eq!(rewrite(&format!("pay {}", a), R), "pay RRR");
+        assert_eq!(rewrite(&format!("{} or {}", a, a), R), "RRR or RRR");
+    }
+
+    #[test]
+    fn keeps_runs_of_spaces() {
+        let a = address(30);
+        assert_eq!(
+            rewrite(&format!("  pay   {}  now ", a), R),
+            "  pay   RRR  now "
+        );
+        assert_eq!(rewrite(&format!("{}  {}", a, a), R), "RRR  RRR");
+        assert_eq!(rewrite("   ", R), "   ");
+    }
+
+    #[test]
+    fn untouched_lines_are_borrowed() {
+        assert!(matches!(rewrite("no address here", R), Cow::Borrowed(_)));
+        assert!(matches!(rewrite(&address(30), R), Cow::Owned(_)));
+    }
+}
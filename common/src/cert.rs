@@ -0,0 +1,33 @@
+// Certificates for the listeners that encrypt: QUIC and TLS.
+//
+// Each reads a certificate chain and private key from the PEM files named by
+// its own pair of variables, or generates a self-signed certificate for
+// "localhost" at startup if they aren't set.
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+pub(crate) type Certificate = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+pub(crate) fn load(cert_var: &str, key_var: &str) -> Result<Certificate, String> {
+    if let (Some(cert_path), Some(key_path)) = (
+        crate::env::var::<String>(cert_var),
+        crate::env::var::<String>(key_var),
+    ) {
+        let certs = CertificateDer::pem_file_iter(&cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Couldn't read certificates from {}: {}", cert_path, e))?;
+        let key = PrivateKeyDer::from_pem_file(&key_path)
+            .map_err(|e| format!("Couldn't read private key from {}: {}", key_path, e))?;
+        return Ok((certs, key));
+    }
+
+    crate::info!(
+        "{}/{} not set, using a self-signed certificate",
+        cert_var,
+        key_var
+    );
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+        .map_err(|e| format!("Couldn't generate certificate: {}", e))?;
+    let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+    Ok((vec![cert.cert.der().clone()], key.into()))
+}
@@ -0,0 +1,211 @@
+// Where a server listens, and where it reads its configuration from, from
+// the command line.
+//
+// Every binary parses its command line with clap, flattening in `Args` (or,
+// for one that doesn't listen, `ConfigArgs`) beside any options of its own,
+// and hands the result to `init` before doing anything else. --help lists
+// the options, and an unknown option or a value that doesn't parse is a usage
+// error, so the process exits before doing anything else. An option given
+// more than once takes its last value, except the repeatable ones.
+//
+// --bind ADDR (default 0.0.0.0) and --port P (default the binary's own, 39456
+// for the problems) say where to listen. Without them, BIND_ADDR and PORT are
+// looked up like any other variable (see env.rs), which is how hosts such as
+// Fly.io and Railway tell a process where to listen. Each --listen ADDR:PORT
+// adds another address to listen on as well, served the same way; see
+// listeners.rs. --uds PATH, or UDS_PATH, adds a Unix socket; see uds.rs.
+// Everything else a problem can be told goes through --set NAME=VALUE or a
+// --config file.
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+// Shown after each binary's --help
+pub const AFTER_HELP: &str =
+    "Every other option is a variable, set with --set, in the environment or in a --config file.";
+
+// Where a binary reads its configuration; see env.rs, config.rs and dry_run.rs
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct ConfigArgs {
+    /// Set a variable, over the environment and the config file
+    #[arg(long, value_name = "NAME=VALUE")]
+    pub set: Vec<String>,
+    /// Read variables not otherwise set from a file of NAME=VALUE lines
+    /// [default: $CONFIG_FILE]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Print every variable read, with where its value came from, and exit
+    #[arg(long)]
+    pub print_config: bool,
+    /// Check the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+    /// Start up as far as serving, binding and releasing every listener, and
+    /// exit
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+// The options of every server
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct Args {
+    /// Address to listen on [default: $BIND_ADDR or 0.0.0.0]
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<IpAddr>,
+    /// Port to listen on [default: $PORT or the server's own]
+    #[arg(long, value_name = "P")]
+    pub port: Option<u16>,
+    /// Another address to listen on as well
+    #[arg(long, value_name = "ADDR:PORT")]
+    pub listen: Vec<SocketAddr>,
+    /// A Unix socket to listen on as well [default: $UDS_PATH]
+    #[arg(long, value_name = "PATH")]
+    pub uds: Option<PathBuf>,
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+static ARGS: OnceLock<Args> = OnceLock::new();
+
+// Keep the parsed command line for the rest of the process to read
+pub fn init(args: Args) {
+    if ARGS.set(args).is_err() {
+        panic!("Command line read before it was parsed");
+    }
+}
+
+// The same for a binary that doesn't listen
+pub fn init_config(config: ConfigArgs) {
+    init(Args {
+        config,
+        ..Args::default()
+    })
+}
+
+// The parsed command line, or no options at all if nothing was parsed (as in
+// tests)
+pub fn args() -> &'static Args {
+    ARGS.get_or_init(Args::default)
+}
+
+// The address given with --bind or BIND_ADDR, or 0.0.0.0
+pub fn bind_addr() -> IpAddr {
+    args()
+        .bind
+        .unwrap_or_else(|| crate::env::var_or("BIND_ADDR", DEFAULT_BIND))
+}
+
+// The port given with --port or PORT, or `default_port`
+pub fn port(default_port: u16) -> u16 {
+    args()
+        .port
+        .unwrap_or_else(|| crate::env::var_or("PORT", default_port))
+}
+
+// Where to listen: the address from `bind_addr` and the port from `port`
+pub fn listen_addr(default_port: u16) -> SocketAddr {
+    SocketAddr::new(bind_addr(), port(default_port))
+}
+
+// The addresses given with --listen
+pub fn extra_addrs() -> Vec<SocketAddr> {
+    args().listen.clone()
+}
+
+// Every address to listen on: `listen_addr` first, then each --listen
+pub fn listen_addrs(default_port: u16) -> Vec<SocketAddr> {
+    let mut addrs = vec![listen_addr(default_port)];
+    addrs.extend(extra_addrs());
+    addrs
+}
+
+// The one address in `addrs`, for a server that can only listen on one.
+// More than that is a usage error.
+pub fn only_addr(addrs: &[SocketAddr], why: &str) -> SocketAddr {
+    match addrs {
+        [addr] => *addr,
+        _ => {
+            eprintln!("--listen isn't supported {}", why);
+            std::process::exit(1);
+        }
+    }
+}
+
+// The Unix socket path given with --uds or UDS_PATH, if any
+pub fn uds_path() -> Option<PathBuf> {
+    args().uds.clone().or_else(|| crate::env::var("UDS_PATH"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    #[command(args_override_self = true)]
+    struct Cli {
+        #[command(flatten)]
+        args: Args,
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Cli::try_parse_from(std::iter::once("server").chain(args.iter().copied())).map(|c| c.args)
+    }
+
+    #[test]
+    fn parses_every_option() {
+        let args = parse(&[
+            "--bind",
+            "127.0.0.1",
+            "--port=5000",
+            "--listen",
+            "[::1]:6000",
+            "--listen=0.0.0.0:7000",
+            "--uds",
+            "/tmp/s",
+            "--set",
+            "A=1",
+            "--set=B=2",
+            "--config",
+            "c.env",
+            "--print-config",
+            "--check-config",
+            "--dry-run",
+        ])
+        .unwrap();
+        assert_eq!(args.bind, Some(IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(args.port, Some(5000));
+        assert_eq!(
+            args.listen,
+            [
+                "[::1]:6000".parse().unwrap(),
+                "0.0.0.0:7000".parse().unwrap()
+            ]
+        );
+        assert_eq!(args.uds, Some(PathBuf::from("/tmp/s")));
+        assert_eq!(args.config.set, ["A=1", "B=2"]);
+        assert_eq!(args.config.config.as_deref(), Some("c.env"));
+        assert!(args.config.print_config && args.config.check_config && args.config.dry_run);
+    }
+
+    #[test]
+    fn the_last_value_wins() {
+        let args = parse(&["--port", "1", "--bind", "::", "--port", "2"]).unwrap();
+        assert_eq!(args.port, Some(2));
+        assert_eq!(args.bind, Some(IpAddr::from([0u16; 8])));
+    }
+
+    #[test]
+    fn rejects_unknown_options_and_bad_values() {
+        assert!(parse(&["--prot", "1"]).is_err());
+        assert!(parse(&["extra"]).is_err());
+        assert!(parse(&["--port", "70000"]).is_err());
+        assert!(parse(&["--bind", "localhost"]).is_err());
+        assert!(parse(&["--listen", "1.2.3.4"]).is_err());
+        assert!(parse(&["--port"]).is_err());
+        let help = parse(&["--help"]).unwrap_err();
+        assert_eq!(help.kind(), clap::error::ErrorKind::DisplayHelp);
+    }
+}
@@ -0,0 +1,263 @@
+// Validation of the environment a server is configured through.
+//
+// Invalid values are otherwise only warned about and replaced by defaults as
+// each module reads them, which is easy to miss in a deploy. `Checker` looks at
+// everything up front: the variables shared by every problem when it is
+// created, and whatever a problem adds for its own. `finish` then refuses to
+// start on any error, and with --check-config on the command line, reports
+// and exits either way without serving anything.
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+
+// Side listeners besides a problem's main one, by protocol
+pub(crate) const TCP_PORTS: [&str; 5] = [
+    "METRICS_PORT",
+    "AGENT_CHECK_PORT",
+    "PPROF_PORT",
+    "WEBSOCKET_PORT",
+    "ISL_PORT",
+];
+pub(crate) const UDP_PORTS: [&str; 2] = ["QUIC_PORT", "LRCP_PORT"];
+
+pub struct Checker {
+    errors: Vec<String>,
+}
+
+fn raw(name: &str) -> Option<String> {
+    crate::env::lookup(name).map(|(value, _)| value)
+}
+
+impl Checker {
+    // Check the variables handled by common, for a problem on `port`
+    pub fn new(port: u16) -> Self {
+        Self::with_main(&[("main listener", port)], &[])
+    }
+
+    // Same as `new`, for a problem whose main socket is UDP
+    pub fn new_udp(port: u16) -> Self {
+        Self::with_main(&[], &[("main socket", port)])
+    }
+
+    // Same as `new`, for several problems served by one process, each main
+    // socket named after its problem
+    pub fn new_many(tcp_main: &[(&str, u16)], udp_main: &[(&str, u16)]) -> Self {
+        Self::with_main(tcp_main, udp_main)
+    }
+
+    fn with_main(tcp_main: &[(&str, u16)], udp_main: &[(&str, u16)]) -> Self {
+        let mut checker = Checker { errors: Vec::new() };
+
+        let mut tcp = tcp_main.to_vec();
+        for name in TCP_PORTS {
+            if let Some(p) = checker.value::<u16>(name) {
+                tcp.push((name, p));
+            }
+        }
+        let mut udp = udp_main.to_vec();
+        udp.extend(
+            UDP_PORTS
+                .into_iter()
+                .filter_map(|name| Some((name, checker.value::<u16>(name)?))),
+        );
+        checker.distinct_ports("TCP", &tcp);
+        checker.distinct_ports("UDP", &udp);
+
+        checker = checker
+            .parse::<std::net::IpAddr>("BIND_ADDR")
+            .parse::<std::net::IpAddr>("PPROF_ADDR")
+            .parse::<u16>("PORT")
+            .parse::<crate::log::Level>("LOG_LEVEL")
+            .positive("MAX_CONNECTIONS")
+            .positive("MAX_CONNECTIONS_PER_IP")
+            .positive("ACCEPT_QUEUE_LEN")
+            .parse::<crate::accept::FullPolicy>("ACCEPT_WHEN_FULL")
+            .positive("ALLOC_STATS_SECS")
+            .positive("DECODE_ERROR_THRESHOLD")
+            .positive("DECODE_ERROR_WINDOW_SECS")
+            .positive("DISCONNECT_THRESHOLD")
+            .positive("DISCONNECT_WINDOW_SECS")
+            .positive("ACCEPT_ERROR_THRESHOLD")
+            .positive("ACCEPT_ERROR_WINDOW_SECS")
+            .parse::<u64>("MIN_BYTES_PER_SEC")
+            .positive("MIN_THROUGHPUT_WINDOW_SECS")
+            .parse::<u64>("HANDOVER_DRAIN_SECS")
+            .parse::<u64>("SHUTDOWN_DRAIN_SECS")
+            .parse::<usize>("CONNECTION_MEMORY_LIMIT")
+            .parse::<bool>("SANDBOX")
+            .positive("LRCP_RETRANSMIT_MILLIS")
+            .positive("LRCP_SESSION_EXPIRY_SECS")
+            .positive("LRCP_MAX_OUTSTANDING")
+            .positive("LRCP_MAX_SESSIONS")
+            .positive("LRCP_MAX_SESSIONS_PER_SOURCE")
+            .parse::<u64>("CONNECTION_TIMEOUT_SECS")
+            .positive("CONNECTION_CONCURRENCY")
+            .positive("CONNECTION_RATE")
+            .positive("STALL_SECS")
+            .parse::<crate::accept::ReapPolicy>("REAP_POLICY")
+            .parse::<u64>("REAP_MIN_IDLE_MILLIS")
+            .parse::<usize>("DNS_CACHE_SIZE")
+            .positive("DNS_TIMEOUT_MILLIS")
+            .parse::<u64>("UDP_AMPLIFICATION_FACTOR")
+            .parse::<u64>("UDP_UNVERIFIED_ALLOWANCE")
+            .parse::<u64>("UDP_RESPONSE_BYTES_PER_SEC")
+            .positive("UDP_MAX_SOURCES")
+            .positive("LISTEN_BACKLOG")
+            .parse::<u32>("SOCKET_RCVBUF")
+            .parse::<u32>("SOCKET_SNDBUF")
+            .file("ACCESS_LIST_FILE")
+            .parent_dir("HANDOVER_SOCKET")
+            .parent_dir("UDS_PATH");
+
+        if let Some(loss) = checker.value::<f64>("LRCP_LOSS") {
+            if !(0.0..=1.0).contains(&loss) {
+                checker.error(format!("LRCP_LOSS={} is not between 0 and 1", loss));
+            }
+        }
+        if let Some(url) = raw("ERROR_WEBHOOK_URL") {
+            if !url.starts_with("http://") {
+                checker.error(format!("ERROR_WEBHOOK_URL={:?} is not an http:// URL", url));
+            }
+        }
+        if let Some(path) = raw("ACCESS_LIST_FILE").filter(|p| Path::new(p).is_file()) {
+            if let Err(e) = crate::access::load(&path) {
+                checker.error(format!("ACCESS_LIST_FILE {}", e));
+            }
+        }
+        match (raw("QUIC_CERT"), raw("QUIC_KEY")) {
+            (Some(_), None) => checker.error("QUIC_CERT is set but QUIC_KEY isn't".to_owned()),
+            (None, Some(_)) => checker.error("QUIC_KEY is set but QUIC_CERT isn't".to_owned()),
+            _ => {}
+        }
+        checker = checker.file("QUIC_CERT").file("QUIC_KEY");
+        #[cfg(feature = "sandbox")]
+        {
+            checker = checker.parse::<crate::sandbox::Syscalls>("SANDBOX_SYSCALLS");
+        }
+        if raw("SHUTDOWN_REPORT").as_deref() != Some("-") {
+            checker = checker.parent_dir("SHUTDOWN_REPORT");
+        }
+        checker
+    }
+
+    // Check only what is added, for a tool that doesn't serve anything
+    pub fn bare() -> Self {
+        Checker { errors: Vec::new() }
+    }
+
+    // Problems served together check some of the same variables, so each
+    // error is reported once
+    fn error(&mut self, message: String) {
+        if !self.errors.contains(&message) {
+            self.errors.push(message);
+        }
+    }
+
+    fn value<T: FromStr>(&mut self, name: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        let value = raw(name)?;
+        match value.parse() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.error(format!("{}={:?} is invalid: {}", name, value, e));
+                None
+            }
+        }
+    }
+
+    fn distinct_ports(&mut self, protocol: &str, ports: &[(&str, u16)]) {
+        for (i, (a, port)) in ports.iter().enumerate() {
+            if let Some((b, _)) = ports[..i].iter().find(|(_, p)| p == port) {
+                self.error(format!(
+                    "{} and {} both use {} port {}",
+                    b, a, protocol, port
+                ));
+            }
+        }
+    }
+
+    // `name`, if set, must parse as a T
+    pub fn parse<T: FromStr>(mut self, name: &str) -> Self
+    where
+        T::Err: Display,
+    {
+        self.value::<T>(name);
+        self
+    }
+
+    // `name`, if set, must be a number above zero
+    pub fn positive(mut self, name: &str) -> Self {
+        if self.value::<u64>(name) == Some(0) {
+            self.error(format!("{} must be at least 1", name));
+        }
+        self
+    }
+
+    // `min` can't be set above `max`; either falls back to `default_min` or
+    // `default_max` when unset
+    pub fn ordered<T: FromStr + PartialOrd + Display>(
+        mut self,
+        min: &str,
+        max: &str,
+        default_min: T,
+        default_max: T,
+    ) -> Self
+    where
+        T::Err: Display,
+    {
+        let low = self.value::<T>(min).unwrap_or(default_min);
+        let high = self.value::<T>(max).unwrap_or(default_max);
+        if low > high {
+            self.error(format!("{}={} is above {}={}", min, low, max, high));
+        }
+        self
+    }
+
+    // `name`, if set, must be a readable file
+    pub fn file(mut self, name: &str) -> Self {
+        if let Some(path) = raw(name) {
+            if let Err(e) = std::fs::File::open(&path) {
+                self.error(format!("{}={:?} can't be read: {}", name, path, e));
+            }
+        }
+        self
+    }
+
+    // `name`, if set, must be a path whose directory exists
+    pub fn parent_dir(mut self, name: &str) -> Self {
+        if let Some(path) = raw(name) {
+            let dir = Path::new(&path)
+                .parent()
+                .filter(|d| !d.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            if !dir.is_dir() {
+                self.error(format!(
+                    "{}={:?} is in a directory that doesn't exist",
+                    name, path
+                ));
+            }
+        }
+        self
+    }
+
+    // Report the result; exits if there are errors or --check-config was given
+    pub fn finish(self) {
+        let check_only = crate::cli::args().config.check_config;
+        for e in &self.errors {
+            eprintln!("Configuration error: {}", e);
+        }
+        if !self.errors.is_empty() && check_only {
+            std::process::exit(1);
+        }
+        if !self.errors.is_empty() {
+            let message = format!("{} configuration error(s)", self.errors.len());
+            crate::report::startup::<(), _>("validate configuration", Err(message));
+        }
+        if check_only {
+            println!("Configuration OK");
+            std::process::exit(0);
+        }
+    }
+}
@@ -0,0 +1,13 @@
+// tokio-console instrumentation.
+//
+// Starts the console-subscriber gRPC server (port 6669 by default, or
+// TOKIO_CONSOLE_BIND) so `tokio-console` can show live task states, poll
+// times and wakeups. Tokio only emits the task events it needs when built with
+// RUSTFLAGS="--cfg tokio_unstable".
+#[cfg(not(tokio_unstable))]
+compile_error!("the `console` feature needs RUSTFLAGS=\"--cfg tokio_unstable\"");
+
+// Must be called from inside the runtime, before spawning the tasks to watch
+pub fn init() {
+    console_subscriber::init();
+}
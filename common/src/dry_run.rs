@@ -0,0 +1,137 @@
+// Rehearsal of startup without serving anything.
+//
+// With --dry-run on the command line, a server starts up as usual until the
+// point where it would accept its first connection, then exits instead. On
+// the way every listener configured through the environment is bound and
+// released, and files the server will write later are opened, so a host can
+// be checked for busy ports, missing permissions and bad paths before traffic
+// is switched to it. Nothing is taken over through HANDOVER_SOCKET in a dry
+// run: the main port has to be free.
+use std::fs::OpenOptions;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn requested() -> bool {
+    crate::cli::args().config.dry_run
+}
+
+fn failures() -> &'static Mutex<Vec<String>> {
+    static FAILURES: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+    FAILURES.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+fn fail(message: String) {
+    eprintln!("Dry run: {}", message);
+    failures()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking dry run failures: {}", e))
+        .push(message);
+}
+
+fn port(name: &str) -> Option<u16> {
+    crate::env::var(name)
+}
+
+// Bind and release the main listeners on `main` and every side listener with
+// a port set. Startup can't go on if any of them fail, so neither does the
+// dry run.
+pub fn bind_listeners(main: &[SocketAddr]) {
+    let main: Vec<_> = main.iter().map(|&addr| ("main listener", addr)).collect();
+    bind_all(&main, &[]);
+}
+
+// Same as `bind_listeners`, for a server whose main socket is UDP
+pub fn bind_udp_listeners(main: SocketAddr) {
+    bind_all(&[], &[("main socket", main)]);
+}
+
+// Same as `bind_listeners`, for several problems served by one process, each
+// main socket named after its problem
+pub fn bind_many_listeners(tcp_main: &[(&str, SocketAddr)], udp_main: &[(&str, SocketAddr)]) {
+    bind_all(tcp_main, udp_main);
+}
+
+// Side listeners always bind every interface
+fn side(name: &str) -> Option<(&str, SocketAddr)> {
+    Some((name, SocketAddr::from(([0, 0, 0, 0], port(name)?))))
+}
+
+fn bind_all(tcp_main: &[(&str, SocketAddr)], udp_main: &[(&str, SocketAddr)]) {
+    if !requested() {
+        return;
+    }
+    let mut tcp = tcp_main.to_vec();
+    tcp.extend(crate::config::TCP_PORTS.into_iter().filter_map(side));
+    for (name, addr) in tcp {
+        if let Err(e) = TcpListener::bind(addr) {
+            fail(format!("{} can't bind TCP {}: {}", name, addr, e));
+        }
+    }
+    let mut udp = udp_main.to_vec();
+    udp.extend(crate::config::UDP_PORTS.into_iter().filter_map(side));
+    for (name, addr) in udp {
+        if let Err(e) = UdpSocket::bind(addr) {
+            fail(format!("{} can't bind UDP {}: {}", name, addr, e));
+        }
+    }
+    if let Some(path) = crate::env::var::<String>("SHUTDOWN_REPORT").filter(|p| p != "-") {
+        writable("SHUTDOWN_REPORT", Path::new(&path));
+    }
+    let failed = !failures()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking dry run failures: {}", e))
+        .is_empty();
+    if failed {
+        finish();
+    }
+}
+
+// Check that `path` can be opened for appending, for what `what` will write
+// there. A file that didn't exist is removed again.
+pub fn writable(what: &str, path: &Path) {
+    if !requested() {
+        return;
+    }
+    let existed = path.exists();
+    match OpenOptions::new().append(true).create(true).open(path) {
+        Ok(_) if !existed => std::fs::remove_file(path).unwrap_or(()),
+        Ok(_) => {}
+        Err(e) => fail(format!("{} can't write to {}: {}", what, path.display(), e)),
+    }
+}
+
+// Check that `what` can reach `addr`
+pub fn reachable(what: &str, addr: &str) {
+    if !requested() {
+        return;
+    }
+    let connected = std::net::ToSocketAddrs::to_socket_addrs(addr).and_then(|mut addrs| {
+        let addr = addrs
+            .next()
+            .ok_or_else(|| std::io::Error::other("no addresses"))?;
+        TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+    });
+    if let Err(e) = connected {
+        fail(format!("{} can't connect to {}: {}", what, addr, e));
+    }
+}
+
+// End a dry run here, where serving would begin
+pub fn finish() {
+    if !requested() {
+        return;
+    }
+    let failures = failures()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking dry run failures: {}", e));
+    if failures.is_empty() {
+        println!("Dry run OK");
+        std::process::exit(0);
+    }
+    eprintln!("Dry run failed with {} problem(s)", failures.len());
+    std::process::exit(1);
+}
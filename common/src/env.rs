@@ -0,0 +1,164 @@
+// Runtime options, looked up by name through layers of configuration.
+//
+// The first layer to set a variable wins:
+//   --set NAME=VALUE on the command line (repeatable)
+//   the environment
+//   a file of NAME=VALUE lines ('#' starts a comment), named by
+//     --config PATH on the command line or else CONFIG_FILE
+//   the default the code reading the variable falls back to
+// Every lookup is recorded with the layer it came from, and with
+// --print-config on the command line a server starts up as far as the point
+// where it would accept its first connection, prints each value it read and
+// where it came from, and exits.
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    CommandLine,
+    Environment,
+    File(String),
+    Default,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::CommandLine => write!(f, "command line"),
+            Source::Environment => write!(f, "environment"),
+            Source::File(path) => write!(f, "file {}", path),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+fn parse_assignments(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut values = BTreeMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected NAME=VALUE", i + 1))?;
+        values.insert(name.trim().to_owned(), value.trim().to_owned());
+    }
+    Ok(values)
+}
+
+// Configuration that can't be read stops startup. This can't go through
+// report::startup, which itself looks up where to send the report.
+fn or_exit<T>(what: &str, result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Couldn't {}: {}", what, e);
+        std::process::exit(1);
+    })
+}
+
+fn command_line() -> &'static BTreeMap<String, String> {
+    static VALUES: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+    VALUES.get_or_init(|| {
+        let assignments = crate::cli::args().config.set.join("\n");
+        or_exit("read --set options", parse_assignments(&assignments))
+    })
+}
+
+fn file() -> &'static Option<(String, BTreeMap<String, String>)> {
+    static FILE: OnceLock<Option<(String, BTreeMap<String, String>)>> = OnceLock::new();
+    FILE.get_or_init(|| {
+        let path = crate::cli::args()
+            .config
+            .config
+            .clone()
+            .or_else(|| std::env::var("CONFIG_FILE").ok())?;
+        let values = std::fs::read_to_string(&path)
+            .map_err(|e| format!("{}: {}", path, e))
+            .and_then(|text| parse_assignments(&text).map_err(|e| format!("{}: {}", path, e)));
+        Some((path.clone(), or_exit("read config file", values)))
+    })
+}
+
+// The raw value of `name` and where it came from, without recording the lookup
+pub fn lookup(name: &str) -> Option<(String, Source)> {
+    if let Some(value) = command_line().get(name) {
+        return Some((value.clone(), Source::CommandLine));
+    }
+    if let Ok(value) = std::env::var(name) {
+        return Some((value, Source::Environment));
+    }
+    let (path, values) = file().as_ref()?;
+    let value = values.get(name)?;
+    Some((value.clone(), Source::File(path.clone())))
+}
+
+// Each variable read so far, with its value and where that came from (None
+// when it isn't set anywhere and the code has no default)
+type Effective = BTreeMap<String, (String, Option<Source>)>;
+
+fn effective() -> &'static Mutex<Effective> {
+    static EFFECTIVE: OnceLock<Mutex<Effective>> = OnceLock::new();
+    EFFECTIVE.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+fn record(name: &str, value: String, source: Option<Source>) {
+    effective()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking effective configuration: {}", e))
+        .insert(name.to_owned(), (value, source));
+}
+
+// Parse a variable, warning about (and ignoring) invalid values
+pub fn var<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    let Some((value, source)) = lookup(name) else {
+        record(name, "(unset)".to_owned(), None);
+        return None;
+    };
+    match value.parse() {
+        Ok(v) => {
+            record(name, value, Some(source));
+            Some(v)
+        }
+        Err(e) => {
+            eprintln!(
+                "Ignoring invalid {}={:?} from {}: {}",
+                name, value, source, e
+            );
+            record(name, format!("(ignored invalid {:?})", value), Some(source));
+            None
+        }
+    }
+}
+
+pub fn var_or<T: FromStr + Display>(name: &str, default: T) -> T
+where
+    T::Err: Display,
+{
+    var(name).unwrap_or_else(|| {
+        record(name, default.to_string(), Some(Source::Default));
+        default
+    })
+}
+
+// Print the effective configuration and exit, if --print-config was given
+pub fn print_config() {
+    if !crate::cli::args().config.print_config {
+        return;
+    }
+    let effective = effective()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking effective configuration: {}", e));
+    let width = effective.keys().map(String::len).max().unwrap_or(0);
+    for (name, (value, source)) in effective.iter() {
+        match source {
+            Some(source) => println!("{:width$}  {}  ({})", name, value, source),
+            None => println!("{:width$}  {}", name, value),
+        }
+    }
+    std::process::exit(0);
+}
@@ -0,0 +1,361 @@
+// The read-and-respond loop of a server built on a decoder and an encoder.
+//
+// `serve_framed` decodes requests off a connection and hands them to a
+// `FramedHandler`, which queues the responses to send. Responses are encoded
+// into one reused buffer and written out together once no other complete
+// request is buffered, each as its own slice of a vectored write, so a
+// pipelined burst is answered with a single writev. A request that fails to
+// decode ends the connection, after whatever the handler has to say about it,
+// and so does the client closing its side. A handler can also have answers
+// finished in the background, which are sent as they come, and can stop
+// reading while too many of them are outstanding. A handler with a memory
+// account has the read and write buffers charged to it, and the connection is
+// closed once they take it over the limit.
+use bytes::BytesMut;
+use futures::StreamExt;
+use std::future::Future;
+use std::io::{self, IoSlice};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+use crate::handler::{ByteStream, Context};
+use crate::memory::Account;
+
+// Most frames handed to a single writev
+const MAX_IOVECS: usize = 64;
+
+// Whether to keep serving after a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    // Send what was queued, then close
+    Close,
+}
+
+pub trait FramedHandler<D: Decoder>: Send {
+    type Response: Send;
+
+    // Handle one request, queueing any responses on `out`
+    fn request(
+        &mut self,
+        request: D::Item,
+        out: &mut Vec<Self::Response>,
+    ) -> impl Future<Output = Flow> + Send;
+
+    // Queue what to send before closing on a request that didn't decode.
+    // `decoder` and `unread`, the bytes buffered after the bad request, are
+    // there to be inspected.
+    fn malformed(
+        &mut self,
+        error: D::Error,
+        decoder: &mut D,
+        unread: &[u8],
+        out: &mut Vec<Self::Response>,
+    ) -> impl Future<Output = ()> + Send;
+
+    // Queue what to send once the client has closed its side
+    fn finish(&mut self, _out: &mut Vec<Self::Response>) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    // The next answer finished in the background, or None if there is none
+    // outstanding
+    fn answer(&mut self) -> impl Future<Output = Option<Self::Response>> + Send {
+        std::future::pending()
+    }
+
+    // Whether to stop reading requests until answers catch up
+    fn backlogged(&self) -> bool {
+        false
+    }
+
+    // The account to charge the connection's buffers to, if any
+    fn account(&self) -> Option<&Account> {
+        None
+    }
+}
+
+enum Step<T, E, R> {
+    Request(Option<Result<T, E>>),
+    Answer(R),
+}
+
+// Decode the next request out of what is already buffered, without reading
+fn buffered<R, D: Decoder>(requests: &mut FramedRead<R, D>) -> Option<Result<D::Item, D::Error>> {
+    let mut buf = std::mem::take(requests.read_buffer_mut());
+    let decoded = requests.decoder_mut().decode(&mut buf).transpose();
+    *requests.read_buffer_mut() = buf;
+    decoded
+}
+
+// Write out `buf`, which holds frames ending at each of `ends`, handing them
+// to writev together instead of writing one after another
+pub async fn write_frames<W: AsyncWrite + Unpin>(
+    wr: &mut W,
+    buf: &[u8],
+    ends: &[usize],
+) -> io::Result<()> {
+    let mut written = 0;
+    // The first frame not yet written in full
+    let mut frame = 0;
+    while written < buf.len() {
+        while ends[frame] <= written {
+            frame += 1;
+        }
+        let mut slices = [IoSlice::new(&[]); MAX_IOVECS];
+        let mut start = written;
+        let mut count = 0;
+        for (slice, &end) in slices.iter_mut().zip(&ends[frame..]) {
+            *slice = IoSlice::new(&buf[start..end]);
+            start = end;
+            count += 1;
+        }
+        match wr.write_vectored(&slices[..count]).await? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => written += n,
+        }
+    }
+    Ok(())
+}
+
+// Serve `stream` with `handler` until either side closes it or `ctx` is
+// cancelled
+pub async fn serve_framed<S, D, E, H>(
+    stream: S,
+    decoder: D,
+    mut encoder: E,
+    mut handler: H,
+    ctx: &Context,
+) where
+    S: ByteStream,
+    D: Decoder + Send,
+    D::Item: Send,
+    D::Error: Send,
+    E: Encoder<H::Response> + Send,
+    H: FramedHandler<D>,
+{
+    let (rd, mut wr) = tokio::io::split(stream);
+    let mut requests = FramedRead::new(rd, decoder);
+    // Responses encoded and not yet written, and where each one ends
+    let mut responses = BytesMut::new();
+    let mut ends = Vec::new();
+    let mut buffers = handler.account().map(Account::hold);
+    let mut out = Vec::new();
+    // A request decoded from the buffer while deciding whether to write
+    let mut next = None;
+    loop {
+        let step = match next.take() {
+            Some(request) => Step::Request(Some(request)),
+            None => {
+                ctx.task.phase("reading request");
+                tokio::select! {
+                    request = requests.next(), if !handler.backlogged() => Step::Request(request),
+                    Some(answer) = handler.answer() => Step::Answer(answer),
+                    _ = ctx.cancel.cancelled() => return,
+                }
+            }
+        };
+
+        ctx.task.phase("handling request");
+        let flow = match step {
+            Step::Answer(answer) => {
+                out.push(answer);
+                Flow::Continue
+            }
+            Step::Request(Some(Ok(request))) => handler.request(request, &mut out).await,
+            Step::Request(Some(Err(error))) => {
+                let unread = std::mem::take(requests.read_buffer_mut());
+                handler
+                    .malformed(error, requests.decoder_mut(), &unread, &mut out)
+                    .await;
+                Flow::Close
+            }
+            Step::Request(None) => {
+                handler.finish(&mut out).await;
+                Flow::Close
+            }
+        };
+        for response in out.drain(..) {
+            // An encoder error doesn't stop the session, and whatever the
+            // encoder wrote before failing is still sent, as FramedWrite did
+            let start = responses.len();
+            encoder.encode(response, &mut responses).unwrap_or(());
+            if responses.len() > start {
+                ends.push(responses.len());
+            }
+        }
+        if let Some(buffers) = &mut buffers {
+            let held = requests.read_buffer().capacity() + responses.capacity();
+            if !buffers.set(held) {
+                crate::info!("Connection buffers over the memory limit, closing");
+                return;
+            }
+        }
+
+        if flow == Flow::Continue && !handler.backlogged() {
+            next = buffered(&mut requests);
+            if next.is_some() {
+                continue;
+            }
+        }
+        ctx.task.phase("writing responses");
+        let flush = async {
+            write_frames(&mut wr, &responses, &ends).await?;
+            wr.flush().await
+        };
+        tokio::select! {
+            flushed = flush => if flushed.is_err() { return },
+            _ = ctx.cancel.cancelled() => return,
+        }
+        responses.clear();
+        ends.clear();
+        if flow == Flow::Close {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::AsyncReadExt;
+    use tokio_util::codec::{LinesCodec, LinesCodecError};
+
+    // Takes at most `limit` bytes a call, and records each call's slices
+    struct Trickle {
+        limit: usize,
+        written: Vec<u8>,
+        calls: Vec<usize>,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            self.calls.push(bufs.len());
+            let mut taken = 0;
+            for buf in bufs {
+                let n = buf.len().min(self.limit - taken);
+                self.written.extend_from_slice(&buf[..n]);
+                taken += n;
+            }
+            Poll::Ready(Ok(taken))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn trickle(limit: usize) -> Trickle {
+        Trickle {
+            limit,
+            written: Vec::new(),
+            calls: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_every_frame_in_one_call_when_it_can() {
+        let mut wr = trickle(usize::MAX);
+        write_frames(&mut wr, b"onetwothree", &[3, 6, 11])
+            .await
+            .unwrap();
+        assert_eq!(wr.written, b"onetwothree");
+        assert_eq!(wr.calls, [3]);
+    }
+
+    #[tokio::test]
+    async fn carries_on_from_partial_writes() {
+        let mut wr = trickle(4);
+        write_frames(&mut wr, b"onetwothree", &[3, 6, 11])
+            .await
+            .unwrap();
+        assert_eq!(wr.written, b"onetwothree");
+        // "onet", then "wo" and "th", then "ree"
+        assert_eq!(wr.calls, [3, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn hands_writev_at_most_max_iovecs_frames() {
+        let buf = vec![b'x'; MAX_IOVECS + 10];
+        let ends: Vec<usize> = (1..=buf.len()).collect();
+        let mut wr = trickle(usize::MAX);
+        write_frames(&mut wr, &buf, &ends).await.unwrap();
+        assert_eq!(wr.written, buf);
+        assert_eq!(wr.calls, [MAX_IOVECS, 10]);
+    }
+
+    // Writes each line out, but fails on the ones that are errors
+    struct Strict;
+
+    impl Encoder<String> for Strict {
+        type Error = io::Error;
+
+        fn encode(&mut self, line: String, dst: &mut BytesMut) -> io::Result<()> {
+            dst.extend_from_slice(line.as_bytes());
+            match line.starts_with("Error") {
+                true => Err(io::ErrorKind::InvalidData.into()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    // Echoes lines until one is "bad"
+    struct Echo;
+
+    impl FramedHandler<LinesCodec> for Echo {
+        type Response = String;
+
+        async fn request(&mut self, line: String, out: &mut Vec<String>) -> Flow {
+            if line == "bad" {
+                out.push(format!("Error: {}\n", line));
+                return Flow::Close;
+            }
+            out.push(line + "\n");
+            Flow::Continue
+        }
+
+        async fn malformed(
+            &mut self,
+            _: LinesCodecError,
+            _: &mut LinesCodec,
+            _: &[u8],
+            _: &mut Vec<String>,
+        ) {
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_what_the_encoder_wrote_before_failing() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let ctx = crate::handler::Context::new("test");
+            serve_framed(server, LinesCodec::new(), Strict, Echo, &ctx).await
+        });
+        client.write_all(b"one\nbad\ntwo\n").await.unwrap();
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        assert_eq!(sent, "one\nError: bad\n");
+    }
+}
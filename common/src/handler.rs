@@ -0,0 +1,71 @@
+// One interface for serving a connection, whatever it arrived over.
+//
+// Each problem implements `ConnectionHandler` once, generically over the
+// stream, and every transport (the TCP accept loop, QUIC, WebSocket, LRCP,
+// ISL) drives it the same way. Something that applies to any connection is
+// then a handler wrapping another one, like ISL's `Encrypted`, rather than
+// another parameter threaded through each problem's functions.
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+
+use crate::tasks::Task;
+
+// Anything a handler can be given to serve
+pub trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> ByteStream for S {}
+
+#[derive(Clone, Debug)]
+pub struct Context {
+    // What the connection came over: "tcp", "uds", "quic", "websocket", "lrcp",
+    // "isl"
+    pub transport: &'static str,
+    // Cancelled when the connection should wind down, for a shutdown, a
+    // handover that ran out of patience or a client that is too slow. Handlers
+    // select on it wherever they wait, and clean up before returning; one that
+    // doesn't return soon enough is aborted.
+    pub cancel: CancellationToken,
+    // Where the handler is at, for finding connections that hang
+    pub task: Task,
+}
+
+impl Context {
+    pub fn new(transport: &'static str) -> Self {
+        Context {
+            transport,
+            cancel: CancellationToken::new(),
+            task: Task::register(transport),
+        }
+    }
+}
+
+pub trait ConnectionHandler: Clone + Send + Sync + 'static {
+    // Serve one connection until it is done with. `peer` is None when the
+    // transport doesn't know it.
+    fn handle<S: ByteStream>(
+        &self,
+        stream: S,
+        peer: Option<SocketAddr>,
+        ctx: Context,
+    ) -> impl Future<Output = ()> + Send;
+}
+
+// Serve a connection in its own task, as every transport does
+pub fn spawn<H: ConnectionHandler, S: ByteStream>(
+    handler: &H,
+    stream: S,
+    peer: Option<SocketAddr>,
+    ctx: Context,
+) -> tokio::task::JoinHandle<()> {
+    let handler = handler.clone();
+    ctx.task.set_peer(peer);
+    let task = ctx.task.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = handler.handle(stream, peer, ctx) => {}
+            _ = task.closed() => crate::debug!("Closed connection from {:?} on request", peer),
+        }
+    })
+}
@@ -0,0 +1,285 @@
+// Zero-downtime restarts by handing the listening socket over to a new process.
+//
+// With HANDOVER_SOCKET set to a path, `bind` first asks whichever process is
+// serving at that path for its listening socket, and only binds a new one if
+// there is nobody there. Either way it then offers its own socket at the path
+// for the next deploy. The old process passes the socket over SCM_RIGHTS,
+// stops accepting, lets the connections it already has finish (for up to
+// HANDOVER_DRAIN_SECS) and exits. Both processes share the same kernel
+// socket throughout, so connections arriving during the switch just wait in
+// its backlog instead of being refused.
+//
+// The socket at the path is only accessible to the user the server runs as,
+// and the listening socket is only handed to a process running as that user
+// too, so nobody else on the host can take the listener or make the server
+// drain and exit.
+//
+// Each listener has a path of its own, HANDOVER_SOCKET followed by the address
+// it listens on (say /run/ph.sock.0.0.0.0:50000), so a listener is only ever
+// taken over by one for the same address. A process offering several starts
+// draining once every one of them has been taken. Listeners on port 0 have
+// no address to be found by and aren't handed over.
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::watch;
+
+const DEFAULT_DRAIN_SECS: u64 = 30;
+
+static DRAIN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+// Listeners offered and not taken yet
+static OFFERED: AtomicUsize = AtomicUsize::new(0);
+
+fn drain_signal() -> &'static watch::Sender<bool> {
+    DRAIN.get_or_init(|| watch::channel(false).0)
+}
+
+// Becomes true once the listener has been handed to another process
+pub(crate) fn draining() -> watch::Receiver<bool> {
+    drain_signal().subscribe()
+}
+
+// How long a process that handed its listener over waits for its connections
+pub(crate) fn drain_timeout() -> Duration {
+    Duration::from_secs(crate::env::var_or(
+        "HANDOVER_DRAIN_SECS",
+        DEFAULT_DRAIN_SECS,
+    ))
+}
+
+// Where the listener on `addr` is offered, for HANDOVER_SOCKET `base`
+fn path_for(base: &Path, addr: SocketAddr) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{}", addr));
+    path.into()
+}
+
+#[cfg(unix)]
+pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+    use std::os::unix::io::AsRawFd;
+
+    let base =
+        crate::env::var::<PathBuf>("HANDOVER_SOCKET").filter(|_| !crate::dry_run::requested());
+    let Some(base) = base else {
+        return crate::tuning::bind_listener(addr).await;
+    };
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"))?;
+    if addr.port() == 0 {
+        return crate::tuning::bind_listener(addr).await;
+    }
+    let path = path_for(&base, addr);
+    let listener = match take_over(path.clone()).await {
+        Some(listener) => {
+            crate::info!("Took over listening socket from {:?}", path);
+            listener
+        }
+        None => crate::tuning::bind_listener(addr).await?,
+    };
+    offer(path, listener.as_raw_fd())?;
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+    crate::tuning::bind_listener(addr).await
+}
+
+// Fetch the listening socket from the process serving at `path`, if any
+#[cfg(unix)]
+async fn take_over(path: PathBuf) -> Option<TcpListener> {
+    use sendfd::RecvWithFd;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+
+    let received = tokio::task::spawn_blocking(move || {
+        // Nobody listening means this is the first process
+        let stream = UnixStream::connect(&path).ok()?;
+        let mut buf = [0u8; 1];
+        let mut fds = [0; 1];
+        match stream.recv_with_fd(&mut buf, &mut fds) {
+            Ok((_, 1)) => Some(fds[0]),
+            Ok(_) => {
+                crate::warn!("Handover peer at {:?} didn't send a socket", path);
+                None
+            }
+            Err(e) => {
+                crate::warn!("Couldn't receive socket from {:?}: {:?}", path, e);
+                None
+            }
+        }
+    })
+    .await
+    .ok()??;
+
+    // The descriptor was created for us by the kernel and nothing else owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(received) };
+    listener
+        .set_nonblocking(true)
+        .and_then(|()| TcpListener::from_std(listener))
+        .map_err(|e| crate::warn!("Couldn't use received socket: {:?}", e))
+        .ok()
+}
+
+// Listen at `path` for the next process, accessible only to this user
+#[cfg(unix)]
+fn listen(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Whatever is there belongs to a process that has already handed over
+    std::fs::remove_file(path).unwrap_or(());
+    let handover = std::os::unix::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(handover)
+}
+
+// The user the process at the other end of `stream` runs as
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &std::os::unix::net::UnixStream) -> io::Result<libc::uid_t> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SO_PEERCRED fills in a ucred of the given size
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn peer_uid(stream: &std::os::unix::net::UnixStream) -> io::Result<libc::uid_t> {
+    use std::os::unix::io::AsRawFd;
+
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+// Send `fd` to the process at the other end of `stream`, if it runs as the
+// same user as this one
+#[cfg(unix)]
+fn hand_over(
+    stream: &std::os::unix::net::UnixStream,
+    fd: std::os::unix::io::RawFd,
+) -> io::Result<()> {
+    use sendfd::SendWithFd;
+
+    let uid = peer_uid(stream)?;
+    // Never fails
+    let ours = unsafe { libc::geteuid() };
+    if uid != ours {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("process connecting runs as uid {}, not {}", uid, ours),
+        ));
+    }
+    stream.send_with_fd(b"L", &[fd]).map(|_| ())
+}
+
+// Serve `fd` at `path` to the next process, then start draining if it was
+// the last listener not handed over
+#[cfg(unix)]
+fn offer(path: PathBuf, fd: std::os::unix::io::RawFd) -> io::Result<()> {
+    let handover = listen(&path)?;
+    OFFERED.fetch_add(1, Ordering::SeqCst);
+
+    std::thread::spawn(move || {
+        for stream in handover.incoming() {
+            let sent = stream.and_then(|s| hand_over(&s, fd));
+            match sent {
+                Ok(_) if OFFERED.fetch_sub(1, Ordering::SeqCst) == 1 => {
+                    crate::info!("Handed listening socket at {:?} over, draining", path);
+                    drain_signal().send_replace(true);
+                    return;
+                }
+                Ok(_) => {
+                    crate::info!("Handed listening socket at {:?} over", path);
+                    return;
+                }
+                Err(e) => crate::warn!("Couldn't hand listening socket over: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_address_has_a_path_of_its_own() {
+        let base = Path::new("/run/ph.sock");
+        let path = |addr: &str| path_for(base, addr.parse().unwrap());
+        assert_eq!(
+            path("0.0.0.0:50000"),
+            Path::new("/run/ph.sock.0.0.0.0:50000")
+        );
+        assert_eq!(path("[::]:50001"), Path::new("/run/ph.sock.[::]:50001"));
+        assert_ne!(path("0.0.0.0:50000"), path("0.0.0.0:50001"));
+        assert_ne!(path("0.0.0.0:50000"), path("127.0.0.1:50000"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hands_the_listener_to_a_process_of_the_same_user() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ph.sock");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handover = listen(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let fd = listener.as_raw_fd();
+        let offering = std::thread::spawn(move || {
+            let (stream, _) = handover.accept().unwrap();
+            hand_over(&stream, fd)
+        });
+        let taken = take_over(path.clone()).await.expect("nothing handed over");
+        offering.join().unwrap().unwrap();
+        assert_eq!(taken.local_addr().unwrap(), addr);
+
+        // Both are the one socket, so a connection to either is accepted by
+        // the new process
+        drop(listener);
+        let connecting = tokio::net::TcpStream::connect(addr);
+        let (connected, accepted) = tokio::join!(connecting, taken.accept());
+        assert_eq!(
+            connected.unwrap().local_addr().unwrap(),
+            accepted.unwrap().1
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn nothing_to_take_over_without_a_process_offering() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(take_over(dir.path().join("ph.sock")).await.is_none());
+    }
+}
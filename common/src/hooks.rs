@@ -0,0 +1,74 @@
+// Connection lifecycle hooks.
+//
+// Hooks registered here are told about every connection a listener with an
+// address accepts (see admission.rs), so cross-cutting features (bans, audit
+// logs, extra metrics) can plug in without touching each problem's handler.
+// Every connection a hook saw in `on_connect` is later reported exactly once
+// to `on_disconnect`.
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // The handler finished
+    Closed,
+    // The handler panicked
+    Panicked,
+    // A hook refused the connection
+    Refused,
+    // The accept queue was full, or on a listener without one, every slot in
+    // the connection budget was taken
+    QueueFull,
+    // Its address already had as many connections open as it may
+    PerIpLimit,
+    // It stayed under the minimum throughput
+    TooSlow,
+    // It was closed to make room for a new connection
+    Reaped,
+}
+
+pub trait ConnectionHook: Send + Sync + 'static {
+    // Called for every accepted connection before it's queued. Returning
+    // false closes it straight away.
+    fn on_connect(&self, _peer: SocketAddr) -> bool {
+        true
+    }
+
+    // `duration` counts from the moment the connection was accepted
+    fn on_disconnect(&self, _peer: SocketAddr, _reason: DisconnectReason, _duration: Duration) {}
+}
+
+fn hooks() -> &'static RwLock<Vec<Arc<dyn ConnectionHook>>> {
+    static HOOKS: OnceLock<RwLock<Vec<Arc<dyn ConnectionHook>>>> = OnceLock::new();
+    HOOKS.get_or_init(|| RwLock::new(Vec::new()))
+}
+
+pub fn register<H: ConnectionHook>(hook: H) {
+    hooks()
+        .write()
+        .unwrap_or_else(|e| panic!("Error locking connection hooks: {}", e))
+        .push(Arc::new(hook));
+}
+
+// Whether every hook admits the connection. All of them are asked even after
+// one refuses, so each sees the matching disconnect.
+pub(crate) fn connect(peer: SocketAddr) -> bool {
+    let hooks = hooks()
+        .read()
+        .unwrap_or_else(|e| panic!("Error locking connection hooks: {}", e));
+    let mut admitted = true;
+    for hook in hooks.iter() {
+        admitted &= hook.on_connect(peer);
+    }
+    admitted
+}
+
+pub(crate) fn disconnect(peer: SocketAddr, reason: DisconnectReason, duration: Duration) {
+    let hooks = hooks()
+        .read()
+        .unwrap_or_else(|e| panic!("Error locking connection hooks: {}", e));
+    for hook in hooks.iter() {
+        hook.on_disconnect(peer, reason, duration);
+    }
+}
@@ -0,0 +1,50 @@
+// Code shared by every problem server
+pub mod accept;
+pub mod access;
+pub mod admission;
+pub mod agent_check;
+pub mod alloc;
+pub mod boguscoin;
+#[cfg(any(feature = "quic", feature = "tls"))]
+mod cert;
+pub mod cli;
+pub mod config;
+#[cfg(feature = "console")]
+pub mod console;
+pub mod dry_run;
+pub mod env;
+pub mod framed;
+pub mod handler;
+pub mod handover;
+pub mod hooks;
+pub mod listeners;
+pub mod log;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod memory;
+pub mod metrics;
+#[cfg(feature = "middleware")]
+pub mod middleware;
+pub mod per_ip;
+#[cfg(feature = "pprof")]
+pub mod profile;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod report;
+pub mod resolve;
+pub mod retry;
+#[cfg(feature = "sandbox")]
+pub mod sandbox;
+pub mod shutdown;
+pub mod summary;
+pub mod tasks;
+pub mod throughput;
+pub mod timer;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tuning;
+pub mod udp_guard;
+#[cfg(unix)]
+mod uds;
+#[cfg(feature = "websocket")]
+pub mod websocket;
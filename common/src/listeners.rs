@@ -0,0 +1,85 @@
+// One accept loop over several listening sockets.
+//
+// A server can listen on more than one address at once, say the loopback
+// interface for local tests as well as the public one, with every
+// connection going through the same accept loop, limits and handler. The
+// first address is the main one: it is the one taken over through
+// HANDOVER_SOCKET, and its port names the server's metrics. The others are
+// bound afresh on every start, waiting a moment for an address still in use,
+// as it is while the process handing over lets go of it.
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+const REBIND_ATTEMPTS: u32 = 20;
+const REBIND_DELAY: Duration = Duration::from_millis(50);
+
+pub struct Listeners {
+    listeners: Vec<TcpListener>,
+    // Where the next accept starts looking, so a busy listener can't starve
+    // the rest
+    next: AtomicUsize,
+}
+
+impl From<TcpListener> for Listeners {
+    fn from(listener: TcpListener) -> Self {
+        Listeners {
+            listeners: vec![listener],
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+// Bind a listener on each of `addrs`, the first through `handover::bind`
+pub async fn bind(addrs: &[SocketAddr]) -> io::Result<Listeners> {
+    let (main, others) = addrs
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"))?;
+    let mut listeners = Listeners::from(crate::handover::bind(*main).await?);
+    for addr in others {
+        let listener = rebind(*addr)
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+        crate::info!("Also listening on {}", addr);
+        listeners.listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+async fn rebind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let mut attempts = 1;
+    loop {
+        match crate::tuning::bind_listener(addr).await {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempts < REBIND_ATTEMPTS => {
+                attempts += 1;
+                tokio::time::sleep(REBIND_DELAY).await;
+            }
+            bound => return bound,
+        }
+    }
+}
+
+impl Listeners {
+    // The main listener's address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    // Accept a connection on whichever listener has one first
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        std::future::poll_fn(|cx| {
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            for i in 0..self.listeners.len() {
+                let listener = &self.listeners[(start + i) % self.listeners.len()];
+                if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                    return Poll::Ready(accepted);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
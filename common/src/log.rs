@@ -0,0 +1,113 @@
+// Levelled logging.
+//
+// LOG_LEVEL (error, warn, info or debug, info unless set) is the least
+// severe level written; it's read like any other variable (see env.rs) the
+// first time something is logged. Errors and warnings go to stderr and the
+// rest to stdout. Per-connection chatter is logged at debug, so a busy server
+// only reports what happens to the server itself unless asked for more.
+//
+// The configuration code that LOG_LEVEL is itself read through (env.rs,
+// cli.rs) and the answers to --check-config, --dry-run and --print-config
+// print directly instead.
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err("expected error, warn, info or debug".to_owned()),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warn => write!(f, "warn"),
+            Level::Info => write!(f, "info"),
+            Level::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+// The least severe level that is written
+pub fn level() -> Level {
+    static LEVEL: OnceLock<Level> = OnceLock::new();
+    *LEVEL.get_or_init(|| crate::env::var_or("LOG_LEVEL", Level::Info))
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+// Used by the macros below; the message is only formatted when it's written
+#[doc(hidden)]
+pub fn write(level: Level, message: fmt::Arguments<'_>) {
+    if !enabled(level) {
+        return;
+    }
+    match level {
+        Level::Error | Level::Warn => eprintln!("{}", message),
+        Level::Info | Level::Debug => println!("{}", message),
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_parse_and_order_by_severity() {
+        assert_eq!("WARN".parse(), Ok(Level::Warn));
+        assert_eq!("debug".parse(), Ok(Level::Debug));
+        assert!("verbose".parse::<Level>().is_err());
+        for level in [Level::Error, Level::Warn, Level::Info, Level::Debug] {
+            assert_eq!(level.to_string().parse(), Ok(level));
+        }
+        assert!(Level::Error < Level::Warn && Level::Info < Level::Debug);
+    }
+}
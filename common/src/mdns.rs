@@ -0,0 +1,57 @@
+// mDNS service advertisement.
+//
+// Announces the server as a `_protohackers._tcp.local.` service named after
+// the problem and port (e.g. `problem3-39456`), with both also in TXT records,
+// so test clients on the same LAN can find it without swapping addresses by
+// hand. Only compiled with the `mdns` feature.
+use std::sync::OnceLock;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+const SERVICE_TYPE: &str = "_protohackers._tcp.local.";
+
+// The daemon answers queries from its own thread for as long as it's alive
+static DAEMON: OnceLock<Option<ServiceDaemon>> = OnceLock::new();
+
+fn hostname() -> String {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "protohackers".to_owned())
+}
+
+pub fn advertise(problem: &str, port: u16) {
+    let daemon = DAEMON.get_or_init(|| match ServiceDaemon::new() {
+        Ok(d) => Some(d),
+        Err(e) => {
+            crate::warn!("Couldn't start mDNS responder: {}", e);
+            None
+        }
+    });
+    let Some(daemon) = daemon else {
+        return;
+    };
+
+    let instance = format!("{}-{}", problem, port);
+    let properties = [("problem", problem.to_owned()), ("port", port.to_string())];
+    let info = match ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance,
+        &format!("{}.local.", hostname()),
+        (),
+        port,
+        &properties[..],
+    ) {
+        Ok(info) => info.enable_addr_auto(),
+        Err(e) => {
+            crate::warn!("Couldn't build mDNS record for {}: {}", instance, e);
+            return;
+        }
+    };
+
+    match daemon.register(info) {
+        Ok(()) => crate::info!("Advertising {} via mDNS as {}", instance, SERVICE_TYPE),
+        Err(e) => crate::warn!("Couldn't advertise {} via mDNS: {}", instance, e),
+    }
+}
@@ -0,0 +1,274 @@
+// Approximate heap usage per connection.
+//
+// Handlers charge a connection's `Account` for what they keep around on its
+// behalf (stored samples, queued output) and credit it back as they let go.
+// The sum over all connections is the connection_memory_bytes gauge, and the
+// open accounts, largest first, are served as /memory on the metrics endpoint.
+// A connection charged past CONNECTION_MEMORY_LIMIT bytes (unlimited unless
+// set) should be closed by its handler, which `charge` signals by returning
+// false.
+//
+// Other tasks can charge an account too, through a `Tab` on it: a chat room
+// queueing an event for a client, or a ticket queued for a dispatcher, is
+// charged to the client it waits for and credited when that client takes it.
+// A `Held` charges for something whose size changes, such as a decode
+// buffer, by setting its current size. Once the account is closed, its tabs
+// charge nothing.
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::metrics::{Counter, Gauge, Scope};
+
+struct Open {
+    problem: String,
+    peer: Option<SocketAddr>,
+    used: Arc<AtomicUsize>,
+}
+
+fn open_accounts() -> &'static Mutex<BTreeMap<u64, Open>> {
+    static OPEN: OnceLock<Mutex<BTreeMap<u64, Open>>> = OnceLock::new();
+    OPEN.get_or_init(|| {
+        crate::metrics::register_page("/memory", render);
+        Mutex::new(BTreeMap::new())
+    })
+}
+
+fn render() -> String {
+    let open = open_accounts()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking memory accounts: {}", e));
+    let mut rows: Vec<(usize, &Open)> = open
+        .values()
+        .map(|a| (a.used.load(Ordering::Relaxed), a))
+        .collect();
+    rows.sort_by_key(|(used, _)| std::cmp::Reverse(*used));
+    let mut out = String::new();
+    for (used, account) in rows {
+        let peer = account
+            .peer
+            .map_or_else(|| "unknown".to_owned(), |p| p.to_string());
+        out.push_str(&format!("{} {} {} bytes\n", account.problem, peer, used));
+    }
+    out
+}
+
+// Opens accounts for one problem's connections
+#[derive(Clone)]
+pub struct Ledger {
+    problem: String,
+    limit: Option<usize>,
+    total: Gauge,
+    exceeded: Counter,
+}
+
+impl Ledger {
+    pub fn new(scope: &Scope) -> Self {
+        Ledger {
+            problem: scope.problem().to_owned(),
+            limit: crate::env::var("CONNECTION_MEMORY_LIMIT"),
+            total: scope.gauge(
+                "connection_memory_bytes",
+                "Approximate heap held on behalf of connections",
+            ),
+            exceeded: scope.counter(
+                "connection_memory_limit_exceeded_total",
+                "Connections that went over the per-connection memory limit",
+            ),
+        }
+    }
+
+    // The same ledger with accounts limited to `limit` bytes instead
+    pub fn with_limit(self, limit: Option<usize>) -> Self {
+        Ledger { limit, ..self }
+    }
+
+    pub fn open(&self, peer: Option<SocketAddr>) -> Account {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let tab = Tab {
+            used: Arc::new(AtomicUsize::new(0)),
+            closed: Arc::new(AtomicBool::new(false)),
+            ledger: self.clone(),
+        };
+        open_accounts()
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking memory accounts: {}", e))
+            .insert(
+                id,
+                Open {
+                    problem: self.problem.clone(),
+                    peer,
+                    used: tab.used.clone(),
+                },
+            );
+        Account { id, tab }
+    }
+}
+
+// A way to charge an account from elsewhere, as long as it is open
+#[derive(Clone)]
+pub struct Tab {
+    used: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+    ledger: Ledger,
+}
+
+impl Tab {
+    // Returns false once the connection is over the limit
+    pub fn charge(&self, bytes: usize) -> bool {
+        if self.closed.load(Ordering::Relaxed) {
+            return true;
+        }
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.ledger.total.add(bytes as i64);
+        match self.ledger.limit {
+            Some(limit) if used > limit => {
+                // Counted once, when it goes over
+                if used - bytes <= limit {
+                    self.ledger.exceeded.inc();
+                }
+                false
+            }
+            _ => true,
+        }
+    }
+
+    pub fn credit(&self, bytes: usize) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        let bytes = bytes.min(self.used());
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+        self.ledger.total.add(-(bytes as i64));
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn within_limit(&self) -> bool {
+        self.ledger.limit.is_none_or(|limit| self.used() <= limit)
+    }
+}
+
+// What one connection holds; closed when dropped
+pub struct Account {
+    id: u64,
+    tab: Tab,
+}
+
+impl Account {
+    // Returns false once the connection is over the limit
+    pub fn charge(&self, bytes: usize) -> bool {
+        self.tab.charge(bytes)
+    }
+
+    pub fn credit(&self, bytes: usize) {
+        self.tab.credit(bytes)
+    }
+
+    pub fn used(&self) -> usize {
+        self.tab.used()
+    }
+
+    pub fn within_limit(&self) -> bool {
+        self.tab.within_limit()
+    }
+
+    pub fn tab(&self) -> Tab {
+        self.tab.clone()
+    }
+
+    // Charge for something that starts out empty
+    pub fn hold(&self) -> Held {
+        Held {
+            tab: self.tab(),
+            bytes: 0,
+        }
+    }
+}
+
+// A charge that follows the size of something, given with `set`, and goes
+// when this is dropped
+pub struct Held {
+    tab: Tab,
+    bytes: usize,
+}
+
+impl Held {
+    // Returns false once the connection is over the limit
+    pub fn set(&mut self, bytes: usize) -> bool {
+        let within = match bytes.checked_sub(self.bytes) {
+            Some(more) => self.tab.charge(more),
+            None => {
+                self.tab.credit(self.bytes - bytes);
+                self.tab.within_limit()
+            }
+        };
+        self.bytes = bytes;
+        within
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.tab.credit(self.bytes);
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.tab.closed.store(true, Ordering::Relaxed);
+        self.tab.ledger.total.add(-(self.used() as i64));
+        open_accounts()
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking memory accounts: {}", e))
+            .remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ledger(port: u16, limit: Option<usize>) -> Ledger {
+        Ledger::new(&Scope::new("memory", port)).with_limit(limit)
+    }
+
+    #[test]
+    fn tabs_and_holds_charge_the_account() {
+        let ledger = ledger(1, Some(100));
+        let account = ledger.open(None);
+        let tab = account.tab();
+        assert!(tab.charge(40));
+        let mut buffer = account.hold();
+        assert!(buffer.set(50));
+        assert_eq!(account.used(), 90);
+        assert!(!buffer.set(70));
+        assert!(!account.within_limit());
+        assert_eq!(ledger.exceeded.get(), 1);
+        assert!(!tab.charge(1));
+        assert_eq!(ledger.exceeded.get(), 1);
+
+        assert!(buffer.set(10));
+        tab.credit(1);
+        assert_eq!((account.used(), ledger.total.get()), (50, 50));
+        drop(buffer);
+        assert_eq!(account.used(), 40);
+    }
+
+    #[test]
+    fn a_closed_account_is_charged_nothing() {
+        let ledger = ledger(2, None);
+        let account = ledger.open(None);
+        let tab = account.tab();
+        tab.charge(30);
+        drop(account);
+        assert_eq!(ledger.total.get(), 0);
+        tab.charge(10);
+        tab.credit(30);
+        assert_eq!(ledger.total.get(), 0);
+    }
+}
@@ -0,0 +1,698 @@
+// Process-wide metrics registry, rendered in the Prometheus text format.
+//
+// Each problem creates a Scope labelled with its name and port and registers
+// its own counters, gauges and histograms through it, so several problems can
+// be told apart on one dashboard. Setting METRICS_PORT serves the registry
+// over HTTP, along with any plain-text debug pages registered with
+// `register_page`, and commands registered with `register_command`, which run
+// on a POST from the local host.
+//
+// A process watching over others (`protohackers supervise`) can have their
+// endpoints merged into its own with `add_upstream`: each scrape fetches them
+// in turn, labels their series with the process they came from, and lists
+// every family once, with the series of all of them.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const PREFIX: &str = "protohackers_";
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+struct Series {
+    // Appended to the family name: "_bucket", "_sum" or "_count" for a
+    // histogram, empty otherwise
+    suffix: &'static str,
+    labels: Vec<(&'static str, String)>,
+    value: Arc<AtomicI64>,
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    // Rendered label set (with the suffix and bucket index, for histograms,
+    // to keep each one's series together and in order) -> series
+    series: BTreeMap<String, Series>,
+}
+
+// The current value of one series, for consumers other than the endpoint
+pub(crate) struct Sample {
+    pub name: &'static str,
+    pub suffix: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: i64,
+}
+
+fn registry() -> &'static Mutex<BTreeMap<&'static str, Family>> {
+    static REGISTRY: OnceLock<Mutex<BTreeMap<&'static str, Family>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+type Page = Box<dyn Fn() -> String + Send + Sync>;
+
+fn pages() -> &'static Mutex<BTreeMap<&'static str, Page>> {
+    static PAGES: OnceLock<Mutex<BTreeMap<&'static str, Page>>> = OnceLock::new();
+    PAGES.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+// Serve the output of `render` at `path` on the metrics endpoint, for state
+// that doesn't fit in a counter or gauge
+pub fn register_page<F>(path: &'static str, render: F)
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    pages()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking debug pages: {}", e))
+        .insert(path, Box::new(render));
+}
+
+fn render_page(path: &str) -> Option<String> {
+    let pages = pages()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking debug pages: {}", e));
+    pages.get(path).map(|render| render())
+}
+
+type Command = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+fn commands() -> &'static Mutex<BTreeMap<&'static str, Command>> {
+    static COMMANDS: OnceLock<Mutex<BTreeMap<&'static str, Command>>> = OnceLock::new();
+    COMMANDS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+// Run `run` with the query string of a POST to `path` on the metrics
+// endpoint, answering with what it returns. Only the local host may POST.
+pub fn register_command<F>(path: &'static str, run: F)
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    commands()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking commands: {}", e))
+        .insert(path, Box::new(run));
+}
+
+fn run_command(path: &str, query: &str) -> Option<String> {
+    let commands = commands()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking commands: {}", e));
+    commands.get(path).map(|run| run(query))
+}
+
+fn upstreams() -> &'static Mutex<Vec<(String, SocketAddr)>> {
+    static UPSTREAMS: OnceLock<Mutex<Vec<(String, SocketAddr)>>> = OnceLock::new();
+    UPSTREAMS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+// Merge the registry served at `addr` into this one's, its series labelled
+// with process=`process`
+pub fn add_upstream(process: &str, addr: SocketAddr) {
+    upstreams()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking metrics upstreams: {}", e))
+        .push((process.to_owned(), addr));
+}
+
+// The method, path and query string of a request
+fn request_line(head: &[u8]) -> Option<(&str, &str, &str)> {
+    let line = head.split(|&b| b == b'\r').next()?;
+    let mut parts = std::str::from_utf8(line).ok()?.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Some((method, path, query))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub(crate) fn render_labels(labels: &[(&str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn register(
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &[(&'static str, String)],
+) -> Arc<AtomicI64> {
+    register_series(name, help, kind, "", render_labels(labels), labels)
+}
+
+fn register_series(
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    suffix: &'static str,
+    key: String,
+    labels: &[(&'static str, String)],
+) -> Arc<AtomicI64> {
+    let mut registry = registry()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking metrics registry: {}", e));
+    let family = registry.entry(name).or_insert_with(|| Family {
+        help,
+        kind,
+        series: BTreeMap::new(),
+    });
+    assert_eq!(
+        family.kind, kind,
+        "metric {} registered with two types",
+        name
+    );
+    family
+        .series
+        .entry(key)
+        .or_insert_with(|| Series {
+            suffix,
+            labels: labels.to_vec(),
+            value: Arc::default(),
+        })
+        .value
+        .clone()
+}
+
+pub(crate) fn samples() -> Vec<Sample> {
+    let registry = registry()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking metrics registry: {}", e));
+    registry
+        .iter()
+        .flat_map(|(name, family)| {
+            family.series.values().map(|series| Sample {
+                name,
+                suffix: series.suffix,
+                labels: series.labels.clone(),
+                value: series.value.load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct Counter(Arc<AtomicI64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n as i64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed) as u64
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Counts of observed values at or below each of a fixed set of bounds, plus
+// their sum, rendered as a Prometheus histogram. Counts are stored
+// cumulatively, the way they are rendered, so observing touches every bucket
+// the value fits in.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    // One per bound, then one for +Inf
+    buckets: Arc<[Arc<AtomicI64>]>,
+    count: Arc<AtomicI64>,
+    sum: Arc<AtomicI64>,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: u64) {
+        let first = self.bounds.partition_point(|&bound| bound < value);
+        for bucket in &self.buckets[first..] {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value as i64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed) as u64
+    }
+
+    // The bound of the bucket that the `q` quantile falls in, or None if no
+    // values were observed or it is above every bound
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q * count as f64).ceil() as u64).max(1);
+        self.bounds
+            .iter()
+            .zip(self.buckets.iter())
+            .find(|(_, bucket)| bucket.load(Ordering::Relaxed) as u64 >= rank)
+            .map(|(&bound, _)| bound)
+    }
+}
+
+// A set of labels shared by every metric a problem registers
+#[derive(Clone, Debug)]
+pub struct Scope {
+    labels: Vec<(&'static str, String)>,
+}
+
+impl Scope {
+    pub fn new(problem: &str, port: u16) -> Self {
+        Scope {
+            labels: vec![("problem", problem.to_owned()), ("port", port.to_string())],
+        }
+    }
+
+    pub fn problem(&self) -> &str {
+        &self.labels[0].1
+    }
+
+    pub fn labels(&self) -> &[(&'static str, String)] {
+        &self.labels
+    }
+
+    fn labels_with(&self, extra: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        let mut labels = self.labels.clone();
+        labels.extend(extra.iter().map(|(k, v)| (*k, v.to_string())));
+        labels
+    }
+
+    pub fn counter(&self, name: &'static str, help: &'static str) -> Counter {
+        self.counter_with(name, help, &[])
+    }
+
+    pub fn counter_with(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Counter {
+        Counter(register(
+            name,
+            help,
+            Kind::Counter,
+            &self.labels_with(labels),
+        ))
+    }
+
+    pub fn gauge(&self, name: &'static str, help: &'static str) -> Gauge {
+        self.gauge_with(name, help, &[])
+    }
+
+    pub fn gauge_with(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Gauge {
+        Gauge(register(name, help, Kind::Gauge, &self.labels_with(labels)))
+    }
+
+    // A histogram with buckets up to each of `bounds`, which must be in
+    // increasing order
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        bounds: &'static [u64],
+    ) -> Histogram {
+        self.histogram_with(name, help, bounds, &[])
+    }
+
+    pub fn histogram_with(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        bounds: &'static [u64],
+        labels: &[(&'static str, &str)],
+    ) -> Histogram {
+        let labels = self.labels_with(labels);
+        let key = render_labels(&labels);
+        let les = bounds
+            .iter()
+            .map(|bound| bound.to_string())
+            .chain(std::iter::once("+Inf".to_owned()));
+        let buckets = les
+            .enumerate()
+            .map(|(i, le)| {
+                let mut labels = labels.clone();
+                labels.push(("le", le));
+                let key = format!("{}_bucket{:03}", key, i);
+                register_series(name, help, Kind::Histogram, "_bucket", key, &labels)
+            })
+            .collect();
+        let count = format!("{}_count", key);
+        let sum = format!("{}_sum", key);
+        Histogram {
+            bounds,
+            buckets,
+            count: register_series(name, help, Kind::Histogram, "_count", count, &labels),
+            sum: register_series(name, help, Kind::Histogram, "_sum", sum, &labels),
+        }
+    }
+}
+
+// `series` holds each one's name suffix, rendered labels and value
+fn render_family(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: Kind,
+    series: &[(&str, String, i64)],
+) {
+    let kind = match kind {
+        Kind::Counter => "counter",
+        Kind::Gauge => "gauge",
+        Kind::Histogram => "histogram",
+    };
+    writeln!(out, "# HELP {}{} {}", PREFIX, name, help).unwrap();
+    writeln!(out, "# TYPE {}{} {}", PREFIX, name, kind).unwrap();
+    for (suffix, labels, value) in series {
+        writeln!(out, "{}{}{}{} {}", PREFIX, name, suffix, labels, value).unwrap();
+    }
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+    {
+        let registry = registry()
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking metrics registry: {}", e));
+        for (name, family) in registry.iter() {
+            let series: Vec<(&str, String, i64)> = family
+                .series
+                .values()
+                .map(|s| {
+                    (
+                        s.suffix,
+                        render_labels(&s.labels),
+                        s.value.load(Ordering::Relaxed),
+                    )
+                })
+                .collect();
+            render_family(&mut out, name, family.help, family.kind, &series);
+        }
+    }
+
+    let stats = crate::alloc::stats();
+    let allocator = render_labels(&[("allocator", crate::alloc::allocator_name().to_owned())]);
+    for (name, help, value) in [
+        (
+            "memory_allocated_bytes",
+            "Bytes allocated by the program",
+            stats.allocated,
+        ),
+        (
+            "memory_resident_bytes",
+            "Resident memory of the process",
+            stats.resident,
+        ),
+    ] {
+        if let Some(value) = value {
+            render_family(
+                &mut out,
+                name,
+                help,
+                Kind::Gauge,
+                &[("", allocator.clone(), value as i64)],
+            );
+        }
+    }
+    out
+}
+
+// The body of a GET for the registry at `addr`, if it answers in time
+async fn fetch(addr: SocketAddr) -> Option<String> {
+    let fetch = async {
+        let mut socket = TcpStream::connect(addr).await.ok()?;
+        socket
+            .write_all(b"GET /metrics HTTP/1.0\r\n\r\n")
+            .await
+            .ok()?;
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.ok()?;
+        let (_, body) = response.split_once("\r\n\r\n")?;
+        Some(body.to_owned())
+    };
+    tokio::time::timeout(UPSTREAM_TIMEOUT, fetch)
+        .await
+        .ok()
+        .flatten()
+}
+
+// `sample` with process=`process` added to its labels
+fn label_sample(sample: &str, process: &str) -> String {
+    let label = format!("process=\"{}\"", escape(process));
+    match sample.split_once('{') {
+        Some((name, rest)) if rest.starts_with('}') => format!("{}{{{}{}", name, label, rest),
+        Some((name, rest)) => format!("{}{{{},{}", name, label, rest),
+        None => match sample.split_once(' ') {
+            Some((name, value)) => format!("{}{{{}}} {}", name, label, value),
+            None => sample.to_owned(),
+        },
+    }
+}
+
+// Registries in the text format, merged: each family's HELP and TYPE once,
+// in the order families first appear, followed by the series of every
+// registry that has it. `labelled` have their series labelled with the
+// process they came from.
+fn merge(own: &str, labelled: &[(String, String)]) -> String {
+    // Family name -> HELP and TYPE lines and series
+    let mut families: Vec<(String, Vec<&str>, Vec<String>)> = Vec::new();
+    let sources = std::iter::once((None, own)).chain(
+        labelled
+            .iter()
+            .map(|(process, text)| (Some(process.as_str()), text.as_str())),
+    );
+    for (process, text) in sources {
+        let mut family = None;
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut words = comment.splitn(3, ' ');
+                let (Some(_), Some(name)) = (words.next(), words.next()) else {
+                    continue;
+                };
+                let index = match families.iter().position(|(n, _, _)| n == name) {
+                    Some(index) => index,
+                    None => {
+                        families.push((name.to_owned(), Vec::new(), Vec::new()));
+                        families.len() - 1
+                    }
+                };
+                let comments = &mut families[index].1;
+                if !comments.contains(&line) && comments.len() < 2 {
+                    comments.push(line);
+                }
+                family = Some(index);
+            } else if let Some(index) = family {
+                let sample = match process {
+                    Some(process) => label_sample(line, process),
+                    None => line.to_owned(),
+                };
+                families[index].2.push(sample);
+            }
+        }
+    }
+    let mut out = String::new();
+    for (_, comments, series) in families {
+        for line in comments {
+            writeln!(out, "{}", line).unwrap();
+        }
+        for line in series {
+            writeln!(out, "{}", line).unwrap();
+        }
+    }
+    out
+}
+
+// The registry, with those of any upstreams merged in
+async fn render_all() -> String {
+    let upstreams = upstreams()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking metrics upstreams: {}", e))
+        .clone();
+    if upstreams.is_empty() {
+        return render();
+    }
+    let mut labelled = Vec::new();
+    for (process, addr) in upstreams {
+        if let Some(text) = fetch(addr).await {
+            labelled.push((process, text));
+        }
+    }
+    merge(&render(), &labelled)
+}
+
+// Read an HTTP request up to the end of its headers, giving up on requests
+// that are too long or cut short
+pub(crate) async fn read_request_head(socket: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match socket.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+        if request.len() > MAX_REQUEST_LEN {
+            return None;
+        }
+    }
+    Some(request)
+}
+
+pub async fn run_endpoint(addr: SocketAddr) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            crate::error!("Couldn't start metrics endpoint on {}: {}", addr, e);
+            return;
+        }
+    };
+    crate::info!("Serving metrics on {}", addr);
+
+    loop {
+        let (mut socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                crate::warn!("Couldn't accept metrics connection: {:?}", e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            let Some(head) = read_request_head(&mut socket).await else {
+                return;
+            };
+            // Anything that isn't a registered page or command gets the
+            // whole registry
+            let output = match request_line(&head) {
+                Some(("POST", path, query)) if peer.ip().is_loopback() => run_command(path, query),
+                Some((_, path, _)) => render_page(path),
+                None => None,
+            };
+            let (content_type, body) = match output {
+                Some(output) => ("text/plain", output),
+                None => ("text/plain; version=0.0.4", render_all().await),
+            };
+            let response = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap_or(());
+        });
+    }
+}
+
+// Serve the registry on METRICS_PORT in the background, if it is set
+pub fn spawn_endpoint_from_env() {
+    if let Some(port) = crate::env::var::<u16>("METRICS_PORT") {
+        tokio::spawn(run_endpoint(SocketAddr::from(([0, 0, 0, 0], port))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_the_request_line() {
+        let head = b"POST /tasks/close?id=3 HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert_eq!(request_line(head), Some(("POST", "/tasks/close", "id=3")));
+        assert_eq!(
+            request_line(b"GET /tasks HTTP/1.0\r\n\r\n"),
+            Some(("GET", "/tasks", ""))
+        );
+        assert_eq!(request_line(b"GET\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn merges_upstream_families_and_labels_their_series() {
+        let own = "\
+# HELP protohackers_restarts_total Restarts
+# TYPE protohackers_restarts_total counter
+protohackers_restarts_total{problem=\"problem0\"} 2
+";
+        let child = "\
+# HELP protohackers_connections_total Connections
+# TYPE protohackers_connections_total counter
+protohackers_connections_total{problem=\"problem0\",port=\"1\"} 5
+# HELP protohackers_memory_resident_bytes Resident memory
+# TYPE protohackers_memory_resident_bytes gauge
+protohackers_memory_resident_bytes 100
+protohackers_empty{} 1
+";
+        let labelled = [
+            ("problem0".to_owned(), child.to_owned()),
+            ("problem1".to_owned(), child.replace("5", "7")),
+        ];
+        let merged = merge(own, &labelled);
+        assert_eq!(
+            merged,
+            "\
+# HELP protohackers_restarts_total Restarts
+# TYPE protohackers_restarts_total counter
+protohackers_restarts_total{problem=\"problem0\"} 2
+# HELP protohackers_connections_total Connections
+# TYPE protohackers_connections_total counter
+protohackers_connections_total{process=\"problem0\",problem=\"problem0\",port=\"1\"} 5
+protohackers_connections_total{process=\"problem1\",problem=\"problem0\",port=\"1\"} 7
+# HELP protohackers_memory_resident_bytes Resident memory
+# TYPE protohackers_memory_resident_bytes gauge
+protohackers_memory_resident_bytes{process=\"problem0\"} 100
+protohackers_empty{process=\"problem0\"} 1
+protohackers_memory_resident_bytes{process=\"problem1\"} 100
+protohackers_empty{process=\"problem1\"} 1
+"
+        );
+    }
+}
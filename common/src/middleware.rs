@@ -0,0 +1,196 @@
+// Tower middleware for connection handlers, enabled with the `middleware`
+// feature.
+//
+// A connection becomes a `Connection` request to a tower `Service`, so any
+// tower layer can sit in front of a problem's handler: `HandlerService` turns
+// a handler into a service, and `ServiceHandler` turns a service stack back
+// into a handler the transports can drive. `Stack` describes the standard
+// layers and is read from the environment:
+//   CONNECTION_TIMEOUT_SECS  drop connections that last longer than this
+//   CONNECTION_CONCURRENCY   connections served at once; the rest wait
+//   CONNECTION_RATE          connections started per second; the rest wait
+// Connections going through a stack are also counted by transport and
+// outcome. Unlike a cancelled one, a connection that times out is dropped
+// on the spot, as tower's timeout does with any service.
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tower::buffer::Buffer;
+use tower::limit::{ConcurrencyLimit, RateLimit};
+use tower::timeout::error::Elapsed;
+use tower::timeout::Timeout;
+use tower::util::BoxCloneSyncService;
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use crate::handler::{ByteStream, ConnectionHandler, Context};
+use crate::metrics::Scope;
+
+// Connections waiting for the rate limiter before new ones are held back
+const RATE_LIMIT_QUEUE_LEN: usize = 1024;
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<(), BoxError>> + Send>>;
+
+pub struct Connection {
+    pub stream: Box<dyn ByteStream>,
+    pub peer: Option<SocketAddr>,
+    pub ctx: Context,
+}
+
+// A handler as the innermost service of a stack
+#[derive(Clone)]
+pub struct HandlerService<H>(pub H);
+
+impl<H: ConnectionHandler> Service<Connection> for HandlerService<H> {
+    type Response = ();
+    type Error = BoxError;
+    type Future = BoxFuture;
+
+    fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, connection: Connection) -> Self::Future {
+        let handler = self.0.clone();
+        Box::pin(async move {
+            handler
+                .handle(connection.stream, connection.peer, connection.ctx)
+                .await;
+            Ok(())
+        })
+    }
+}
+
+// A service stack as a handler
+#[derive(Clone)]
+pub struct ServiceHandler<S>(pub S);
+
+impl<S> ConnectionHandler for ServiceHandler<S>
+where
+    S: Service<Connection, Response = (), Error = BoxError> + Clone + Send + Sync + 'static,
+    S::Future: Send,
+{
+    async fn handle<T: ByteStream>(&self, stream: T, peer: Option<SocketAddr>, ctx: Context) {
+        let connection = Connection {
+            stream: Box::new(stream),
+            peer,
+            ctx,
+        };
+        if let Err(e) = self.0.clone().oneshot(connection).await {
+            crate::debug!("Connection from {:?} ended by middleware: {}", peer, e);
+        }
+    }
+}
+
+// Counts connections by transport and outcome
+#[derive(Clone)]
+pub struct MeasureLayer {
+    scope: Scope,
+}
+
+impl MeasureLayer {
+    pub fn new(scope: &Scope) -> Self {
+        MeasureLayer {
+            scope: scope.clone(),
+        }
+    }
+}
+
+impl<S> Layer<S> for MeasureLayer {
+    type Service = Measured<S>;
+
+    fn layer(&self, inner: S) -> Measured<S> {
+        Measured {
+            inner,
+            scope: self.scope.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Measured<S> {
+    inner: S,
+    scope: Scope,
+}
+
+impl<S> Service<Connection> for Measured<S>
+where
+    S: Service<Connection, Response = (), Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = ();
+    type Error = BoxError;
+    type Future = BoxFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, connection: Connection) -> Self::Future {
+        let transport = connection.ctx.transport;
+        let scope = self.scope.clone();
+        let served = self.inner.call(connection);
+        Box::pin(async move {
+            let result = served.await;
+            let outcome = match &result {
+                Ok(()) => "ok",
+                Err(e) if e.is::<Elapsed>() => "timeout",
+                Err(_) => "error",
+            };
+            scope
+                .counter_with(
+                    "middleware_connections_total",
+                    "Connections through the middleware stack by transport and outcome",
+                    &[("transport", transport), ("outcome", outcome)],
+                )
+                .inc();
+            result
+        })
+    }
+}
+
+pub type BoxHandler = ServiceHandler<BoxCloneSyncService<Connection, (), BoxError>>;
+
+// The standard layers, each left out unless configured
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stack {
+    pub timeout: Option<Duration>,
+    pub concurrency: Option<usize>,
+    pub rate_per_sec: Option<u64>,
+}
+
+impl Stack {
+    pub fn from_env() -> Self {
+        Stack {
+            timeout: crate::env::var("CONNECTION_TIMEOUT_SECS").map(Duration::from_secs),
+            concurrency: crate::env::var("CONNECTION_CONCURRENCY"),
+            rate_per_sec: crate::env::var("CONNECTION_RATE"),
+        }
+    }
+
+    // Put `handler` behind the configured layers: connections are counted,
+    // then rate limited, then wait for a concurrency slot, and then time out.
+    // Has to be called inside the runtime, which runs the rate limiter.
+    pub fn wrap<H: ConnectionHandler>(&self, handler: H, scope: &Scope) -> BoxHandler {
+        let mut service = BoxCloneSyncService::new(HandlerService(handler));
+        if let Some(timeout) = self.timeout {
+            service = BoxCloneSyncService::new(Timeout::new(service, timeout));
+        }
+        if let Some(concurrency) = self.concurrency {
+            service = BoxCloneSyncService::new(ConcurrencyLimit::new(service, concurrency.max(1)));
+        }
+        if let Some(rate) = self.rate_per_sec {
+            // RateLimit keeps its window in the service, so every connection
+            // has to go through the same one
+            let limited = RateLimit::new(
+                service,
+                tower::limit::rate::Rate::new(rate.max(1), Duration::from_secs(1)),
+            );
+            service = BoxCloneSyncService::new(Buffer::new(limited, RATE_LIMIT_QUEUE_LEN));
+        }
+        ServiceHandler(BoxCloneSyncService::new(
+            MeasureLayer::new(scope).layer(service),
+        ))
+    }
+}
@@ -0,0 +1,74 @@
+// Caps how many connections one source address can have open at once.
+//
+// With MAX_CONNECTIONS_PER_IP set, each connection a problem admits on any of
+// its listeners takes a slot, given back when the connection finishes; one
+// arriving while its address already has that many open is closed at once (see
+// admission.rs). The count is per problem, so under `protohackers all` a
+// client can still hold that many connections to every problem. IPv4-mapped
+// IPv6 addresses count as the IPv4 address they map.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::metrics::{Gauge, Scope};
+
+pub struct PerIpLimit {
+    max: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+    addresses: Gauge,
+}
+
+// One connection's share of its address's limit, given back on drop
+pub struct IpSlot {
+    limit: Arc<PerIpLimit>,
+    ip: IpAddr,
+}
+
+impl PerIpLimit {
+    pub fn new(max: usize, scope: &Scope) -> Arc<Self> {
+        Arc::new(PerIpLimit {
+            max,
+            open: Mutex::new(HashMap::new()),
+            addresses: scope.gauge(
+                "connected_addresses",
+                "Source addresses with connections open",
+            ),
+        })
+    }
+
+    // A slot for another connection from `ip`, unless it has `max` already
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpSlot> {
+        let ip = ip.to_canonical();
+        let mut open = self
+            .open
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking per-IP connections: {}", e));
+        let count = open.get(&ip).copied().unwrap_or(0);
+        if count >= self.max {
+            return None;
+        }
+        open.insert(ip, count + 1);
+        self.addresses.set(open.len() as i64);
+        Some(IpSlot {
+            limit: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self
+            .limit
+            .open
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking per-IP connections: {}", e));
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+        self.limit.addresses.set(open.len() as i64);
+    }
+}
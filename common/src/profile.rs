@@ -0,0 +1,171 @@
+// On-demand CPU profiling, for finding hotspots during stress runs.
+//
+// When PPROF_PORT is set, `GET /debug/pprof/profile?seconds=N` samples the
+// whole process for N seconds and answers with a flamegraph SVG, or with a
+// pprof protobuf when `format=proto` is also given (for `go tool pprof`).
+// Only one profile can be collected at a time.
+//
+// Anyone who can reach the endpoint can keep the process busy profiling, so
+// it listens on 127.0.0.1 unless PPROF_ADDR names another address to bind.
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use pprof::protos::Message;
+
+const DEFAULT_PROFILE_SECS: u64 = 10;
+const MAX_PROFILE_SECS: u64 = 300;
+const SAMPLE_FREQUENCY: i32 = 99;
+const DEFAULT_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Flamegraph,
+    Proto,
+}
+
+// Returns the profile length and format asked for, or None for any other path
+fn parse_request(head: &[u8]) -> Option<(u64, Format)> {
+    let line = head.split(|&b| b == b'\r').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let target = line.strip_prefix("GET ")?.split(' ').next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/debug/pprof/profile" {
+        return None;
+    }
+
+    let mut seconds = DEFAULT_PROFILE_SECS;
+    let mut format = Format::Flamegraph;
+    for (key, value) in query.split('&').filter_map(|kv| kv.split_once('=')) {
+        match key {
+            "seconds" => seconds = value.parse().unwrap_or(DEFAULT_PROFILE_SECS),
+            "format" if value == "proto" => format = Format::Proto,
+            _ => {}
+        }
+    }
+    Some((seconds.clamp(1, MAX_PROFILE_SECS), format))
+}
+
+fn collect(seconds: u64, format: Format) -> Result<Vec<u8>, pprof::Error> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    std::thread::sleep(Duration::from_secs(seconds));
+    let report = guard.report().build()?;
+
+    let mut out = Vec::new();
+    match format {
+        Format::Flamegraph => report.flamegraph(&mut out)?,
+        Format::Proto => out = report.pprof()?.encode_to_vec(),
+    }
+    Ok(out)
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) {
+    let head = format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await.unwrap_or(());
+    socket.write_all(body).await.unwrap_or(());
+}
+
+async fn handle(mut socket: TcpStream) {
+    let Some(head) = crate::metrics::read_request_head(&mut socket).await else {
+        return;
+    };
+    let Some((seconds, format)) = parse_request(&head) else {
+        respond(&mut socket, "404 Not Found", "text/plain", b"not found\n").await;
+        return;
+    };
+    if PROFILING.swap(true, Ordering::AcqRel) {
+        respond(
+            &mut socket,
+            "409 Conflict",
+            "text/plain",
+            b"already profiling\n",
+        )
+        .await;
+        return;
+    }
+
+    crate::info!("Collecting a {}s CPU profile", seconds);
+    let result = tokio::task::spawn_blocking(move || collect(seconds, format)).await;
+    PROFILING.store(false, Ordering::Release);
+
+    match result {
+        // An idle process gives no samples, and an empty flamegraph
+        Ok(Ok(body)) if body.is_empty() => {
+            respond(
+                &mut socket,
+                "500 Internal Server Error",
+                "text/plain",
+                b"no samples collected\n",
+            )
+            .await;
+        }
+        Ok(Ok(body)) => {
+            let content_type = match format {
+                Format::Flamegraph => "image/svg+xml",
+                Format::Proto => "application/octet-stream",
+            };
+            respond(&mut socket, "200 OK", content_type, &body).await;
+        }
+        Ok(Err(e)) => {
+            let body = format!("couldn't collect profile: {}\n", e);
+            respond(
+                &mut socket,
+                "500 Internal Server Error",
+                "text/plain",
+                body.as_bytes(),
+            )
+            .await;
+        }
+        Err(e) => {
+            let body = format!("profiler task failed: {}\n", e);
+            respond(
+                &mut socket,
+                "500 Internal Server Error",
+                "text/plain",
+                body.as_bytes(),
+            )
+            .await;
+        }
+    }
+}
+
+pub async fn run_endpoint(addr: SocketAddr) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            crate::error!("Couldn't start profiling endpoint on {}: {}", addr, e);
+            return;
+        }
+    };
+    crate::info!("Serving CPU profiles on {}", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, _addr)) => {
+                tokio::spawn(handle(socket));
+            }
+            Err(e) => crate::warn!("Couldn't accept profiling connection: {:?}", e),
+        }
+    }
+}
+
+// Serve profiles on PPROF_PORT in the background, if it is set, on
+// PPROF_ADDR or loopback
+pub fn spawn_endpoint_from_env() {
+    if let Some(port) = crate::env::var::<u16>("PPROF_PORT") {
+        let addr = crate::env::var_or("PPROF_ADDR", DEFAULT_ADDR);
+        tokio::spawn(run_endpoint(SocketAddr::from((addr, port))));
+    }
+}
@@ -0,0 +1,104 @@
+// QUIC listener for the stream-oriented problems, enabled with the `quic`
+// feature. Every bidirectional stream a peer opens is served as its own
+// logical connection by the same handler used for TCP sockets.
+//
+// The certificate is read from the PEM files in QUIC_CERT and QUIC_KEY, or
+// generated (self-signed for "localhost") at startup if they aren't set.
+//
+// The listener stops accepting connections and streams once `shutdown` is
+// cancelled, which also tells every stream's handler to wind down.
+use quinn::{Connection, Endpoint, RecvStream, SendStream, ServerConfig};
+use std::net::SocketAddr;
+use tokio::io::Join;
+use tokio_util::sync::CancellationToken;
+
+use crate::admission::Admission;
+use crate::handler::{ConnectionHandler, Context};
+
+pub type QuicStream = Join<RecvStream, SendStream>;
+
+async fn serve_connection<H: ConnectionHandler>(
+    connection: Connection,
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    let addr = connection.remote_address();
+    crate::debug!("Accepted QUIC connection from {:?}", addr);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept_bi() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+        match accepted {
+            Ok((send, recv)) => {
+                // Each stream is a connection to the handler, and admitted as one
+                let Some(admitted) = admission.admit(Some(addr)) else {
+                    continue;
+                };
+                let stream: QuicStream = tokio::io::join(recv, send);
+                let ctx = Context {
+                    cancel: shutdown.child_token(),
+                    ..Context::new("quic")
+                };
+                admitted.spawn(&handler, stream, ctx);
+            }
+            Err(e) => {
+                crate::debug!("QUIC connection from {:?} closed: {}", addr, e);
+                return;
+            }
+        }
+    }
+}
+
+pub async fn run_quic_acceptor<H: ConnectionHandler>(
+    addr: SocketAddr,
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    let endpoint = match crate::cert::load("QUIC_CERT", "QUIC_KEY")
+        .and_then(|(certs, key)| {
+            ServerConfig::with_single_cert(certs, key).map_err(|e| e.to_string())
+        })
+        .and_then(|config| Endpoint::server(config, addr).map_err(|e| e.to_string()))
+    {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            crate::error!("Couldn't start QUIC listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    crate::info!("Listening for QUIC connections on {}", addr);
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = shutdown.cancelled() => return,
+        };
+        let Some(incoming) = incoming else {
+            return;
+        };
+        let (handler, admission, shutdown) = (handler.clone(), admission.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            match incoming.await {
+                Ok(connection) => serve_connection(connection, handler, admission, shutdown).await,
+                Err(e) => crate::warn!("Couldn't accept QUIC connection: {}", e),
+            }
+        });
+    }
+}
+
+// Start a QUIC listener on QUIC_PORT in the background, if it is set, until
+// `shutdown` is cancelled
+pub fn spawn_from_env<H: ConnectionHandler>(
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    if let Some(port) = crate::env::var::<u16>("QUIC_PORT") {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        tokio::spawn(run_quic_acceptor(addr, handler, admission, shutdown));
+    }
+}
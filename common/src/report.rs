@@ -0,0 +1,272 @@
+// Error reporting to a webhook or a command, for unattended deployments.
+//
+// When ERROR_WEBHOOK_URL is set (plain http:// only), handler panics, bursts of
+// errors and failed startups are POSTed to it as small JSON objects carrying
+// whatever context is known (problem, port, peer address). When ALERT_COMMAND
+// is set, it is run with `sh -c` for each of them too, with the same JSON on
+// its standard input and ALERT_KIND and ALERT_MESSAGE in its environment.
+// Reports are sent from their own thread so a slow or unreachable webhook
+// never holds up a connection.
+//
+// A burst is a counter growing by more than a threshold within a sliding
+// window, each set through the environment:
+//   DECODE_ERROR_THRESHOLD/_WINDOW_SECS  malformed requests (default 100/60)
+//   DISCONNECT_THRESHOLD/_WINDOW_SECS    connections rejected for a full
+//                                        queue, too slow or panicked (50/60)
+//   ACCEPT_ERROR_THRESHOLD/_WINDOW_SECS  failed accept calls (10/60)
+// After a report the same burst isn't reported again for a window.
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{Counter, Scope};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_DECODE_ERROR_THRESHOLD: u64 = 100;
+const DEFAULT_DISCONNECT_THRESHOLD: u64 = 50;
+const DEFAULT_ACCEPT_ERROR_THRESHOLD: u64 = 10;
+const DEFAULT_WINDOW_SECS: u64 = 60;
+
+// Samples a burst watcher keeps per window
+const SAMPLES_PER_WINDOW: u32 = 10;
+
+struct Webhook {
+    host: String,
+    path: String,
+}
+
+fn webhook() -> Option<Webhook> {
+    let url = crate::env::var::<String>("ERROR_WEBHOOK_URL")?;
+    let Some(rest) = url.strip_prefix("http://") else {
+        crate::warn!(
+            "Ignoring ERROR_WEBHOOK_URL={:?}: only http:// is supported",
+            url
+        );
+        return None;
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    Some(Webhook {
+        host: host.to_owned(),
+        path: path.to_owned(),
+    })
+}
+
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn render(kind: &str, message: &str, context: &[(&str, String)]) -> String {
+    let mut body = format!(
+        "{{\"kind\":\"{}\",\"message\":\"{}\"",
+        json_escape(kind),
+        json_escape(message)
+    );
+    for (k, v) in context {
+        body.push_str(&format!(",\"{}\":\"{}\"", json_escape(k), json_escape(v)));
+    }
+    body.push('}');
+    body
+}
+
+fn post(webhook: &Webhook, body: &str) -> std::io::Result<()> {
+    let host = if webhook.host.contains(':') {
+        webhook.host.clone()
+    } else {
+        format!("{}:80", webhook.host)
+    };
+    let addr = host
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other("webhook host didn't resolve"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        webhook.path,
+        webhook.host,
+        body.len(),
+        body
+    )?;
+    // Only wait for the server to take the request, the response isn't used
+    let mut status = [0; 12];
+    stream.read(&mut status).map(|_| ())
+}
+
+fn run_command(command: &str, kind: &str, message: &str, body: &str) -> std::io::Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("ALERT_KIND", kind)
+        .env("ALERT_MESSAGE", message)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that doesn't read its input is fine too
+        stdin.write_all(body.as_bytes()).unwrap_or(());
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("alert command {}", status)));
+    }
+    Ok(())
+}
+
+fn alerting() -> bool {
+    webhook().is_some() || crate::env::var::<String>("ALERT_COMMAND").is_some()
+}
+
+// Send a report in the background. The returned handle can be joined to wait
+// for delivery, e.g. right before the process exits.
+pub fn report(kind: &str, message: &str, context: &[(&str, String)]) -> Option<JoinHandle<()>> {
+    let webhook = webhook();
+    let command = crate::env::var::<String>("ALERT_COMMAND");
+    if webhook.is_none() && command.is_none() {
+        return None;
+    }
+    let body = render(kind, message, context);
+    let (kind, message) = (kind.to_owned(), message.to_owned());
+    Some(std::thread::spawn(move || {
+        if let Some(webhook) = webhook {
+            if let Err(e) = post(&webhook, &body) {
+                crate::warn!("Couldn't deliver error report: {}", e);
+            }
+        }
+        if let Some(command) = command {
+            if let Err(e) = run_command(&command, &kind, &message, &body) {
+                crate::warn!("Couldn't run alert command: {}", e);
+            }
+        }
+    }))
+}
+
+pub fn report_panic(scope: &Scope, peer: &str, payload: &(dyn std::any::Any + Send)) {
+    let message = if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "non-string panic payload".to_owned()
+    };
+    let mut context: Vec<(&str, String)> = scope
+        .labels()
+        .iter()
+        .map(|(k, v)| (*k, v.clone()))
+        .collect();
+    context.push(("peer", peer.to_owned()));
+    report("panic", &message, &context);
+}
+
+// Unwrap a startup step, reporting and exiting if it failed
+pub fn startup<T, E: Debug>(what: &str, result: Result<T, E>) -> T {
+    match result {
+        Ok(v) => v,
+        Err(e) => {
+            let message = format!("Couldn't {}: {:?}", what, e);
+            crate::error!("{}", message);
+            if let Some(handle) = report("startup", &message, &[]) {
+                handle.join().unwrap_or(());
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+// Report whenever the sum of `counters` grows by more than
+// <setting>_THRESHOLD within <setting>_WINDOW_SECS
+pub fn watch_bursts(
+    scope: &Scope,
+    setting: &str,
+    kind: &'static str,
+    what: &'static str,
+    counters: Vec<Counter>,
+    default_threshold: u64,
+) {
+    if !alerting() {
+        return;
+    }
+    let threshold = crate::env::var_or(&format!("{}_THRESHOLD", setting), default_threshold);
+    let window = Duration::from_secs(
+        crate::env::var_or(&format!("{}_WINDOW_SECS", setting), DEFAULT_WINDOW_SECS).max(1),
+    );
+    let context: Vec<(&str, String)> = scope
+        .labels()
+        .iter()
+        .map(|(k, v)| (*k, v.clone()))
+        .collect();
+    let total = move || counters.iter().map(Counter::get).sum::<u64>();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(window / SAMPLES_PER_WINDOW);
+        // Totals over the last window, oldest first
+        let mut samples: VecDeque<(Instant, u64)> = VecDeque::new();
+        let mut quiet_until = None;
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            let current = total();
+            samples.push_back((now, current));
+            while samples.front().is_some_and(|(at, _)| now - *at > window) {
+                samples.pop_front();
+            }
+            let grown = current - samples.front().map_or(current, |(_, total)| *total);
+            if grown > threshold && quiet_until.is_none_or(|until| now >= until) {
+                let message = format!("{} {} in the last {:?}", grown, what, window);
+                report(kind, &message, &context);
+                quiet_until = Some(now + window);
+            }
+        }
+    });
+}
+
+// Report bursts of malformed input to a problem
+pub fn watch_decode_errors(scope: &Scope, what: &'static str, errors: Counter) {
+    watch_bursts(
+        scope,
+        "DECODE_ERROR",
+        "decode_errors",
+        what,
+        vec![errors],
+        DEFAULT_DECODE_ERROR_THRESHOLD,
+    );
+}
+
+// Report bursts of connections dropped for an error, and of failed accepts
+pub(crate) fn watch_accept_errors(scope: &Scope, dropped: Vec<Counter>, accept_errors: Counter) {
+    watch_bursts(
+        scope,
+        "DISCONNECT",
+        "disconnects",
+        "connections dropped",
+        dropped,
+        DEFAULT_DISCONNECT_THRESHOLD,
+    );
+    watch_bursts(
+        scope,
+        "ACCEPT_ERROR",
+        "accept_errors",
+        "failed accepts",
+        vec![accept_errors],
+        DEFAULT_ACCEPT_ERROR_THRESHOLD,
+    );
+}
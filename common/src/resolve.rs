@@ -0,0 +1,85 @@
+// Name resolution for outbound connections.
+//
+// Without the `resolver` feature, host names go through tokio's lookup, which
+// runs the system's blocking getaddrinfo on the blocking pool. With it, they
+// go through a hickory resolver shared by the whole process, configured from
+// /etc/resolv.conf, that caches answers for their TTL (up to DNS_CACHE_SIZE
+// names, default 64) and gives up on a query after DNS_TIMEOUT_MILLIS
+// (default 2000). Addresses given as IP literals are never looked up.
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+#[cfg(feature = "resolver")]
+const DEFAULT_DNS_CACHE_SIZE: usize = 64;
+#[cfg(feature = "resolver")]
+const DEFAULT_DNS_TIMEOUT_MILLIS: u64 = 2000;
+
+#[cfg(feature = "resolver")]
+fn resolver() -> &'static hickory_resolver::TokioAsyncResolver {
+    use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    static RESOLVER: OnceLock<hickory_resolver::TokioAsyncResolver> = OnceLock::new();
+    RESOLVER.get_or_init(|| {
+        let (config, mut opts) =
+            hickory_resolver::system_conf::read_system_conf().unwrap_or_else(|e| {
+                crate::warn!(
+                    "Couldn't read system DNS configuration, using defaults: {}",
+                    e
+                );
+                (ResolverConfig::default(), ResolverOpts::default())
+            });
+        opts.cache_size = crate::env::var_or("DNS_CACHE_SIZE", DEFAULT_DNS_CACHE_SIZE);
+        opts.timeout = Duration::from_millis(crate::env::var_or(
+            "DNS_TIMEOUT_MILLIS",
+            DEFAULT_DNS_TIMEOUT_MILLIS,
+        ));
+        hickory_resolver::TokioAsyncResolver::tokio(config, opts)
+    })
+}
+
+// The addresses a "host:port" stands for
+#[cfg(feature = "resolver")]
+pub async fn lookup(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = addr.parse() {
+        return Ok(vec![addr]);
+    }
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("expected host:port, got {:?}", addr),
+        )
+    };
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let ips = resolver()
+        .lookup_ip(host.trim_start_matches('[').trim_end_matches(']'))
+        .await
+        .map_err(|e| io::Error::other(format!("couldn't resolve {}: {}", host, e)))?;
+    Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+}
+
+// The addresses a "host:port" stands for
+#[cfg(not(feature = "resolver"))]
+pub async fn lookup(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    Ok(tokio::net::lookup_host(addr).await?.collect())
+}
+
+// Connect to "host:port", trying each address it resolves to in turn
+pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in lookup(addr).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolved to no addresses", addr),
+        )
+    }))
+}
@@ -0,0 +1,200 @@
+// Retrying outbound operations with exponential backoff.
+//
+// The delay before each retry doubles from `initial` up to `max`, and is
+// jittered to between half and all of that, so clients that failed together
+// don't all retry together. Delays are tokio sleeps, so they follow tokio's
+// clock (and a paused one in tests). Waiting stops as soon as the given
+// cancellation token fires.
+use rand::Rng;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    // Attempts in total, including the first; None retries forever
+    max_attempts: Option<u32>,
+}
+
+// Why `retry` stopped without a result
+#[derive(Debug)]
+pub enum Stopped<E> {
+    Cancelled,
+    // Every attempt failed; this is the last error
+    GaveUp(E),
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            max_attempts: None,
+        }
+    }
+
+    pub fn max_attempts(self, attempts: u32) -> Self {
+        Backoff {
+            max_attempts: Some(attempts.max(1)),
+            ..self
+        }
+    }
+
+    // How long to wait after failed attempt number `attempt`, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        let ceiling = self.initial.saturating_mul(1 << doublings).min(self.max);
+        let half = ceiling / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=ceiling - half)
+    }
+}
+
+// Run `op` until it succeeds, it has failed `max_attempts` times, or `cancel`
+// fires. `op` is given the attempt number, and failures are logged as `what`.
+pub async fn retry<T, E, F, Fut>(
+    backoff: &Backoff,
+    what: &str,
+    cancel: &CancellationToken,
+    mut op: F,
+) -> Result<T, Stopped<E>>
+where
+    E: Display,
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        let result = tokio::select! {
+            result = op(attempt) => result,
+            _ = cancel.cancelled() => return Err(Stopped::Cancelled),
+        };
+        let e = match result {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if backoff.max_attempts.is_some_and(|max| attempt >= max) {
+            crate::error!("Couldn't {} after {} attempts: {}", what, attempt, e);
+            return Err(Stopped::GaveUp(e));
+        }
+        let delay = backoff.delay(attempt);
+        crate::warn!(
+            "Couldn't {} (attempt {}): {}, retrying in {:.1?}",
+            what,
+            attempt,
+            e,
+            delay
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel.cancelled() => return Err(Stopped::Cancelled),
+        }
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::time::Instant;
+
+    const INITIAL: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(1);
+
+    // The delay before the retry after `attempt`, before jitter
+    fn ceiling(attempt: u32) -> Duration {
+        (INITIAL * 2u32.pow(attempt - 1)).min(MAX)
+    }
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let backoff = Backoff::new(INITIAL, MAX);
+        for attempt in 1..=8 {
+            for _ in 0..20 {
+                let delay = backoff.delay(attempt);
+                assert!(delay >= ceiling(attempt) / 2, "{:?}", delay);
+                assert!(delay <= ceiling(attempt), "{:?}", delay);
+            }
+        }
+        assert!(backoff.delay(u32::MAX) <= MAX);
+        assert_eq!(Backoff::new(MAX, INITIAL).max, MAX);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_back_off_until_success() {
+        let backoff = Backoff::new(INITIAL, MAX);
+        let mut started = Vec::new();
+        let result = retry(&backoff, "test", &CancellationToken::new(), |attempt| {
+            started.push(Instant::now());
+            async move {
+                match attempt {
+                    6 => Ok(attempt),
+                    _ => Err("failed"),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 6);
+        for (attempt, gap) in (1..).zip(started.windows(2).map(|w| w[1] - w[0])) {
+            assert!(gap >= ceiling(attempt) / 2, "{:?}", gap);
+            assert!(gap <= ceiling(attempt), "{:?}", gap);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_attempts() {
+        let backoff = Backoff::new(INITIAL, MAX).max_attempts(3);
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = retry(&backoff, "test", &CancellationToken::new(), |n| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            async move { Err(n) }
+        })
+        .await;
+        assert!(matches!(result, Err(Stopped::GaveUp(3))));
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        // Zero attempts still means one
+        let backoff = Backoff::new(INITIAL, MAX).max_attempts(0);
+        let result: Result<(), _> = retry(
+            &backoff,
+            "test",
+            &CancellationToken::new(),
+            |n| async move { Err(n) },
+        )
+        .await;
+        assert!(matches!(result, Err(Stopped::GaveUp(1))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_stops_a_wait() {
+        let backoff = Backoff::new(Duration::from_secs(60), Duration::from_secs(60));
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceller.cancel();
+        });
+        let start = Instant::now();
+        let result: Result<(), _> =
+            retry(&backoff, "test", &cancel, |n| async move { Err(n) }).await;
+        assert!(matches!(result, Err(Stopped::Cancelled)));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_stops_an_attempt() {
+        let backoff = Backoff::new(INITIAL, MAX);
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceller.cancel();
+        });
+        let result: Result<(), Stopped<&str>> =
+            retry(&backoff, "test", &cancel, |_| std::future::pending()).await;
+        assert!(matches!(result, Err(Stopped::Cancelled)));
+    }
+}
@@ -0,0 +1,468 @@
+// Process hardening, enabled with the `sandbox` feature and SANDBOX=true.
+//
+// It comes in two steps because the two mechanisms apply differently:
+// Landlock only restricts the calling thread and the threads it starts later,
+// so `restrict_paths_from_env` has to run before the runtime spawns its
+// workers, while seccomp filters can be synced to every thread at once, so
+// `restrict_syscalls_from_env` runs after the listeners are bound.
+//
+// Filesystem access is limited to reading SANDBOX_READ_PATHS (default
+// /etc:/proc:/sys:/dev) and reading and writing SANDBOX_WRITE_PATHS, plus the
+// directory of HANDOVER_SOCKET. Landlock is best effort: on kernels without it
+// the process carries on unrestricted, with a warning.
+//
+// The seccomp filter works one of two ways, picked with SANDBOX_SYSCALLS. By
+// default ("deny") it denies a fixed list of syscalls a network server never
+// needs, such as execve, ptrace, mount or module loading, along with clone
+// making new namespaces and packet sockets. With "allow" it allows only the
+// syscalls the servers are known to make and denies everything else, which
+// is tighter but can break with an allocator or feature that starts using
+// something new. Either way clone3 answers ENOSYS: its flags are out of the
+// filter's reach, and the C library falls back to clone, whose flags aren't.
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const DEFAULT_READ_PATHS: &str = "/etc:/proc:/sys:/dev";
+
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_adjtimex,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+    libc::SYS_personality,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_fsopen,
+    libc::SYS_open_tree,
+    libc::SYS_move_mount,
+];
+
+// What the servers call, for SANDBOX_SYSCALLS=allow. clone and socket are
+// allowed separately, for some arguments only.
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // Files and descriptors
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_flock,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_mkdirat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getcwd,
+    libc::SYS_getdents64,
+    // Sockets
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_connect,
+    libc::SYS_shutdown,
+    libc::SYS_socketpair,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    // Waiting
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_eventfd2,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_futex,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_sched_yield,
+    libc::SYS_restart_syscall,
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_membarrier,
+    // Threads, signals and the process
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_tgkill,
+    libc::SYS_prctl,
+    // For the clone3 filter that goes on after, and filters can only tighten
+    libc::SYS_seccomp,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getrlimit,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_getrandom,
+    libc::SYS_uname,
+    libc::SYS_wait4,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+];
+
+// The clone flags that make new namespaces
+const CLONE_NAMESPACES: &[libc::c_int] = &[
+    libc::CLONE_NEWNS,
+    libc::CLONE_NEWCGROUP,
+    libc::CLONE_NEWUTS,
+    libc::CLONE_NEWIPC,
+    libc::CLONE_NEWUSER,
+    libc::CLONE_NEWPID,
+    libc::CLONE_NEWNET,
+    libc::CLONE_NEWTIME,
+];
+
+// How the seccomp filter picks the syscalls it stops, from SANDBOX_SYSCALLS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscalls {
+    // Deny a list of syscalls and allow the rest
+    Deny,
+    // Allow a list of syscalls and deny the rest
+    Allow,
+}
+
+impl FromStr for Syscalls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deny" => Ok(Syscalls::Deny),
+            "allow" => Ok(Syscalls::Allow),
+            _ => Err(format!(
+                "unknown syscall filter {:?}, expected deny or allow",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Syscalls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Syscalls::Deny => "deny",
+            Syscalls::Allow => "allow",
+        })
+    }
+}
+
+fn enabled() -> bool {
+    crate::env::var_or("SANDBOX", false)
+}
+
+fn paths(name: &str, default: &str) -> Vec<PathBuf> {
+    crate::env::var_or::<String>(name, default.to_owned())
+        .split(':')
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn restrict_paths(read: &[PathBuf], write: &[PathBuf]) -> Result<RulesetStatus, String> {
+    let abi = ABI::V5;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))
+        .and_then(|r| r.create())
+        .and_then(|r| r.add_rules(path_beneath_rules(read, AccessFs::from_read(abi))))
+        .and_then(|r| r.add_rules(path_beneath_rules(write, AccessFs::from_all(abi))))
+        .and_then(|r| r.restrict_self())
+        .map_err(|e| e.to_string())?;
+    Ok(status.ruleset)
+}
+
+// Limit filesystem access; call before starting any threads
+pub fn restrict_paths_from_env() {
+    if !enabled() {
+        return;
+    }
+    let read = paths("SANDBOX_READ_PATHS", DEFAULT_READ_PATHS);
+    let mut write = paths("SANDBOX_WRITE_PATHS", "");
+    // The next process has to be able to find the handover socket
+    if let Some(dir) = crate::env::var::<PathBuf>("HANDOVER_SOCKET")
+        .as_deref()
+        .and_then(Path::parent)
+    {
+        write.push(dir.to_owned());
+    }
+
+    match crate::report::startup("restrict filesystem access", restrict_paths(&read, &write)) {
+        RulesetStatus::FullyEnforced => crate::info!("Filesystem access restricted"),
+        RulesetStatus::PartiallyEnforced => {
+            crate::info!(
+                "Filesystem access partly restricted, the kernel lacks newer Landlock rights"
+            )
+        }
+        RulesetStatus::NotEnforced => {
+            crate::warn!("Landlock isn't available, filesystem access is not restricted")
+        }
+    }
+}
+
+// A rule matching when argument `arg` compares to `value` by `op`
+fn rule(arg: u8, len: SeccompCmpArgLen, op: SeccompCmpOp, value: u64) -> SeccompRule {
+    let condition = SeccompCondition::new(arg, len, op, value).unwrap();
+    SeccompRule::new(vec![condition]).unwrap()
+}
+
+fn compile(
+    rules: BTreeMap<i64, Vec<SeccompRule>>,
+    mismatch: SeccompAction,
+    matched: SeccompAction,
+) -> Result<BpfProgram, String> {
+    let arch = std::env::consts::ARCH
+        .try_into()
+        .map_err(|e| format!("{}", e))?;
+    let filter = SeccompFilter::new(rules, mismatch, matched, arch).map_err(|e| e.to_string())?;
+    filter
+        .try_into()
+        .map_err(|e: seccompiler::BackendError| e.to_string())
+}
+
+// The filters for `syscalls`, to install in order
+fn filters(syscalls: Syscalls) -> Result<Vec<BpfProgram>, String> {
+    let packet = libc::AF_PACKET as u64;
+    let namespaces = CLONE_NAMESPACES.iter().map(|&flag| flag as u64);
+    let deny = SeccompAction::Errno(libc::EPERM as u32);
+    let main = match syscalls {
+        Syscalls::Deny => {
+            let mut rules: BTreeMap<_, _> = DENIED_SYSCALLS.iter().map(|&s| (s, vec![])).collect();
+            // Any one namespace flag is enough to deny a clone
+            let clone = namespaces
+                .map(|flag| {
+                    rule(
+                        0,
+                        SeccompCmpArgLen::Qword,
+                        SeccompCmpOp::MaskedEq(flag),
+                        flag,
+                    )
+                })
+                .collect();
+            rules.insert(libc::SYS_clone, clone);
+            let socket = rule(0, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, packet);
+            rules.insert(libc::SYS_socket, vec![socket]);
+            compile(rules, SeccompAction::Allow, deny)?
+        }
+        Syscalls::Allow => {
+            let mut rules: BTreeMap<_, _> = ALLOWED_SYSCALLS.iter().map(|&s| (s, vec![])).collect();
+            let mask = namespaces.fold(0, |mask, flag| mask | flag);
+            let clone = rule(0, SeccompCmpArgLen::Qword, SeccompCmpOp::MaskedEq(mask), 0);
+            rules.insert(libc::SYS_clone, vec![clone]);
+            let socket = rule(0, SeccompCmpArgLen::Dword, SeccompCmpOp::Ne, packet);
+            rules.insert(libc::SYS_socket, vec![socket]);
+            compile(rules, deny, SeccompAction::Allow)?
+        }
+    };
+    // Installed last, so that for clone3 its answer is the one that counts
+    let clone3 = BTreeMap::from([(libc::SYS_clone3, vec![])]);
+    let clone3 = compile(
+        clone3,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::ENOSYS as u32),
+    )?;
+    Ok(vec![main, clone3])
+}
+
+fn restrict_syscalls(syscalls: Syscalls) -> Result<(), String> {
+    for filter in filters(syscalls)? {
+        seccompiler::apply_filter_all_threads(&filter).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Deny syscalls the server never needs, in every thread; call once the
+// listeners are bound
+pub fn restrict_syscalls_from_env() {
+    if !enabled() {
+        return;
+    }
+    let syscalls = crate::env::var_or("SANDBOX_SYSCALLS", Syscalls::Deny);
+    crate::report::startup("restrict syscalls", restrict_syscalls(syscalls));
+    crate::info!("Syscalls restricted ({} list)", syscalls);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run `check` in a child process under the filters for `syscalls`,
+    // returning its exit status
+    fn in_child(syscalls: Syscalls, check: fn() -> bool) -> i32 {
+        let filters = filters(syscalls).unwrap();
+        // The child only makes raw syscalls before exiting
+        match unsafe { libc::fork() } {
+            0 => {
+                let applied = filters.iter().all(|f| seccompiler::apply_filter(f).is_ok());
+                unsafe { libc::_exit(if applied && check() { 0 } else { 1 }) }
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                libc::WEXITSTATUS(status)
+            }
+        }
+    }
+
+    fn errno() -> i32 {
+        std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+    }
+
+    // Namespaces, packet sockets and clone3 are refused; an ordinary socket
+    // and a plain fork aren't
+    fn refuses_namespaces_and_packet_sockets() -> bool {
+        unsafe {
+            let flags = (libc::CLONE_NEWUSER | libc::SIGCHLD) as libc::c_long;
+            let clone = libc::syscall(libc::SYS_clone, flags, 0, 0, 0, 0);
+            if clone == 0 {
+                libc::_exit(1);
+            }
+            if clone != -1 || errno() != libc::EPERM {
+                return false;
+            }
+            if libc::syscall(libc::SYS_clone3, 0, 0) != -1 || errno() != libc::ENOSYS {
+                return false;
+            }
+            if libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) != -1 || errno() != libc::EPERM {
+                return false;
+            }
+            let tcp = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+            if tcp < 0 {
+                return false;
+            }
+            libc::close(tcp);
+            let plain = libc::syscall(libc::SYS_clone, libc::SIGCHLD as libc::c_long, 0, 0, 0, 0);
+            if plain == 0 {
+                libc::_exit(0);
+            }
+            plain > 0 && libc::waitpid(plain as libc::pid_t, std::ptr::null_mut(), 0) > 0
+        }
+    }
+
+    #[test]
+    fn denylist_blocks_namespaces_and_packet_sockets() {
+        assert_eq!(
+            in_child(Syscalls::Deny, refuses_namespaces_and_packet_sockets),
+            0
+        );
+    }
+
+    #[test]
+    fn allowlist_blocks_namespaces_and_packet_sockets() {
+        assert_eq!(
+            in_child(Syscalls::Allow, refuses_namespaces_and_packet_sockets),
+            0
+        );
+    }
+
+    #[test]
+    fn allowlist_blocks_what_it_doesnt_list() {
+        let status = in_child(Syscalls::Allow, || unsafe {
+            libc::syscall(libc::SYS_sethostname, 0, 0) == -1
+                && errno() == libc::EPERM
+                && libc::syscall(libc::SYS_mknodat, 0, 0, 0, 0) == -1
+                && errno() == libc::EPERM
+        });
+        assert_eq!(status, 0);
+    }
+}
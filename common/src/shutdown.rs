@@ -0,0 +1,64 @@
+// Graceful shutdown on SIGINT or SIGTERM.
+//
+// `on_signal` hands out a token that is cancelled when the process gets
+// either signal. An accept loop given it stops accepting, cancels the token in
+// every connection's Context so handlers can finish what they are doing and
+// say goodbye, and waits up to SHUTDOWN_DRAIN_SECS (default 5) for them to
+// return before it does. A second signal exits at once, for when that is
+// taking too long.
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_DRAIN_SECS: u64 = 5;
+
+static SHUTDOWN: OnceLock<CancellationToken> = OnceLock::new();
+
+// Cancelled on the first SIGINT or SIGTERM. Must be called from within a
+// runtime, which the first call starts listening for signals on.
+pub fn on_signal() -> CancellationToken {
+    SHUTDOWN
+        .get_or_init(|| {
+            let shutdown = CancellationToken::new();
+            let cancel = shutdown.clone();
+            tokio::spawn(async move {
+                wait_for_signal().await;
+                crate::info!("Shutting down, send another signal to exit now");
+                cancel.cancel();
+                wait_for_signal().await;
+                std::process::exit(1);
+            });
+            shutdown
+        })
+        .clone()
+}
+
+// How long connections get to finish once told to wind down
+pub fn drain_period() -> Duration {
+    Duration::from_secs(crate::env::var_or(
+        "SHUTDOWN_DRAIN_SECS",
+        DEFAULT_DRAIN_SECS,
+    ))
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            crate::warn!("Couldn't listen for SIGTERM: {}", e);
+            tokio::signal::ctrl_c().await.unwrap_or(());
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    tokio::signal::ctrl_c().await.unwrap_or(());
+}
@@ -0,0 +1,112 @@
+// Machine-readable report written when the process shuts down.
+//
+// With SHUTDOWN_REPORT set to a path (or "-" for stdout), every problem's
+// metrics are written out as one JSON object, along with how long the problem
+// was up, when the process gets SIGINT or SIGTERM or finishes draining after a
+// handover. Keeping the reports of two checker runs makes it easy to compare
+// revisions.
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use crate::metrics::Scope;
+use crate::report::json_escape;
+
+fn destination() -> Option<String> {
+    crate::env::var("SHUTDOWN_REPORT")
+}
+
+fn started() -> &'static Mutex<Vec<(Scope, Instant)>> {
+    static STARTED: OnceLock<Mutex<Vec<(Scope, Instant)>>> = OnceLock::new();
+    STARTED.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+// Start timing a problem's uptime. The report is written when its accept
+// loop returns, on a shutdown or after draining. A problem restarted in the
+// same process keeps the time it first started.
+pub(crate) fn register(scope: &Scope) {
+    let mut started = started()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking shutdown report: {}", e));
+    if !started.iter().any(|(s, _)| s.labels() == scope.labels()) {
+        started.push((scope.clone(), Instant::now()));
+    }
+}
+
+pub fn render() -> String {
+    let started = started()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking shutdown report: {}", e));
+    let samples = crate::metrics::samples();
+
+    let mut out = String::from("{\"problems\":[");
+    for (i, (scope, since)) in started.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push('{');
+        for (k, v) in scope.labels() {
+            write!(out, "\"{}\":\"{}\",", k, json_escape(v)).unwrap();
+        }
+        write!(
+            out,
+            "\"uptime_secs\":{:.3},\"metrics\":{{",
+            since.elapsed().as_secs_f64()
+        )
+        .unwrap();
+        // A problem's metrics are those carrying all of its labels; any
+        // others they have are kept in the key
+        let mut first = true;
+        for sample in &samples {
+            if !scope.labels().iter().all(|l| sample.labels.contains(l)) {
+                continue;
+            }
+            let extra: Vec<(&str, String)> = sample
+                .labels
+                .iter()
+                .filter(|l| !scope.labels().contains(l))
+                .cloned()
+                .collect();
+            let key = format!(
+                "{}{}{}",
+                sample.name,
+                sample.suffix,
+                crate::metrics::render_labels(&extra)
+            );
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            write!(out, "\"{}\":{}", json_escape(&key), sample.value).unwrap();
+        }
+        out.push_str("}}");
+    }
+    out.push_str("]}\n");
+    out
+}
+
+// Write the report wherever SHUTDOWN_REPORT says, if it is set
+pub fn write_from_env() {
+    let Some(destination) = destination() else {
+        return;
+    };
+    let report = render();
+    if destination == "-" {
+        print!("{}", report);
+    } else if let Err(e) = std::fs::write(&destination, report) {
+        crate::warn!("Couldn't write shutdown report to {}: {}", destination, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_restarted_problem_is_reported_once() {
+        let scope = Scope::new("problem0", 61001);
+        register(&scope);
+        register(&Scope::new("problem0", 61001));
+        assert_eq!(render().matches("\"port\":\"61001\"").count(), 1);
+    }
+}
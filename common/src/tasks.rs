@@ -0,0 +1,382 @@
+// What every connection's task is doing, to find the ones that hang.
+//
+// Each connection a transport hands to a handler has a `Task`, registered
+// with its transport, peer and start time for as long as the connection's
+// context is alive. Handlers name the phase they are in as they go, and bytes
+// moving through a socket from the shared accept loop count as progress too.
+// The open tasks, oldest first, are served as /tasks on the metrics endpoint
+// and printed on SIGUSR1. A task that hasn't progressed in STALL_SECS
+// (default 30) is flagged as stalled there, and logged the first time it is.
+// A client that is quiet for that long stalls its task as well; the phase
+// tells those apart from a select loop that is stuck.
+//
+// `list` gives the same for tooling to look through, along with the problem
+// and the bytes moved through sockets from the shared accept loop, and
+// `close` forcibly closes a connection: its handler is dropped on the spot,
+// and the socket with it. A POST to /tasks/close?id=N on the metrics endpoint
+// does the same from outside.
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_STALL_SECS: u64 = 30;
+
+struct State {
+    transport: &'static str,
+    problem: OnceLock<String>,
+    peer: Mutex<Option<SocketAddr>>,
+    started: Instant,
+    // Milliseconds from `started` to the last progress
+    progressed: AtomicU64,
+    phase: Mutex<&'static str>,
+    reported: AtomicBool,
+    read: AtomicU64,
+    written: AtomicU64,
+    closed: CancellationToken,
+}
+
+impl State {
+    fn idle(&self) -> Duration {
+        let progressed = Duration::from_millis(self.progressed.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(progressed)
+    }
+
+    fn phase(&self) -> &'static str {
+        *self
+            .phase
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking task phase: {}", e))
+    }
+
+    fn peer(&self) -> String {
+        self.peer
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking task peer: {}", e))
+            .map_or_else(|| "unknown".to_owned(), |p| p.to_string())
+    }
+}
+
+// One open connection, as `list` found it
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub transport: &'static str,
+    pub problem: Option<String>,
+    pub peer: Option<SocketAddr>,
+    pub age: Duration,
+    pub idle: Duration,
+    pub phase: &'static str,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+fn open_tasks() -> &'static Mutex<BTreeMap<u64, Arc<State>>> {
+    static OPEN: OnceLock<Mutex<BTreeMap<u64, Arc<State>>>> = OnceLock::new();
+    OPEN.get_or_init(|| {
+        crate::metrics::register_page("/tasks", render);
+        crate::metrics::register_command("/tasks/close", close_command);
+        Mutex::new(BTreeMap::new())
+    })
+}
+
+fn stall_after() -> Duration {
+    static STALL: OnceLock<Duration> = OnceLock::new();
+    *STALL.get_or_init(|| {
+        Duration::from_secs(crate::env::var_or("STALL_SECS", DEFAULT_STALL_SECS).max(1))
+    })
+}
+
+// Every open connection, oldest first
+pub fn list() -> Vec<TaskInfo> {
+    open_tasks()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking open tasks: {}", e))
+        .iter()
+        .map(|(&id, state)| TaskInfo {
+            id,
+            transport: state.transport,
+            problem: state.problem.get().cloned(),
+            peer: *state
+                .peer
+                .lock()
+                .unwrap_or_else(|e| panic!("Error locking task peer: {}", e)),
+            age: state.started.elapsed(),
+            idle: state.idle(),
+            phase: state.phase(),
+            bytes_read: state.read.load(Ordering::Relaxed),
+            bytes_written: state.written.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+// Close connection `id` without waiting for its handler, returning whether
+// it was open
+pub fn close(id: u64) -> bool {
+    let open = open_tasks()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking open tasks: {}", e));
+    let Some(state) = open.get(&id) else {
+        return false;
+    };
+    state.closed.cancel();
+    true
+}
+
+// Close the task named by the "id=N" in `query`
+fn close_command(query: &str) -> String {
+    let id = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("id="))
+        .and_then(|id| id.parse::<u64>().ok());
+    match id {
+        Some(id) if close(id) => format!("Closed task {}\n", id),
+        Some(id) => format!("No open task {}\n", id),
+        None => "Usage: /tasks/close?id=N\n".to_owned(),
+    }
+}
+
+fn render() -> String {
+    let open = open_tasks()
+        .lock()
+        .unwrap_or_else(|e| panic!("Error locking open tasks: {}", e));
+    let stall_after = stall_after();
+    let mut out = String::new();
+    for (id, state) in open.iter() {
+        let idle = state.idle();
+        writeln!(
+            out,
+            "{} {} {} {} age {:.1}s idle {:.1}s read {} written {} phase {:?}{}",
+            id,
+            state.problem.get().map_or("-", |p| p),
+            state.transport,
+            state.peer(),
+            state.started.elapsed().as_secs_f64(),
+            idle.as_secs_f64(),
+            state.read.load(Ordering::Relaxed),
+            state.written.load(Ordering::Relaxed),
+            state.phase(),
+            if idle >= stall_after { " STALLED" } else { "" }
+        )
+        .unwrap_or(());
+    }
+    out
+}
+
+// One connection's entry; removed when the last clone is dropped
+struct Registration {
+    id: u64,
+    state: Arc<State>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        open_tasks()
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking open tasks: {}", e))
+            .remove(&self.id);
+    }
+}
+
+#[derive(Clone)]
+pub struct Task(Arc<Registration>);
+
+impl Task {
+    pub fn register(transport: &'static str) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(State {
+            transport,
+            problem: OnceLock::new(),
+            peer: Mutex::new(None),
+            started: Instant::now(),
+            progressed: AtomicU64::new(0),
+            phase: Mutex::new("starting"),
+            reported: AtomicBool::new(false),
+            read: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            closed: CancellationToken::new(),
+        });
+        open_tasks()
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking open tasks: {}", e))
+            .insert(id, state.clone());
+        Task(Arc::new(Registration { id, state }))
+    }
+
+    pub(crate) fn set_peer(&self, peer: Option<SocketAddr>) {
+        *self
+            .0
+            .state
+            .peer
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking task peer: {}", e)) = peer;
+    }
+
+    // The problem serving the connection, if the transport knows it
+    pub(crate) fn set_problem(&self, problem: &str) {
+        self.0.state.problem.get_or_init(|| problem.to_owned());
+    }
+
+    // Enter `phase`, which counts as progress
+    pub fn phase(&self, phase: &'static str) {
+        *self
+            .0
+            .state
+            .phase
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking task phase: {}", e)) = phase;
+        self.progress();
+    }
+
+    // Time since the last progress
+    pub(crate) fn idle(&self) -> Duration {
+        self.0.state.idle()
+    }
+
+    pub(crate) fn age(&self) -> Duration {
+        self.0.state.started.elapsed()
+    }
+
+    pub fn progress(&self) {
+        let state = &self.0.state;
+        let elapsed = state.started.elapsed().as_millis() as u64;
+        state.progressed.store(elapsed, Ordering::Relaxed);
+        state.reported.store(false, Ordering::Relaxed);
+    }
+
+    // `n` bytes came in, which counts as progress
+    pub(crate) fn read(&self, n: usize) {
+        self.0.state.read.fetch_add(n as u64, Ordering::Relaxed);
+        self.progress();
+    }
+
+    // `n` bytes went out, which counts as progress
+    pub(crate) fn wrote(&self, n: usize) {
+        self.0.state.written.fetch_add(n as u64, Ordering::Relaxed);
+        self.progress();
+    }
+
+    // Resolves once `close` is called for this connection
+    pub(crate) async fn closed(&self) {
+        self.0.state.closed.cancelled().await
+    }
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Task")
+            .field("id", &self.0.id)
+            .field("phase", &self.0.state.phase())
+            .finish()
+    }
+}
+
+// Log tasks as they stall, and print every task on SIGUSR1. Only the first
+// call in a process does anything.
+pub fn spawn_from_env() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        tokio::spawn(report_stalls());
+        #[cfg(unix)]
+        tokio::spawn(dump_on_signal());
+    });
+}
+
+async fn report_stalls() {
+    let stall_after = stall_after();
+    let mut checks = tokio::time::interval(stall_after / 2);
+    loop {
+        checks.tick().await;
+        let open: Vec<(u64, Arc<State>)> = open_tasks()
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking open tasks: {}", e))
+            .iter()
+            .map(|(id, state)| (*id, state.clone()))
+            .collect();
+        for (id, state) in open {
+            let idle = state.idle();
+            if idle >= stall_after && !state.reported.swap(true, Ordering::Relaxed) {
+                crate::warn!(
+                    "Task {} ({} {}) stalled in phase {:?} for {:.0}s",
+                    id,
+                    state.transport,
+                    state.peer(),
+                    state.phase(),
+                    idle.as_secs_f64()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn dump_on_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut dumps = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            crate::warn!("Couldn't listen for SIGUSR1, tasks won't be dumped: {}", e);
+            return;
+        }
+    };
+    while dumps.recv().await.is_some() {
+        eprint!("Open tasks:\n{}", render());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{self, ByteStream, ConnectionHandler, Context};
+
+    // Never finishes with a connection by itself
+    #[derive(Clone)]
+    struct Hang;
+
+    impl ConnectionHandler for Hang {
+        async fn handle<S: ByteStream>(&self, _: S, _: Option<SocketAddr>, _: Context) {
+            std::future::pending().await
+        }
+    }
+
+    fn is_open(id: u64) -> bool {
+        list().iter().any(|task| task.id == id)
+    }
+
+    #[tokio::test]
+    async fn close_drops_a_live_handler_and_its_entry() {
+        let (_client, server) = tokio::io::duplex(64);
+        let ctx = Context::new("test");
+        let id = ctx.task.0.id;
+        let handled = handler::spawn(&Hang, server, None, ctx);
+        assert!(is_open(id));
+
+        assert!(close(id));
+        handled.await.unwrap();
+        assert!(!is_open(id));
+        assert!(!close(id));
+    }
+
+    #[tokio::test]
+    async fn the_close_command_takes_the_id_from_the_query() {
+        let (_client, server) = tokio::io::duplex(64);
+        let ctx = Context::new("test");
+        let id = ctx.task.0.id;
+        let handled = handler::spawn(&Hang, server, None, ctx);
+
+        assert_eq!(close_command("id=x"), "Usage: /tasks/close?id=N\n");
+        assert_eq!(
+            close_command(&format!("id={}", id)),
+            format!("Closed task {}\n", id)
+        );
+        handled.await.unwrap();
+        assert_eq!(
+            close_command(&format!("id={}", id)),
+            format!("No open task {}\n", id)
+        );
+    }
+}
@@ -0,0 +1,140 @@
+// Minimum throughput enforcement against slowloris-style clients.
+//
+// With MIN_BYTES_PER_SEC set, every connection from the shared accept loop is
+// measured over windows of MIN_THROUGHPUT_WINDOW_SECS (default 10). A window
+// is too slow if the client trickled in some bytes but fewer than the floor,
+// or if writes to it were held up and fewer than the floor went out. Idle
+// connections are left alone: a chat client that says nothing for a minute
+// isn't an attack. The first window that's too slow closes the connection.
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::tasks::Task;
+
+const DEFAULT_WINDOW_SECS: u64 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Floor {
+    pub bytes_per_sec: u64,
+    pub window: Duration,
+}
+
+impl Floor {
+    pub fn from_env() -> Option<Self> {
+        Some(Floor {
+            bytes_per_sec: crate::env::var("MIN_BYTES_PER_SEC")?,
+            window: Duration::from_secs(
+                crate::env::var_or("MIN_THROUGHPUT_WINDOW_SECS", DEFAULT_WINDOW_SECS).max(1),
+            ),
+        })
+    }
+
+    fn min_bytes(&self) -> u64 {
+        (self.bytes_per_sec as f64 * self.window.as_secs_f64()) as u64
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Meter {
+    read: AtomicU64,
+    written: AtomicU64,
+    write_blocked: AtomicBool,
+}
+
+impl Meter {
+    // Wait until a window goes by below the floor
+    pub(crate) async fn too_slow(&self, floor: Floor) {
+        let mut windows = tokio::time::interval(floor.window);
+        windows.tick().await;
+        loop {
+            windows.tick().await;
+            let read = self.read.swap(0, Ordering::Relaxed);
+            let written = self.written.swap(0, Ordering::Relaxed);
+            let write_blocked = self.write_blocked.swap(false, Ordering::Relaxed);
+            let min = floor.min_bytes();
+            if (read > 0 && read < min) || (write_blocked && written < min) {
+                return;
+            }
+        }
+    }
+}
+
+// A connection's socket, counting what goes through it when a floor is set,
+// and marking its task as making progress whenever anything does
+pub struct Guarded<S = TcpStream> {
+    inner: S,
+    meter: Option<Arc<Meter>>,
+    task: Task,
+}
+
+impl<S> Guarded<S> {
+    pub(crate) fn new(inner: S, meter: Option<Arc<Meter>>, task: Task) -> Self {
+        Guarded { inner, meter, task }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl Guarded<TcpStream> {
+    pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Guarded<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = &result {
+            let n = buf.filled().len() - before;
+            if let Some(meter) = &self.meter {
+                meter.read.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            self.task.read(n);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Guarded<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            self.task.wrote(*n);
+        }
+        if let Some(meter) = &self.meter {
+            match &result {
+                Poll::Ready(Ok(n)) => {
+                    meter.written.fetch_add(*n as u64, Ordering::Relaxed);
+                }
+                Poll::Pending => meter.write_blocked.store(true, Ordering::Relaxed),
+                _ => {}
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
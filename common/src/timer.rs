@@ -0,0 +1,356 @@
+// Hierarchical timer wheel.
+//
+// Keeps any number of keyed deadlines behind a single driver task instead of
+// one tokio sleep per timer, as for the retransmission timeouts of every LRCP
+// session on a listener. Time advances in fixed ticks; level 0 has one slot
+// per tick and each level above covers 64 times the span of the one below, so
+// scheduling and cancelling are O(1) and a timer is moved down a level at most
+// once per level before it fires. Expired keys come out of the receiver
+// returned by `Timers::new`.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{Instant, MissedTickBehavior};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+
+struct Entry<K> {
+    key: K,
+    deadline: u64,
+    id: u64,
+}
+
+struct Wheel<K> {
+    levels: Vec<Vec<Vec<Entry<K>>>>,
+    // Ticks processed so far
+    now: u64,
+    // Live timer id for every key; entries with any other id were cancelled
+    // or rescheduled and are dropped when reached
+    live: HashMap<K, u64>,
+    next_id: u64,
+}
+
+impl<K: Clone + Eq + Hash> Wheel<K> {
+    fn new() -> Self {
+        Wheel {
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            now: 0,
+            live: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn place(&mut self, entry: Entry<K>) {
+        let max_span = 1u64 << (SLOT_BITS as usize * LEVELS);
+        // Timers beyond the top level wait in its furthest slot and get
+        // placed again when it comes round
+        let deadline = entry.deadline.min(self.now + max_span - 1);
+        let delta = deadline - self.now;
+        let level = (0..LEVELS)
+            .find(|&l| delta < 1u64 << (SLOT_BITS as usize * (l + 1)))
+            .unwrap_or(LEVELS - 1);
+        let slot = (deadline >> (SLOT_BITS as usize * level)) as usize & (SLOTS - 1);
+        self.levels[level][slot].push(entry);
+    }
+
+    fn schedule(&mut self, key: K, deadline: u64, current: u64) {
+        // The driver doesn't advance an empty wheel, so catch up here instead
+        // of stepping through every idle tick later
+        if self.live.is_empty() {
+            self.now = self.now.max(current);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.live.insert(key.clone(), id);
+        self.place(Entry {
+            key,
+            deadline: deadline.max(self.now + 1),
+            id,
+        });
+    }
+
+    fn cancel(&mut self, key: &K) -> bool {
+        self.live.remove(key).is_some()
+    }
+
+    // Process every tick up to and including `to`, collecting the expired keys
+    fn advance(&mut self, to: u64, expired: &mut Vec<K>) {
+        while self.now < to {
+            self.now += 1;
+            let now = self.now;
+
+            // Bring down the timers from every level whose slot boundary we
+            // just crossed, highest first
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS as usize * level;
+                if now & ((1u64 << shift) - 1) == 0 {
+                    let slot = (now >> shift) as usize & (SLOTS - 1);
+                    for entry in std::mem::take(&mut self.levels[level][slot]) {
+                        if self.live.get(&entry.key) == Some(&entry.id) {
+                            self.place(entry);
+                        }
+                    }
+                }
+            }
+
+            let slot = now as usize & (SLOTS - 1);
+            for entry in std::mem::take(&mut self.levels[0][slot]) {
+                if self.live.get(&entry.key) != Some(&entry.id) {
+                    continue;
+                }
+                if entry.deadline <= now {
+                    self.live.remove(&entry.key);
+                    expired.push(entry.key);
+                } else {
+                    self.place(entry);
+                }
+            }
+        }
+    }
+}
+
+struct Shared<K> {
+    wheel: Mutex<Wheel<K>>,
+    start: Instant,
+    tick: Duration,
+    // Wakes the driver when the first timer is scheduled into an empty wheel
+    wake: Arc<Notify>,
+}
+
+impl<K> Shared<K> {
+    fn ticks_at(&self, at: Instant) -> u64 {
+        let elapsed = at.saturating_duration_since(self.start);
+        (elapsed.as_nanos() / self.tick.as_nanos()) as u64
+    }
+}
+
+// Lets an idle driver notice the last handle going away
+impl<K> Drop for Shared<K> {
+    fn drop(&mut self) {
+        self.wake.notify_one();
+    }
+}
+
+#[derive(Clone)]
+pub struct Timers<K> {
+    shared: Arc<Shared<K>>,
+}
+
+impl<K: Clone + Eq + Hash + Send + 'static> Timers<K> {
+    // Timers fire on the first tick at or after their deadline, so `tick` is
+    // the precision traded for fewer wakeups
+    pub fn new(tick: Duration) -> (Self, mpsc::UnboundedReceiver<K>) {
+        let wake = Arc::new(Notify::new());
+        let shared = Arc::new(Shared {
+            wheel: Mutex::new(Wheel::new()),
+            start: Instant::now(),
+            tick,
+            wake: wake.clone(),
+        });
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(drive(Arc::downgrade(&shared), wake, tx));
+        (Timers { shared }, rx)
+    }
+
+    fn wheel(&self) -> std::sync::MutexGuard<'_, Wheel<K>> {
+        self.shared
+            .wheel
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking timer wheel: {}", e))
+    }
+
+    // Fire `key` after `after`, replacing any timer already set for it
+    pub fn schedule(&self, key: K, after: Duration) {
+        let now = Instant::now();
+        // Round up so a timer never fires early
+        let deadline = self.shared.ticks_at(now + after + self.shared.tick);
+        let current = self.shared.ticks_at(now);
+        let was_empty = {
+            let mut wheel = self.wheel();
+            let was_empty = wheel.live.is_empty();
+            wheel.schedule(key, deadline, current);
+            was_empty
+        };
+        if was_empty {
+            self.shared.wake.notify_one();
+        }
+    }
+
+    // Returns whether there was a timer to cancel
+    pub fn cancel(&self, key: &K) -> bool {
+        self.wheel().cancel(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.wheel().live.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Runs until every `Timers` handle or the receiver is gone
+async fn drive<K: Clone + Eq + Hash + Send + 'static>(
+    shared: Weak<Shared<K>>,
+    wake: Arc<Notify>,
+    tx: mpsc::UnboundedSender<K>,
+) {
+    let mut expired = Vec::new();
+    let Some(tick) = shared.upgrade().map(|s| s.tick) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(tick);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        let idle = {
+            let Some(shared) = shared.upgrade() else {
+                return;
+            };
+            let mut wheel = shared
+                .wheel
+                .lock()
+                .unwrap_or_else(|e| panic!("Error locking timer wheel: {}", e));
+            wheel.advance(shared.ticks_at(Instant::now()), &mut expired);
+            wheel.live.is_empty()
+        };
+        for key in expired.drain(..) {
+            if tx.send(key).is_err() {
+                return;
+            }
+        }
+
+        if idle {
+            // Nothing to fire, so sleep until something is scheduled. The
+            // permit is kept if the notification came in before this point.
+            wake.notified().await;
+            if shared.strong_count() == 0 {
+                return;
+            }
+            interval.reset();
+        } else {
+            interval.tick().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Advance `wheel` one tick at a time up to `to`, noting the tick each key
+    // fired on
+    fn fire(wheel: &mut Wheel<u32>, to: u64) -> Vec<(u64, u32)> {
+        let mut fired = Vec::new();
+        let mut expired = Vec::new();
+        while wheel.now < to {
+            let now = wheel.now + 1;
+            wheel.advance(now, &mut expired);
+            fired.extend(expired.drain(..).map(|key| (now, key)));
+        }
+        fired
+    }
+
+    #[test]
+    fn fires_on_the_deadline() {
+        let mut wheel = Wheel::new();
+        wheel.schedule(1, 5, 0);
+        assert_eq!(fire(&mut wheel, 4), []);
+        assert_eq!(fire(&mut wheel, 10), [(5, 1)]);
+        assert!(wheel.live.is_empty());
+    }
+
+    #[test]
+    fn cancelled_and_rescheduled_timers_fire_once_if_at_all() {
+        let mut wheel = Wheel::new();
+        wheel.schedule(1, 5, 0);
+        wheel.schedule(2, 5, 0);
+        assert!(wheel.cancel(&1));
+        assert!(!wheel.cancel(&1));
+        // Replaces the timer due at 5
+        wheel.schedule(2, 200, 0);
+        assert_eq!(fire(&mut wheel, 300), [(200, 2)]);
+        assert!(!wheel.cancel(&2));
+    }
+
+    #[test]
+    fn timers_cascade_down_to_fire_on_their_tick() {
+        let mut wheel = Wheel::new();
+        // Either side of the boundaries of the first three levels
+        let deadlines = [1, 63, 64, 65, 4095, 4096, 4097, 262_143, 262_144, 300_000];
+        for (key, &deadline) in deadlines.iter().enumerate() {
+            wheel.schedule(key as u32, deadline, 0);
+        }
+        let fired = fire(&mut wheel, 300_000);
+        let expected: Vec<_> = deadlines
+            .iter()
+            .enumerate()
+            .map(|(key, &deadline)| (deadline, key as u32))
+            .collect();
+        assert_eq!(fired, expected);
+    }
+
+    #[test]
+    fn cascades_from_a_wheel_that_has_already_turned() {
+        let mut wheel = Wheel::new();
+        wheel.schedule(0, 127, 0);
+        fire(&mut wheel, 127);
+        // Lands in the level 1 slot that was just emptied
+        wheel.schedule(1, 127 + 4095, 127);
+        assert_eq!(fire(&mut wheel, 5000), [(4222, 1)]);
+    }
+
+    #[test]
+    fn fires_in_deadline_order() {
+        let mut wheel = Wheel::new();
+        let deadlines = [700, 3, 70_000, 64, 5000, 9, 700];
+        for (key, &deadline) in deadlines.iter().enumerate() {
+            wheel.schedule(key as u32, deadline, 0);
+        }
+        let fired = fire(&mut wheel, 100_000);
+        let ticks: Vec<_> = fired.iter().map(|&(tick, _)| tick).collect();
+        assert_eq!(ticks, [3, 9, 64, 700, 700, 5000, 70_000]);
+        // Timers due on the same tick fire in the order they were scheduled
+        assert_eq!(fired[3..5], [(700, 0), (700, 6)]);
+    }
+
+    #[test]
+    fn an_idle_wheel_catches_up_when_scheduled_into() {
+        let mut wheel = Wheel::new();
+        wheel.schedule(1, 1_000_010, 1_000_000);
+        assert_eq!(wheel.now, 1_000_000);
+        assert_eq!(fire(&mut wheel, 1_000_010), [(1_000_010, 1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_keys_come_out_of_the_receiver() {
+        let tick = Duration::from_millis(10);
+        let (timers, mut expired) = Timers::new(tick);
+        let start = Instant::now();
+        timers.schedule("later", Duration::from_millis(500));
+        timers.schedule("sooner", Duration::from_millis(100));
+        timers.schedule("never", Duration::from_millis(50));
+        assert!(timers.cancel(&"never"));
+        assert_eq!(timers.len(), 2);
+
+        assert_eq!(expired.recv().await, Some("sooner"));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100) && elapsed <= Duration::from_millis(120));
+        assert_eq!(expired.recv().await, Some("later"));
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(timers.is_empty());
+
+        // And again once the wheel has gone idle
+        timers.schedule("again", Duration::from_secs(5));
+        assert_eq!(expired.recv().await, Some("again"));
+        drop(timers);
+        assert_eq!(expired.recv().await, None);
+    }
+}
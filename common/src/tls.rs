@@ -0,0 +1,238 @@
+// TLS for TCP connections, enabled with the `tls` feature.
+//
+// The certificate is read from the PEM files in TLS_CERT and TLS_KEY, or
+// generated (self-signed for "localhost") at startup if they aren't set. A
+// server offering several protocols on one port names them for ALPN, and the
+// client picks one of them during the handshake.
+//
+// Servers can also dial out over TLS, as the problem5 proxy does. The server
+// they reach is checked against the system's root certificates, or only those
+// in a given PEM file, or not at all for testing against self-signed ones.
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::ServerConfig;
+
+pub use tokio_rustls::client::TlsStream as ClientTlsStream;
+pub use tokio_rustls::server::TlsStream;
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::handler::ByteStream;
+
+// Clients that don't finish the handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Whether TLS is turned on, with TLS=true
+pub fn enabled() -> bool {
+    crate::env::var_or("TLS", false)
+}
+
+// An acceptor offering `protocols` through ALPN, in order of preference
+pub fn acceptor(protocols: &[&str]) -> Result<TlsAcceptor, String> {
+    let (certs, key) = crate::cert::load("TLS_CERT", "TLS_KEY")?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| e.to_string())?;
+    config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Complete the server side of the handshake on `stream`
+pub async fn accept<S: ByteStream>(acceptor: &TlsAcceptor, stream: S) -> io::Result<TlsStream<S>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+}
+
+// How a client checks the server's certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verify {
+    // Against the system's root certificates
+    System,
+    // Against the certificates in this PEM file only
+    Ca(String),
+    // Not at all
+    Nothing,
+}
+
+// A connector for clients that check servers as `verify` says
+pub fn connector(verify: &Verify) -> Result<TlsConnector, String> {
+    let builder = ClientConfig::builder();
+    let config = match verify {
+        Verify::System => {
+            let mut roots = RootCertStore::empty();
+            let loaded = rustls_native_certs::load_native_certs();
+            if let Some(e) = loaded.errors.first() {
+                crate::warn!("Couldn't load some system root certificates: {}", e);
+            }
+            let (added, _) = roots.add_parsable_certificates(loaded.certs);
+            if added == 0 {
+                return Err("no usable system root certificates".to_owned());
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        Verify::Ca(path) => {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("Couldn't read certificates from {}: {}", path, e))?;
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("no usable certificates in {}", path));
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        Verify::Nothing => {
+            let provider = CryptoProvider::get_default()
+                .cloned()
+                .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+                .with_no_client_auth()
+        }
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// Complete the client side of the handshake on `stream`, asking for
+// `server_name` through SNI and checking the certificate is for it
+pub async fn connect<S: ByteStream>(
+    connector: &TlsConnector,
+    server_name: &str,
+    stream: S,
+) -> io::Result<ClientTlsStream<S>> {
+    let server_name = ServerName::try_from(server_name.to_owned())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, connector.connect(server_name, stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+}
+
+// Accepts whatever certificate the server presents, though its signatures
+// still have to check out
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// The protocol the client picked through ALPN, if any
+pub fn protocol<S>(stream: &TlsStream<S>) -> Option<&str> {
+    stream
+        .get_ref()
+        .1
+        .alpn_protocol()
+        .and_then(|p| std::str::from_utf8(p).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // A server answering one connection's first line over TLS, with a
+    // self-signed certificate for "localhost"
+    async fn echo_once() -> std::net::SocketAddr {
+        let acceptor = acceptor(&[]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = accept(&acceptor, socket).await {
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn dial(verify: &Verify, server_name: &str) -> io::Result<Vec<u8>> {
+        let socket = TcpStream::connect(echo_once().await).await?;
+        let mut stream = connect(&connector(verify).unwrap(), server_name, socket).await?;
+        stream.write_all(b"hello").await?;
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await?;
+        Ok(echoed)
+    }
+
+    #[tokio::test]
+    async fn connects_without_verification() {
+        assert_eq!(dial(&Verify::Nothing, "localhost").await.unwrap(), b"hello");
+        // Any name goes, since the certificate isn't checked
+        assert_eq!(
+            dial(&Verify::Nothing, "example.com").await.unwrap(),
+            b"hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_a_certificate_it_cant_verify() {
+        let Ok(connector) = connector(&Verify::System) else {
+            // No system roots in this environment
+            return;
+        };
+        let socket = TcpStream::connect(echo_once().await).await.unwrap();
+        assert!(connect(&connector, "localhost", socket).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_server_names_and_ca_files() {
+        let socket = TcpStream::connect(echo_once().await).await.unwrap();
+        let connector = connector(&Verify::Nothing).unwrap();
+        let e = connect(&connector, "not a name", socket).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(super::connector(&Verify::Ca("/nonexistent.pem".to_owned())).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+// Socket and descriptor limits, checked and applied at startup.
+//
+// Listeners bound here get a backlog of LISTEN_BACKLOG (default 1024, the
+// same as tokio's) and, if set, SOCKET_RCVBUF and SOCKET_SNDBUF bytes of
+// kernel buffer, which the connections they accept inherit. UDP sockets get
+// the same buffer sizes through `tune_udp`. The kernel quietly clamps all
+// three (net.core.somaxconn, net.core.rmem_max, net.core.wmem_max), so each is
+// compared with what the kernel allows and a warning printed when it falls
+// short. A listener taken over through HANDOVER_SOCKET keeps the settings of
+// the process that bound it.
+//
+// `check_open_files` makes sure the process may open a descriptor for every
+// connection it is configured to hold, plus some to spare. If the soft
+// RLIMIT_NOFILE is too low it is raised, as far as the hard limit allows, and
+// if that still isn't enough a warning says so.
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpSocket, ToSocketAddrs, UdpSocket};
+
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+// Descriptors for everything besides connections: listeners, side endpoints,
+// files, the runtime's own
+const SPARE_DESCRIPTORS: usize = 64;
+
+// Bind a TCP listener on `addr` with the configured backlog and buffer sizes
+pub(crate) async fn bind_listener<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        match listen(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // Like TcpListener::bind, so a restart doesn't wait for TIME_WAIT to pass
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    if let Some(size) = crate::env::var::<u32>("SOCKET_RCVBUF") {
+        socket.set_recv_buffer_size(size)?;
+        check_buffer("SOCKET_RCVBUF", size, socket.recv_buffer_size()?);
+    }
+    if let Some(size) = crate::env::var::<u32>("SOCKET_SNDBUF") {
+        socket.set_send_buffer_size(size)?;
+        check_buffer("SOCKET_SNDBUF", size, socket.send_buffer_size()?);
+    }
+    socket.bind(addr)?;
+    let backlog = crate::env::var_or("LISTEN_BACKLOG", DEFAULT_LISTEN_BACKLOG);
+    check_backlog(backlog);
+    socket.listen(backlog)
+}
+
+// Apply SOCKET_RCVBUF and SOCKET_SNDBUF, if set, to `socket`
+pub fn tune_udp(socket: &UdpSocket) {
+    let socket = socket2::SockRef::from(socket);
+    if let Some(size) = crate::env::var::<u32>("SOCKET_RCVBUF") {
+        match socket
+            .set_recv_buffer_size(size as usize)
+            .and_then(|()| socket.recv_buffer_size())
+        {
+            Ok(got) => check_buffer("SOCKET_RCVBUF", size, got as u32),
+            Err(e) => crate::warn!("Couldn't set UDP receive buffer: {}", e),
+        }
+    }
+    if let Some(size) = crate::env::var::<u32>("SOCKET_SNDBUF") {
+        match socket
+            .set_send_buffer_size(size as usize)
+            .and_then(|()| socket.send_buffer_size())
+        {
+            Ok(got) => check_buffer("SOCKET_SNDBUF", size, got as u32),
+            Err(e) => crate::warn!("Couldn't set UDP send buffer: {}", e),
+        }
+    }
+}
+
+fn check_buffer(name: &str, asked: u32, got: u32) {
+    // Linux reports twice the size asked for, keeping half for bookkeeping
+    #[cfg(target_os = "linux")]
+    let got = got / 2;
+    if got < asked {
+        crate::warn!(
+            "Warning: {}={} was cut to {} by the kernel (raise net.core.{})",
+            name,
+            asked,
+            got,
+            if name == "SOCKET_RCVBUF" {
+                "rmem_max"
+            } else {
+                "wmem_max"
+            }
+        );
+    }
+}
+
+fn check_backlog(backlog: u32) {
+    #[cfg(target_os = "linux")]
+    if let Some(max) = std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+    {
+        if max < backlog {
+            crate::warn!(
+                "Warning: LISTEN_BACKLOG={} is cut to {} by the kernel (raise net.core.somaxconn)",
+                backlog,
+                max
+            );
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = backlog;
+}
+
+// Make sure `connections` connections can be open at once, raising the soft
+// open file limit if needed
+#[cfg(unix)]
+pub fn check_open_files(connections: usize) {
+    let needed = connections.saturating_add(SPARE_DESCRIPTORS) as libc::rlim_t;
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Only writes to `limit`
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        crate::warn!(
+            "Couldn't read the open file limit: {}",
+            io::Error::last_os_error()
+        );
+        return;
+    }
+    if limit.rlim_cur >= needed {
+        return;
+    }
+    let raised = libc::rlimit {
+        rlim_cur: needed.min(limit.rlim_max),
+        rlim_max: limit.rlim_max,
+    };
+    // Only reads `raised`
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+        crate::info!(
+            "Raised the open file limit from {} to {}",
+            limit.rlim_cur,
+            raised.rlim_cur
+        );
+        limit = raised;
+    } else {
+        crate::warn!(
+            "Couldn't raise the open file limit: {}",
+            io::Error::last_os_error()
+        );
+    }
+    if limit.rlim_cur < needed {
+        crate::warn!(
+            "Warning: the open file limit is {}, but {} connections need {}; \
+             connections past that will fail to be accepted (raise the hard \
+             limit, or lower MAX_CONNECTIONS or ACCEPT_QUEUE_LEN)",
+            limit.rlim_cur,
+            connections,
+            needed
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub fn check_open_files(_connections: usize) {}
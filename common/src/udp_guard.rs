@@ -0,0 +1,272 @@
+// Keeps UDP servers from being used to flood someone else.
+//
+// A UDP source address can be forged, so a server answering whatever address
+// a datagram claims to come from can be made to send its responses to a
+// victim, and more bytes than the attacker spent when responses are bigger
+// than requests. The guard tracks every source address a server hears from:
+//   - until the protocol has verified the source (it answered something only
+//     the real peer could have received), each datagram received from it
+//     lets UDP_AMPLIFICATION_FACTOR (default 3) times its size be sent back.
+//     On top of that a source is given UDP_UNVERIFIED_ALLOWANCE (default
+//     1000) bytes once, when first heard from, so that a short first request
+//     can still get a full datagram back. What isn't sent carries over to
+//     later responses.
+//   - responses to any source are capped at UDP_RESPONSE_BYTES_PER_SEC
+//     (default 256 KiB, 0 for no cap), with bursts up to a second's worth
+// Responses over either cap are dropped, as if lost.
+//
+// At most UDP_MAX_SOURCES (default 65536) sources are tracked one by one, so
+// a flood from forged addresses can't grow the table without limit. Past
+// that, every source not already tracked shares a single allowance, under
+// both caps as if it were one unverified source, until expired sources make
+// room again.
+use std::collections::hash_map::{Entry, HashMap};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_AMPLIFICATION_FACTOR: u64 = 3;
+const DEFAULT_UNVERIFIED_ALLOWANCE: u64 = 1000;
+const DEFAULT_RESPONSE_BYTES_PER_SEC: u64 = 256 * 1024;
+const DEFAULT_MAX_SOURCES: usize = 65536;
+// Sources not heard from for this long are forgotten, unverifying them
+const SOURCE_EXPIRY: Duration = Duration::from_secs(120);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Source {
+    received: u64,
+    // Bytes that may still be sent while unverified
+    credit: u64,
+    verified: bool,
+    // Bytes that may be sent right now under the rate cap
+    allowance: f64,
+    refilled: Instant,
+    last_seen: Instant,
+    // Whether responses are being dropped, to log only when that starts
+    limited: bool,
+}
+
+impl Source {
+    fn new(credit: u64, bytes_per_sec: u64, now: Instant) -> Self {
+        Source {
+            received: 0,
+            credit,
+            verified: false,
+            allowance: bytes_per_sec as f64,
+            refilled: now,
+            last_seen: now,
+            limited: false,
+        }
+    }
+}
+
+struct Sources {
+    by_addr: HashMap<SocketAddr, Source>,
+    // Shared by the sources that didn't fit in `by_addr`
+    overflow: Source,
+    pruned: Instant,
+}
+
+pub struct Guard {
+    amplification: u64,
+    unverified_allowance: u64,
+    bytes_per_sec: u64,
+    max_sources: usize,
+    sources: Mutex<Sources>,
+}
+
+impl Guard {
+    pub fn from_env() -> Self {
+        Self::new(
+            crate::env::var_or("UDP_AMPLIFICATION_FACTOR", DEFAULT_AMPLIFICATION_FACTOR),
+            crate::env::var_or("UDP_UNVERIFIED_ALLOWANCE", DEFAULT_UNVERIFIED_ALLOWANCE),
+            crate::env::var_or("UDP_RESPONSE_BYTES_PER_SEC", DEFAULT_RESPONSE_BYTES_PER_SEC),
+            crate::env::var_or("UDP_MAX_SOURCES", DEFAULT_MAX_SOURCES),
+        )
+    }
+
+    fn new(
+        amplification: u64,
+        unverified_allowance: u64,
+        bytes_per_sec: u64,
+        max_sources: usize,
+    ) -> Self {
+        let now = Instant::now();
+        Guard {
+            amplification,
+            unverified_allowance,
+            bytes_per_sec,
+            max_sources,
+            sources: Mutex::new(Sources {
+                by_addr: HashMap::new(),
+                overflow: Source::new(unverified_allowance, bytes_per_sec, now),
+                pruned: now,
+            }),
+        }
+    }
+
+    // A source first heard from at `now`
+    fn source(&self, now: Instant) -> Source {
+        Source::new(self.unverified_allowance, self.bytes_per_sec, now)
+    }
+
+    fn sources(&self) -> std::sync::MutexGuard<'_, Sources> {
+        self.sources
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking UDP sources: {}", e))
+    }
+
+    // Count a datagram of `len` bytes from `from`
+    pub fn received(&self, from: SocketAddr, len: usize) {
+        let mut sources = self.sources();
+        let now = Instant::now();
+        if now.duration_since(sources.pruned) >= PRUNE_INTERVAL {
+            sources
+                .by_addr
+                .retain(|_, s| now.duration_since(s.last_seen) < SOURCE_EXPIRY);
+            if now.duration_since(sources.overflow.last_seen) >= SOURCE_EXPIRY {
+                sources.overflow = self.source(now);
+            }
+            sources.pruned = now;
+        }
+        let tracked = sources.by_addr.len();
+        let source = match sources.by_addr.entry(from) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if tracked < self.max_sources => entry.insert(self.source(now)),
+            Entry::Vacant(_) => {
+                if sources.overflow.received == 0 {
+                    crate::warn!(
+                        "Tracking {} UDP sources, the rest share one allowance",
+                        tracked
+                    );
+                }
+                &mut sources.overflow
+            }
+        };
+        let len = len as u64;
+        source.received += len;
+        source.credit += self.amplification * len;
+        source.last_seen = now;
+    }
+
+    // Lift the amplification cap for `source`, once the protocol knows it is
+    // the real peer
+    pub fn verify(&self, source: SocketAddr) {
+        if let Some(source) = self.sources().by_addr.get_mut(&source) {
+            source.verified = true;
+        }
+    }
+
+    // Whether a response of `len` bytes may be sent to `to`, counting it if so
+    pub fn allow(&self, to: SocketAddr, len: usize) -> bool {
+        let mut sources = self.sources();
+        let sources = &mut *sources;
+        let source = match sources.by_addr.get_mut(&to) {
+            Some(source) => source,
+            None if sources.overflow.received > 0 => &mut sources.overflow,
+            // Never heard from, so nothing to respond to
+            None => return false,
+        };
+        let len = len as u64;
+        let now = Instant::now();
+        if self.bytes_per_sec > 0 {
+            let elapsed = now.duration_since(source.refilled).as_secs_f64();
+            source.allowance = (source.allowance + elapsed * self.bytes_per_sec as f64)
+                .min(self.bytes_per_sec as f64);
+            source.refilled = now;
+        }
+        let reason = if !source.verified && len > source.credit {
+            Some("unverified source")
+        } else if self.bytes_per_sec > 0 && (len as f64) > source.allowance {
+            Some("rate limit")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            if !source.limited {
+                crate::info!("Dropping responses to {} ({})", to, reason);
+                source.limited = true;
+            }
+            return false;
+        }
+        source.limited = false;
+        if !source.verified {
+            source.credit -= len;
+        }
+        if self.bytes_per_sec > 0 {
+            source.allowance -= len as f64;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(n: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], n))
+    }
+
+    #[test]
+    fn tracks_no_more_than_max_sources() {
+        let guard = Guard::new(3, 0, 0, 10);
+        for n in 0..1000 {
+            guard.received(source(n), 10);
+        }
+        assert_eq!(guard.sources().by_addr.len(), 10);
+    }
+
+    #[test]
+    fn untracked_sources_share_one_amplification_cap() {
+        let guard = Guard::new(3, 0, 0, 1);
+        guard.received(source(0), 10);
+        guard.received(source(1), 10);
+        guard.received(source(2), 10);
+        // The tracked source has its own cap
+        assert!(guard.allow(source(0), 30));
+        assert!(!guard.allow(source(0), 1));
+        // The other two sent 20 bytes between them
+        assert!(guard.allow(source(1), 40));
+        assert!(guard.allow(source(2), 20));
+        assert!(!guard.allow(source(2), 1));
+        assert!(!guard.allow(source(1), 1));
+    }
+
+    #[test]
+    fn verifying_an_untracked_source_lifts_nothing() {
+        let guard = Guard::new(3, 0, 0, 1);
+        guard.received(source(0), 10);
+        guard.received(source(1), 10);
+        guard.verify(source(1));
+        assert!(!guard.allow(source(1), 31));
+    }
+
+    #[test]
+    fn gives_the_flat_allowance_once_per_source() {
+        let guard = Guard::new(3, 1000, 0, 10);
+        guard.received(source(0), 1);
+        assert!(guard.allow(source(0), 1003));
+        // Later requests only earn their amplified size
+        for _ in 0..10 {
+            guard.received(source(0), 1);
+            assert!(!guard.allow(source(0), 992));
+            assert!(guard.allow(source(0), 3));
+        }
+        assert!(!guard.allow(source(0), 1));
+        // and another source gets its own
+        guard.received(source(1), 1);
+        assert!(guard.allow(source(1), 1003));
+    }
+
+    #[test]
+    fn large_requests_allow_amplified_responses() {
+        let guard = Guard::new(3, 1000, 0, 10);
+        guard.received(source(0), 500);
+        assert!(!guard.allow(source(0), 2501));
+        assert!(guard.allow(source(0), 2500));
+        guard.received(source(0), 500);
+        assert!(!guard.allow(source(0), 1501));
+        assert!(guard.allow(source(0), 1500));
+    }
+}
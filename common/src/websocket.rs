@@ -0,0 +1,158 @@
+// WebSocket endpoint for the stream-oriented problems, enabled with the
+// `websocket` feature, so they can be poked from a browser console.
+//
+// The handler gets one end of an in-memory duplex pipe: frame payloads
+// received from the client are written into it, and whatever the handler
+// writes back is sent as a frame (text if it is valid UTF-8, binary otherwise).
+// Once `shutdown` is cancelled the listener stops accepting and handlers are
+// told to wind down; a client's connection closes when its handler returns.
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+use crate::admission::{Admission, Admitted};
+use crate::handler::{ConnectionHandler, Context};
+
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum FrameMode {
+    // Pass payloads through untouched
+    Raw,
+    // Terminate text frames with a newline if they don't end in one, so each
+    // frame is one request for line-based protocols
+    Lines,
+}
+
+async fn serve_client<H: ConnectionHandler>(
+    socket: TcpStream,
+    admitted: Admitted,
+    mode: FrameMode,
+    handler: H,
+    ctx: Context,
+) {
+    let mut ws = match tokio_tungstenite::accept_async(socket).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            crate::debug!("WebSocket handshake failed: {}", e);
+            return;
+        }
+    };
+    let (handler_end, mut pipe) = tokio::io::duplex(PIPE_CAPACITY);
+    admitted.spawn(&handler, handler_end, ctx);
+
+    let mut buf = vec![0u8; PIPE_CAPACITY];
+    loop {
+        tokio::select! {
+            msg = ws.next() => {
+                let written = match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        let mut payload = text.as_bytes().to_vec();
+                        if let FrameMode::Lines = mode {
+                            if !payload.ends_with(b"\n") {
+                                payload.push(b'\n');
+                            }
+                        }
+                        pipe.write_all(&payload).await
+                    }
+                    Some(Ok(Message::Binary(payload))) => pipe.write_all(&payload).await,
+                    Some(Ok(Message::Close(_))) | None => return,
+                    // Pings are answered by tungstenite itself
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(e)) => {
+                        crate::debug!("Error reading WebSocket frame: {}", e);
+                        return;
+                    }
+                };
+                if written.is_err() {
+                    // The handler is gone
+                    ws.close(None).await.unwrap_or(());
+                    return;
+                }
+            },
+            n = pipe.read(&mut buf) => {
+                let n = match n {
+                    Ok(0) | Err(_) => {
+                        ws.close(None).await.unwrap_or(());
+                        return;
+                    }
+                    Ok(n) => n,
+                };
+                let msg = match std::str::from_utf8(&buf[..n]) {
+                    Ok(text) => Message::text(text),
+                    Err(_) => Message::binary(buf[..n].to_vec()),
+                };
+                if let Err(e) = ws.send(msg).await {
+                    crate::debug!("Error writing WebSocket frame: {}", e);
+                    return;
+                }
+            },
+        }
+    }
+}
+
+pub async fn run_websocket_acceptor<H: ConnectionHandler>(
+    addr: SocketAddr,
+    mode: FrameMode,
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            crate::error!("Couldn't start WebSocket listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    crate::info!("Listening for WebSocket connections on {}", addr);
+
+    let mut failed_accepts = 0;
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+        match accepted {
+            Ok((socket, addr)) => {
+                failed_accepts = 0;
+                crate::debug!("Accepted WebSocket connection from {:?}", addr);
+                let Some(admitted) = admission.admit(Some(addr)) else {
+                    continue;
+                };
+                let ctx = Context {
+                    cancel: shutdown.child_token(),
+                    ..Context::new("websocket")
+                };
+                tokio::spawn(serve_client(socket, admitted, mode, handler.clone(), ctx));
+            }
+            Err(e) => {
+                let delay =
+                    admission.accept_failed("WebSocket connection", &e, &mut failed_accepts);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.cancelled() => return,
+                }
+            }
+        }
+    }
+}
+
+// Start a WebSocket listener on WEBSOCKET_PORT in the background, if it is
+// set, until `shutdown` is cancelled
+pub fn spawn_from_env<H: ConnectionHandler>(
+    mode: FrameMode,
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    if let Some(port) = crate::env::var::<u16>("WEBSOCKET_PORT") {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        tokio::spawn(run_websocket_acceptor(
+            addr, mode, handler, admission, shutdown,
+        ));
+    }
+}
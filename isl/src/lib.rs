@@ -0,0 +1,528 @@
+// Insecure Sockets Layer (problem 8) cipher, independent of any application.
+//
+// A client opens with a cipher spec, a list of byte operations ending in 0x00,
+// and everything after it in either direction is passed through those
+// operations. Each byte is transformed according to its position in its own
+// direction of the stream. `CipherStream` wraps any stream, reading the spec
+// first and then encrypting and decrypting transparently.
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use common::admission::Admission;
+use common::handler::{self, ByteStream, ConnectionHandler};
+
+// Longest cipher spec a client may send, terminator included
+pub const MAX_SPEC_LEN: usize = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    ReverseBits,
+    Xor(u8),
+    XorPos,
+    Add(u8),
+    AddPos,
+}
+
+impl Op {
+    fn apply(self, b: u8, pos: u64) -> u8 {
+        match self {
+            Op::ReverseBits => b.reverse_bits(),
+            Op::Xor(n) => b ^ n,
+            Op::XorPos => b ^ pos as u8,
+            Op::Add(n) => b.wrapping_add(n),
+            Op::AddPos => b.wrapping_add(pos as u8),
+        }
+    }
+
+    fn invert(self, b: u8, pos: u64) -> u8 {
+        match self {
+            Op::Add(n) => b.wrapping_sub(n),
+            Op::AddPos => b.wrapping_sub(pos as u8),
+            // Everything else undoes itself
+            op => op.apply(b, pos),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SpecError {
+    UnknownOp(u8),
+    // An operation was missing its argument, or the spec its terminator
+    Truncated,
+    TooLong,
+    // The spec leaves every byte as it was
+    NoOp,
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecError::UnknownOp(op) => write!(f, "unknown cipher operation {:#04x}", op),
+            SpecError::Truncated => write!(f, "cipher spec ended early"),
+            SpecError::TooLong => write!(f, "cipher spec longer than {} bytes", MAX_SPEC_LEN),
+            SpecError::NoOp => write!(f, "cipher spec doesn't change anything"),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+impl From<SpecError> for io::Error {
+    fn from(e: SpecError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CipherSpec {
+    ops: Vec<Op>,
+}
+
+impl CipherSpec {
+    pub fn new(ops: Vec<Op>) -> Self {
+        CipherSpec { ops }
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    // Parse a spec from the start of `bytes`, returning it with the number of
+    // bytes it took up, terminator included
+    pub fn parse(bytes: &[u8]) -> Result<(CipherSpec, usize), SpecError> {
+        let mut ops = Vec::new();
+        let mut i = 0;
+        loop {
+            if i >= MAX_SPEC_LEN {
+                return Err(SpecError::TooLong);
+            }
+            let op = *bytes.get(i).ok_or(SpecError::Truncated)?;
+            let arg = || bytes.get(i + 1).copied().ok_or(SpecError::Truncated);
+            let (op, len) = match op {
+                0x00 => return Ok((CipherSpec { ops }, i + 1)),
+                0x01 => (Op::ReverseBits, 1),
+                0x02 => (Op::Xor(arg()?), 2),
+                0x03 => (Op::XorPos, 1),
+                0x04 => (Op::Add(arg()?), 2),
+                0x05 => (Op::AddPos, 1),
+                other => return Err(SpecError::UnknownOp(other)),
+            };
+            ops.push(op);
+            i += len;
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for op in &self.ops {
+            match op {
+                Op::ReverseBits => out.push(0x01),
+                Op::Xor(n) => out.extend_from_slice(&[0x02, *n]),
+                Op::XorPos => out.push(0x03),
+                Op::Add(n) => out.extend_from_slice(&[0x04, *n]),
+                Op::AddPos => out.push(0x05),
+            }
+        }
+        out.push(0x00);
+        out
+    }
+
+    pub fn encrypt_byte(&self, b: u8, pos: u64) -> u8 {
+        self.ops.iter().fold(b, |b, op| op.apply(b, pos))
+    }
+
+    pub fn decrypt_byte(&self, b: u8, pos: u64) -> u8 {
+        self.ops.iter().rev().fold(b, |b, op| op.invert(b, pos))
+    }
+
+    // Encrypt `data` in place, its first byte being at stream position `pos`
+    pub fn encrypt(&self, data: &mut [u8], pos: u64) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = self.encrypt_byte(*b, pos + i as u64);
+        }
+    }
+
+    pub fn decrypt(&self, data: &mut [u8], pos: u64) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = self.decrypt_byte(*b, pos + i as u64);
+        }
+    }
+
+    // Positions only matter modulo 256, so trying every byte at every such
+    // position settles it
+    pub fn is_noop(&self) -> bool {
+        (0..=255u8).all(|pos| (0..=255u8).all(|b| self.encrypt_byte(b, pos as u64) == b))
+    }
+}
+
+// Read a cipher spec off the front of a stream, one byte at a time so nothing
+// after it is consumed
+pub async fn read_spec<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<CipherSpec> {
+    let mut bytes = Vec::new();
+    loop {
+        let b = match reader.read_u8().await {
+            Ok(b) => b,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(SpecError::Truncated.into())
+            }
+            Err(e) => return Err(e),
+        };
+        bytes.push(b);
+        match CipherSpec::parse(&bytes) {
+            Ok((spec, _)) if spec.is_noop() => return Err(SpecError::NoOp.into()),
+            Ok((spec, _)) => return Ok(spec),
+            Err(SpecError::Truncated) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// A stream whose traffic is encrypted with a cipher spec in both directions
+pub struct CipherStream<S> {
+    inner: S,
+    spec: CipherSpec,
+    read_pos: u64,
+    write_pos: u64,
+    // Encrypted bytes accepted from the caller but not yet taken by `inner`
+    pending: Vec<u8>,
+}
+
+impl<S> CipherStream<S> {
+    pub fn new(inner: S, spec: CipherSpec) -> Self {
+        CipherStream {
+            inner,
+            spec,
+            read_pos: 0,
+            write_pos: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn spec(&self) -> &CipherSpec {
+        &self.spec
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> CipherStream<S> {
+    // Server side: read the client's spec, refusing no-op ones
+    pub async fn accept(mut inner: S) -> io::Result<Self> {
+        let spec = read_spec(&mut inner).await?;
+        Ok(CipherStream::new(inner, spec))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CipherStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    self.pending.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CipherStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let already = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let this = &mut *self;
+            let fresh = &mut buf.filled_mut()[already..];
+            this.spec.decrypt(fresh, this.read_pos);
+            this.read_pos += fresh.len() as u64;
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CipherStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Bytes are encrypted by position as soon as they're accepted, so
+        // anything the inner stream didn't take yet has to go out first
+        if self.poll_write_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        let this = &mut *self;
+        let start = this.pending.len();
+        this.pending.extend_from_slice(buf);
+        this.spec
+            .encrypt(&mut this.pending[start..], this.write_pos);
+        this.write_pos += buf.len() as u64;
+        // Whatever doesn't go out now is written on the next call or flush
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.poll_write_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.poll_write_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Negotiates a cipher with each connection and has the handler inside serve
+// the decrypted stream, so any stream problem can be served over ISL
+#[derive(Clone)]
+pub struct Encrypted<H>(pub H);
+
+impl<H: ConnectionHandler> ConnectionHandler for Encrypted<H> {
+    async fn handle<S: ByteStream>(
+        &self,
+        stream: S,
+        peer: Option<SocketAddr>,
+        ctx: handler::Context,
+    ) {
+        ctx.task.phase("reading cipher spec");
+        let accepted = tokio::select! {
+            accepted = CipherStream::accept(stream) => accepted,
+            _ = ctx.cancel.cancelled() => return,
+        };
+        match accepted {
+            Ok(stream) => {
+                let ctx = handler::Context {
+                    transport: "isl",
+                    ..ctx
+                };
+                self.0.handle(stream, peer, ctx).await
+            }
+            // Dropping the connection is the only answer a bad spec gets
+            Err(e) => common::debug!("Rejected ISL connection from {:?}: {}", peer, e),
+        }
+    }
+}
+
+// Accept TCP connections on `addr` and serve them through `Encrypted(handler)`
+// until `shutdown` is cancelled
+pub async fn run_isl_acceptor<H: ConnectionHandler>(
+    addr: SocketAddr,
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            common::error!("Couldn't start ISL listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    common::info!("Listening for ISL connections on {}", addr);
+
+    let handler = Encrypted(handler);
+    let mut failed_accepts = 0;
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+        let (socket, peer) = match accepted {
+            Ok(s) => s,
+            Err(e) => {
+                let delay = admission.accept_failed("ISL connection", &e, &mut failed_accepts);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.cancelled() => return,
+                }
+                continue;
+            }
+        };
+        failed_accepts = 0;
+        common::debug!("Accepted ISL connection from {:?}", peer);
+        let Some(admitted) = admission.admit(Some(peer)) else {
+            continue;
+        };
+        let ctx = handler::Context {
+            cancel: shutdown.child_token(),
+            ..handler::Context::new("isl")
+        };
+        admitted.spawn(&handler, socket, ctx);
+    }
+}
+
+// Start an ISL listener on ISL_PORT in the background, if it is set, until
+// `shutdown` is cancelled
+pub fn spawn_from_env<H: ConnectionHandler>(
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    if let Some(port) = common::env::var::<u16>("ISL_PORT") {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        tokio::spawn(run_isl_acceptor(addr, handler, admission, shutdown));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn spec(bytes: &[u8]) -> CipherSpec {
+        let (spec, len) = CipherSpec::parse(bytes).unwrap();
+        assert_eq!(len, bytes.len());
+        spec
+    }
+
+    fn all_specs() -> Vec<CipherSpec> {
+        vec![
+            spec(&[0x02, 0x01, 0x01, 0x00]),
+            spec(&[0x05, 0x05, 0x00]),
+            spec(&[0x02, 0x7b, 0x05, 0x01, 0x00]),
+            spec(&[0x04, 0x03, 0x03, 0x01, 0x05, 0x02, 0xa0, 0x00]),
+        ]
+    }
+
+    #[test]
+    fn encrypts_the_spec_examples() {
+        let mut data = *b"hello";
+        spec(&[0x02, 0x01, 0x01, 0x00]).encrypt(&mut data, 0);
+        assert_eq!(data, [0x96, 0x26, 0xb6, 0xb6, 0x76]);
+        let mut data = *b"hello";
+        spec(&[0x05, 0x05, 0x00]).encrypt(&mut data, 0);
+        assert_eq!(data, [0x68, 0x67, 0x70, 0x72, 0x77]);
+    }
+
+    #[test]
+    fn round_trips_at_any_position() {
+        let plain: Vec<u8> = (0..=255u8).collect();
+        for spec in all_specs() {
+            for pos in [0, 1, 255, 256, 1000, u32::MAX as u64 + 7] {
+                let mut data = plain.clone();
+                spec.encrypt(&mut data, pos);
+                assert_ne!(data, plain, "{:?} at {}", spec, pos);
+                spec.decrypt(&mut data, pos);
+                assert_eq!(data, plain, "{:?} at {}", spec, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn encrypting_in_pieces_matches_all_at_once() {
+        let plain: Vec<u8> = (0..600).map(|i| (i * 7) as u8).collect();
+        for spec in all_specs() {
+            let mut whole = plain.clone();
+            spec.encrypt(&mut whole, 0);
+            let mut pieces = plain.clone();
+            let (a, b) = pieces.split_at_mut(257);
+            spec.encrypt(a, 0);
+            spec.encrypt(b, 257);
+            assert_eq!(pieces, whole);
+        }
+    }
+
+    #[test]
+    fn no_op_specs() {
+        for bytes in [
+            &[0x00][..],
+            &[0x02, 0x00, 0x00],
+            &[0x02, 0xa0, 0x02, 0xa0, 0x00],
+            &[0x01, 0x01, 0x00],
+            &[0x04, 0x80, 0x04, 0x80, 0x00],
+        ] {
+            assert!(spec(bytes).is_noop(), "{:x?}", bytes);
+        }
+        for bytes in [
+            &[0x02, 0x01, 0x00][..],
+            &[0x03, 0x00],
+            &[0x02, 0xa0, 0x02, 0xa1, 0x00],
+        ] {
+            assert!(!spec(bytes).is_noop(), "{:x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn parses_and_rejects_specs() {
+        for spec in all_specs() {
+            assert_eq!(CipherSpec::parse(&spec.to_bytes()).unwrap().0, spec);
+        }
+        assert!(matches!(
+            CipherSpec::parse(&[0x02]),
+            Err(SpecError::Truncated)
+        ));
+        assert!(matches!(
+            CipherSpec::parse(&[0x01]),
+            Err(SpecError::Truncated)
+        ));
+        assert!(matches!(
+            CipherSpec::parse(&[0x06, 0x00]),
+            Err(SpecError::UnknownOp(0x06))
+        ));
+        assert!(matches!(
+            CipherSpec::parse(&[0x01; 100]),
+            Err(SpecError::TooLong)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_specs_and_refuses_no_ops() {
+        let mut input = &[0x02, 0x01, 0x00, b'x'][..];
+        assert_eq!(
+            read_spec(&mut input).await.unwrap(),
+            spec(&[0x02, 0x01, 0x00])
+        );
+        // Nothing after the spec was consumed
+        assert_eq!(input, b"x");
+        let mut input = &[0x01, 0x01, 0x00][..];
+        assert_eq!(
+            read_spec(&mut input).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    // Writes through a pipe so small that nearly every write is partial, and
+    // checks the other end decrypts to what went in
+    #[tokio::test]
+    async fn survives_partial_writes() {
+        let plain: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        for spec in all_specs() {
+            let (near, far) = tokio::io::duplex(3);
+            let mut writer = CipherStream::new(near, spec.clone());
+            let mut reader = CipherStream::new(far, spec);
+            let expected = plain.clone();
+            let reading = tokio::spawn(async move {
+                let mut got = Vec::new();
+                reader.read_to_end(&mut got).await.unwrap();
+                assert_eq!(got, expected);
+            });
+            for chunk in plain.chunks(37) {
+                writer.write_all(chunk).await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+            reading.await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,859 @@
+// Line Reversal Control Protocol (problem 7) as a reusable transport.
+//
+// LRCP gives reliable, ordered byte streams over UDP. `Listener` owns the
+// socket, routes datagrams to their sessions and hands out each new session
+// as a `Session`, which is an ordinary AsyncRead + AsyncWrite stream, so
+// applications never see acknowledgements, retransmissions or expiry.
+// Everything sent goes through a common::udp_guard::Guard; a source counts as
+// verified once it acknowledges data a session sent it.
+//
+// A session belongs to the address that connected it: datagrams with its id
+// from anywhere else are for some other session. At most LRCP_MAX_SESSIONS
+// sessions are open at once, and at most LRCP_MAX_SESSIONS_PER_SOURCE from
+// one IP address; a connect past either is answered with a close. One that
+// arrives while the accept queue is full is dropped, as if lost, and the peer
+// connects again later.
+//
+// With the testkit feature, `testkit` has an LRCP client for other crates to
+// test their applications over LRCP with.
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, Notify};
+use tokio_util::sync::CancellationToken;
+
+use common::admission::Admission;
+use common::handler::{self, ConnectionHandler};
+use common::timer::Timers;
+use common::udp_guard::Guard;
+
+mod message;
+mod session;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+
+use message::{Message, MAX_MESSAGE_LEN};
+
+// Bytes buffered between a session and its application in each direction
+const PIPE_LEN: usize = 64 * 1024;
+// Datagrams waiting for a session task before more are dropped
+const INBOUND_QUEUE_LEN: usize = 64;
+// New sessions waiting for `accept`
+const ACCEPT_QUEUE_LEN: usize = 64;
+// Precision of the sessions' retransmission timers
+const TIMER_TICK: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    // How long unacknowledged data waits before being sent again
+    pub retransmit_interval: Duration,
+    // How long a session with unacknowledged data lives without any progress
+    pub session_expiry: Duration,
+    // Unacknowledged bytes a session sends before it stops reading from the
+    // application
+    pub max_outstanding: usize,
+    // Fraction of datagrams dropped on purpose, in both directions, to see
+    // how sessions cope with a lossy network
+    pub loss: f64,
+    pub max_sessions: usize,
+    pub max_sessions_per_source: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            retransmit_interval: Duration::from_secs(3),
+            session_expiry: Duration::from_secs(60),
+            max_outstanding: PIPE_LEN,
+            loss: 0.0,
+            max_sessions: 10_000,
+            max_sessions_per_source: 100,
+        }
+    }
+}
+
+impl Config {
+    // Defaults, overridden by LRCP_RETRANSMIT_MILLIS, LRCP_SESSION_EXPIRY_SECS,
+    // LRCP_MAX_OUTSTANDING, LRCP_LOSS, LRCP_MAX_SESSIONS and
+    // LRCP_MAX_SESSIONS_PER_SOURCE
+    pub fn from_env() -> Self {
+        let default = Config::default();
+        let loss = common::env::var_or("LRCP_LOSS", default.loss);
+        if loss > 0.0 {
+            common::info!("Dropping {:.0}% of LRCP datagrams", loss * 100.0);
+        }
+        Config {
+            retransmit_interval: common::env::var("LRCP_RETRANSMIT_MILLIS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.retransmit_interval),
+            session_expiry: common::env::var("LRCP_SESSION_EXPIRY_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.session_expiry),
+            // With nothing allowed in flight a session could never send anything
+            max_outstanding: common::env::var_or("LRCP_MAX_OUTSTANDING", default.max_outstanding)
+                .max(1),
+            loss: loss.clamp(0.0, 1.0),
+            max_sessions: common::env::var_or("LRCP_MAX_SESSIONS", default.max_sessions),
+            max_sessions_per_source: common::env::var_or(
+                "LRCP_MAX_SESSIONS_PER_SOURCE",
+                default.max_sessions_per_source,
+            ),
+        }
+    }
+
+    // Whether to drop the next datagram
+    pub(crate) fn lose(&self) -> bool {
+        self.loss > 0.0 && rand::random::<f64>() < self.loss
+    }
+}
+
+pub struct Listener {
+    incoming: mpsc::Receiver<Session>,
+    local_addr: SocketAddr,
+}
+
+impl Listener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Listener> {
+        Self::bind_with(addr, Config::default()).await
+    }
+
+    pub async fn bind_with<A: ToSocketAddrs>(addr: A, config: Config) -> io::Result<Listener> {
+        let socket = UdpSocket::bind(addr).await?;
+        common::tuning::tune_udp(&socket);
+        let socket = Arc::new(socket);
+        let local_addr = socket.local_addr()?;
+        let (tx, rx) = mpsc::channel(ACCEPT_QUEUE_LEN);
+        tokio::spawn(route(socket, config, Arc::new(Guard::from_env()), tx));
+        Ok(Listener {
+            incoming: rx,
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Wait for the next new session
+    pub async fn accept(&mut self) -> io::Result<Session> {
+        self.incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::other("LRCP listener stopped"))
+    }
+}
+
+// Hand every session accepted on `addr` to `handler` in its own task, the way
+// the TCP listeners do with sockets, until `shutdown` is cancelled
+pub async fn run_lrcp_acceptor<H: ConnectionHandler>(
+    addr: SocketAddr,
+    config: Config,
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    let listener = match Listener::bind_with(addr, config).await {
+        Ok(l) => l,
+        Err(e) => {
+            common::error!("Couldn't start LRCP listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    common::info!("Listening for LRCP sessions on {}", addr);
+    serve(listener, handler, admission, shutdown).await
+}
+
+// Hand every session accepted on `listener` to `handler` in its own task
+// until `shutdown` is cancelled or the listener stops, then give the sessions
+// already open up to SHUTDOWN_DRAIN_SECS to finish
+pub async fn serve<H: ConnectionHandler>(
+    mut listener: Listener,
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    let mut open = Vec::new();
+    loop {
+        let session = tokio::select! {
+            session = listener.accept() => session,
+            _ = shutdown.cancelled() => break,
+        };
+        let Ok(session) = session else {
+            break;
+        };
+        common::debug!(
+            "Accepted LRCP session {} from {:?}",
+            session.id(),
+            session.peer_addr()
+        );
+        let Some(admitted) = admission.admit(Some(session.peer_addr())) else {
+            continue;
+        };
+        let ctx = handler::Context {
+            cancel: shutdown.child_token(),
+            ..handler::Context::new("lrcp")
+        };
+        open.retain(|session: &tokio::task::JoinHandle<()>| !session.is_finished());
+        open.push(admitted.spawn(&handler, session, ctx));
+    }
+
+    // On a shutdown, sessions were told to wind down along with it
+    let finished = async {
+        for session in open {
+            session.await.unwrap_or(());
+        }
+    };
+    if tokio::time::timeout(common::shutdown::drain_period(), finished)
+        .await
+        .is_err()
+    {
+        common::warn!("Stopped waiting for LRCP sessions to finish");
+    }
+}
+
+// Start an LRCP listener on LRCP_PORT in the background, if it is set, until
+// `shutdown` is cancelled
+pub fn spawn_from_env<H: ConnectionHandler>(
+    handler: H,
+    admission: Admission,
+    shutdown: CancellationToken,
+) {
+    if let Some(port) = common::env::var::<u16>("LRCP_PORT") {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        tokio::spawn(run_lrcp_acceptor(
+            addr,
+            Config::from_env(),
+            handler,
+            admission,
+            shutdown,
+        ));
+    }
+}
+
+// Sessions are told apart by the address they're from as well as by their id,
+// so that nobody else can send into one by guessing its id
+type SessionKey = (SocketAddr, u32);
+
+// A session the router delivers datagrams to
+struct Routed {
+    inbound: mpsc::Sender<Message>,
+    // Tells this session's finishing and timer apart from those of an earlier
+    // one with the same key
+    generation: u64,
+    // Wakes the session when its timer fires
+    due: Arc<Notify>,
+}
+
+// The open sessions, by key and counted by source IP address
+#[derive(Default)]
+struct Sessions {
+    by_key: HashMap<SessionKey, Routed>,
+    by_source: HashMap<IpAddr, usize>,
+}
+
+impl Sessions {
+    fn insert(&mut self, key: SessionKey, routed: Routed) {
+        *self.by_source.entry(key.0.ip()).or_insert(0) += 1;
+        self.by_key.insert(key, routed);
+    }
+
+    fn remove(&mut self, key: SessionKey) {
+        if self.by_key.remove(&key).is_none() {
+            return;
+        }
+        if let Entry::Occupied(mut count) = self.by_source.entry(key.0.ip()) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+
+    // Whether another session from `peer` would be one too many. Sessions are
+    // counted by IP address, since a host can pick any source port.
+    fn full(&self, peer: SocketAddr, config: &Config) -> bool {
+        self.by_key.len() >= config.max_sessions
+            || self.by_source.get(&peer.ip()).copied().unwrap_or(0)
+                >= config.max_sessions_per_source
+    }
+}
+
+async fn send_close(socket: &UdpSocket, guard: &Guard, session: u32, to: SocketAddr) {
+    let close = Message::Close { session }.encode();
+    if guard.allow(to, close.len()) {
+        socket.send_to(&close, to).await.unwrap_or(0);
+    }
+}
+
+async fn route(
+    socket: Arc<UdpSocket>,
+    config: Config,
+    guard: Arc<Guard>,
+    accepted: mpsc::Sender<Session>,
+) {
+    let mut sessions = Sessions::default();
+    let (finished_tx, mut finished) = mpsc::unbounded_channel::<session::TimerKey>();
+    let (timers, mut fired) = Timers::new(TIMER_TICK);
+    let mut generation = 0;
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN + 1];
+
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            Some((key, ended)) = finished.recv() => {
+                timers.cancel(&(key, ended));
+                if sessions.by_key.get(&key).is_some_and(|s| s.generation == ended) {
+                    sessions.remove(key);
+                }
+                continue;
+            }
+            Some((key, due)) = fired.recv() => {
+                if let Some(routed) = sessions.by_key.get(&key).filter(|s| s.generation == due) {
+                    routed.due.notify_one();
+                }
+                continue;
+            }
+        };
+        let (n, from) = match received {
+            Ok(r) => r,
+            Err(e) => {
+                common::warn!("Couldn't receive LRCP datagram: {:?}", e);
+                continue;
+            }
+        };
+        if config.lose() {
+            continue;
+        }
+        guard.received(from, n);
+        let Some(message) = Message::parse(&buf[..n]) else {
+            continue;
+        };
+        let id = match &message {
+            Message::Connect { session }
+            | Message::Data { session, .. }
+            | Message::Ack { session, .. }
+            | Message::Close { session } => *session,
+        };
+        // The same id from anywhere else is some other session
+        let key = (from, id);
+
+        // A session whose task has finished, before the router has heard
+        if sessions
+            .by_key
+            .get(&key)
+            .is_some_and(|s| s.inbound.is_closed())
+        {
+            sessions.remove(key);
+        }
+
+        match (sessions.by_key.get(&key), &message) {
+            (Some(routed), _) => {
+                // A full queue means the session is swamped; the peer will
+                // retransmit
+                routed.inbound.try_send(message).unwrap_or(());
+            }
+            (None, Message::Connect { .. }) if sessions.full(from, &config) => {
+                common::debug!("Too many LRCP sessions, refusing {} from {}", id, from);
+                send_close(&socket, &guard, id, from).await;
+            }
+            (None, Message::Connect { .. }) => {
+                let room = match accepted.try_reserve() {
+                    Ok(room) => room,
+                    // The peer will connect again
+                    Err(mpsc::error::TrySendError::Full(())) => continue,
+                    // Nobody is accepting anymore
+                    Err(mpsc::error::TrySendError::Closed(())) => return,
+                };
+                let (tx, rx) = mpsc::channel(INBOUND_QUEUE_LEN);
+                let (app, ours) = tokio::io::duplex(PIPE_LEN);
+                let sender = session::Sender {
+                    socket: socket.clone(),
+                    guard: guard.clone(),
+                    config,
+                };
+                generation += 1;
+                let timer = session::Timer {
+                    timers: timers.clone(),
+                    key: (key, generation),
+                    due: Arc::new(Notify::new()),
+                };
+                let due = timer.due.clone();
+                let (finished, ended) = (finished_tx.clone(), generation);
+                tokio::spawn(async move {
+                    session::run(id, from, sender, rx, ours, timer).await;
+                    finished.send((key, ended)).unwrap_or(());
+                });
+                sessions.insert(
+                    key,
+                    Routed {
+                        inbound: tx,
+                        generation,
+                        due,
+                    },
+                );
+                room.send(Session {
+                    id,
+                    peer: from,
+                    pipe: app,
+                });
+            }
+            (None, _) => send_close(&socket, &guard, id, from).await,
+        }
+    }
+}
+
+// An established LRCP session, as seen by the application
+pub struct Session {
+    id: u32,
+    peer: SocketAddr,
+    pipe: DuplexStream,
+}
+
+impl Session {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // The address that connected the session, and the only one it takes
+    // datagrams from
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for Session {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.pipe).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Session {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.pipe).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.pipe).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.pipe).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::Peer;
+    use common::accept::AcceptLimits;
+    use common::handler::{ByteStream, Context as Ctx};
+    use message::data_chunk_len;
+    use tokio::time::Instant;
+
+    const SESSION: u32 = 12345;
+    // Bytes the test client sends ahead of the server's acknowledgements
+    const WINDOW: usize = 8 * 1024;
+    const TICK: Duration = Duration::from_millis(50);
+
+    fn config() -> Config {
+        Config {
+            retransmit_interval: Duration::from_millis(100),
+            ..Config::default()
+        }
+    }
+
+    // Start a listener whose sessions echo everything back
+    async fn echo_server(config: Config) -> SocketAddr {
+        let mut listener = Listener::bind_with("127.0.0.1:0", config).await.unwrap();
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            while let Ok(session) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = tokio::io::split(session);
+                    tokio::io::copy(&mut reader, &mut writer).await.unwrap_or(0);
+                });
+            }
+        });
+        addr
+    }
+
+    // A bare-bones LRCP peer: sends `input` over one session, go-back-N with a
+    // fixed window, until `expected` bytes have come back. With
+    // `withhold_acks` it acknowledges nothing until the server stops
+    // acknowledging what it sends.
+    async fn exchange(
+        server: SocketAddr,
+        input: &[u8],
+        expected: usize,
+        mut withhold_acks: bool,
+    ) -> Vec<u8> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        let send = |message: Message| {
+            let socket = &socket;
+            async move { socket.send(&message.encode()).await.unwrap() }
+        };
+
+        let mut connected = false;
+        let mut acked = 0;
+        let mut next = 0;
+        let mut output = Vec::new();
+        let mut last_progress = Instant::now();
+        let mut tick = tokio::time::interval(TICK);
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+
+        while output.len() < expected || acked < input.len() {
+            let mut resend = false;
+            tokio::select! {
+                n = socket.recv(&mut buf) => match Message::parse(&buf[..n.unwrap()]) {
+                    Some(Message::Ack { length, .. }) => {
+                        connected = true;
+                        if length as usize > acked {
+                            acked = length as usize;
+                            next = next.max(acked);
+                            last_progress = Instant::now();
+                        }
+                    }
+                    Some(Message::Data { pos, data, .. }) => {
+                        if pos as usize == output.len() {
+                            output.extend_from_slice(&data);
+                        }
+                        if !withhold_acks {
+                            send(Message::Ack { session: SESSION, length: output.len() as u32 }).await;
+                        }
+                    }
+                    Some(Message::Close { .. }) => panic!("server closed the session"),
+                    _ => {}
+                },
+                _ = tick.tick() => {
+                    if !connected {
+                        send(Message::Connect { session: SESSION }).await;
+                        continue;
+                    }
+                    if withhold_acks && last_progress.elapsed() > 6 * TICK {
+                        withhold_acks = false;
+                    }
+                    if !withhold_acks {
+                        send(Message::Ack { session: SESSION, length: output.len() as u32 }).await;
+                    }
+                    resend = true;
+                }
+            }
+            if resend {
+                next = acked;
+            }
+            while connected && next < input.len().min(acked + WINDOW) {
+                let end = input.len().min(acked + WINDOW);
+                let len = data_chunk_len(SESSION, next as u32, &input[next..end]);
+                send(Message::Data {
+                    session: SESSION,
+                    pos: next as u32,
+                    data: input[next..next + len].to_vec(),
+                })
+                .await;
+                next += len;
+            }
+        }
+        output
+    }
+
+    fn lines(count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| format!("{:099}\n", i).into_bytes())
+            .collect()
+    }
+
+    // The server's replies back up while the client isn't acknowledging
+    // them, which mustn't stop the session from taking in acknowledgements
+    // once they come
+    #[tokio::test]
+    async fn keeps_acknowledging_while_the_application_is_backed_up() {
+        let server = echo_server(config()).await;
+        let input = lines(4000);
+        let output = tokio::time::timeout(
+            Duration::from_secs(30),
+            exchange(server, &input, input.len(), true),
+        )
+        .await
+        .expect("session stalled");
+        assert_eq!(output, input);
+    }
+
+    // Half of what the server sends and receives is dropped, connects and
+    // acknowledgements included, so everything gets through on retransmits
+    #[tokio::test]
+    async fn echoes_everything_despite_losing_half_the_datagrams() {
+        let server = echo_server(Config {
+            loss: 0.5,
+            ..config()
+        })
+        .await;
+        let input = lines(300);
+        let output = tokio::time::timeout(
+            Duration::from_secs(60),
+            exchange(server, &input, input.len(), false),
+        )
+        .await
+        .expect("session stalled");
+        assert_eq!(output, input);
+    }
+
+    // A handler that echoes everything back
+    #[derive(Clone)]
+    struct Echo;
+
+    impl ConnectionHandler for Echo {
+        async fn handle<S: ByteStream>(&self, socket: S, _: Option<SocketAddr>, _: Ctx) {
+            let (mut reader, mut writer) = tokio::io::split(socket);
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap_or(0);
+        }
+    }
+
+    // `serve` runs a ConnectionHandler on each session
+    #[tokio::test]
+    async fn serves_a_connection_handler() {
+        let listener = Listener::bind_with("127.0.0.1:0", config()).await.unwrap();
+        let server = listener.local_addr();
+        let scope = common::metrics::Scope::new("lrcp", server.port());
+        let admission = Admission::new(&scope, AcceptLimits::from_env());
+        tokio::spawn(serve(listener, Echo, admission, CancellationToken::new()));
+
+        let mut alice = Peer::connect(server, 1).await;
+        let mut bob = Peer::connect(server, 2).await;
+        // Sent in pieces, which LRCP reassembles into the one line
+        alice.send("hello ");
+        alice.send("there\n");
+        bob.send("hi\n");
+        assert_eq!(alice.line().await, "hello there\n");
+        assert_eq!(bob.line().await, "hi\n");
+        let long = format!("{}\n", "x".repeat(2000));
+        bob.send(&long);
+        assert_eq!(bob.line().await, long);
+    }
+
+    #[tokio::test]
+    async fn stops_serving_on_shutdown() {
+        let listener = Listener::bind_with("127.0.0.1:0", config()).await.unwrap();
+        let scope = common::metrics::Scope::new("lrcp", listener.local_addr().port());
+        let shutdown = CancellationToken::new();
+        let admission = Admission::new(&scope, AcceptLimits::from_env());
+        let serving = tokio::spawn(serve(listener, Echo, admission, shutdown.clone()));
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), serving)
+            .await
+            .expect("serve() kept going after shutdown")
+            .unwrap();
+    }
+
+    // Send `message` to `server` and wait for the answer
+    async fn ask(socket: &UdpSocket, message: Message) -> Option<Message> {
+        socket.send(&message.encode()).await.unwrap();
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+        let n = tokio::time::timeout(Duration::from_secs(1), socket.recv(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Message::parse(&buf[..n])
+    }
+
+    async fn client(server: SocketAddr) -> UdpSocket {
+        client_from([127, 0, 0, 1], server).await
+    }
+
+    async fn client_from(ip: [u8; 4], server: SocketAddr) -> UdpSocket {
+        let socket = UdpSocket::bind(SocketAddr::from((ip, 0))).await.unwrap();
+        socket.connect(server).await.unwrap();
+        socket
+    }
+
+    #[tokio::test]
+    async fn refuses_sessions_past_the_cap_for_a_source() {
+        let server = echo_server(Config {
+            max_sessions_per_source: 2,
+            ..config()
+        })
+        .await;
+        let socket = client(server).await;
+        for session in [1, 2] {
+            let ack = ask(&socket, Message::Connect { session }).await;
+            assert_eq!(ack, Some(Message::Ack { session, length: 0 }));
+        }
+        let refused = ask(&socket, Message::Connect { session: 3 }).await;
+        assert_eq!(refused, Some(Message::Close { session: 3 }));
+        // Nor does another port on the same host get round it
+        let refused = ask(&client(server).await, Message::Connect { session: 3 }).await;
+        assert_eq!(refused, Some(Message::Close { session: 3 }));
+        // Other hosts have room of their own
+        let other = client_from([127, 0, 0, 2], server).await;
+        let ack = ask(&other, Message::Connect { session: 3 }).await;
+        assert_eq!(
+            ack,
+            Some(Message::Ack {
+                session: 3,
+                length: 0
+            })
+        );
+    }
+
+    // Data that's never acknowledged is sent again every retransmission
+    // interval, until the session expires
+    #[tokio::test]
+    async fn retransmits_until_the_session_expires() {
+        let server = echo_server(Config {
+            session_expiry: Duration::from_millis(500),
+            ..config()
+        })
+        .await;
+        let socket = client(server).await;
+        let ack = ask(&socket, Message::Connect { session: 1 }).await;
+        assert_eq!(
+            ack,
+            Some(Message::Ack {
+                session: 1,
+                length: 0
+            })
+        );
+        let data = Message::Data {
+            session: 1,
+            pos: 0,
+            data: b"hi\n".to_vec(),
+        };
+        let data = data.encode();
+        socket.send(&data).await.unwrap();
+
+        let started = Instant::now();
+        let mut sent = 0;
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+        while let Ok(n) =
+            tokio::time::timeout(Duration::from_millis(300), socket.recv(&mut buf)).await
+        {
+            if buf[..n.unwrap()] == data[..] {
+                sent += 1;
+            }
+        }
+        // Once at first, then every 100ms for about 500ms
+        assert!((4..=8).contains(&sent), "sent {} times", sent);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn sessions_only_take_datagrams_from_their_peer() {
+        let server = echo_server(config()).await;
+        let peer = client(server).await;
+        let ack = ask(&peer, Message::Connect { session: 1 }).await;
+        assert_eq!(
+            ack,
+            Some(Message::Ack {
+                session: 1,
+                length: 0
+            })
+        );
+
+        // To anyone else the id is of a session that isn't open
+        let intruder = client(server).await;
+        let data = Message::Data {
+            session: 1,
+            pos: 0,
+            data: b"injected\n".to_vec(),
+        };
+        assert_eq!(
+            ask(&intruder, data).await,
+            Some(Message::Close { session: 1 })
+        );
+
+        // The session carries on with its peer, and nothing was injected
+        let data = Message::Data {
+            session: 1,
+            pos: 0,
+            data: b"hi\n".to_vec(),
+        };
+        let ack = ask(&peer, data).await;
+        assert_eq!(
+            ack,
+            Some(Message::Ack {
+                session: 1,
+                length: 3
+            })
+        );
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+        let n = peer.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            Message::parse(&buf[..n]),
+            Some(Message::Data {
+                session: 1,
+                pos: 0,
+                data: b"hi\n".to_vec()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn forgets_sessions_once_they_finish() {
+        let server = echo_server(Config {
+            max_sessions: 1,
+            ..config()
+        })
+        .await;
+        let socket = client(server).await;
+        let ack = ask(&socket, Message::Connect { session: 1 }).await;
+        assert_eq!(
+            ack,
+            Some(Message::Ack {
+                session: 1,
+                length: 0
+            })
+        );
+        let closed = ask(&socket, Message::Close { session: 1 }).await;
+        assert_eq!(closed, Some(Message::Close { session: 1 }));
+
+        // Room for another, without session 1 being heard from again
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while ask(&socket, Message::Connect { session: 2 }).await
+                != Some(Message::Ack {
+                    session: 2,
+                    length: 0,
+                })
+            {
+                tokio::time::sleep(TICK).await;
+            }
+        })
+        .await
+        .expect("finished session still counted");
+    }
+
+    #[tokio::test]
+    async fn keeps_routing_with_the_accept_queue_full() {
+        // Never accepting anything
+        let listener = Listener::bind_with("127.0.0.1:0", config()).await.unwrap();
+        let socket = client(listener.local_addr()).await;
+        for session in 0..ACCEPT_QUEUE_LEN as u32 {
+            let ack = ask(&socket, Message::Connect { session }).await;
+            assert_eq!(ack, Some(Message::Ack { session, length: 0 }));
+        }
+        let session = ACCEPT_QUEUE_LEN as u32;
+        assert_eq!(ask(&socket, Message::Connect { session }).await, None);
+        let ack = ask(&socket, Message::Connect { session: 0 }).await;
+        assert_eq!(
+            ack,
+            Some(Message::Ack {
+                session: 0,
+                length: 0
+            })
+        );
+    }
+}
@@ -0,0 +1,216 @@
+// LRCP wire format.
+//
+// Every message is a single datagram of at most 1000 bytes: fields separated
+// and surrounded by `/`, the first naming the message type. Inside the payload
+// of a data message `/` and `\` are escaped with a backslash. Numbers are
+// non-negative and below 2^31. Anything that doesn't follow these rules is
+// ignored by the caller.
+pub const MAX_MESSAGE_LEN: usize = 1000;
+const MAX_NUMBER: u32 = 1 << 31;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Connect {
+        session: u32,
+    },
+    Data {
+        session: u32,
+        pos: u32,
+        data: Vec<u8>,
+    },
+    Ack {
+        session: u32,
+        length: u32,
+    },
+    Close {
+        session: u32,
+    },
+}
+
+fn number(field: &[u8]) -> Option<u32> {
+    if field.is_empty() || !field.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let n: u64 = std::str::from_utf8(field).ok()?.parse().ok()?;
+    (n < MAX_NUMBER as u64).then_some(n as u32)
+}
+
+// Split on unescaped slashes, unescaping as we go. Returns None for a stray
+// backslash.
+fn fields(body: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut fields = vec![Vec::new()];
+    let mut bytes = body.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'\\' => match bytes.next() {
+                Some(&escaped @ (b'\\' | b'/')) => fields.last_mut()?.push(escaped),
+                _ => return None,
+            },
+            b'/' => fields.push(Vec::new()),
+            _ => fields.last_mut()?.push(b),
+        }
+    }
+    Some(fields)
+}
+
+impl Message {
+    pub fn parse(datagram: &[u8]) -> Option<Message> {
+        if datagram.len() > MAX_MESSAGE_LEN || datagram.len() < 2 {
+            return None;
+        }
+        let body = datagram.strip_prefix(b"/")?.strip_suffix(b"/")?;
+        // A trailing slash that was escaped doesn't close the message
+        let escapes = body.iter().rev().take_while(|&&b| b == b'\\').count();
+        if escapes % 2 == 1 {
+            return None;
+        }
+
+        let fields = fields(body)?;
+        match (fields[0].as_slice(), &fields[1..]) {
+            (b"connect", [session]) => Some(Message::Connect {
+                session: number(session)?,
+            }),
+            (b"data", [session, pos, data]) => Some(Message::Data {
+                session: number(session)?,
+                pos: number(pos)?,
+                data: data.clone(),
+            }),
+            (b"ack", [session, length]) => Some(Message::Ack {
+                session: number(session)?,
+                length: number(length)?,
+            }),
+            (b"close", [session]) => Some(Message::Close {
+                session: number(session)?,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Message::Connect { session } => format!("/connect/{}/", session).into_bytes(),
+            Message::Data { session, pos, data } => {
+                let mut out = format!("/data/{}/{}/", session, pos).into_bytes();
+                escape_into(data, &mut out);
+                out.push(b'/');
+                out
+            }
+            Message::Ack { session, length } => {
+                format!("/ack/{}/{}/", session, length).into_bytes()
+            }
+            Message::Close { session } => format!("/close/{}/", session).into_bytes(),
+        }
+    }
+}
+
+fn escape_into(data: &[u8], out: &mut Vec<u8>) {
+    for &b in data {
+        if b == b'/' || b == b'\\' {
+            out.push(b'\\');
+        }
+        out.push(b);
+    }
+}
+
+// How many bytes from the front of `data` fit in one data message for this
+// session and position once escaped
+pub fn data_chunk_len(session: u32, pos: u32, data: &[u8]) -> usize {
+    let header = format!("/data/{}/{}/", session, pos).len() + 1;
+    let mut room = MAX_MESSAGE_LEN - header;
+    let mut len = 0;
+    for &b in data {
+        let cost = if b == b'/' || b == b'\\' { 2 } else { 1 };
+        if cost > room {
+            break;
+        }
+        room -= cost;
+        len += 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codecs::testkit;
+
+    const SESSION: u32 = 12345;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Connect { session: 12345 },
+            Message::Data {
+                session: 12345,
+                pos: 0,
+                data: b"hello\n".to_vec(),
+            },
+            Message::Data {
+                session: 0,
+                pos: MAX_NUMBER - 1,
+                data: b"/\\ a\\/b /".to_vec(),
+            },
+            Message::Data {
+                session: 1,
+                pos: 2,
+                data: Vec::new(),
+            },
+            Message::Ack {
+                session: 12345,
+                length: 6,
+            },
+            Message::Close { session: 12345 },
+        ]
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for message in messages() {
+            let encoded = message.encode();
+            assert_eq!(
+                testkit::datagram_round_trip(Message::parse, &encoded),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_messages_are_ignored() {
+        for datagram in [
+            &b"/connect/"[..],
+            b"/connect/2147483648/",
+            b"/connect/-1/",
+            b"/connect/1/2/",
+            b"/data/1/0/a/b/",
+            b"/data/1/0/a\\b/",
+            b"/data/1/0/a\\/",
+            b"/ack/1/",
+            b"/hello/1/",
+            b"connect/1/",
+        ] {
+            assert_eq!(Message::parse(datagram), None, "{:?}", datagram);
+        }
+        let mut long = Message::Data {
+            session: 1,
+            pos: 0,
+            data: vec![b'x'; MAX_MESSAGE_LEN],
+        }
+        .encode();
+        assert_eq!(Message::parse(&long), None);
+        long.truncate(MAX_MESSAGE_LEN - 1);
+        long.push(b'/');
+        assert!(Message::parse(&long).is_some());
+    }
+
+    #[test]
+    fn data_chunks_fit_in_a_message() {
+        let data = [b'/'; MAX_MESSAGE_LEN];
+        let len = data_chunk_len(SESSION, 0, &data);
+        let message = Message::Data {
+            session: SESSION,
+            pos: 0,
+            data: data[..len].to_vec(),
+        };
+        assert!(message.encode().len() <= MAX_MESSAGE_LEN);
+        assert!(message.encode().len() + 2 > MAX_MESSAGE_LEN);
+    }
+}
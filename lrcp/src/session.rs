@@ -0,0 +1,259 @@
+// One LRCP session, driven by its own task.
+//
+// The task sits between the datagrams the listener routes to it and the
+// application's end of an in-memory pipe: incoming data is acknowledged and
+// queued for the pipe in order, and whatever the application writes is sent
+// out as data messages and kept until acknowledged, being retransmitted every
+// retransmission interval. Without any acknowledgement progress for the
+// session expiry time the session is dropped. Those intervals are timed on the
+// listener's common::timer wheel, which wakes the session when one is up,
+// rather than by a timer of the session's own. Once the application shuts down
+// its side and everything it wrote has been acknowledged, the session is
+// closed.
+//
+// Writing to the pipe never holds up the task, as it would if the
+// application were itself waiting for its replies to be acknowledged.
+// Incoming data waits in a queue of at most `max_outstanding` bytes instead,
+// and data that doesn't fit isn't acknowledged, so the peer sends it again
+// later.
+use crate::message::{data_chunk_len, Message};
+use crate::{Config, SessionKey};
+use common::timer::Timers;
+use common::udp_guard::Guard;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+const READ_CHUNK: usize = 4096;
+
+// Where a session's datagrams go out, and what may stop them
+pub(crate) struct Sender {
+    pub socket: Arc<UdpSocket>,
+    pub guard: Arc<Guard>,
+    pub config: Config,
+}
+
+// Identifies a session's timer on the wheel: its key, and which of the
+// sessions that had that key
+pub(crate) type TimerKey = (SessionKey, u64);
+
+// A session's retransmission timer
+pub(crate) struct Timer {
+    pub timers: Timers<TimerKey>,
+    pub key: TimerKey,
+    // Notified by the listener when the timer fires
+    pub due: Arc<Notify>,
+}
+
+struct Session {
+    id: u32,
+    peer: SocketAddr,
+    sender: Sender,
+    // Bytes received in order so far, and the last of them not yet written to
+    // the application
+    received: u32,
+    pending: Vec<u8>,
+    // Whether the application is still reading
+    app_reading: bool,
+    // Bytes sent so far, and the ones from `acked` up to there not yet acknowledged
+    sent: u32,
+    acked: u32,
+    unacked: Vec<u8>,
+    // Last time the peer acknowledged something new (or the session started)
+    last_progress: Instant,
+}
+
+impl Session {
+    async fn send(&self, message: &Message) {
+        if self.sender.config.lose() {
+            return;
+        }
+        let datagram = message.encode();
+        if !self.sender.guard.allow(self.peer, datagram.len()) {
+            return;
+        }
+        if let Err(e) = self.sender.socket.send_to(&datagram, self.peer).await {
+            common::debug!("Couldn't send to {}: {:?}", self.peer, e);
+        }
+    }
+
+    // Send `data`, which starts at stream position `pos`, in as many messages
+    // as it takes
+    async fn send_data(&self, mut pos: u32, mut data: &[u8]) {
+        while !data.is_empty() {
+            let len = data_chunk_len(self.id, pos, data);
+            self.send(&Message::Data {
+                session: self.id,
+                pos,
+                data: data[..len].to_vec(),
+            })
+            .await;
+            pos += len as u32;
+            data = &data[len..];
+        }
+    }
+
+    // Close the session if the application is done and nothing is left in flight
+    async fn finished(&self, app_open: bool) -> bool {
+        if app_open || !self.unacked.is_empty() {
+            return false;
+        }
+        self.send(&Message::Close { session: self.id }).await;
+        true
+    }
+
+    async fn retransmit(&self) {
+        self.send_data(self.acked, &self.unacked).await;
+    }
+
+    // Returns false if the session should be closed
+    async fn handle(&mut self, message: Message) -> bool {
+        match message {
+            Message::Connect { .. } => {
+                self.send(&Message::Ack {
+                    session: self.id,
+                    length: 0,
+                })
+                .await;
+            }
+            Message::Data { pos, data, .. } => {
+                // Once nobody is reading anymore, don't claim to have taken
+                // the data
+                if pos == self.received
+                    && self.app_reading
+                    && self.pending.len() + data.len() <= self.sender.config.max_outstanding
+                {
+                    self.pending.extend_from_slice(&data);
+                    self.received += data.len() as u32;
+                }
+                self.send(&Message::Ack {
+                    session: self.id,
+                    length: self.received,
+                })
+                .await;
+            }
+            Message::Ack { length, .. } => {
+                if length <= self.acked {
+                    return true;
+                }
+                if length > self.sent {
+                    common::debug!("Session {} acknowledged data never sent, closing", self.id);
+                    self.send(&Message::Close { session: self.id }).await;
+                    return false;
+                }
+                // Only the real peer could know how much it got
+                self.sender.guard.verify(self.peer);
+                self.unacked.drain(..(length - self.acked) as usize);
+                self.acked = length;
+                self.last_progress = Instant::now();
+                if self.acked < self.sent {
+                    self.retransmit().await;
+                }
+            }
+            Message::Close { .. } => {
+                self.send(&Message::Close { session: self.id }).await;
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub(crate) async fn run(
+    id: u32,
+    peer: SocketAddr,
+    sender: Sender,
+    mut inbound: mpsc::Receiver<Message>,
+    app: DuplexStream,
+    timer: Timer,
+) {
+    let config = sender.config;
+    let (mut app, mut app_writer) = tokio::io::split(app);
+    let mut session = Session {
+        id,
+        peer,
+        sender,
+        received: 0,
+        pending: Vec::new(),
+        app_reading: true,
+        sent: 0,
+        acked: 0,
+        unacked: Vec::new(),
+        last_progress: Instant::now(),
+    };
+    session
+        .send(&Message::Ack {
+            session: id,
+            length: 0,
+        })
+        .await;
+
+    // Whether the timer is set; it only is while something is unacknowledged
+    let mut timing = false;
+    let mut buf = vec![0u8; READ_CHUNK];
+    // The application stops writing for good once it shuts down its end
+    let mut app_open = true;
+
+    loop {
+        // Stop taking more from the application while too much is in flight
+        let room = config
+            .max_outstanding
+            .saturating_sub(session.unacked.len())
+            .min(READ_CHUNK);
+        tokio::select! {
+            message = inbound.recv() => {
+                let Some(message) = message else { return; };
+                if !session.handle(message).await || session.finished(app_open).await {
+                    return;
+                }
+            }
+            written = app_writer.write(&session.pending), if !session.pending.is_empty() => {
+                match written {
+                    Ok(n) if n > 0 => {
+                        session.pending.drain(..n);
+                    }
+                    _ => {
+                        session.app_reading = false;
+                        session.pending.clear();
+                    }
+                }
+            }
+            read = app.read(&mut buf[..room]), if app_open && room > 0 => {
+                match read {
+                    Ok(0) | Err(_) => {
+                        app_open = false;
+                        if session.finished(app_open).await {
+                            return;
+                        }
+                    }
+                    Ok(n) => {
+                        if !timing {
+                            // Nothing was in flight, so nothing was overdue
+                            session.last_progress = Instant::now();
+                            timer.timers.schedule(timer.key, config.retransmit_interval);
+                            timing = true;
+                        }
+                        session.send_data(session.sent, &buf[..n]).await;
+                        session.unacked.extend_from_slice(&buf[..n]);
+                        session.sent += n as u32;
+                    }
+                }
+            }
+            _ = timer.due.notified(), if timing => {
+                if session.unacked.is_empty() {
+                    timing = false;
+                    continue;
+                }
+                if session.last_progress.elapsed() >= config.session_expiry {
+                    common::debug!("Session {} expired", id);
+                    return;
+                }
+                session.retransmit().await;
+                timer.timers.schedule(timer.key, config.retransmit_interval);
+            }
+        }
+    }
+}
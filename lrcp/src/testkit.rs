@@ -0,0 +1,115 @@
+// An LRCP client for tests.
+//
+// Problems served over LRCP are tested like their TCP counterparts, by talking
+// to them, which needs a peer that does LRCP's side of the work: connect,
+// retransmit until acknowledged and acknowledge what comes in. `Peer` does
+// that with a task of its own, leaving the test to send and read lines.
+use crate::message::{data_chunk_len, Message, MAX_MESSAGE_LEN};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+// How often unacknowledged data is sent again
+const TICK: Duration = Duration::from_millis(50);
+
+// An interactive LRCP peer, its session run by a task of its own: what's
+// sent is retransmitted every tick until acknowledged, and what's received
+// is acknowledged as it comes
+pub struct Peer {
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    received: Vec<u8>,
+}
+
+impl Peer {
+    // Connect to `server` as session `session`
+    pub async fn connect(server: SocketAddr, session: u32) -> Peer {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        let (outgoing, mut to_send) = mpsc::unbounded_channel::<Vec<u8>>();
+        let (received, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut connected = false;
+            let mut sent = Vec::new();
+            let mut acked = 0;
+            let mut length = 0;
+            let mut tick = tokio::time::interval(TICK);
+            let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+            loop {
+                let mut from = None;
+                tokio::select! {
+                    n = socket.recv(&mut buf) => match Message::parse(&buf[..n.unwrap()]) {
+                        Some(Message::Ack { length: l, .. }) => {
+                            connected = true;
+                            acked = acked.max(l as usize);
+                        }
+                        Some(Message::Data { pos, data, .. }) => {
+                            if pos as usize == length {
+                                length += data.len();
+                                received.send(data).unwrap_or(());
+                            }
+                            let ack = Message::Ack { session, length: length as u32 };
+                            socket.send(&ack.encode()).await.unwrap();
+                        }
+                        Some(Message::Close { .. }) => return,
+                        _ => {}
+                    },
+                    bytes = to_send.recv() => match bytes {
+                        Some(bytes) => {
+                            from = Some(sent.len());
+                            sent.extend_from_slice(&bytes);
+                        }
+                        None => {
+                            socket.send(&Message::Close { session }.encode()).await.unwrap();
+                            return;
+                        }
+                    },
+                    _ = tick.tick() => {
+                        if !connected {
+                            socket.send(&Message::Connect { session }.encode()).await.unwrap();
+                        }
+                        from = Some(acked);
+                    }
+                }
+                let Some(mut pos) = from.filter(|_| connected) else {
+                    continue;
+                };
+                while pos < sent.len() {
+                    let len = data_chunk_len(session, pos as u32, &sent[pos..]);
+                    let data = sent[pos..pos + len].to_vec();
+                    let message = Message::Data {
+                        session,
+                        pos: pos as u32,
+                        data,
+                    };
+                    socket.send(&message.encode()).await.unwrap();
+                    pos += len;
+                }
+            }
+        });
+        Peer {
+            outgoing,
+            incoming,
+            received: Vec::new(),
+        }
+    }
+
+    // Send `line`, which needn't be a whole line
+    pub fn send(&self, line: &str) {
+        self.outgoing.send(line.as_bytes().to_vec()).unwrap();
+    }
+
+    // The next line received, newline included
+    pub async fn line(&mut self) -> String {
+        loop {
+            if let Some(i) = self.received.iter().position(|&b| b == b'\n') {
+                let line = self.received.drain(..=i).collect();
+                return String::from_utf8(line).unwrap();
+            }
+            let more = tokio::time::timeout(Duration::from_secs(10), self.incoming.recv());
+            let more = more.await.expect("no line came").expect("session closed");
+            self.received.extend_from_slice(&more);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+// Several problems served on one port, for when only one can be exposed.
+//
+// Experimental. Which problem a connection is for is guessed from the first
+// bytes it sends: a JSON value is a primality request (problem 1), an 'I' or
+// 'Q' starts a price message (problem 2), and anything else, or nothing
+// within SNIFF_TIMEOUT_MILLIS, is someone waiting for the chat room's
+// greeting (problem 3). A chat client that speaks before being greeted with a
+// name starting with 'I' or 'Q' is taken for problem 2.
+//
+// With the `tls` feature and TLS=true, the port speaks TLS instead, and a
+// client names the problem it wants through ALPN: "prime", "means" or
+// "chat". Clients that don't are sniffed as above, inside the encryption.
+use common::accept::AcceptLimits;
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::metrics::{Counter, Scope};
+use protolib::Problem;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{self, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_SNIFF_TIMEOUT_MILLIS: u64 = 500;
+
+// Bytes looked at to tell the problems apart, enough for a price message
+const SNIFF_LEN: usize = 9;
+
+#[derive(Clone)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    // How long to wait for a client to speak before taking it for a chat user
+    pub sniff_timeout: Duration,
+    pub prime: problem1::Config,
+    pub means: problem2::Config,
+    pub chat: problem3::Config,
+    #[cfg(feature = "tls")]
+    pub tls: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env(),
+            sniff_timeout: Duration::from_millis(common::env::var_or(
+                "SNIFF_TIMEOUT_MILLIS",
+                DEFAULT_SNIFF_TIMEOUT_MILLIS,
+            )),
+            prime: Problem::from_env(),
+            means: Problem::from_env(),
+            chat: Problem::from_env(),
+            #[cfg(feature = "tls")]
+            tls: common::tls::enabled(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Route {
+    Prime,
+    Means,
+    Chat,
+}
+
+// ALPN protocol names, in order of preference
+#[cfg(feature = "tls")]
+const PROTOCOLS: [(&str, Route); 3] = [
+    ("prime", Route::Prime),
+    ("means", Route::Means),
+    ("chat", Route::Chat),
+];
+
+fn route(first: &[u8]) -> Route {
+    match first.first() {
+        Some(b'I' | b'Q') => Route::Means,
+        _ => match first.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{' | b'[') => Route::Prime,
+            _ => Route::Chat,
+        },
+    }
+}
+
+// A stream that gives back the bytes already read from it before reading on
+struct Replay<S> {
+    first: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.first.len() {
+            let n = buf.remaining().min(self.first.len() - self.pos);
+            buf.put_slice(&self.first[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Clone)]
+struct Sniffer<P, M, C> {
+    prime: P,
+    means: M,
+    chat: C,
+    timeout: Duration,
+    routed: [Counter; 3],
+}
+
+impl<P, M, C> Sniffer<P, M, C>
+where
+    P: ConnectionHandler,
+    M: ConnectionHandler,
+    C: ConnectionHandler,
+{
+    async fn serve<S: ByteStream>(
+        &self,
+        route: Route,
+        socket: S,
+        peer: Option<SocketAddr>,
+        ctx: Context,
+    ) {
+        self.routed[route as usize].inc();
+        match route {
+            Route::Prime => self.prime.handle(socket, peer, ctx).await,
+            Route::Means => self.means.handle(socket, peer, ctx).await,
+            Route::Chat => self.chat.handle(socket, peer, ctx).await,
+        }
+    }
+}
+
+impl<P, M, C> ConnectionHandler for Sniffer<P, M, C>
+where
+    P: ConnectionHandler,
+    M: ConnectionHandler,
+    C: ConnectionHandler,
+{
+    async fn handle<S: ByteStream>(&self, mut socket: S, peer: Option<SocketAddr>, ctx: Context) {
+        ctx.task.phase("sniffing");
+        let mut first = vec![0; SNIFF_LEN];
+        let read = tokio::select! {
+            read = tokio::time::timeout(self.timeout, socket.read(&mut first)) => read,
+            _ = ctx.cancel.cancelled() => return,
+        };
+        let n = match read {
+            Ok(Ok(0)) => return,
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => {
+                common::debug!("Error reading from {:?}: {:?}", peer, e);
+                return;
+            }
+            // Silent clients are waiting for the chat greeting
+            Err(_) => 0,
+        };
+        first.truncate(n);
+        let route = route(&first);
+        let socket = Replay {
+            first,
+            pos: 0,
+            inner: socket,
+        };
+        self.serve(route, socket, peer, ctx).await
+    }
+}
+
+// Routes by the protocol picked in the TLS handshake
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+struct Tls<P, M, C> {
+    acceptor: common::tls::TlsAcceptor,
+    sniffer: Sniffer<P, M, C>,
+}
+
+#[cfg(feature = "tls")]
+impl<P, M, C> ConnectionHandler for Tls<P, M, C>
+where
+    P: ConnectionHandler,
+    M: ConnectionHandler,
+    C: ConnectionHandler,
+{
+    async fn handle<S: ByteStream>(&self, socket: S, peer: Option<SocketAddr>, ctx: Context) {
+        ctx.task.phase("TLS handshake");
+        let accepted = tokio::select! {
+            accepted = common::tls::accept(&self.acceptor, socket) => accepted,
+            _ = ctx.cancel.cancelled() => return,
+        };
+        let stream = match accepted {
+            Ok(stream) => stream,
+            Err(e) => {
+                common::debug!("TLS handshake with {:?} failed: {}", peer, e);
+                return;
+            }
+        };
+        let ctx = Context {
+            transport: "tls",
+            ..ctx
+        };
+        let route = common::tls::protocol(&stream)
+            .and_then(|name| PROTOCOLS.iter().find(|(p, _)| *p == name))
+            .map(|(_, route)| *route);
+        match route {
+            Some(route) => self.sniffer.serve(route, stream, peer, ctx).await,
+            None => self.sniffer.handle(stream, peer, ctx).await,
+        }
+    }
+}
+
+// Serve problems 1 to 3 on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let port = listener.local_addr().unwrap().port();
+    let scope = Scope::new("multiplex", port);
+    let (prime, means, chat) = (
+        problem1::Config::name(),
+        problem2::Config::name(),
+        problem3::Config::name(),
+    );
+    let routed = |problem| {
+        scope.counter_with(
+            "multiplex_connections_total",
+            "Connections by the problem they were routed to",
+            &[("routed_to", problem)],
+        )
+    };
+    let sniffer = Sniffer {
+        prime: problem1::handler(&Scope::new(prime, port), &config.prime),
+        means: problem2::handler(&Scope::new(means, port), &config.means),
+        chat: problem3::handler(&Scope::new(chat, port), &config.chat),
+        timeout: config.sniff_timeout,
+        routed: [routed(prime), routed(means), routed(chat)],
+    };
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "tls")]
+    if config.tls {
+        let protocols = PROTOCOLS.map(|(name, _)| name);
+        let acceptor = common::report::startup("set up TLS", common::tls::acceptor(&protocols));
+        let tls = Tls { acceptor, sniffer };
+        common::accept::run_acceptor(listener, &scope, admission, shutdown, tls).await;
+        return;
+    }
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, sniffer).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    #[test]
+    fn routes_by_the_first_bytes() {
+        assert_eq!(route(br#"{"method":"#), Route::Prime);
+        assert_eq!(route(b"  \n[1,2]"), Route::Prime);
+        assert_eq!(route(b"I\0\0\0\x01\0\0\0\x02"), Route::Means);
+        assert_eq!(route(b"Q\0\0\0\x01\0\0\0\x02"), Route::Means);
+        assert_eq!(route(b"alice\n"), Route::Chat);
+        // A chat user named after a price message can't be told apart
+        assert_eq!(route(b"Ingrid\n"), Route::Means);
+        assert_eq!(route(b""), Route::Chat);
+    }
+
+    // Says which problem it was picked for, then echoes what it reads
+    #[derive(Clone)]
+    struct Tag(&'static str);
+
+    impl ConnectionHandler for Tag {
+        async fn handle<S: ByteStream>(&self, socket: S, _: Option<SocketAddr>, _: Context) {
+            let (mut rd, mut wr) = tokio::io::split(socket);
+            wr.write_all(self.0.as_bytes()).await.unwrap();
+            tokio::io::copy(&mut rd, &mut wr).await.unwrap_or(0);
+        }
+    }
+
+    fn sniffer(port: u16) -> Sniffer<Tag, Tag, Tag> {
+        let scope = Scope::new("multiplex", port);
+        let routed = |problem| scope.counter_with("routed", "Routed", &[("routed_to", problem)]);
+        Sniffer {
+            prime: Tag("prime:"),
+            means: Tag("means:"),
+            chat: Tag("chat:"),
+            timeout: Duration::from_millis(DEFAULT_SNIFF_TIMEOUT_MILLIS),
+            routed: [routed("prime"), routed("means"), routed("chat")],
+        }
+    }
+
+    // What the handler `sniffer` picks for a client sending `sent`, and then
+    // closing, answers
+    async fn answer(sniffer: Sniffer<Tag, Tag, Tag>, sent: &[u8]) -> String {
+        let (mut client, server) = duplex(1024);
+        tokio::spawn(async move { sniffer.handle(server, None, Context::new("test")).await });
+        client.write_all(sent).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).await.unwrap();
+        answer
+    }
+
+    #[tokio::test]
+    async fn hands_each_problem_the_whole_stream() {
+        let request = "{\"method\":\"isPrime\",\"number\":7}\n";
+        assert_eq!(
+            answer(sniffer(1), request.as_bytes()).await,
+            format!("prime:{}", request)
+        );
+        assert_eq!(
+            answer(sniffer(1), b"Q\0\0\0\x01\0\0\0\x02").await,
+            "means:Q\0\0\0\x01\0\0\0\x02"
+        );
+        assert_eq!(answer(sniffer(1), b"bob\n").await, "chat:bob\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_silent_client_is_taken_for_a_chat_user() {
+        let (mut client, server) = duplex(1024);
+        let sniffer = sniffer(2);
+        tokio::spawn(async move { sniffer.handle(server, None, Context::new("test")).await });
+        let started = tokio::time::Instant::now();
+        let mut greeting = [0; 5];
+        client.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"chat:");
+        assert!(started.elapsed() >= Duration::from_millis(DEFAULT_SNIFF_TIMEOUT_MILLIS));
+
+        // Names starting with 'I' or 'Q' are fine once greeted
+        client.write_all(b"Ingrid\n").await.unwrap();
+        let mut echoed = [0; 7];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"Ingrid\n");
+    }
+
+    #[tokio::test]
+    async fn replay_reads_the_sniffed_bytes_then_the_rest() {
+        let (mut client, server) = duplex(1024);
+        let mut replay = Replay {
+            first: b"abc".to_vec(),
+            pos: 0,
+            inner: server,
+        };
+        client.write_all(b"def").await.unwrap();
+        drop(client);
+
+        // Short reads pick up where the last one left off
+        let mut start = [0; 2];
+        replay.read_exact(&mut start).await.unwrap();
+        assert_eq!(&start, b"ab");
+        let mut rest = Vec::new();
+        replay.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"cdef");
+    }
+}
@@ -0,0 +1,51 @@
+use clap::Parser;
+use multiplex::Config;
+use protolib::Problem;
+use std::net::SocketAddr;
+
+fn check_config(port: u16) {
+    let checker = common::config::Checker::new(port).positive("SNIFF_TIMEOUT_MILLIS");
+    let checker = <problem1::Config as Problem>::check_config(checker);
+    let checker = <problem2::Config as Problem>::check_config(checker);
+    <problem3::Config as Problem>::check_config(checker)
+        .parse::<bool>("TLS")
+        .file("TLS_CERT")
+        .file("TLS_KEY")
+        .finish();
+}
+
+#[derive(Parser)]
+#[command(
+    about = "Problems 1 to 3 served on one port",
+    after_help = common::cli::AFTER_HELP,
+    args_override_self = true
+)]
+struct Cli {
+    #[command(flatten)]
+    args: common::cli::Args,
+}
+
+fn main() {
+    common::cli::init(Cli::parse().args);
+    let addrs = common::cli::listen_addrs(39456);
+    check_config(addrs[0].port());
+    common::dry_run::bind_listeners(&addrs);
+    // Landlock only covers threads started after it, so it goes before the runtime
+    #[cfg(feature = "sandbox")]
+    common::sandbox::restrict_paths_from_env();
+    run(&addrs);
+}
+
+#[tokio::main]
+async fn run(addrs: &[SocketAddr]) {
+    #[cfg(feature = "console")]
+    common::console::init();
+    let listener = common::report::startup("bind listener", common::listeners::bind(addrs).await);
+    common::alloc::spawn_stats_reporter();
+    common::metrics::spawn_endpoint_from_env();
+    #[cfg(feature = "pprof")]
+    common::profile::spawn_endpoint_from_env();
+    #[cfg(feature = "sandbox")]
+    common::sandbox::restrict_syscalls_from_env();
+    multiplex::run(listener, common::shutdown::on_signal(), Config::from_env()).await;
+}
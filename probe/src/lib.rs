@@ -0,0 +1,196 @@
+// Minimal valid exchanges with each kind of server, to tell whether it's up.
+//
+// Each probe connects, does the smallest thing a working server must answer
+// correctly, and hangs up:
+//   echo   sends a random byte and expects it back
+//   prime  asks whether 7 is prime
+//   means  inserts one price and queries a range holding only it
+//   chat   joins the room under a random "probe" name and leaves again, which
+//          the room's users see
+// A multiplexer can be probed as prime, means and chat at the same address.
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Echo,
+    Prime,
+    Means,
+    Chat,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "echo" => Ok(Self::Echo),
+            "prime" => Ok(Self::Prime),
+            "means" => Ok(Self::Means),
+            "chat" => Ok(Self::Chat),
+            _ => Err(format!("unknown probe kind {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Echo => write!(f, "echo"),
+            Self::Prime => write!(f, "prime"),
+            Self::Means => write!(f, "means"),
+            Self::Chat => write!(f, "chat"),
+        }
+    }
+}
+
+// A server to probe, written kind=host:port
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub kind: Kind,
+    pub addr: String,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, addr) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected kind=host:port, got {:?}", s))?;
+        Ok(Target {
+            kind: kind.trim().parse()?,
+            addr: addr.trim().to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.kind, self.addr)
+    }
+}
+
+// Comma-separated targets, as PROBE_TARGETS is written
+#[derive(Clone, Debug)]
+pub struct Targets(pub Vec<Target>);
+
+impl FromStr for Targets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|t| !t.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Targets)
+    }
+}
+
+impl fmt::Display for Targets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let targets: Vec<String> = self.0.iter().map(Target::to_string).collect();
+        write!(f, "{}", targets.join(","))
+    }
+}
+
+type ProbeResult = Result<(), String>;
+
+async fn echo(stream: TcpStream) -> ProbeResult {
+    let (mut rd, mut wr) = stream.into_split();
+    let byte = rand::thread_rng().gen::<u8>();
+    wr.write_all(&[byte]).await.map_err(|e| e.to_string())?;
+    let mut back = [0u8];
+    rd.read_exact(&mut back).await.map_err(|e| e.to_string())?;
+    if back[0] != byte {
+        return Err(format!("sent byte {}, got {} back", byte, back[0]));
+    }
+    Ok(())
+}
+
+async fn prime(stream: TcpStream) -> ProbeResult {
+    let (rd, mut wr) = stream.into_split();
+    wr.write_all(b"{\"method\":\"isPrime\",\"number\":7}\n")
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    BufReader::new(rd)
+        .read_line(&mut line)
+        .await
+        .map_err(|e| e.to_string())?;
+    let response: serde_json::Value =
+        serde_json::from_str(&line).map_err(|e| format!("bad response {:?}: {}", line, e))?;
+    if response["method"] != "isPrime" || response["prime"] != true {
+        return Err(format!("7 isn't prime according to {:?}", line.trim_end()));
+    }
+    Ok(())
+}
+
+fn message(msg_type: u8, first: i32, second: i32) -> [u8; 9] {
+    let mut msg = [0u8; 9];
+    msg[0] = msg_type;
+    msg[1..5].copy_from_slice(&first.to_be_bytes());
+    msg[5..9].copy_from_slice(&second.to_be_bytes());
+    msg
+}
+
+async fn means(stream: TcpStream) -> ProbeResult {
+    let (mut rd, mut wr) = stream.into_split();
+    let price = rand::thread_rng().gen_range(1..1000);
+    let mut requests = message(b'I', 1000, price).to_vec();
+    requests.extend_from_slice(&message(b'Q', 0, 2000));
+    wr.write_all(&requests).await.map_err(|e| e.to_string())?;
+    let mut mean = [0u8; 4];
+    rd.read_exact(&mut mean).await.map_err(|e| e.to_string())?;
+    let mean = i32::from_be_bytes(mean);
+    if mean != price {
+        return Err(format!("inserted price {}, mean came back {}", price, mean));
+    }
+    Ok(())
+}
+
+async fn chat(stream: TcpStream) -> ProbeResult {
+    let (rd, mut wr) = stream.into_split();
+    let mut rd = BufReader::new(rd);
+    let mut greeting = String::new();
+    rd.read_line(&mut greeting)
+        .await
+        .map_err(|e| e.to_string())?;
+    if greeting.is_empty() {
+        return Err("closed before greeting".to_owned());
+    }
+    let name = format!("probe{}", rand::thread_rng().gen_range(0..1_000_000));
+    wr.write_all(format!("{}\n", name).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut listing = String::new();
+    rd.read_line(&mut listing)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !listing.starts_with('*') {
+        return Err(format!("joined as {}, got {:?}", name, listing.trim_end()));
+    }
+    Ok(())
+}
+
+// Exercise `target` once, giving up after `timeout`
+pub async fn probe(target: &Target, timeout: Duration) -> ProbeResult {
+    let exchange = async {
+        let stream = common::resolve::connect(&target.addr)
+            .await
+            .map_err(|e| format!("couldn't connect: {}", e))?;
+        match target.kind {
+            Kind::Echo => echo(stream).await,
+            Kind::Prime => prime(stream).await,
+            Kind::Means => means(stream).await,
+            Kind::Chat => chat(stream).await,
+        }
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| format!("no answer within {:?}", timeout))?
+}
@@ -0,0 +1,146 @@
+// Watchdog for running servers.
+//
+// Probes every target in PROBE_TARGETS (kind=host:port, comma-separated, see
+// lib.rs for the kinds) each PROBE_INTERVAL_SECS (default 10), giving each
+// PROBE_TIMEOUT_MILLIS (default 2000) to answer, and logs failures. When a
+// target has failed PROBE_FAILURES times in a row (default 3), the failure is
+// reported like any other (ERROR_WEBHOOK_URL, ALERT_COMMAND), and
+// PROBE_RESTART_COMMAND, if set, is run with `sh -c` to restart it, with
+// PROBE_KIND, PROBE_ADDR and PROBE_ERROR in its environment. The count then
+// starts over.
+//
+// With --once, every target is probed a single time and the exit status says
+// whether all of them answered, for use as a liveness check.
+use clap::Parser;
+use probe::{Target, Targets};
+use std::process::Command;
+use std::time::Duration;
+
+const DEFAULT_PROBE_INTERVAL_SECS: u64 = 10;
+const DEFAULT_PROBE_TIMEOUT_MILLIS: u64 = 2000;
+const DEFAULT_PROBE_FAILURES: u32 = 3;
+
+#[derive(Parser)]
+#[command(
+    about = "Watchdog probing running servers",
+    after_help = common::cli::AFTER_HELP,
+    args_override_self = true
+)]
+struct Cli {
+    /// Probe every target once, exiting 0 if all of them answered
+    #[arg(long)]
+    once: bool,
+    #[command(flatten)]
+    config: common::cli::ConfigArgs,
+}
+
+fn check_config() {
+    common::config::Checker::bare()
+        .parse::<Targets>("PROBE_TARGETS")
+        .positive("PROBE_INTERVAL_SECS")
+        .positive("PROBE_TIMEOUT_MILLIS")
+        .positive("PROBE_FAILURES")
+        .finish();
+}
+
+fn restart(command: &str, target: &Target, error: &str) {
+    println!("Restarting {} with {:?}", target, command);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("PROBE_KIND", target.kind.to_string())
+        .env("PROBE_ADDR", &target.addr)
+        .env("PROBE_ERROR", error)
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Restart command for {} failed: {}", target, status),
+        Err(e) => eprintln!("Couldn't run restart command for {}: {}", target, e),
+    }
+}
+
+// Probe `target` forever, acting on every `failures` failures in a row
+async fn watch(target: Target, interval: Duration, timeout: Duration, failures: u32) {
+    let restart_command = common::env::var::<String>("PROBE_RESTART_COMMAND");
+    let mut ticks = tokio::time::interval(interval);
+    let mut failed = 0;
+    loop {
+        ticks.tick().await;
+        let error = match probe::probe(&target, timeout).await {
+            Ok(()) => {
+                if failed > 0 {
+                    println!("{} is answering again", target);
+                }
+                failed = 0;
+                continue;
+            }
+            Err(e) => e,
+        };
+        failed += 1;
+        println!(
+            "Probe of {} failed ({} in a row): {}",
+            target, failed, error
+        );
+        if failed < failures {
+            continue;
+        }
+        failed = 0;
+        common::report::report(
+            "probe",
+            &error,
+            &[
+                ("kind", target.kind.to_string()),
+                ("addr", target.addr.clone()),
+            ],
+        );
+        if let Some(command) = restart_command.clone() {
+            let (target, error) = (target.clone(), error.clone());
+            tokio::task::spawn_blocking(move || restart(&command, &target, &error))
+                .await
+                .unwrap_or(());
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    common::cli::init_config(cli.config);
+    check_config();
+    let Some(Targets(targets)) = common::env::var::<Targets>("PROBE_TARGETS") else {
+        eprintln!("PROBE_TARGETS isn't set, nothing to probe");
+        std::process::exit(1);
+    };
+    let interval = Duration::from_secs(common::env::var_or(
+        "PROBE_INTERVAL_SECS",
+        DEFAULT_PROBE_INTERVAL_SECS,
+    ));
+    let timeout = Duration::from_millis(common::env::var_or(
+        "PROBE_TIMEOUT_MILLIS",
+        DEFAULT_PROBE_TIMEOUT_MILLIS,
+    ));
+    let failures = common::env::var_or("PROBE_FAILURES", DEFAULT_PROBE_FAILURES);
+    common::env::print_config();
+
+    if cli.once {
+        let mut healthy = true;
+        for target in &targets {
+            match probe::probe(target, timeout).await {
+                Ok(()) => println!("{} OK", target),
+                Err(e) => {
+                    println!("{} FAILED: {}", target, e);
+                    healthy = false;
+                }
+            }
+        }
+        std::process::exit(if healthy { 0 } else { 1 });
+    }
+
+    let watchers: Vec<_> = targets
+        .into_iter()
+        .map(|target| tokio::spawn(watch(target, interval, timeout, failures)))
+        .collect();
+    for watcher in watchers {
+        watcher.await.unwrap_or(());
+    }
+}
@@ -0,0 +1,64 @@
+// Throughput benchmark for the echo server. Start the server (with or without
+// the `uring` feature) and run:
+//
+//     cargo run --release --example echo_bench -- [addr] [clients] [megabytes per client]
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn client(addr: String, bytes: usize) {
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (mut rd, mut wr) = socket.into_split();
+
+    let writer = tokio::spawn(async move {
+        let chunk = [0x5au8; 16 * 1024];
+        let mut sent = 0;
+        while sent < bytes {
+            let n = chunk.len().min(bytes - sent);
+            wr.write_all(&chunk[..n]).await.unwrap();
+            sent += n;
+        }
+        wr.shutdown().await.unwrap();
+    });
+
+    let mut buf = [0u8; 16 * 1024];
+    let mut received = 0;
+    while received < bytes {
+        match rd.read(&mut buf).await.unwrap() {
+            0 => break,
+            n => received += n,
+        }
+    }
+    writer.await.unwrap();
+    assert_eq!(
+        received, bytes,
+        "echo server returned fewer bytes than sent"
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:39456".to_owned());
+    let clients: usize = args.next().map(|a| a.parse().unwrap()).unwrap_or(5);
+    let megabytes: usize = args.next().map(|a| a.parse().unwrap()).unwrap_or(64);
+    let bytes = megabytes * 1024 * 1024;
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| tokio::spawn(client(addr.clone(), bytes)))
+        .collect();
+    for t in tasks {
+        t.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    let total = (clients * bytes) as f64 / (1024. * 1024.);
+    println!(
+        "{} clients echoed {:.0} MiB in {:.2?} ({:.1} MiB/s)",
+        clients,
+        total,
+        elapsed,
+        total / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,153 @@
+use common::accept::{AcceptLimits, ReapPolicy};
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::metrics::{Counter, Scope};
+use stats::Stats;
+use std::net::SocketAddr;
+use tee::Tee;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+mod serve;
+mod stats;
+mod tee;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
+
+pub use serve::serve;
+pub use tee::TeeTarget;
+
+async fn socket_echo<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    echoed: Counter,
+    stats: Option<Stats>,
+    ctx: Context,
+) {
+    let mut buf: [u8; 1024] = [0; 1024];
+    // Only used with in-band statistics
+    let mut pending = Vec::new();
+    let mut out = Vec::new();
+
+    loop {
+        ctx.task.phase("reading");
+        let read = tokio::select! {
+            read = socket.read(&mut buf) => read,
+            _ = ctx.cancel.cancelled() => return,
+        };
+        let n_read = match read {
+            Ok(0) => {
+                common::debug!("read returned zero: assuming the session is finished");
+                // A partial marker at the very end is just data
+                socket.write_all(&pending).await.unwrap_or(());
+                return;
+            }
+            Ok(n) => {
+                common::debug!("Read {:?} bytes: {:?}", n, &buf[0..n]);
+                n
+            }
+            Err(e) => {
+                common::debug!("Error reading socket: {:?}", e);
+                return;
+            }
+        };
+
+        let data = match &stats {
+            Some(stats) => {
+                pending.extend_from_slice(&buf[0..n_read]);
+                out.clear();
+                stats.process(&mut pending, &mut out);
+                &out[..]
+            }
+            None => &buf[0..n_read],
+        };
+        ctx.task.phase("writing");
+        let written = tokio::select! {
+            written = socket.write_all(data) => written,
+            _ = ctx.cancel.cancelled() => return,
+        };
+        if let Err(e) = written {
+            common::debug!("Couldn't write to socket: {:?}", e);
+            return;
+        }
+        echoed.add(n_read as u64);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+    // Where to mirror echoed traffic, and how many chunks may queue for it
+    pub tee: Option<TeeTarget>,
+    pub tee_queue_len: usize,
+    // Answer \0STATS\0 markers in the stream with a report
+    pub stats: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            // Echo clients often sit idle, so make room instead of refusing
+            limits: AcceptLimits::from_env_reaping(ReapPolicy::LongestIdle),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+            tee: common::env::var("TEE"),
+            tee_queue_len: common::env::var_or("TEE_QUEUE_LEN", tee::DEFAULT_TEE_QUEUE_LEN),
+            stats: common::env::var_or("ECHO_STATS", false),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Echo {
+    echoed: Counter,
+    tee: Option<Tee>,
+    stats: Option<Stats>,
+}
+
+impl ConnectionHandler for Echo {
+    async fn handle<S: ByteStream>(&self, socket: S, _peer: Option<SocketAddr>, ctx: Context) {
+        let (echoed, stats) = (self.echoed.clone(), self.stats.clone());
+        match &self.tee {
+            Some(tee) => socket_echo(tee.wrap(socket), echoed, stats, ctx).await,
+            None => socket_echo(socket, echoed, stats, ctx).await,
+        }
+    }
+}
+
+// Echo on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem0", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let echoed = scope.counter("echo_bytes_total", "Bytes echoed back to clients");
+    let echo = Echo {
+        echoed: echoed.clone(),
+        tee: config
+            .tee
+            .map(|target| Tee::new(&scope, target, config.tee_queue_len)),
+        stats: config.stats.then(|| Stats::new(&scope, echoed.clone())),
+    };
+
+    #[cfg(feature = "middleware")]
+    let echo = config.middleware.wrap(echo, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(echo.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "websocket")]
+    common::websocket::spawn_from_env(
+        common::websocket::FrameMode::Raw,
+        echo.clone(),
+        admission.clone(),
+        shutdown.clone(),
+    );
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(echo.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "isl")]
+    isl::spawn_from_env(echo.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, echo).await;
+}
@@ -1,50 +1,17 @@
-use tokio::io;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
+use clap::Parser;
 
-async fn socket_echo(mut socket: TcpStream) {
-    let mut buf: [u8; 1024] = [0; 1024];
-
-    loop {
-        socket.readable().await.unwrap_or(());
-        let n_read;
-        match socket.try_read(&mut buf) {
-            Ok(n) if n == 0 => {
-                println!("try_read returned zero: assuming the session is finished");
-                return;
-            }
-            Ok(n) => {
-                n_read = n;
-                println!("Read {:?} bytes: {:?}", n_read, &buf[0..n_read]);
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                println!("try_read would block: keep waiting");
-                continue;
-            }
-            _ => {
-                println!("Unknown error reading socket");
-                return;
-            }
-        };
-
-        if let Err(e) = socket.write_all(&buf[0..n_read]).await {
-            eprintln!("Couldn't write to socket: {:?}", e);
-            return;
-        }
-    }
+#[derive(Parser)]
+#[command(
+    about = "Protohackers problem 0: Smoke Test",
+    after_help = common::cli::AFTER_HELP,
+    args_override_self = true
+)]
+struct Cli {
+    #[command(flatten)]
+    args: common::cli::Args,
 }
 
-#[tokio::main]
-async fn main() {
-    let listener = TcpListener::bind("0.0.0.0:39456").await.unwrap();
-
-    loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                println!("Accepted connection from {:?}", addr);
-                tokio::spawn(socket_echo(socket));
-            }
-            Err(e) => println!("Couldn't accept connection: {:?}", e),
-        }
-    }
+fn main() {
+    common::cli::init(Cli::parse().args);
+    problem0::serve(&common::cli::listen_addrs(39456));
 }
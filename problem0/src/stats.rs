@@ -0,0 +1,54 @@
+// In-band statistics, enabled with ECHO_STATS=true.
+//
+// Whenever the bytes `\0STATS\0` show up in the stream they are replaced by a
+// one-line report (bytes echoed, uptime, active sessions) and echoing carries
+// on, which is handy when problem0 is used as a probe target. A trailing
+// partial marker is held back until the next read tells whether it completes.
+use common::metrics::{Counter, Gauge, Scope};
+use std::time::Instant;
+
+const MARKER: &[u8] = b"\0STATS\0";
+
+#[derive(Clone)]
+pub struct Stats {
+    started: Instant,
+    echoed: Counter,
+    active: Gauge,
+}
+
+impl Stats {
+    pub fn new(scope: &Scope, echoed: Counter) -> Self {
+        Stats {
+            started: Instant::now(),
+            echoed,
+            active: scope.gauge("connections_active", "Connections being served"),
+        }
+    }
+
+    fn report(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(
+            format!(
+                "STATS bytes_echoed={} uptime_secs={} active_sessions={}\n",
+                self.echoed.get(),
+                self.started.elapsed().as_secs(),
+                self.active.get()
+            )
+            .as_bytes(),
+        );
+    }
+
+    // Move everything that can be answered from `pending` to `out`, replacing
+    // markers with reports
+    pub fn process(&self, pending: &mut Vec<u8>, out: &mut Vec<u8>) {
+        while let Some(i) = pending.windows(MARKER.len()).position(|w| w == MARKER) {
+            out.extend_from_slice(&pending[..i]);
+            self.report(out);
+            pending.drain(..i + MARKER.len());
+        }
+        let keep = (1..MARKER.len())
+            .rev()
+            .find(|&k| pending.ends_with(&MARKER[..k]))
+            .unwrap_or(0);
+        out.extend(pending.drain(..pending.len() - keep));
+    }
+}
@@ -0,0 +1,176 @@
+// Traffic tee: mirror everything echoed to a secondary sink.
+//
+// TEE=file:<path> appends to a file, TEE=tcp:<host:port> streams to a TCP
+// listener (reconnecting with backoff if it goes away, and looking the host up
+// again through common::resolve each time). Output from all connections is
+// interleaved as it is echoed. The sink is fed through a bounded queue of
+// TEE_QUEUE_LEN chunks; when it can't keep up, chunks are dropped and counted
+// rather than slowing down the echo itself.
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+
+use common::metrics::{Counter, Scope};
+use common::retry::{retry, Backoff};
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_TEE_QUEUE_LEN: usize = 1024;
+// Reconnection delays start here and back off to at most a minute
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub enum TeeTarget {
+    File(PathBuf),
+    Tcp(String),
+}
+
+impl FromStr for TeeTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", path)) => Ok(Self::File(path.into())),
+            Some(("tcp", addr)) => Ok(Self::Tcp(addr.to_owned())),
+            _ => Err(format!(
+                "expected file:<path> or tcp:<host:port>, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Tee {
+    tx: mpsc::Sender<Vec<u8>>,
+    dropped: Counter,
+}
+
+impl Tee {
+    pub fn new(scope: &Scope, target: TeeTarget, queue_len: usize) -> Self {
+        let (tx, rx) = mpsc::channel(queue_len.max(1));
+        let written = scope.counter("tee_bytes_total", "Bytes mirrored to the tee sink");
+        match &target {
+            TeeTarget::File(path) => common::dry_run::writable("TEE", path),
+            TeeTarget::Tcp(addr) => common::dry_run::reachable("TEE", addr),
+        }
+        common::info!("Mirroring echoed traffic to {:?}", target);
+        tokio::spawn(run_sink(target, rx, written));
+        Tee {
+            tx,
+            dropped: scope.counter(
+                "tee_dropped_bytes_total",
+                "Bytes not mirrored because the tee sink fell behind",
+            ),
+        }
+    }
+
+    pub fn wrap<S>(&self, inner: S) -> TeeStream<S> {
+        TeeStream {
+            inner,
+            tee: self.clone(),
+        }
+    }
+
+    fn mirror(&self, data: &[u8]) {
+        if self.tx.try_send(data.to_vec()).is_err() {
+            self.dropped.add(data.len() as u64);
+        }
+    }
+}
+
+async fn write_file(path: &PathBuf, rx: &mut mpsc::Receiver<Vec<u8>>, written: &Counter) {
+    let mut file = match tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+    {
+        Ok(f) => f,
+        Err(e) => {
+            common::error!("Couldn't open tee file {:?}: {}", path, e);
+            return;
+        }
+    };
+    while let Some(chunk) = rx.recv().await {
+        if let Err(e) = file.write_all(&chunk).await {
+            common::error!("Couldn't write to tee file {:?}: {}", path, e);
+            return;
+        }
+        written.add(chunk.len() as u64);
+    }
+}
+
+async fn write_tcp(addr: &str, rx: &mut mpsc::Receiver<Vec<u8>>, written: &Counter) {
+    let backoff = Backoff::new(RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+    // The sink lives as long as the server
+    let cancel = CancellationToken::new();
+    let what = format!("connect to tee sink {}", addr);
+    loop {
+        // Whatever piles up meanwhile is dropped by the bounded queue
+        let connected = retry(&backoff, &what, &cancel, |_| common::resolve::connect(addr)).await;
+        let Ok(mut stream) = connected else {
+            return;
+        };
+        loop {
+            let Some(chunk) = rx.recv().await else {
+                return;
+            };
+            if let Err(e) = stream.write_all(&chunk).await {
+                common::warn!("Lost connection to tee sink {}: {}", addr, e);
+                break;
+            }
+            written.add(chunk.len() as u64);
+        }
+    }
+}
+
+async fn run_sink(target: TeeTarget, mut rx: mpsc::Receiver<Vec<u8>>, written: Counter) {
+    match &target {
+        TeeTarget::File(path) => write_file(path, &mut rx, &written).await,
+        TeeTarget::Tcp(addr) => write_tcp(addr, &mut rx, &written).await,
+    }
+}
+
+// A stream whose writes are also sent to the tee
+pub struct TeeStream<S> {
+    inner: S,
+    tee: Tee,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TeeStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TeeStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.tee.mirror(&buf[..n]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,50 @@
+// io_uring backend for the echo server, enabled with the `uring` feature.
+// tokio-uring runs a single-threaded runtime and owns the buffers while an
+// operation is in flight, so the echo loop passes the same Vec back and forth
+// instead of reading into a borrowed slice.
+use std::net::SocketAddr;
+use tokio_uring::buf::IoBuf;
+use tokio_uring::net::{TcpListener, TcpStream};
+
+async fn socket_echo(socket: TcpStream) {
+    let mut buf = vec![0u8; 1024];
+
+    loop {
+        let (res, read_buf) = socket.read(buf).await;
+        let n_read = match res {
+            Ok(0) => {
+                common::debug!("read returned zero: assuming the session is finished");
+                return;
+            }
+            Ok(n) => n,
+            Err(e) => {
+                common::debug!("Error reading socket: {:?}", e);
+                return;
+            }
+        };
+
+        let (res, slice) = socket.write_all(read_buf.slice(..n_read)).await;
+        if let Err(e) = res {
+            common::debug!("Couldn't write to socket: {:?}", e);
+            return;
+        }
+        buf = slice.into_inner();
+    }
+}
+
+pub fn serve(addr: SocketAddr) {
+    tokio_uring::start(async {
+        let listener = TcpListener::bind(addr).unwrap();
+        common::alloc::spawn_stats_reporter();
+
+        loop {
+            match listener.accept().await {
+                Ok((socket, addr)) => {
+                    common::debug!("Accepted connection from {:?}", addr);
+                    tokio_uring::spawn(socket_echo(socket));
+                }
+                Err(e) => common::warn!("Couldn't accept connection: {:?}", e),
+            }
+        }
+    })
+}
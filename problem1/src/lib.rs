@@ -0,0 +1,399 @@
+mod number;
+mod serve;
+mod slowlog;
+
+use bytes::{Bytes, BytesMut};
+use codecs::BytesLinesCodec;
+use common::accept::AcceptLimits;
+use common::framed::{serve_framed, Flow, FramedHandler};
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::metrics::{Counter, Scope};
+use num_integer::Roots;
+pub use number::Strictness;
+use number::Verdict;
+pub use serve::serve;
+use slowlog::SlowLog;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_util::codec::{BytesCodec, Decoder};
+use tokio_util::sync::CancellationToken;
+
+// Requests longer than this are rejected instead of buffered indefinitely
+const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+fn is_prime(i: u64) -> bool {
+    match i {
+        0 => false,
+        1 => false,
+        _ => (2..=i.sqrt())
+            .into_iter()
+            .all(|x| i.rem_euclid(x) != 0 || i == x),
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    prime: Counter,
+    composite: Counter,
+    malformed: Counter,
+    slow_log: SlowLog,
+}
+
+impl Metrics {
+    fn new(scope: &Scope, config: &Config) -> Self {
+        let requests = |result| {
+            scope.counter_with(
+                "prime_requests_total",
+                "isPrime requests by outcome",
+                &[("result", result)],
+            )
+        };
+        Metrics {
+            prime: requests("prime"),
+            composite: requests("composite"),
+            malformed: requests("malformed"),
+            slow_log: SlowLog::new(scope, config.slow_request, config.slow_log_len),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+    pub max_line_length: usize,
+    // Also accept a JSON array of requests on one line, answered with an
+    // array of responses in the same order. Not part of the protocol, so off
+    // by default.
+    pub batch: bool,
+    // Primality checks slower than this go in the slow log, which keeps the
+    // last `slow_log_len`
+    pub slow_request: Duration,
+    pub slow_log_len: usize,
+    // How far "number" may stray from a plain integer; see number.rs
+    pub strictness: Strictness,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env(),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+            max_line_length: common::env::var_or("MAX_LINE_LENGTH", DEFAULT_MAX_LINE_LENGTH),
+            batch: common::env::var_or("BATCH_REQUESTS", false),
+            slow_request: Duration::from_micros(common::env::var_or(
+                "SLOW_REQUEST_MICROS",
+                slowlog::DEFAULT_SLOW_REQUEST_MICROS,
+            )),
+            slow_log_len: common::env::var_or("SLOW_LOG_LEN", slowlog::DEFAULT_SLOW_LOG_LEN),
+            strictness: common::env::var_or("NUMBER_STRICTNESS", Strictness::Strict),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Options {
+    max_line_length: usize,
+    batch: bool,
+    strictness: Strictness,
+}
+
+// Responses, newline included
+const PRIME: &str = "{\"method\":\"isPrime\",\"prime\":true}\n";
+const COMPOSITE: &str = "{\"method\":\"isPrime\",\"prime\":false}\n";
+
+// The response line to a single request, or the error to send before
+// disconnecting
+fn answer(
+    value: &serde_json::Value,
+    strictness: Strictness,
+    metrics: &Metrics,
+) -> Result<&'static str, &'static str> {
+    let method = value.get("method");
+    let number = value.get("number");
+    if !(value.is_object() && method.is_some() && number.is_some())
+        || method.unwrap_or(&serde_json::Value::Null)
+            != &serde_json::Value::String("isPrime".to_owned())
+    {
+        metrics.malformed.inc();
+        return Err("{\"error\": \"Malformed request (missing or incorrect member in response)\"}");
+    }
+
+    let prime = match number::classify(number.unwrap(), strictness) {
+        Verdict::Check(n) => {
+            common::debug!("Returning response for number: {}", n);
+            metrics.slow_log.time(n, || is_prime(n))
+        }
+        Verdict::NotPrime => false,
+        Verdict::Malformed => {
+            metrics.malformed.inc();
+            return Err("{\"error\": \"Malformed request (no number)\"}");
+        }
+    };
+    if prime {
+        metrics.prime.inc();
+        Ok(PRIME)
+    } else {
+        metrics.composite.inc();
+        Ok(COMPOSITE)
+    }
+}
+
+// Lines parsed as JSON
+struct JsonLines(BytesLinesCodec);
+
+fn json(line: Option<BytesMut>) -> io::Result<Option<serde_json::Value>> {
+    Ok(line.map(|line| serde_json::from_slice(&line)).transpose()?)
+}
+
+impl Decoder for JsonLines {
+    type Item = serde_json::Value;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        json(self.0.decode(buf)?)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        json(self.0.decode_eof(buf)?)
+    }
+}
+
+// One client's requests
+struct Session {
+    options: Options,
+    metrics: Metrics,
+    // Where batch responses are assembled, reusing the allocation once the
+    // last one has been sent
+    batch: BytesMut,
+}
+
+impl Session {
+    // The response to a request or batch of them, or the error to send
+    // before disconnecting
+    fn respond(&mut self, value: serde_json::Value) -> Result<Bytes, &'static str> {
+        let requests = match value {
+            serde_json::Value::Array(requests) if self.options.batch => requests,
+            value => {
+                let response = answer(&value, self.options.strictness, &self.metrics)?;
+                return Ok(Bytes::from_static(response.as_bytes()));
+            }
+        };
+        self.batch.clear();
+        self.batch.extend_from_slice(b"[");
+        for (i, request) in requests.iter().enumerate() {
+            // A single malformed request fails the whole batch
+            let response = answer(request, self.options.strictness, &self.metrics)?;
+            if i > 0 {
+                self.batch.extend_from_slice(b",");
+            }
+            self.batch.extend_from_slice(response.trim_end().as_bytes());
+        }
+        self.batch.extend_from_slice(b"]\n");
+        Ok(self.batch.split().freeze())
+    }
+}
+
+impl FramedHandler<JsonLines> for Session {
+    type Response = Bytes;
+
+    async fn request(&mut self, value: serde_json::Value, out: &mut Vec<Bytes>) -> Flow {
+        common::debug!("Starting service iteration for value: {:?}", value);
+        match self.respond(value) {
+            Ok(response) => {
+                out.push(response);
+                Flow::Continue
+            }
+            Err(error) => {
+                out.push(Bytes::from_static(error.as_bytes()));
+                Flow::Close
+            }
+        }
+    }
+
+    async fn malformed(
+        &mut self,
+        error: io::Error,
+        _decoder: &mut JsonLines,
+        _unread: &[u8],
+        out: &mut Vec<Bytes>,
+    ) {
+        common::debug!("Error parsing value: {:?}", error);
+        self.metrics.malformed.inc();
+        out.push(Bytes::from_static(
+            b"{\"error\": \"Malformed request (error parsing value)\"}",
+        ));
+    }
+}
+
+#[derive(Clone)]
+struct Primes {
+    options: Options,
+    metrics: Metrics,
+}
+
+impl ConnectionHandler for Primes {
+    async fn handle<S: ByteStream>(&self, socket: S, _peer: Option<SocketAddr>, ctx: Context) {
+        let session = Session {
+            options: self.options,
+            metrics: self.metrics.clone(),
+            batch: BytesMut::new(),
+        };
+        let decoder = JsonLines(BytesLinesCodec::new(self.options.max_line_length));
+        serve_framed(socket, decoder, BytesCodec::new(), session, &ctx).await
+    }
+}
+
+// The handler `run` serves, for a server that accepts connections itself
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    let metrics = Metrics::new(scope, config);
+    common::report::watch_decode_errors(scope, "malformed requests", metrics.malformed.clone());
+    Primes {
+        options: Options {
+            max_line_length: config.max_line_length,
+            batch: config.batch,
+            strictness: config.strictness,
+        },
+        metrics,
+    }
+}
+
+// Answer primality requests on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem1", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let primes = handler(&scope, &config);
+
+    #[cfg(feature = "middleware")]
+    let primes = config.middleware.wrap(primes, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(primes.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "websocket")]
+    common::websocket::spawn_from_env(
+        common::websocket::FrameMode::Lines,
+        primes.clone(),
+        admission.clone(),
+        shutdown.clone(),
+    );
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(primes.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "isl")]
+    isl::spawn_from_env(primes.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, primes).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codecs::testkit;
+    use serde_json::json;
+
+    fn codec() -> JsonLines {
+        JsonLines(BytesLinesCodec::new(64))
+    }
+
+    #[test]
+    fn requests_decode_however_they_arrive() {
+        let input = b"{\"method\":\"isPrime\",\"number\":7}\r\n[1, 2.5]\n{}";
+        let values = testkit::assert_splits_agree(codec, input).unwrap();
+        assert_eq!(
+            values,
+            [
+                json!({"method": "isPrime", "number": 7}),
+                json!([1, 2.5]),
+                json!({}),
+            ]
+        );
+        let error = testkit::assert_splits_agree(codec, b"{}\n{nope}\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_request_cut_off_by_the_end_of_the_stream_is_an_error() {
+        // Short of its newline the line is complete, and is still a request
+        testkit::assert_truncations_fail(codec, b"{\"method\":\"isPrime\",\"number\":7}");
+    }
+
+    fn session(batch: bool) -> Session {
+        let config = Config::from_env();
+        Session {
+            options: Options {
+                max_line_length: 64,
+                batch,
+                strictness: Strictness::Strict,
+            },
+            metrics: Metrics::new(&Scope::new("problem1", 0), &config),
+            batch: BytesMut::new(),
+        }
+    }
+
+    fn is_prime_request(number: u64) -> serde_json::Value {
+        json!({"method": "isPrime", "number": number})
+    }
+
+    #[test]
+    fn answers_single_requests_with_a_line_each() {
+        let mut session = session(false);
+        let response = session.respond(is_prime_request(7)).unwrap();
+        assert_eq!(response, PRIME.as_bytes());
+        assert_eq!(
+            session.respond(is_prime_request(8)).unwrap(),
+            COMPOSITE.as_bytes()
+        );
+    }
+
+    #[test]
+    fn answers_a_batch_in_order() {
+        let mut session = session(true);
+        let batch = json!([
+            is_prime_request(7),
+            is_prime_request(8),
+            is_prime_request(13)
+        ]);
+        let response = session.respond(batch).unwrap();
+        let expected = json!([
+            {"method": "isPrime", "prime": true},
+            {"method": "isPrime", "prime": false},
+            {"method": "isPrime", "prime": true},
+        ]);
+        assert!(response.ends_with(b"\n"));
+        let parsed: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(parsed, expected);
+        // The buffer is reused for the next batch
+        let response = session.respond(json!([is_prime_request(2)])).unwrap();
+        assert_eq!(
+            &response[..],
+            b"[{\"method\":\"isPrime\",\"prime\":true}]\n"
+        );
+        assert_eq!(session.respond(json!([])).unwrap(), &b"[]\n"[..]);
+    }
+
+    #[test]
+    fn one_malformed_request_fails_the_batch() {
+        let mut session = session(true);
+        let batch = json!([is_prime_request(7), {"method": "isPrime"}, is_prime_request(8)]);
+        assert!(session.respond(batch).is_err());
+    }
+
+    #[test]
+    fn arrays_are_malformed_without_batching() {
+        let mut session = session(false);
+        assert!(session.respond(json!([is_prime_request(7)])).is_err());
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let request = json!({"method": "isPrime", "number": -1.5e300});
+        let encoded = format!("{}\n", request);
+        assert_eq!(testkit::round_trip(codec, encoded.as_bytes()), request);
+    }
+}
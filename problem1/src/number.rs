@@ -0,0 +1,161 @@
+// What an isPrime request's "number" member can be, and what is done with it.
+//
+// serde_json reads integers that fit in 64 bits as integers, and anything
+// else that is a number (with a fraction or an exponent, "-0", or too big) as
+// a float. NUMBER_STRICTNESS picks one of two columns:
+//
+//   "number" is                          strict (default)   lenient
+//   a non-negative integer               checked            checked
+//   a negative integer                   not prime          not prime
+//   an integer too big for 64 bits       not prime          not prime
+//   a float with a fraction              not prime          not prime
+//   a whole float (7.0, 7e0, -0)         not prime          checked, up to 2^53
+//   a string holding a number ("7")      malformed          as the number
+//   any other string, a boolean, null,
+//     an array or an object              malformed          malformed
+//   missing                              malformed          malformed
+//
+// A number past the range of a float (1e400) doesn't parse as JSON at all,
+// so the whole request is malformed either way. Whole floats above 2^53 may
+// not be the number the client wrote, so they are never checked.
+use serde_json::{Number, Value};
+use std::fmt;
+use std::str::FromStr;
+
+// Largest whole float every smaller whole number is exactly representable below
+const MAX_EXACT_FLOAT: f64 = (1u64 << 53) as f64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strictness {
+    Strict,
+    Lenient,
+}
+
+impl FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            _ => Err(format!("unknown number strictness {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Strictness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Strict => write!(f, "strict"),
+            Self::Lenient => write!(f, "lenient"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Check(u64),
+    NotPrime,
+    Malformed,
+}
+
+// What to do with a present "number" member
+pub(crate) fn classify(value: &Value, strictness: Strictness) -> Verdict {
+    match (value, strictness) {
+        (Value::Number(n), _) => classify_number(n, strictness),
+        (Value::String(s), Strictness::Lenient) => match s.trim().parse::<Number>() {
+            Ok(n) => classify_number(&n, strictness),
+            Err(_) => Verdict::Malformed,
+        },
+        _ => Verdict::Malformed,
+    }
+}
+
+fn classify_number(n: &Number, strictness: Strictness) -> Verdict {
+    if let Some(n) = n.as_u64() {
+        return Verdict::Check(n);
+    }
+    if n.is_i64() {
+        return Verdict::NotPrime;
+    }
+    let f = n.as_f64().unwrap_or(f64::NAN);
+    match strictness {
+        Strictness::Lenient if f.fract() == 0.0 && (0.0..=MAX_EXACT_FLOAT).contains(&f) => {
+            Verdict::Check(f as u64)
+        }
+        _ => Verdict::NotPrime,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Verdict::*;
+
+    // The table above, row by row: the "number" member as JSON, then what
+    // each strictness makes of it
+    const TABLE: &[(&str, Verdict, Verdict)] = &[
+        ("7", Check(7), Check(7)),
+        ("0", Check(0), Check(0)),
+        ("18446744073709551615", Check(u64::MAX), Check(u64::MAX)),
+        ("-7", NotPrime, NotPrime),
+        ("-9223372036854775808", NotPrime, NotPrime),
+        ("18446744073709551616", NotPrime, NotPrime),
+        ("1e30", NotPrime, NotPrime),
+        ("7.5", NotPrime, NotPrime),
+        ("-7.5", NotPrime, NotPrime),
+        ("1e-3", NotPrime, NotPrime),
+        ("7.0", NotPrime, Check(7)),
+        ("7e0", NotPrime, Check(7)),
+        ("70E-1", NotPrime, Check(7)),
+        ("-0", NotPrime, Check(0)),
+        ("-0.0", NotPrime, Check(0)),
+        ("-7.0", NotPrime, NotPrime),
+        ("9007199254740992.0", NotPrime, Check(1 << 53)),
+        ("9007199254740994.0", NotPrime, NotPrime),
+        ("\"7\"", Malformed, Check(7)),
+        ("\" 7 \"", Malformed, Check(7)),
+        ("\"7.0\"", Malformed, Check(7)),
+        ("\"7.5\"", Malformed, NotPrime),
+        ("\"-7\"", Malformed, NotPrime),
+        ("\"seven\"", Malformed, Malformed),
+        ("\"\"", Malformed, Malformed),
+        ("true", Malformed, Malformed),
+        ("false", Malformed, Malformed),
+        ("null", Malformed, Malformed),
+        ("[7]", Malformed, Malformed),
+        ("{\"number\": 7}", Malformed, Malformed),
+    ];
+
+    #[test]
+    fn classify_follows_the_table() {
+        for &(json, strict, lenient) in TABLE {
+            let value: Value = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                classify(&value, Strictness::Strict),
+                strict,
+                "strict {}",
+                json
+            );
+            assert_eq!(
+                classify(&value, Strictness::Lenient),
+                lenient,
+                "lenient {}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn numbers_past_the_range_of_a_float_are_not_json() {
+        assert!(serde_json::from_str::<Value>("1e400").is_err());
+    }
+
+    #[test]
+    fn strictness_parses_and_displays() {
+        for strictness in [Strictness::Strict, Strictness::Lenient] {
+            assert_eq!(strictness.to_string().parse(), Ok(strictness));
+        }
+        assert!("loose".parse::<Strictness>().is_err());
+    }
+}
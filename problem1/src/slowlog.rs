@@ -0,0 +1,165 @@
+// CPU accounting for primality checks.
+//
+// Each check is timed with the calling thread's CPU clock, so time spent
+// descheduled doesn't count. Checks slower than SLOW_REQUEST_MICROS are logged
+// and kept in a ring buffer of the last SLOW_LOG_LEN, served as /slow on the
+// metrics endpoint to help spot pathological inputs.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::metrics::{Counter, Scope};
+
+pub const DEFAULT_SLOW_REQUEST_MICROS: u64 = 100_000;
+pub const DEFAULT_SLOW_LOG_LEN: usize = 32;
+
+fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Can't fail for the calling thread's own clock
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+struct SlowRequest {
+    number: String,
+    cpu: Duration,
+}
+
+#[derive(Clone)]
+pub struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<SlowRequest>>>,
+    cpu_micros: Counter,
+    slow: Counter,
+}
+
+impl SlowLog {
+    pub fn new(scope: &Scope, threshold: Duration, capacity: usize) -> Self {
+        let log = SlowLog {
+            threshold,
+            capacity,
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+            cpu_micros: scope.counter(
+                "prime_check_cpu_microseconds_total",
+                "CPU time spent checking primality",
+            ),
+            slow: scope.counter(
+                "prime_slow_requests_total",
+                "Primality checks over the slow request threshold",
+            ),
+        };
+        let page = log.clone();
+        common::metrics::register_page("/slow", move || page.render());
+        log
+    }
+
+    // Run a check, accounting for the CPU time it took
+    pub fn time<T>(&self, number: u64, check: impl FnOnce() -> T) -> T {
+        let start = thread_cpu_time();
+        let result = check();
+        let cpu = thread_cpu_time().saturating_sub(start);
+
+        self.cpu_micros.add(cpu.as_micros() as u64);
+        if cpu >= self.threshold {
+            common::warn!("Slow request: checking {} took {:?} of CPU", number, cpu);
+            self.slow.inc();
+            let mut entries = self
+                .entries
+                .lock()
+                .unwrap_or_else(|e| panic!("Error locking slow request log: {}", e));
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            if self.capacity > 0 {
+                entries.push_back(SlowRequest {
+                    number: number.to_string(),
+                    cpu,
+                });
+            }
+        }
+        result
+    }
+
+    // Slowest first
+    fn render(&self) -> String {
+        let entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking slow request log: {}", e));
+        let mut slowest: Vec<&SlowRequest> = entries.iter().collect();
+        slowest.sort_by_key(|r| std::cmp::Reverse(r.cpu));
+        slowest
+            .iter()
+            .map(|r| format!("{:>12.3?} {}\n", r.cpu, r.number))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(port: u16, threshold: Duration, capacity: usize) -> SlowLog {
+        SlowLog::new(&Scope::new("problem1", port), threshold, capacity)
+    }
+
+    // Keep the CPU busy for `cpu` of this thread's CPU time
+    fn burn(cpu: Duration) {
+        let start = thread_cpu_time();
+        while thread_cpu_time() - start < cpu {
+            std::hint::spin_loop();
+        }
+    }
+
+    fn numbers(log: &SlowLog) -> Vec<String> {
+        let entries = log.entries.lock().unwrap();
+        entries.iter().map(|r| r.number.clone()).collect()
+    }
+
+    #[test]
+    fn only_keeps_checks_over_the_threshold() {
+        let log = log(1001, Duration::from_millis(5), 10);
+        assert!(log.time(1, || true));
+        log.time(2, || burn(Duration::from_millis(6)));
+        assert_eq!(numbers(&log), ["2"]);
+        assert_eq!(log.slow.get(), 1);
+        assert!(log.cpu_micros.get() >= 6000);
+    }
+
+    #[test]
+    fn keeps_the_last_capacity_slow_checks() {
+        let log = log(1002, Duration::ZERO, 2);
+        for n in 1..=3 {
+            log.time(n, || ());
+        }
+        assert_eq!(numbers(&log), ["2", "3"]);
+        assert_eq!(log.slow.get(), 3);
+    }
+
+    #[test]
+    fn keeps_nothing_with_no_capacity() {
+        let log = log(1003, Duration::ZERO, 0);
+        log.time(1, || ());
+        assert!(numbers(&log).is_empty());
+        assert_eq!(log.render(), "");
+        assert_eq!(log.slow.get(), 1);
+    }
+
+    #[test]
+    fn renders_the_slowest_first() {
+        let log = log(1004, Duration::ZERO, 10);
+        log.time(1, || burn(Duration::from_millis(1)));
+        log.time(5, || burn(Duration::from_millis(5)));
+        log.time(3, || burn(Duration::from_millis(3)));
+        let page = log.render();
+        let order: Vec<&str> = page
+            .lines()
+            .map(|line| line.split_whitespace().last().unwrap())
+            .collect();
+        assert_eq!(order, ["5", "3", "1"]);
+    }
+}
@@ -0,0 +1,122 @@
+// Content-addressed storage for file contents.
+//
+// Revisions refer to their contents by SHA-256 digest, and each distinct
+// content is kept once however many revisions of however many files have it,
+// along with a count of those revisions. Contents go when the last revision
+// referring to them does.
+use sha2::{Digest as _, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub(crate) type Digest = [u8; 32];
+
+#[cfg(test)]
+pub(crate) fn digest(data: &[u8]) -> Digest {
+    Sha256::digest(data).into()
+}
+
+// A digest worked out a chunk at a time, for contents read in pieces
+#[derive(Default)]
+pub(crate) struct Hasher(Sha256);
+
+impl Hasher {
+    pub(crate) fn update(&mut self, chunk: &[u8]) {
+        self.0.update(chunk);
+    }
+
+    pub(crate) fn finish(self) -> Digest {
+        self.0.finalize().into()
+    }
+}
+
+struct Blob {
+    data: Arc<[u8]>,
+    refs: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct Blobs {
+    blobs: HashMap<Digest, Blob>,
+}
+
+impl Blobs {
+    // Count a reference to `data`, whose digest is `digest`, storing it if
+    // it's new. Returns whether it was.
+    pub(crate) fn insert(&mut self, digest: Digest, data: Vec<u8>) -> bool {
+        match self.blobs.get_mut(&digest) {
+            Some(blob) => {
+                blob.refs += 1;
+                false
+            }
+            None => {
+                let data = data.into();
+                self.blobs.insert(digest, Blob { data, refs: 1 });
+                true
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, digest: &Digest) -> Option<Arc<[u8]>> {
+        self.blobs.get(digest).map(|blob| blob.data.clone())
+    }
+
+    // Drop a reference to the contents with `digest`, returning how many
+    // bytes that freed
+    pub(crate) fn release(&mut self, digest: &Digest) -> usize {
+        let Some(blob) = self.blobs.get_mut(digest) else {
+            return 0;
+        };
+        blob.refs -= 1;
+        if blob.refs > 0 {
+            return 0;
+        }
+        self.blobs.remove(digest).map_or(0, |blob| blob.data.len())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.blobs.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn refs(&self, digest: &Digest) -> usize {
+        self.blobs.get(digest).map_or(0, |blob| blob.refs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_references_to_the_same_contents() {
+        let mut blobs = Blobs::default();
+        let hello = digest(b"hello");
+        assert!(blobs.insert(hello, b"hello".to_vec()));
+        assert!(!blobs.insert(hello, b"hello".to_vec()));
+        assert!(blobs.insert(digest(b"world"), b"world".to_vec()));
+        assert_eq!((blobs.len(), blobs.refs(&hello)), (2, 2));
+
+        assert_eq!(blobs.release(&hello), 0);
+        assert_eq!(&blobs.get(&hello).unwrap()[..], b"hello");
+        assert_eq!(blobs.release(&hello), 5);
+        assert!(blobs.get(&hello).is_none());
+        assert_eq!(blobs.release(&hello), 0);
+        assert_eq!(blobs.len(), 1);
+    }
+
+    #[test]
+    fn digests_differ_by_contents() {
+        assert_eq!(digest(b"a\n"), digest(b"a\n"));
+        assert_ne!(digest(b"a\n"), digest(b"a"));
+        assert_ne!(digest(b""), digest(b"\0"));
+    }
+
+    #[test]
+    fn hashing_in_chunks_gives_the_same_digest() {
+        let mut hasher = Hasher::default();
+        for chunk in [&b"hel"[..], b"", b"lo\n"] {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finish(), digest(b"hello\n"));
+    }
+}
@@ -0,0 +1,468 @@
+// Voracious Code Storage: a version control server for text files, spoken
+// in lines.
+//
+// The server says READY whenever it waits for a command. Commands are HELP,
+// PUT file length (followed by that many bytes of data), GET file [revision]
+// and LIST dir, in any case, and are answered with an OK line or an ERR line.
+// A PUT of anything but text (printable ASCII, tabs and newlines) is refused.
+// An unknown command, a command line longer than MAX_LINE_LENGTH or a file
+// larger than MAX_FILE_SIZE ends the connection. Files are kept in memory,
+// shared by every connection; see tree.rs.
+//
+// File data moves VCS_CHUNK_SIZE bytes at a time: a PUT is hashed and checked
+// for text as it's read, without allocating the whole length it claims up
+// front, and once a byte turns out not to be text the rest is read and thrown
+// away. A GET is written from the stored contents, with no copy.
+//
+// LIST dir [after] answers with at most VCS_LIST_PAGE entries, in name order,
+// starting after `after`: the last entry of the page before, as its name,
+// with a trailing slash for a directory. A full page means there may be more.
+use blobs::{Digest, Hasher};
+use common::accept::AcceptLimits;
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::metrics::{Counter, Scope};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_util::sync::CancellationToken;
+use tree::{Entry, Missing, Tree};
+
+mod blobs;
+mod serve;
+mod tree;
+
+pub use serve::serve;
+
+const DEFAULT_MAX_LINE_LENGTH: usize = 1024;
+const DEFAULT_MAX_FILE_SIZE: usize = 1024 * 1024;
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+const DEFAULT_LIST_PAGE: usize = 1000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    pub max_line_length: usize,
+    pub max_file_size: usize,
+    // Revisions kept per file, or None for all of them
+    pub max_revisions: Option<usize>,
+    // File data read or written at a time
+    pub chunk_size: usize,
+    // Entries per LIST response
+    pub list_page: usize,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env().busy_message(b"ERR server busy\n"),
+            max_line_length: common::env::var_or("MAX_LINE_LENGTH", DEFAULT_MAX_LINE_LENGTH),
+            max_file_size: common::env::var_or("MAX_FILE_SIZE", DEFAULT_MAX_FILE_SIZE),
+            max_revisions: common::env::var("VCS_MAX_REVISIONS"),
+            chunk_size: common::env::var_or("VCS_CHUNK_SIZE", DEFAULT_CHUNK_SIZE),
+            list_page: common::env::var_or("VCS_LIST_PAGE", DEFAULT_LIST_PAGE),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    help: Counter,
+    put: Counter,
+    get: Counter,
+    list: Counter,
+    illegal: Counter,
+}
+
+impl Metrics {
+    fn new(scope: &Scope) -> Self {
+        let requests = |method| {
+            scope.counter_with(
+                "vcs_requests_total",
+                "Commands received by method",
+                &[("method", method)],
+            )
+        };
+        Metrics {
+            help: requests("help"),
+            put: requests("put"),
+            get: requests("get"),
+            list: requests("list"),
+            illegal: requests("illegal"),
+        }
+    }
+}
+
+fn is_text(data: &[u8]) -> bool {
+    data.iter()
+        .all(|&b| (b' '..=b'~').contains(&b) || b"\t\r\n".contains(&b))
+}
+
+// "r3" or "3"
+fn parse_revision(revision: &str) -> Option<usize> {
+    revision.strip_prefix('r').unwrap_or(revision).parse().ok()
+}
+
+// What reading a PUT's data came to
+enum Upload {
+    Text(Digest, Vec<u8>),
+    NotText,
+}
+
+#[derive(Clone)]
+struct Vcs {
+    tree: Arc<Tree>,
+    max_line_length: usize,
+    max_file_size: usize,
+    chunk_size: usize,
+    list_page: usize,
+    metrics: Metrics,
+}
+
+impl Vcs {
+    // The contents to send for a GET, or the error to answer with
+    fn get(&self, args: &[&str]) -> Result<Arc<[u8]>, &'static [u8]> {
+        let (path, revision) = match args {
+            [path] => (path, None),
+            [path, revision] => match parse_revision(revision) {
+                Some(revision) => (path, Some(revision)),
+                None => return Err(b"ERR no such revision\n"),
+            },
+            _ => return Err(b"ERR usage: GET file [revision]\n"),
+        };
+        if !tree::is_file_name(path) {
+            return Err(b"ERR illegal file name\n");
+        }
+        self.tree
+            .get(path, revision)
+            .map_err(|missing| match missing {
+                Missing::File => &b"ERR no such file\n"[..],
+                Missing::Revision => b"ERR no such revision\n",
+            })
+    }
+
+    // Write `data` to `wr` a chunk at a time
+    async fn send<W: AsyncWrite + Unpin>(&self, wr: &mut W, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(self.chunk_size) {
+            wr.write_all(chunk).await?;
+        }
+        Ok(())
+    }
+
+    // Read `len` bytes of file data from `rd` a chunk at a time, hashing
+    // them as they come
+    async fn upload<R: AsyncRead + Unpin>(&self, rd: &mut R, len: usize) -> io::Result<Upload> {
+        let mut chunk = vec![0; self.chunk_size.min(len)];
+        let mut hasher = Hasher::default();
+        let mut data = Vec::new();
+        let mut text = true;
+        let mut left = len;
+        while left > 0 {
+            let chunk = &mut chunk[..left.min(self.chunk_size)];
+            rd.read_exact(chunk).await?;
+            left -= chunk.len();
+            if text && !is_text(chunk) {
+                text = false;
+                data = Vec::new();
+            }
+            if text {
+                hasher.update(chunk);
+                data.extend_from_slice(chunk);
+            }
+        }
+        Ok(match text {
+            true => Upload::Text(hasher.finish(), data),
+            false => Upload::NotText,
+        })
+    }
+
+    fn list(&self, out: &mut Vec<u8>, args: &[&str]) {
+        let (path, after) = match args {
+            [path] => (path, None),
+            [path, after] => (path, Some(*after)),
+            _ => return out.extend_from_slice(b"ERR usage: LIST dir [after]\n"),
+        };
+        if !tree::is_dir_name(path) {
+            return out.extend_from_slice(b"ERR illegal dir name\n");
+        }
+        let entries = self.tree.list(path, after, self.list_page);
+        writeln!(out, "OK {}", entries.len()).unwrap();
+        for entry in entries {
+            match entry {
+                Entry::File { name, revision } => writeln!(out, "{} r{}", name, revision),
+                Entry::Dir(name) => writeln!(out, "{}/ DIR", name),
+            }
+            .unwrap();
+        }
+    }
+
+    async fn serve<S: ByteStream>(&self, stream: S, ctx: &Context) {
+        let (rd, mut wr) = tokio::io::split(stream);
+        let mut rd = BufReader::new(rd);
+        let mut line = Vec::new();
+        let mut out = Vec::new();
+        loop {
+            out.extend_from_slice(b"READY\n");
+            ctx.task.phase("writing response");
+            tokio::select! {
+                written = wr.write_all(&out) => if written.is_err() { return },
+                _ = ctx.cancel.cancelled() => return,
+            }
+            out.clear();
+
+            line.clear();
+            ctx.task.phase("reading command");
+            let mut limited = (&mut rd).take(self.max_line_length as u64 + 1);
+            let n = tokio::select! {
+                n = limited.read_until(b'\n', &mut line) => n,
+                _ = ctx.cancel.cancelled() => return,
+            };
+            let n = match n {
+                Ok(n) => n,
+                Err(e) => {
+                    common::debug!("Error reading command: {:?}", e);
+                    return;
+                }
+            };
+            if line.pop() != Some(b'\n') {
+                if n > self.max_line_length {
+                    common::debug!(
+                        "Command longer than {} bytes, closing",
+                        self.max_line_length
+                    );
+                }
+                return;
+            }
+
+            let command = String::from_utf8_lossy(&line);
+            let mut words = command.split_whitespace();
+            let method = words.next().unwrap_or("");
+            let args: Vec<&str> = words.collect();
+            match method.to_ascii_uppercase().as_str() {
+                "HELP" => {
+                    self.metrics.help.inc();
+                    out.extend_from_slice(b"OK usage: HELP|GET|PUT|LIST\n");
+                }
+                "GET" => {
+                    self.metrics.get.inc();
+                    let data = match self.get(&args) {
+                        Ok(data) => data,
+                        Err(error) => {
+                            out.extend_from_slice(error);
+                            continue;
+                        }
+                    };
+                    writeln!(out, "OK {}", data.len()).unwrap();
+                    ctx.task.phase("writing file");
+                    let sent = async {
+                        wr.write_all(&out).await?;
+                        self.send(&mut wr, &data).await
+                    };
+                    tokio::select! {
+                        sent = sent => if sent.is_err() { return },
+                        _ = ctx.cancel.cancelled() => return,
+                    }
+                    out.clear();
+                }
+                "LIST" => {
+                    self.metrics.list.inc();
+                    self.list(&mut out, &args);
+                }
+                "PUT" => {
+                    self.metrics.put.inc();
+                    let [path, len] = args[..] else {
+                        out.extend_from_slice(b"ERR usage: PUT file length newline data\n");
+                        continue;
+                    };
+                    let Ok(len) = len.parse::<usize>() else {
+                        out.extend_from_slice(b"ERR usage: PUT file length newline data\n");
+                        continue;
+                    };
+                    if !tree::is_file_name(path) {
+                        out.extend_from_slice(b"ERR illegal file name\n");
+                        continue;
+                    }
+                    if len > self.max_file_size {
+                        let error =
+                            format!("ERR files are limited to {} bytes\n", self.max_file_size);
+                        wr.write_all(error.as_bytes()).await.unwrap_or(());
+                        return;
+                    }
+                    ctx.task.phase("reading file");
+                    let upload = tokio::select! {
+                        upload = self.upload(&mut rd, len) => upload,
+                        _ = ctx.cancel.cancelled() => return,
+                    };
+                    match upload {
+                        Ok(Upload::Text(digest, data)) => {
+                            let revision = self.tree.put_hashed(path, digest, data);
+                            writeln!(out, "OK r{}", revision).unwrap();
+                        }
+                        Ok(Upload::NotText) => out.extend_from_slice(b"ERR text files only\n"),
+                        Err(_) => return,
+                    }
+                }
+                _ => {
+                    self.metrics.illegal.inc();
+                    let error = format!("ERR illegal method: {}\n", method);
+                    wr.write_all(error.as_bytes()).await.unwrap_or(());
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for Vcs {
+    async fn handle<S: ByteStream>(&self, stream: S, _peer: Option<SocketAddr>, ctx: Context) {
+        self.serve(stream, &ctx).await
+    }
+}
+
+// The handler `run` serves, for a server that accepts connections itself
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    Vcs {
+        tree: Arc::new(Tree::new(
+            config.max_revisions,
+            scope.gauge(
+                "vcs_stored_bytes",
+                "Bytes stored, each distinct file content counted once",
+            ),
+            scope.gauge("vcs_stored_contents", "Distinct file contents stored"),
+        )),
+        max_line_length: config.max_line_length,
+        max_file_size: config.max_file_size,
+        chunk_size: config.chunk_size,
+        list_page: config.list_page,
+        metrics: Metrics::new(scope),
+    }
+}
+
+// Run the version control server on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem10", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let vcs = handler(&scope, &config);
+
+    #[cfg(feature = "middleware")]
+    let vcs = config.middleware.wrap(vcs, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(vcs.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(vcs.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "isl")]
+    isl::spawn_from_env(vcs.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, vcs).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    struct Client {
+        rd: BufReader<ReadHalf<DuplexStream>>,
+        wr: WriteHalf<DuplexStream>,
+    }
+
+    impl Client {
+        // A session with a server moving file data `chunk_size` bytes at a
+        // time and listing `list_page` entries at a time
+        fn start(port: u16, chunk_size: usize, list_page: usize) -> Client {
+            let config = Config {
+                chunk_size,
+                list_page,
+                ..Config::from_env()
+            };
+            let vcs = handler(&Scope::new("problem10", port), &config);
+            let (client, server) = tokio::io::duplex(16);
+            tokio::spawn(async move { vcs.handle(server, None, Context::new("test")).await });
+            let (rd, wr) = tokio::io::split(client);
+            Client {
+                rd: BufReader::new(rd),
+                wr,
+            }
+        }
+
+        async fn line(&mut self) -> String {
+            let mut line = String::new();
+            self.rd.read_line(&mut line).await.unwrap();
+            line
+        }
+
+        // Send `request`, returning the line answering it
+        async fn ask(&mut self, request: &[u8]) -> String {
+            assert_eq!(self.line().await, "READY\n");
+            self.wr.write_all(request).await.unwrap();
+            self.line().await
+        }
+
+        async fn list(&mut self, request: &str) -> Vec<String> {
+            let ok = self.ask(request.as_bytes()).await;
+            let n: usize = ok.trim().strip_prefix("OK ").unwrap().parse().unwrap();
+            let mut entries = Vec::new();
+            for _ in 0..n {
+                entries.push(self.line().await.trim_end().to_owned());
+            }
+            entries
+        }
+    }
+
+    #[tokio::test]
+    async fn moves_files_larger_than_a_chunk() {
+        let mut client = Client::start(10001, 3, 10);
+        let data = "a file\nspanning\nseveral chunks\n".repeat(20);
+        let put = format!("PUT /big {}\n{}", data.len(), data);
+        assert_eq!(client.ask(put.as_bytes()).await, "OK r1\n");
+
+        assert_eq!(
+            client.ask(b"GET /big\n").await,
+            format!("OK {}\n", data.len())
+        );
+        let mut got = vec![0; data.len()];
+        client.rd.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, data.as_bytes());
+
+        // The same contents again, hashed chunk by chunk, aren't a new revision
+        assert_eq!(client.ask(put.as_bytes()).await, "OK r1\n");
+    }
+
+    #[tokio::test]
+    async fn reads_past_data_that_isnt_text() {
+        let mut client = Client::start(10002, 4, 10);
+        let mut put = b"PUT /bin 10\nok\x00ok ok\n\n".to_vec();
+        put.extend_from_slice(b"PUT /txt 3\nok\n");
+        assert_eq!(client.ask(&put).await, "ERR text files only\n");
+        // The rest of the data was skipped, not taken for commands
+        assert_eq!(client.line().await, "READY\n");
+        assert_eq!(client.line().await, "OK r1\n");
+        assert_eq!(client.ask(b"GET /bin\n").await, "ERR no such file\n");
+    }
+
+    #[tokio::test]
+    async fn lists_a_page_at_a_time() {
+        let mut client = Client::start(10003, 64, 2);
+        for name in ["c", "a", "b/x", "b", "d"] {
+            let put = format!("PUT /dir/{} 2\nx\n", name);
+            assert_eq!(client.ask(put.as_bytes()).await, "OK r1\n");
+        }
+        assert_eq!(client.list("LIST /dir\n").await, ["a r1", "b r1"]);
+        assert_eq!(client.list("LIST /dir b\n").await, ["b/ DIR", "c r1"]);
+        assert_eq!(client.list("LIST /dir/ c\n").await, ["d r1"]);
+        assert!(client.list("LIST /dir d\n").await.is_empty());
+        assert_eq!(
+            client.ask(b"LIST /dir a b\n").await,
+            "ERR usage: LIST dir [after]\n"
+        );
+    }
+}
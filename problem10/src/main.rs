@@ -0,0 +1,17 @@
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(
+    about = "Protohackers problem 10: Voracious Code Storage",
+    after_help = common::cli::AFTER_HELP,
+    args_override_self = true
+)]
+struct Cli {
+    #[command(flatten)]
+    args: common::cli::Args,
+}
+
+fn main() {
+    common::cli::init(Cli::parse().args);
+    problem10::serve(&common::cli::listen_addrs(39456));
+}
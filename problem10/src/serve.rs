@@ -0,0 +1,49 @@
+// Everything the problem10 binary does, callable so that the protohackers
+// binary can run the problem too.
+use crate::Config;
+use common::config::Checker;
+use common::listeners::Listeners;
+use protolib::{Problem, Transport};
+use std::io;
+use std::net::SocketAddr;
+use tokio_util::sync::CancellationToken;
+
+// This problem's own checks, on top of those `checker` makes
+pub fn check_config(checker: Checker) -> Checker {
+    checker
+        .positive("MAX_LINE_LENGTH")
+        .positive("MAX_FILE_SIZE")
+        .positive("VCS_MAX_REVISIONS")
+        .positive("VCS_CHUNK_SIZE")
+        .positive("VCS_LIST_PAGE")
+}
+
+// Check the configuration, then serve on `addrs` until the process is stopped
+pub fn serve(addrs: &[SocketAddr]) {
+    protolib::main::<Config>(addrs)
+}
+
+impl Problem for Config {
+    const TRANSPORT: Transport = Transport::Tcp;
+    type Listener = Listeners;
+
+    fn name() -> &'static str {
+        "problem10"
+    }
+
+    fn from_env() -> Self {
+        Config::from_env()
+    }
+
+    fn check_config(checker: Checker) -> Checker {
+        check_config(checker)
+    }
+
+    async fn bind(&self, addrs: &[SocketAddr]) -> io::Result<Listeners> {
+        common::listeners::bind(addrs).await
+    }
+
+    async fn serve(&self, listener: Listeners, shutdown: CancellationToken) {
+        crate::run(listener, shutdown, *self).await
+    }
+}
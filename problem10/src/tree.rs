@@ -0,0 +1,344 @@
+// The files stored so far, with every revision of each.
+//
+// Paths are absolute and slash-separated, made of letters, digits, '.', '_'
+// and '-'. Directories aren't created on their own: they exist once a file is
+// stored somewhere under them, and as files are never deleted they stay. A
+// file's revisions are numbered from 1, and storing the same contents as the
+// latest revision doesn't make a new one. With VCS_MAX_REVISIONS set, only
+// that many of a file's latest revisions are kept, older ones being dropped
+// as new ones come (numbering carries on, and asking for a dropped one is
+// the same as asking for one that never was). Contents are kept in a
+// content-addressed store shared by every file; see blobs.rs.
+//
+// Listings come a page at a time, in name order with a file before a
+// directory of the same name, each page starting after a cursor: the last
+// entry of the page before, as its name, with a trailing slash for a
+// directory. Entries stored meanwhile show up in later pages if they sort
+// after the cursor, and no entry is listed twice.
+use crate::blobs::{Blobs, Digest};
+use common::metrics::Gauge;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+fn is_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.contains("//")
+        && path
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"/._-".contains(&b))
+}
+
+pub(crate) fn is_file_name(path: &str) -> bool {
+    is_path(path) && !path.ends_with('/')
+}
+
+// Directory names may end with a slash or not
+pub(crate) fn is_dir_name(path: &str) -> bool {
+    is_path(path)
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty())
+}
+
+// The revisions of one file still kept
+#[derive(Default)]
+struct Revisions {
+    // The number of the first of `digests`
+    first: usize,
+    digests: VecDeque<Digest>,
+}
+
+impl Revisions {
+    // The latest revision's number
+    fn latest(&self) -> usize {
+        self.first + self.digests.len() - 1
+    }
+
+    fn get(&self, revision: usize) -> Option<&Digest> {
+        self.digests.get(revision.checked_sub(self.first)?)
+    }
+}
+
+#[derive(Default)]
+struct Dir {
+    dirs: BTreeMap<String, Dir>,
+    files: BTreeMap<String, Revisions>,
+}
+
+impl Dir {
+    fn find(&self, path: &str) -> Option<&Dir> {
+        components(path).try_fold(self, |dir, name| dir.dirs.get(name))
+    }
+}
+
+// What a directory listing shows for one name
+pub(crate) enum Entry {
+    File { name: String, revision: usize },
+    Dir(String),
+}
+
+pub(crate) enum Missing {
+    File,
+    Revision,
+}
+
+#[derive(Default)]
+struct Store {
+    root: Dir,
+    blobs: Blobs,
+}
+
+pub(crate) struct Tree {
+    store: Mutex<Store>,
+    // Revisions kept per file, or None for all of them
+    max_revisions: Option<usize>,
+    stored: Gauge,
+    contents: Gauge,
+}
+
+impl Tree {
+    pub(crate) fn new(max_revisions: Option<usize>, stored: Gauge, contents: Gauge) -> Self {
+        Tree {
+            store: Mutex::new(Store::default()),
+            max_revisions,
+            stored,
+            contents,
+        }
+    }
+
+    fn store(&self) -> std::sync::MutexGuard<'_, Store> {
+        self.store
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking file tree: {}", e))
+    }
+
+    // Store `data` at `path`, a valid file name, returning its revision
+    #[cfg(test)]
+    pub(crate) fn put(&self, path: &str, data: Vec<u8>) -> usize {
+        self.put_hashed(path, crate::blobs::digest(&data), data)
+    }
+
+    // `put` for `data` whose digest, `digest`, was worked out as it was read
+    pub(crate) fn put_hashed(&self, path: &str, digest: Digest, data: Vec<u8>) -> usize {
+        let (parent, name) = path.rsplit_once('/').unwrap();
+        let mut store = self.store();
+        let Store { root, blobs } = &mut *store;
+        let dir = components(parent).fold(root, |dir, name| {
+            dir.dirs.entry(name.to_owned()).or_default()
+        });
+        let revisions = dir
+            .files
+            .entry(name.to_owned())
+            .or_insert_with(|| Revisions {
+                first: 1,
+                digests: VecDeque::new(),
+            });
+        if revisions.digests.back() == Some(&digest) {
+            return revisions.latest();
+        }
+        let len = data.len();
+        if blobs.insert(digest, data) {
+            self.stored.add(len as i64);
+        }
+        revisions.digests.push_back(digest);
+        while self
+            .max_revisions
+            .is_some_and(|max| revisions.digests.len() > max)
+        {
+            let dropped = revisions.digests.pop_front().unwrap();
+            revisions.first += 1;
+            self.stored.add(-(blobs.release(&dropped) as i64));
+        }
+        self.contents.set(blobs.len() as i64);
+        revisions.latest()
+    }
+
+    // Revision `revision` of the file at `path`, or its latest with None
+    pub(crate) fn get(&self, path: &str, revision: Option<usize>) -> Result<Arc<[u8]>, Missing> {
+        let (parent, name) = path.rsplit_once('/').unwrap();
+        let store = self.store();
+        let revisions = store
+            .root
+            .find(parent)
+            .and_then(|dir| dir.files.get(name))
+            .ok_or(Missing::File)?;
+        let revision = revision.unwrap_or(revisions.latest());
+        revisions
+            .get(revision)
+            .and_then(|digest| store.blobs.get(digest))
+            .ok_or(Missing::Revision)
+    }
+
+    // Up to `limit` of the entries in the directory at `path`, by name,
+    // starting after `after` (a cursor) or from the first. A name can be both
+    // a file and a directory, and is then listed as both.
+    pub(crate) fn list(&self, path: &str, after: Option<&str>, limit: usize) -> Vec<Entry> {
+        let store = self.store();
+        let Some(dir) = store.root.find(path) else {
+            return Vec::new();
+        };
+        // A file sorts before a directory of the same name
+        let (files_from, dirs_from) = match after {
+            None => (Bound::Unbounded, Bound::Unbounded),
+            Some(after) => match after.strip_suffix('/') {
+                Some(name) => (Bound::Excluded(name), Bound::Excluded(name)),
+                None => (Bound::Excluded(after), Bound::Included(after)),
+            },
+        };
+        let mut files = dir
+            .files
+            .range::<str, _>((files_from, Bound::Unbounded))
+            .peekable();
+        let mut dirs = dir
+            .dirs
+            .range::<str, _>((dirs_from, Bound::Unbounded))
+            .peekable();
+        let mut entries = Vec::new();
+        while entries.len() < limit {
+            let file_first = match (files.peek(), dirs.peek()) {
+                (None, None) => break,
+                (Some((file, _)), Some((dir, _))) => file <= dir,
+                (file, _) => file.is_some(),
+            };
+            let entry = if file_first {
+                let (name, revisions) = files.next().unwrap();
+                Entry::File {
+                    name: name.clone(),
+                    revision: revisions.latest(),
+                }
+            } else {
+                Entry::Dir(dirs.next().unwrap().0.clone())
+            };
+            entries.push(entry);
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blobs;
+    use common::metrics::Scope;
+
+    // `port` keeps each test's gauges apart from the others'
+    fn tree(port: u16, max_revisions: Option<usize>) -> Tree {
+        let scope = Scope::new("problem10", port);
+        Tree::new(
+            max_revisions,
+            scope.gauge("stored", ""),
+            scope.gauge("contents", ""),
+        )
+    }
+
+    fn get(tree: &Tree, path: &str, revision: Option<usize>) -> Option<Vec<u8>> {
+        tree.get(path, revision).ok().map(|data| data.to_vec())
+    }
+
+    fn listed(tree: &Tree, path: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+        tree.list(path, after, limit)
+            .into_iter()
+            .map(|entry| match entry {
+                Entry::File { name, revision } => format!("{} r{}", name, revision),
+                Entry::Dir(name) => format!("{}/", name),
+            })
+            .collect()
+    }
+
+    fn refs(tree: &Tree, data: &[u8]) -> usize {
+        tree.store().blobs.refs(&blobs::digest(data))
+    }
+
+    #[test]
+    fn identical_contents_are_stored_once() {
+        let tree = tree(1, None);
+        assert_eq!(tree.put("/a/one", b"same\n".to_vec()), 1);
+        assert_eq!(tree.put("/b/two", b"same\n".to_vec()), 1);
+        assert_eq!(tree.put("/a/one", b"other\n".to_vec()), 2);
+        // Back to what it was: a new revision, but no new contents
+        assert_eq!(tree.put("/a/one", b"same\n".to_vec()), 3);
+        assert_eq!(tree.store().blobs.len(), 2);
+        assert_eq!(refs(&tree, b"same\n"), 3);
+        assert_eq!(tree.stored.get(), 11);
+        assert_eq!(tree.contents.get(), 2);
+
+        assert_eq!(get(&tree, "/a/one", Some(1)).unwrap(), b"same\n");
+        assert_eq!(get(&tree, "/a/one", Some(2)).unwrap(), b"other\n");
+        assert_eq!(get(&tree, "/a/one", None).unwrap(), b"same\n");
+        assert_eq!(get(&tree, "/b/two", None).unwrap(), b"same\n");
+    }
+
+    #[test]
+    fn storing_the_latest_contents_again_changes_nothing() {
+        let tree = tree(2, None);
+        assert_eq!(tree.put("/f", b"x".to_vec()), 1);
+        assert_eq!(tree.put("/f", b"x".to_vec()), 1);
+        assert_eq!(refs(&tree, b"x"), 1);
+        assert!(get(&tree, "/f", Some(2)).is_none());
+        assert!(get(&tree, "/f", Some(0)).is_none());
+    }
+
+    #[test]
+    fn dropped_revisions_release_their_contents() {
+        let tree = tree(3, Some(2));
+        tree.put("/f", b"one".to_vec());
+        tree.put("/g", b"one".to_vec());
+        tree.put("/f", b"two".to_vec());
+        assert_eq!(tree.put("/f", b"three".to_vec()), 3);
+        // Revision 1 of /f is gone, but /g still has its contents
+        assert!(get(&tree, "/f", Some(1)).is_none());
+        assert_eq!(get(&tree, "/f", Some(2)).unwrap(), b"two");
+        assert_eq!(get(&tree, "/g", None).unwrap(), b"one");
+        assert_eq!(refs(&tree, b"one"), 1);
+
+        assert_eq!(tree.put("/f", b"four".to_vec()), 4);
+        assert_eq!(refs(&tree, b"two"), 0);
+        assert_eq!(tree.store().blobs.len(), 3);
+        assert_eq!(tree.stored.get(), 12);
+
+        assert_eq!(listed(&tree, "/", None, 10), ["f r4", "g r1"]);
+    }
+
+    #[test]
+    fn revisions_sharing_contents_with_dropped_ones_keep_them() {
+        let tree = tree(4, Some(1));
+        tree.put("/f", b"a".to_vec());
+        tree.put("/f", b"b".to_vec());
+        tree.put("/f", b"a".to_vec());
+        assert_eq!(get(&tree, "/f", None).unwrap(), b"a");
+        assert_eq!((refs(&tree, b"a"), refs(&tree, b"b")), (1, 0));
+        assert_eq!(tree.stored.get(), 1);
+    }
+
+    #[test]
+    fn lists_a_page_at_a_time_after_a_cursor() {
+        let tree = tree(5, None);
+        for path in ["/d/b", "/d/a/x", "/d/a", "/d/c/y", "/d/e", "/other"] {
+            tree.put(path, b"x".to_vec());
+        }
+        let all = ["a r1", "a/", "b r1", "c/", "e r1"];
+        assert_eq!(listed(&tree, "/d", None, 100), all);
+
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = tree.list("/d/", after.as_deref(), 2);
+            let Some(last) = page.last() else { break };
+            after = Some(match last {
+                Entry::File { name, .. } => name.clone(),
+                Entry::Dir(name) => format!("{}/", name),
+            });
+            pages.push(page.len());
+        }
+        assert_eq!(pages, [2, 2, 1]);
+
+        // A file and a directory with the same name are both listed
+        assert_eq!(listed(&tree, "/d", Some("a"), 1), ["a/"]);
+        assert_eq!(listed(&tree, "/d", Some("a/"), 1), ["b r1"]);
+        assert_eq!(listed(&tree, "/d", Some("bb"), 10), ["c/", "e r1"]);
+        assert!(listed(&tree, "/d", Some("e"), 10).is_empty());
+        assert!(listed(&tree, "/nowhere", None, 10).is_empty());
+    }
+}
@@ -0,0 +1,745 @@
+// Connections to the authority server, one per site, kept open between
+// visits.
+//
+// Each site gets a task of its own the first time it's visited, holding the
+// site's connection to the authority, the target populations it was given when
+// dialling and the policies created since. Visits for the site queue up for
+// that task and are applied in order, except that visits queued behind another
+// are skipped in favour of the newest: the policies only have to match the
+// latest one. A visit's deletes and creates go out all at once, and the
+// replies are matched to them in order, as the authority answers in the order
+// it's asked. A policy is only forgotten once the authority confirms deleting
+// it, or answers the delete with an error: then it's already gone, as when the
+// reply to an earlier delete was lost with its connection, and the rest of the
+// replies are still matched up. A connection that fails is dropped and the
+// visit tried once more on a new one, in case the old one had just gone stale
+// (the policies made over it are assumed to stand, as they belong to the
+// site); dialling backs off between attempts.
+use crate::message::{self, Action, Message, MessageCodec, Target};
+use codecs::ProtocolError;
+use common::metrics::{Counter, Gauge};
+use common::retry::{Backoff, Stopped};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tokio_util::sync::CancellationToken;
+
+// Visits waiting for a site's task before clients reporting on it have to wait
+const SITE_QUEUE_LEN: usize = 64;
+
+// Species -> count, as seen on one visit
+pub(crate) type Counts = HashMap<String, u32>;
+
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    pub connections: Gauge,
+    pub failures: Counter,
+    pub created: Counter,
+    pub deleted: Counter,
+}
+
+// How to reach the authority server
+pub(crate) struct Dialer {
+    pub addr: String,
+    pub timeout: Duration,
+    pub backoff: Backoff,
+    pub max_message_length: usize,
+    pub metrics: Metrics,
+}
+
+// How the authority server didn't say what the protocol says it should
+#[derive(Debug, Error)]
+pub(crate) enum AuthorityViolation {
+    #[error("authority error: {0}")]
+    Refused(String),
+    #[error("expected {expected}, got {got:?}")]
+    Unexpected {
+        expected: &'static str,
+        got: Message,
+    },
+}
+
+// Why talking to the authority server failed: the connection, or a violation
+pub(crate) type AuthorityError = ProtocolError<AuthorityViolation>;
+
+fn unexpected(expected: &'static str, got: Message) -> AuthorityError {
+    ProtocolError::Protocol(AuthorityViolation::Unexpected { expected, got })
+}
+
+// One connection to the authority, dialled to a site
+struct Connection {
+    messages: FramedRead<OwnedReadHalf, MessageCodec>,
+    wr: OwnedWriteHalf,
+    out: Vec<u8>,
+    timeout: Duration,
+    connections: Gauge,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connections.dec();
+    }
+}
+
+impl Connection {
+    async fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.out.clear();
+        message.encode(&mut self.out);
+        self.wr.write_all(&self.out).await
+    }
+
+    async fn receive(&mut self) -> Result<Message, AuthorityError> {
+        let message = tokio::time::timeout(self.timeout, self.messages.next())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?;
+        match message {
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Some(Ok(Message::Error(e))) => {
+                Err(ProtocolError::Protocol(AuthorityViolation::Refused(e)))
+            }
+            Some(message) => Ok(message?),
+        }
+    }
+
+    async fn request(&mut self, message: &Message) -> Result<Message, AuthorityError> {
+        self.send(message).await?;
+        self.receive().await
+    }
+
+    // Send `messages` in one go, without waiting for replies
+    async fn send_all(&mut self, messages: &[Message]) -> io::Result<()> {
+        self.out.clear();
+        for message in messages {
+            message.encode(&mut self.out);
+        }
+        self.wr.write_all(&self.out).await
+    }
+
+    // Connect and dial `site`, returning its target populations too
+    async fn open(dialer: &Dialer, site: u32) -> Result<(Connection, Vec<Target>), AuthorityError> {
+        let stream = tokio::time::timeout(dialer.timeout, common::resolve::connect(&dialer.addr))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))?;
+        let (rd, wr) = stream.into_split();
+        dialer.metrics.connections.inc();
+        let mut conn = Connection {
+            messages: FramedRead::new(rd, MessageCodec::new(dialer.max_message_length)),
+            wr,
+            out: Vec::new(),
+            timeout: dialer.timeout,
+            connections: dialer.metrics.connections.clone(),
+        };
+        match conn.request(&Message::hello()).await? {
+            Message::Hello { protocol, version }
+                if protocol == message::PROTOCOL && version == message::VERSION => {}
+            other => return Err(unexpected("Hello", other)),
+        }
+        match conn.request(&Message::DialAuthority { site }).await? {
+            Message::TargetPopulations {
+                site: dialled,
+                populations,
+            } if dialled == site => Ok((conn, populations)),
+            other => Err(unexpected("target populations for the site", other)),
+        }
+    }
+}
+
+// What a site has to do with a species seen `count` times: nothing if the
+// count is within its target, otherwise one of the two policies
+fn wanted(target: &Target, count: u32) -> Option<Action> {
+    if count < target.min {
+        Some(Action::Conserve)
+    } else if count > target.max {
+        Some(Action::Cull)
+    } else {
+        None
+    }
+}
+
+// A request sent ahead of its reply, to know what the reply is about
+enum Pending {
+    Delete { species: String, policy: u32 },
+    Create { species: String, action: Action },
+}
+
+struct Site {
+    site: u32,
+    dialer: Arc<Dialer>,
+    conn: Option<Connection>,
+    // From the authority; they don't change, so they outlive connections
+    targets: Option<Vec<Target>>,
+    // Species -> (policy id, action)
+    policies: HashMap<String, (u32, Action)>,
+}
+
+impl Site {
+    // The site's connection, dialling one if there isn't one
+    async fn connect(&mut self) -> Option<Connection> {
+        if let Some(conn) = self.conn.take() {
+            return Some(conn);
+        }
+        let what = format!("dial site {} at {}", self.site, self.dialer.addr);
+        let attempt = |_| Connection::open(&self.dialer, self.site);
+        let cancel = CancellationToken::new();
+        match common::retry::retry(&self.dialer.backoff, &what, &cancel, attempt).await {
+            Ok((conn, targets)) => {
+                self.targets = Some(targets);
+                Some(conn)
+            }
+            Err(Stopped::Cancelled) | Err(Stopped::GaveUp(_)) => None,
+        }
+    }
+
+    // Create and delete policies over `conn` until they match `counts`
+    async fn update(
+        &mut self,
+        conn: &mut Connection,
+        counts: &Counts,
+    ) -> Result<(), AuthorityError> {
+        let targets = self.targets.as_deref().unwrap_or_default();
+        let mut requests = Vec::new();
+        let mut pending = Vec::new();
+        for target in targets {
+            let count = counts.get(&target.species).copied().unwrap_or(0);
+            let wanted = wanted(target, count);
+            let current = self.policies.get(&target.species).copied();
+            if current.map(|(_, action)| action) == wanted {
+                continue;
+            }
+            let species = target.species.clone();
+            if let Some((policy, _)) = current {
+                requests.push(Message::DeletePolicy { policy });
+                let species = species.clone();
+                pending.push(Pending::Delete { species, policy });
+            }
+            if let Some(action) = wanted {
+                let create = Message::CreatePolicy {
+                    species: species.clone(),
+                    action,
+                };
+                requests.push(create);
+                pending.push(Pending::Create { species, action });
+            }
+        }
+        if requests.is_empty() {
+            return Ok(());
+        }
+        conn.send_all(&requests).await?;
+
+        let dialer = self.dialer.clone();
+        let metrics = &dialer.metrics;
+        for request in pending {
+            match (request, conn.receive().await) {
+                (Pending::Delete { species, policy }, Ok(Message::Ok)) => {
+                    metrics.deleted.inc();
+                    self.forget(&species, policy);
+                }
+                (
+                    Pending::Delete { species, policy },
+                    Err(ProtocolError::Protocol(AuthorityViolation::Refused(e))),
+                ) => {
+                    common::debug!(
+                        "Policy {} for site {} already gone: {}",
+                        policy,
+                        self.site,
+                        e
+                    );
+                    self.forget(&species, policy);
+                }
+                (Pending::Create { species, action }, Ok(Message::PolicyResult { policy })) => {
+                    metrics.created.inc();
+                    self.policies.insert(species, (policy, action));
+                }
+                (_, Err(e)) => return Err(e),
+                (Pending::Delete { .. }, Ok(other)) => return Err(unexpected("OK", other)),
+                (Pending::Create { .. }, Ok(other)) => {
+                    return Err(unexpected("a policy result", other))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Drop `policy` for `species`, unless the species has another by now
+    fn forget(&mut self, species: &str, policy: u32) {
+        if self.policies.get(species).map(|&(id, _)| id) == Some(policy) {
+            self.policies.remove(species);
+        }
+    }
+
+    async fn run(mut self, mut visits: mpsc::Receiver<Counts>) {
+        while let Some(mut counts) = visits.recv().await {
+            while let Ok(newer) = visits.try_recv() {
+                counts = newer;
+            }
+            let mut fresh = self.conn.is_none();
+            loop {
+                let Some(mut conn) = self.connect().await else {
+                    self.dialer.metrics.failures.inc();
+                    break;
+                };
+                match self.update(&mut conn, &counts).await {
+                    Ok(()) => {
+                        self.conn = Some(conn);
+                        break;
+                    }
+                    // Dropping the connection, for the next try to dial another
+                    Err(e) => {
+                        common::warn!("Error updating policies for site {}: {}", self.site, e);
+                        if fresh {
+                            self.dialer.metrics.failures.inc();
+                            break;
+                        }
+                        fresh = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct Authorities {
+    dialer: Arc<Dialer>,
+    sites: Mutex<HashMap<u32, mpsc::Sender<Counts>>>,
+}
+
+impl Authorities {
+    pub(crate) fn new(dialer: Dialer) -> Self {
+        Authorities {
+            dialer: Arc::new(dialer),
+            sites: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Queue a visit to `site` for the authority to hear about, waiting only
+    // if the site's queue is full
+    pub(crate) async fn visit(&self, site: u32, counts: Counts) {
+        let sender = self
+            .sites
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking site table: {}", e))
+            .entry(site)
+            .or_insert_with(|| {
+                let (sender, visits) = mpsc::channel(SITE_QUEUE_LEN);
+                let task = Site {
+                    site,
+                    dialer: self.dialer.clone(),
+                    conn: None,
+                    targets: None,
+                    policies: HashMap::new(),
+                };
+                tokio::spawn(task.run(visits));
+                sender
+            })
+            .clone();
+        // The site's task never stops, so this only waits for room
+        sender.send(counts).await.unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::metrics::Scope;
+    use proptest::prelude::*;
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // How the simulated authority misbehaves
+    #[derive(Clone, Copy)]
+    struct Behaviour {
+        // Connections closed as soon as they're accepted
+        refuse_first: usize,
+        // Policy requests answered on a connection before hanging up
+        hang_up_after: Option<usize>,
+        // Policy requests held back until this many have arrived
+        batch: usize,
+    }
+
+    const NORMAL: Behaviour = Behaviour {
+        refuse_first: 0,
+        hang_up_after: None,
+        batch: 1,
+    };
+
+    // Policy id -> (site, species, action) of the policies in force
+    type Live = Arc<Mutex<HashMap<u32, (u32, String, Action)>>>;
+
+    struct Simulated {
+        addr: String,
+        // What the authority was asked, in order
+        log: Arc<Mutex<Vec<String>>>,
+        live: Live,
+        connections: Arc<AtomicUsize>,
+    }
+
+    fn targets() -> Vec<Target> {
+        let target = |species: &str, min, max| Target {
+            species: species.to_owned(),
+            min,
+            max,
+        };
+        vec![target("a", 1, 5), target("b", 0, 2), target("c", 10, 20)]
+    }
+
+    async fn simulate(behaviour: Behaviour) -> Simulated {
+        simulate_with(behaviour, targets()).await
+    }
+
+    // An authority giving every site `targets`
+    async fn simulate_with(behaviour: Behaviour, targets: Vec<Target>) -> Simulated {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let simulated = Simulated {
+            addr: listener.local_addr().unwrap().to_string(),
+            log: Arc::default(),
+            live: Arc::default(),
+            connections: Arc::default(),
+        };
+        let log = simulated.log.clone();
+        let live = simulated.live.clone();
+        let connections = simulated.connections.clone();
+        let policies = Arc::new(AtomicU32::new(0));
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                if connections.fetch_add(1, Ordering::SeqCst) < behaviour.refuse_first {
+                    continue;
+                }
+                let (log, live) = (log.clone(), live.clone());
+                let targets = targets.clone();
+                tokio::spawn(serve(
+                    socket,
+                    behaviour,
+                    targets,
+                    log,
+                    live,
+                    policies.clone(),
+                ));
+            }
+        });
+        simulated
+    }
+
+    async fn serve(
+        socket: TcpStream,
+        behaviour: Behaviour,
+        targets: Vec<Target>,
+        log: Arc<Mutex<Vec<String>>>,
+        live: Live,
+        policies: Arc<AtomicU32>,
+    ) {
+        let (rd, mut wr) = socket.into_split();
+        let mut messages = FramedRead::new(rd, MessageCodec::new(1 << 20));
+        let mut held = Vec::new();
+        let mut answered = 0;
+        let mut dialled = None;
+        while let Some(Ok(message)) = messages.next().await {
+            let log = |entry: String| log.lock().unwrap().push(entry);
+            let immediate = match message {
+                Message::Hello { .. } => Some(Message::hello()),
+                Message::DialAuthority { site } => {
+                    log(format!("dial {}", site));
+                    dialled = Some(site);
+                    Some(Message::TargetPopulations {
+                        site,
+                        populations: targets.clone(),
+                    })
+                }
+                Message::CreatePolicy { species, action } => {
+                    log(format!("create {} {:?}", species, action));
+                    let policy = policies.fetch_add(1, Ordering::SeqCst) + 1;
+                    let site = dialled.expect("policy created before dialling");
+                    live.lock().unwrap().insert(policy, (site, species, action));
+                    held.push(Message::PolicyResult { policy });
+                    None
+                }
+                Message::DeletePolicy { policy } => {
+                    log(format!("delete {}", policy));
+                    match live.lock().unwrap().remove(&policy) {
+                        Some(_) => held.push(Message::Ok),
+                        None => held.push(Message::Error("no such policy".to_owned())),
+                    }
+                    None
+                }
+                other => Some(Message::Error(format!("unexpected {:?}", other))),
+            };
+            let replies = match immediate {
+                Some(reply) => vec![reply],
+                None if held.len() < behaviour.batch => continue,
+                None => {
+                    answered += held.len();
+                    std::mem::take(&mut held)
+                }
+            };
+            let mut out = Vec::new();
+            for reply in replies {
+                reply.encode(&mut out);
+            }
+            wr.write_all(&out).await.unwrap();
+            if behaviour.hang_up_after.is_some_and(|n| answered >= n) {
+                return;
+            }
+        }
+    }
+
+    impl Simulated {
+        fn authorities(&self) -> (Authorities, Metrics) {
+            // A scope of its own, so tests running at once don't share counters
+            let port = self.addr.rsplit(':').next().unwrap().parse().unwrap();
+            let scope = Scope::new("problem11", port);
+            let metrics = Metrics {
+                connections: scope.gauge("connections", ""),
+                failures: scope.counter("failures", ""),
+                created: scope.counter("created", ""),
+                deleted: scope.counter("deleted", ""),
+            };
+            let dialer = Dialer {
+                addr: self.addr.clone(),
+                timeout: Duration::from_secs(2),
+                backoff: Backoff::new(Duration::from_millis(10), Duration::from_millis(50))
+                    .max_attempts(3),
+                max_message_length: 1 << 20,
+                metrics: metrics.clone(),
+            };
+            (Authorities::new(dialer), metrics)
+        }
+
+        // Wait for the log to have `len` entries, returning them
+        async fn logged(&self, len: usize) -> Vec<String> {
+            until(|| self.log.lock().unwrap().len() >= len).await;
+            self.log.lock().unwrap().clone()
+        }
+    }
+
+    async fn until(done: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("timed out")
+    }
+
+    fn counts(counts: &[(&str, u32)]) -> Counts {
+        counts.iter().map(|&(s, n)| (s.to_owned(), n)).collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_policies_in_line_over_one_connection() {
+        let authority = simulate(NORMAL).await;
+        let (authorities, metrics) = authority.authorities();
+        authorities.visit(1, counts(&[("a", 10), ("c", 15)])).await;
+        assert_eq!(authority.logged(2).await, ["dial 1", "create a Cull"]);
+        authorities.visit(1, counts(&[("a", 0)])).await;
+        assert_eq!(
+            authority.logged(5).await[2..],
+            ["delete 1", "create a Conserve", "create c Conserve"]
+        );
+        // Nothing changes, so nothing is asked
+        authorities.visit(1, counts(&[("a", 0), ("b", 1)])).await;
+        authorities.visit(2, counts(&[])).await;
+        assert_eq!(
+            authority.logged(8).await[5..],
+            ["dial 2", "create a Conserve", "create c Conserve"]
+        );
+        until(|| metrics.created.get() == 5).await;
+        assert_eq!(metrics.deleted.get(), 1);
+        assert_eq!(authority.connections.load(Ordering::SeqCst), 2);
+        assert_eq!(metrics.connections.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn redials_when_the_authority_hangs_up() {
+        let authority = simulate(Behaviour {
+            hang_up_after: Some(1),
+            ..NORMAL
+        })
+        .await;
+        let (authorities, metrics) = authority.authorities();
+        authorities.visit(1, counts(&[("a", 10), ("c", 15)])).await;
+        assert_eq!(authority.logged(2).await, ["dial 1", "create a Cull"]);
+        // The kept connection is dead by now; the visit goes over a new one,
+        // which still knows about the policy made over the old one
+        authorities.visit(1, counts(&[("a", 3), ("c", 15)])).await;
+        assert_eq!(authority.logged(4).await[2..], ["dial 1", "delete 1"]);
+        until(|| metrics.deleted.get() == 1).await;
+        assert_eq!(metrics.failures.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn backs_off_and_retries_dialling() {
+        let authority = simulate(Behaviour {
+            refuse_first: 2,
+            ..NORMAL
+        })
+        .await;
+        let (authorities, metrics) = authority.authorities();
+        authorities.visit(1, counts(&[("a", 10), ("c", 15)])).await;
+        assert_eq!(authority.logged(2).await, ["dial 1", "create a Cull"]);
+        assert_eq!(authority.connections.load(Ordering::SeqCst), 3);
+        assert_eq!(metrics.failures.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let authority = simulate(Behaviour {
+            refuse_first: 3,
+            ..NORMAL
+        })
+        .await;
+        let (authorities, metrics) = authority.authorities();
+        authorities.visit(1, counts(&[("a", 10)])).await;
+        until(|| metrics.failures.get() == 1).await;
+        assert!(authority.log.lock().unwrap().is_empty());
+        // The next visit starts over, and gets through
+        authorities.visit(1, counts(&[("a", 10), ("c", 15)])).await;
+        assert_eq!(authority.logged(2).await, ["dial 1", "create a Cull"]);
+    }
+
+    // A delete answered with an error is for a policy already gone, as when
+    // the reply to deleting it was lost; the replies after it still count
+    #[tokio::test]
+    async fn takes_an_error_deleting_a_policy_as_already_deleted() {
+        let authority = simulate(NORMAL).await;
+        let (authorities, metrics) = authority.authorities();
+        authorities.visit(1, counts(&[("a", 10), ("c", 15)])).await;
+        assert_eq!(authority.logged(2).await, ["dial 1", "create a Cull"]);
+        until(|| metrics.created.get() == 1).await;
+        authority.live.lock().unwrap().remove(&1);
+
+        authorities.visit(1, counts(&[("a", 0), ("c", 15)])).await;
+        assert_eq!(
+            authority.logged(4).await[2..],
+            ["delete 1", "create a Conserve"]
+        );
+        until(|| metrics.created.get() == 2).await;
+        // The policy created after the error is known, and deleted when due,
+        // over the same connection
+        authorities.visit(1, counts(&[("a", 3), ("c", 15)])).await;
+        assert_eq!(authority.logged(5).await[4..], ["delete 2"]);
+        until(|| authority.live.lock().unwrap().is_empty()).await;
+        assert_eq!(metrics.deleted.get(), 1);
+        assert_eq!(metrics.failures.get(), 0);
+        assert_eq!(authority.connections.load(Ordering::SeqCst), 1);
+    }
+
+    // The authority only answers once it has all three requests, so they
+    // have to be sent without waiting for replies
+    #[tokio::test]
+    async fn pipelines_requests_and_matches_up_replies() {
+        let authority = simulate(Behaviour { batch: 3, ..NORMAL }).await;
+        let (authorities, metrics) = authority.authorities();
+        authorities.visit(1, counts(&[("a", 10), ("b", 5)])).await;
+        assert_eq!(
+            authority.logged(4).await,
+            [
+                "dial 1",
+                "create a Cull",
+                "create b Cull",
+                "create c Conserve"
+            ]
+        );
+        until(|| metrics.created.get() == 3).await;
+        // Each delete names the policy its species was given
+        authorities.visit(1, counts(&[("a", 3), ("c", 15)])).await;
+        assert_eq!(
+            authority.logged(7).await[4..],
+            ["delete 1", "delete 2", "delete 3"]
+        );
+    }
+
+    // What a site's policies should be after a visit that saw `counts`, as
+    // (site, species, action), sorted
+    fn expected(site: u32, targets: &[Target], counts: &Counts) -> Vec<(u32, String, Action)> {
+        let mut expected: Vec<_> = targets
+            .iter()
+            .filter_map(|target| {
+                let count = counts.get(&target.species).copied().unwrap_or(0);
+                let action = wanted(target, count)?;
+                Some((site, target.species.clone(), action))
+            })
+            .collect();
+        expected.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        expected
+    }
+
+    fn in_force(live: &Live) -> Vec<(u32, String, Action)> {
+        let mut policies: Vec<_> = live.lock().unwrap().values().cloned().collect();
+        policies.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        policies
+    }
+
+    fn target_strategy() -> impl Strategy<Value = Vec<Target>> {
+        prop::collection::btree_map("[a-e]", (0u32..8, 0u32..8), 0..5).prop_map(|targets| {
+            targets
+                .into_iter()
+                .map(|(species, (a, b))| Target {
+                    species,
+                    min: a.min(b),
+                    max: a.max(b),
+                })
+                .collect()
+        })
+    }
+
+    // Site visits as clients send them: the same species can come up more
+    // than once, with the same count or a conflicting one, and species
+    // without a target can be counted too
+    fn visit_strategy() -> impl Strategy<Value = (u32, Vec<(String, u32)>)> {
+        let observation = ("[a-f]", 0u32..12);
+        (0u32..3, prop::collection::vec(observation, 0..8))
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(48))]
+
+        // However the visits go, each site ends up with exactly the policies
+        // its last valid visit calls for: one per species out of range, of
+        // the right kind, and none for the rest
+        #[test]
+        fn policies_converge_on_the_latest_visit(
+            targets in target_strategy(),
+            visits in prop::collection::vec(visit_strategy(), 1..12),
+        ) {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let (wanted, got) = runtime.block_on(async {
+                let authority = simulate_with(NORMAL, targets.clone()).await;
+                let (authorities, _) = authority.authorities();
+                let mut latest = HashMap::new();
+                for (site, populations) in visits {
+                    // Conflicting counts get the client an error instead
+                    let Some(counts) = crate::counts(populations) else {
+                        continue;
+                    };
+                    latest.insert(site, counts.clone());
+                    authorities.visit(site, counts).await;
+                }
+                let mut wanted: Vec<_> = latest
+                    .iter()
+                    .flat_map(|(&site, counts)| expected(site, &targets, counts))
+                    .collect();
+                wanted.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+                let settled = tokio::time::timeout(Duration::from_secs(5), async {
+                    while in_force(&authority.live) != wanted {
+                        tokio::time::sleep(Duration::from_millis(2)).await;
+                    }
+                });
+                settled.await.unwrap_or(());
+                // Nothing more changes once they match
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                (wanted, in_force(&authority.live))
+            });
+            prop_assert_eq!(got, wanted);
+        }
+    }
+}
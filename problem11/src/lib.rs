@@ -0,0 +1,247 @@
+// Pest Control: clients report how many of each species they counted on
+// site visits, and the server has the site's authority create and delete
+// policies to keep each species within its target population.
+//
+// Both sides start by sending Hello. After that a client sends SiteVisit
+// messages, which get no reply unless they're invalid: anything unexpected or
+// malformed, including a visit counting the same species twice with different
+// counts, is answered with an Error and the connection is closed. Visits are
+// handed to the site's connection to the authority server at AUTHORITY; see
+// authority.rs.
+use authority::{Authorities, Counts, Dialer};
+use common::accept::AcceptLimits;
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::metrics::{Counter, Scope};
+use common::retry::Backoff;
+use message::{Message, MessageCodec};
+use std::collections::hash_map::Entry;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tokio_util::sync::CancellationToken;
+
+mod authority;
+mod message;
+mod serve;
+
+pub use serve::serve;
+
+const DEFAULT_AUTHORITY: &str = "pestcontrol.protohackers.com:20547";
+const DEFAULT_AUTHORITY_TIMEOUT_MILLIS: u64 = 5000;
+const DEFAULT_AUTHORITY_CONNECT_ATTEMPTS: u32 = 3;
+const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+const CONNECT_BACKOFF_INITIAL: Duration = Duration::from_millis(100);
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+    // The authority server, as host:port
+    pub authority: String,
+    // How long the authority server gets to accept a connection or to answer
+    pub authority_timeout: Duration,
+    pub authority_connect_attempts: u32,
+    // Longest message accepted, from clients or the authority server
+    pub max_message_length: usize,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env().busy_message(message::BUSY),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+            authority: common::env::var_or("AUTHORITY", DEFAULT_AUTHORITY.to_owned()),
+            authority_timeout: Duration::from_millis(common::env::var_or(
+                "AUTHORITY_TIMEOUT_MILLIS",
+                DEFAULT_AUTHORITY_TIMEOUT_MILLIS,
+            )),
+            authority_connect_attempts: common::env::var_or(
+                "AUTHORITY_CONNECT_ATTEMPTS",
+                DEFAULT_AUTHORITY_CONNECT_ATTEMPTS,
+            ),
+            max_message_length: common::env::var_or(
+                "MAX_MESSAGE_LENGTH",
+                DEFAULT_MAX_MESSAGE_LENGTH,
+            ),
+        }
+    }
+}
+
+// Each species' count in a visit, unless it counts one twice differently
+fn counts(populations: Vec<(String, u32)>) -> Option<Counts> {
+    let mut counts = Counts::new();
+    for (species, count) in populations {
+        match counts.entry(species) {
+            Entry::Occupied(seen) if *seen.get() != count => return None,
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(count);
+            }
+        }
+    }
+    Some(counts)
+}
+
+async fn send<W: AsyncWrite + Unpin>(wr: &mut W, message: &Message) -> std::io::Result<()> {
+    let mut out = Vec::new();
+    message.encode(&mut out);
+    wr.write_all(&out).await
+}
+
+#[derive(Clone)]
+struct PestControl {
+    authorities: Arc<Authorities>,
+    max_message_length: usize,
+    visits: Counter,
+    errors: Counter,
+}
+
+impl PestControl {
+    // Serve a client until it leaves, or until the error to send it
+    async fn serve<S: ByteStream>(
+        &self,
+        rd: tokio::io::ReadHalf<S>,
+        ctx: &Context,
+    ) -> Option<String> {
+        let mut messages = FramedRead::new(rd, MessageCodec::new(self.max_message_length));
+        let mut greeted = false;
+        loop {
+            ctx.task.phase("waiting for messages");
+            let message = tokio::select! {
+                message = messages.next() => message,
+                _ = ctx.cancel.cancelled() => return None,
+            };
+            let message = match message {
+                None => return None,
+                Some(Ok(message)) => message,
+                Some(Err(e)) => return Some(e.to_string()),
+            };
+            match message {
+                Message::Hello { protocol, version } if !greeted => {
+                    if protocol != message::PROTOCOL || version != message::VERSION {
+                        return Some("unsupported protocol".to_owned());
+                    }
+                    greeted = true;
+                }
+                _ if !greeted => return Some("expected Hello first".to_owned()),
+                Message::SiteVisit { site, populations } => {
+                    let Some(counts) = counts(populations) else {
+                        return Some("conflicting counts for a species".to_owned());
+                    };
+                    self.visits.inc();
+                    ctx.task.phase("queueing visit");
+                    tokio::select! {
+                        _ = self.authorities.visit(site, counts) => {}
+                        _ = ctx.cancel.cancelled() => return None,
+                    }
+                }
+                other => return Some(format!("unexpected message {:?}", other)),
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for PestControl {
+    async fn handle<S: ByteStream>(&self, stream: S, _peer: Option<SocketAddr>, ctx: Context) {
+        let (rd, mut wr) = tokio::io::split(stream);
+        if send(&mut wr, &Message::hello()).await.is_err() {
+            return;
+        }
+        if let Some(error) = self.serve(rd, &ctx).await {
+            common::debug!("Client error: {}", error);
+            self.errors.inc();
+            send(&mut wr, &Message::Error(error)).await.unwrap_or(());
+        }
+    }
+}
+
+// The handler `run` serves, for a server that accepts connections itself
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    let dialer = Dialer {
+        addr: config.authority.clone(),
+        timeout: config.authority_timeout,
+        backoff: Backoff::new(CONNECT_BACKOFF_INITIAL, CONNECT_BACKOFF_MAX)
+            .max_attempts(config.authority_connect_attempts),
+        max_message_length: config.max_message_length,
+        metrics: authority::Metrics {
+            connections: scope.gauge(
+                "pest_authority_connections",
+                "Connections open to the authority server",
+            ),
+            failures: scope.counter(
+                "pest_authority_failures_total",
+                "Visits the authority server couldn't be told about",
+            ),
+            created: scope.counter("pest_policies_created_total", "Policies created"),
+            deleted: scope.counter("pest_policies_deleted_total", "Policies deleted"),
+        },
+    };
+    PestControl {
+        authorities: Arc::new(Authorities::new(dialer)),
+        max_message_length: config.max_message_length,
+        visits: scope.counter("pest_visits_total", "Site visits reported"),
+        errors: scope.counter(
+            "pest_client_errors_total",
+            "Clients disconnected for sending something invalid",
+        ),
+    }
+}
+
+// Run Pest Control on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem11", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let pests = handler(&scope, &config);
+
+    #[cfg(feature = "middleware")]
+    let pests = config.middleware.wrap(pests, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(pests.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(pests.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "isl")]
+    isl::spawn_from_env(pests.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, pests).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        // Counting a species twice is fine as long as the counts agree
+        #[test]
+        fn counts_agree_or_conflict(
+            populations in prop::collection::vec(("[a-d]", 0u32..4), 0..10),
+        ) {
+            let conflicting = populations.iter().any(|(species, count)| {
+                populations.iter().any(|(other, n)| other == species && n != count)
+            });
+            match counts(populations.clone()) {
+                None => prop_assert!(conflicting),
+                Some(counts) => {
+                    prop_assert!(!conflicting);
+                    for (species, count) in &populations {
+                        prop_assert_eq!(counts.get(species), Some(count));
+                    }
+                    let species: std::collections::HashSet<_> =
+                        populations.iter().map(|(species, _)| species).collect();
+                    prop_assert_eq!(counts.len(), species.len());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,377 @@
+// The Pest Control wire format, spoken both to clients and to the authority
+// server.
+//
+// A message is a type byte, its total length as a big-endian u32 (counting
+// the whole message), its fields and a checksum byte making all of its bytes
+// sum to 0 mod 256. Integers are big-endian u32s, strings a u32 length and
+// that many bytes, arrays a u32 count and that many elements. A message whose
+// length doesn't match its fields, with a bad checksum or of an unknown type
+// is an error, as is one longer than the decoder's limit.
+use bytes::{Buf, BytesMut};
+use std::io;
+use tokio_util::codec::Decoder;
+
+const HELLO: u8 = 0x50;
+const ERROR: u8 = 0x51;
+const OK: u8 = 0x52;
+const DIAL_AUTHORITY: u8 = 0x53;
+const TARGET_POPULATIONS: u8 = 0x54;
+const CREATE_POLICY: u8 = 0x55;
+const DELETE_POLICY: u8 = 0x56;
+const POLICY_RESULT: u8 = 0x57;
+const SITE_VISIT: u8 = 0x58;
+
+// An Error telling a client the server is too busy to take it, checksum and all
+pub(crate) const BUSY: &[u8] = b"\x51\x00\x00\x00\x15\x00\x00\x00\x0bserver busy\x15";
+
+const CULL: u8 = 0x90;
+const CONSERVE: u8 = 0xa0;
+
+// Type, length and checksum
+const MIN_LENGTH: usize = 6;
+
+pub(crate) const PROTOCOL: &str = "pestcontrol";
+pub(crate) const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    Cull,
+    Conserve,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Target {
+    pub species: String,
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Message {
+    Hello {
+        protocol: String,
+        version: u32,
+    },
+    Error(String),
+    Ok,
+    DialAuthority {
+        site: u32,
+    },
+    TargetPopulations {
+        site: u32,
+        populations: Vec<Target>,
+    },
+    CreatePolicy {
+        species: String,
+        action: Action,
+    },
+    DeletePolicy {
+        policy: u32,
+    },
+    PolicyResult {
+        policy: u32,
+    },
+    SiteVisit {
+        site: u32,
+        populations: Vec<(String, u32)>,
+    },
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+// Reads fields off a message's contents, None meaning they ran out
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn bytes(&mut self, n: usize) -> Option<&[u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        Some(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+
+    fn array<T>(&mut self, mut element: impl FnMut(&mut Self) -> Option<T>) -> Option<Vec<T>> {
+        let count = self.u32()?;
+        // Not preallocated, as the count is only as trustworthy as the client
+        (0..count).map(|_| element(self)).collect()
+    }
+}
+
+fn parse(kind: u8, fields: &mut Fields) -> io::Result<Message> {
+    let short = || invalid("message shorter than its fields");
+    let message = match kind {
+        HELLO => Message::Hello {
+            protocol: fields.str().ok_or_else(short)?,
+            version: fields.u32().ok_or_else(short)?,
+        },
+        ERROR => Message::Error(fields.str().ok_or_else(short)?),
+        OK => Message::Ok,
+        DIAL_AUTHORITY => Message::DialAuthority {
+            site: fields.u32().ok_or_else(short)?,
+        },
+        TARGET_POPULATIONS => Message::TargetPopulations {
+            site: fields.u32().ok_or_else(short)?,
+            populations: fields
+                .array(|f| {
+                    Some(Target {
+                        species: f.str()?,
+                        min: f.u32()?,
+                        max: f.u32()?,
+                    })
+                })
+                .ok_or_else(short)?,
+        },
+        CREATE_POLICY => Message::CreatePolicy {
+            species: fields.str().ok_or_else(short)?,
+            action: match fields.u8().ok_or_else(short)? {
+                CULL => Action::Cull,
+                CONSERVE => Action::Conserve,
+                _ => return Err(invalid("unknown policy action")),
+            },
+        },
+        DELETE_POLICY => Message::DeletePolicy {
+            policy: fields.u32().ok_or_else(short)?,
+        },
+        POLICY_RESULT => Message::PolicyResult {
+            policy: fields.u32().ok_or_else(short)?,
+        },
+        SITE_VISIT => Message::SiteVisit {
+            site: fields.u32().ok_or_else(short)?,
+            populations: fields
+                .array(|f| Some((f.str()?, f.u32()?)))
+                .ok_or_else(short)?,
+        },
+        other => return Err(invalid(&format!("unknown message type 0x{:02x}", other))),
+    };
+    if !fields.0.is_empty() {
+        return Err(invalid("message longer than its fields"));
+    }
+    Ok(message)
+}
+
+pub(crate) struct MessageCodec {
+    max_length: usize,
+}
+
+impl MessageCodec {
+    pub(crate) fn new(max_length: usize) -> Self {
+        MessageCodec { max_length }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if buf.len() < 5 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize;
+        if len < MIN_LENGTH {
+            return Err(invalid("message length too short"));
+        }
+        if len > self.max_length {
+            return Err(invalid("message too long"));
+        }
+        if buf.len() < len {
+            buf.reserve(len - buf.len());
+            return Ok(None);
+        }
+        let message = buf.split_to(len);
+        if message.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(invalid("bad checksum"));
+        }
+        parse(message[0], &mut Fields(&message[5..len - 1])).map(Some)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(message) => Ok(Some(message)),
+            None if buf.has_remaining() => Err(invalid("connection closed mid-message")),
+            None => Ok(None),
+        }
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+impl Message {
+    pub(crate) fn hello() -> Self {
+        Message::Hello {
+            protocol: PROTOCOL.to_owned(),
+            version: VERSION,
+        }
+    }
+
+    // Append the message, checksum and all, to `out`
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        let kind = match self {
+            Message::Hello { .. } => HELLO,
+            Message::Error(_) => ERROR,
+            Message::Ok => OK,
+            Message::DialAuthority { .. } => DIAL_AUTHORITY,
+            Message::TargetPopulations { .. } => TARGET_POPULATIONS,
+            Message::CreatePolicy { .. } => CREATE_POLICY,
+            Message::DeletePolicy { .. } => DELETE_POLICY,
+            Message::PolicyResult { .. } => POLICY_RESULT,
+            Message::SiteVisit { .. } => SITE_VISIT,
+        };
+        out.push(kind);
+        // The length, filled in once known
+        put_u32(out, 0);
+        match self {
+            Message::Hello { protocol, version } => {
+                put_str(out, protocol);
+                put_u32(out, *version);
+            }
+            Message::Error(message) => put_str(out, message),
+            Message::Ok => {}
+            Message::DialAuthority { site } => put_u32(out, *site),
+            Message::TargetPopulations { site, populations } => {
+                put_u32(out, *site);
+                put_u32(out, populations.len() as u32);
+                for target in populations {
+                    put_str(out, &target.species);
+                    put_u32(out, target.min);
+                    put_u32(out, target.max);
+                }
+            }
+            Message::CreatePolicy { species, action } => {
+                put_str(out, species);
+                out.push(match action {
+                    Action::Cull => CULL,
+                    Action::Conserve => CONSERVE,
+                });
+            }
+            Message::DeletePolicy { policy } | Message::PolicyResult { policy } => {
+                put_u32(out, *policy)
+            }
+            Message::SiteVisit { site, populations } => {
+                put_u32(out, *site);
+                put_u32(out, populations.len() as u32);
+                for (species, count) in populations {
+                    put_str(out, species);
+                    put_u32(out, *count);
+                }
+            }
+        }
+        let len = (out.len() - start + 1) as u32;
+        out[start + 1..start + 5].copy_from_slice(&len.to_be_bytes());
+        let sum = out[start..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        out.push(sum.wrapping_neg());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codecs::testkit;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::hello(),
+            Message::Error("bad".into()),
+            Message::Ok,
+            Message::DialAuthority { site: 12345 },
+            Message::TargetPopulations {
+                site: 12345,
+                populations: vec![
+                    Target {
+                        species: "dog".into(),
+                        min: 1,
+                        max: 3,
+                    },
+                    Target {
+                        species: "rat".into(),
+                        min: 0,
+                        max: 10,
+                    },
+                ],
+            },
+            Message::CreatePolicy {
+                species: "dog".into(),
+                action: Action::Conserve,
+            },
+            Message::CreatePolicy {
+                species: "rat".into(),
+                action: Action::Cull,
+            },
+            Message::DeletePolicy { policy: 123 },
+            Message::PolicyResult { policy: 123 },
+            Message::SiteVisit {
+                site: 12345,
+                populations: vec![("dog".into(), 1), ("rat".into(), 5)],
+            },
+            Message::SiteVisit {
+                site: 0,
+                populations: vec![],
+            },
+        ]
+    }
+
+    fn encode(message: &Message) -> Vec<u8> {
+        let mut out = Vec::new();
+        message.encode(&mut out);
+        out
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for message in messages() {
+            let codec = || MessageCodec::new(1024);
+            assert_eq!(testkit::round_trip(codec, &encode(&message)), message);
+        }
+    }
+
+    #[test]
+    fn messages_decode_however_they_arrive() {
+        let input: Vec<u8> = messages().iter().flat_map(encode).collect();
+        let decoded = testkit::assert_splits_agree(|| MessageCodec::new(1024), &input).unwrap();
+        assert_eq!(decoded, messages());
+
+        let mut bad = encode(&Message::Ok);
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        let error = testkit::assert_splits_agree(|| MessageCodec::new(1024), &bad).unwrap_err();
+        assert_eq!(error.to_string(), "bad checksum");
+    }
+
+    #[test]
+    fn a_message_cut_off_by_the_end_of_the_stream_is_an_error() {
+        for message in messages() {
+            testkit::assert_truncations_fail(|| MessageCodec::new(1024), &encode(&message));
+        }
+    }
+
+    #[test]
+    fn the_busy_error_is_well_formed() {
+        let message = testkit::round_trip(|| MessageCodec::new(1024), BUSY);
+        assert_eq!(message, Message::Error("server busy".into()));
+    }
+}
@@ -0,0 +1,65 @@
+// Throughput benchmark for the asset price server. Start the server (with or
+// without the `uring` feature) and run:
+//
+//     cargo run --release --example means_bench -- [addr] [clients] [inserts per client]
+//
+// Each client pipelines its inserts followed by one query per 100 inserts and
+// waits for all the query responses.
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn message(msg_type: u8, first: i32, second: i32) -> [u8; 9] {
+    let mut msg = [0u8; 9];
+    msg[0] = msg_type;
+    msg[1..5].copy_from_slice(&first.to_be_bytes());
+    msg[5..9].copy_from_slice(&second.to_be_bytes());
+    msg
+}
+
+async fn client(addr: String, inserts: i32) {
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (mut rd, mut wr) = socket.into_split();
+
+    let queries = inserts / 100;
+    let mut requests = Vec::with_capacity((inserts + queries) as usize * 9);
+    for i in 0..inserts {
+        requests.extend_from_slice(&message(b'I', i, i % 1000));
+        if i % 100 == 99 {
+            requests.extend_from_slice(&message(b'Q', 0, i));
+        }
+    }
+    let writer = tokio::spawn(async move { wr.write_all(&requests).await.unwrap() });
+
+    let mut response = [0u8; 4];
+    for _ in 0..queries {
+        rd.read_exact(&mut response).await.unwrap();
+    }
+    writer.await.unwrap();
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:39456".to_owned());
+    let clients: usize = args.next().map(|a| a.parse().unwrap()).unwrap_or(10);
+    let inserts: i32 = args.next().map(|a| a.parse().unwrap()).unwrap_or(100_000);
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| tokio::spawn(client(addr.clone(), inserts)))
+        .collect();
+    for t in tasks {
+        t.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    let messages = clients as f64 * (inserts + inserts / 100) as f64;
+    println!(
+        "{} clients sent {:.0} messages in {:.2?} ({:.0} msg/s)",
+        clients,
+        messages,
+        elapsed,
+        messages / elapsed.as_secs_f64()
+    );
+}
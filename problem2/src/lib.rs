@@ -0,0 +1,628 @@
+use bytes::{Buf, BytesMut};
+use codecs::ProtocolError;
+use common::accept::AcceptLimits;
+use common::framed::{serve_framed, Flow, FramedHandler};
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::memory::{Account, Ledger};
+use common::metrics::{Counter, Scope};
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::ops::Bound::Included;
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+use tokio_util::sync::CancellationToken;
+
+mod quarantine;
+mod serve;
+mod store;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
+
+pub use quarantine::Quarantine;
+pub use serve::serve;
+use store::Store;
+
+// Every request is a type byte followed by two big-endian i32
+const MESSAGE_LEN: usize = 9;
+// Frames remembered by the decoder for quarantine captures, including the
+// one being decoded
+const RECENT_FRAMES: usize = 4;
+// A stored price's key and value plus its share of the B-tree node, roughly
+const PRICE_ENTRY_BYTES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+enum AssetProtoRequest {
+    Insert { timestamp: i32, price: i32 },
+    Query { beginning: i32, end: i32 },
+}
+enum AssetProtoResponse {
+    PeriodMean(i32),
+    ErrorResponse(String),
+}
+#[derive(Debug, Error)]
+enum AssetProtoViolation {
+    // A message that is neither an insert nor a query
+    #[error("unknown message type {0:#04x}")]
+    WrongMessageType(u8),
+    // Sent as an error response, which ends the session
+    #[error("{0}")]
+    Rejected(String),
+}
+
+type AssetProtoError = ProtocolError<AssetProtoViolation>;
+
+#[derive(Default)]
+struct AssetProtoCodec {
+    // Raw bytes of the last few frames decoded
+    recent: VecDeque<u8>,
+    // Bytes decoded so far
+    consumed: u64,
+}
+
+impl AssetProtoCodec {
+    fn recent(&mut self) -> &[u8] {
+        self.recent.make_contiguous()
+    }
+
+    // Offset in the stream of the last frame decoded
+    fn last_frame_offset(&self) -> u64 {
+        self.consumed.saturating_sub(MESSAGE_LEN as u64)
+    }
+}
+
+impl Decoder for AssetProtoCodec {
+    type Item = AssetProtoRequest;
+    type Error = AssetProtoError;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < MESSAGE_LEN {
+            return Ok(None);
+        }
+
+        let data = src[0..MESSAGE_LEN].to_vec();
+        src.advance(MESSAGE_LEN);
+        if self.recent.len() == RECENT_FRAMES * MESSAGE_LEN {
+            self.recent.drain(..MESSAGE_LEN);
+        }
+        self.recent.extend(&data);
+        self.consumed += MESSAGE_LEN as u64;
+
+        let msg_type = data[0];
+        let mut bytes_array = [0u8; 4];
+        bytes_array.copy_from_slice(&data[1..5]);
+        let first_int = i32::from_be_bytes(bytes_array);
+        bytes_array.copy_from_slice(&data[5..9]);
+        let second_int = i32::from_be_bytes(bytes_array);
+        match msg_type as char {
+            'I' => Ok(Some(AssetProtoRequest::Insert {
+                timestamp: first_int,
+                price: second_int,
+            })),
+            'Q' => Ok(Some(AssetProtoRequest::Query {
+                beginning: first_int,
+                end: second_int,
+            })),
+            _ => Err(ProtocolError::Protocol(
+                AssetProtoViolation::WrongMessageType(msg_type),
+            )),
+        }
+    }
+}
+
+impl Encoder<AssetProtoResponse> for AssetProtoCodec {
+    type Error = AssetProtoError;
+
+    fn encode(&mut self, item: AssetProtoResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            AssetProtoResponse::PeriodMean(m) => {
+                dst.extend_from_slice(&m.to_be_bytes());
+                Ok(())
+            }
+            AssetProtoResponse::ErrorResponse(s) => {
+                dst.extend_from_slice(("Error: ".to_owned() + &s).as_bytes());
+                Err(ProtocolError::Protocol(AssetProtoViolation::Rejected(s)))
+            }
+        }
+    }
+}
+
+// Limits enforced in validated arithmetic mode (VALIDATED_ARITHMETIC=true).
+// Inserts outside them are dropped, queries are clipped to the timestamp
+// range, and means are computed exactly with integers instead of floats.
+#[derive(Clone, Copy, Debug)]
+pub struct Bounds {
+    pub min_timestamp: i32,
+    pub max_timestamp: i32,
+    pub min_price: i32,
+    pub max_price: i32,
+}
+
+impl Bounds {
+    pub fn from_env() -> Option<Self> {
+        if !common::env::var_or("VALIDATED_ARITHMETIC", false) {
+            return None;
+        }
+        Some(Bounds {
+            min_timestamp: common::env::var_or("MIN_TIMESTAMP", i32::MIN),
+            max_timestamp: common::env::var_or("MAX_TIMESTAMP", i32::MAX),
+            min_price: common::env::var_or("MIN_PRICE", i32::MIN),
+            max_price: common::env::var_or("MAX_PRICE", i32::MAX),
+        })
+    }
+
+    fn accepts(&self, timestamp: i32, price: i32) -> bool {
+        (self.min_timestamp..=self.max_timestamp).contains(&timestamp)
+            && (self.min_price..=self.max_price).contains(&price)
+    }
+}
+
+// Mean rounded half away from zero, like f64::round. The sum can't overflow an
+// i128, and the mean of i32s always fits back in an i32.
+fn exact_mean<'a>(prices: impl Iterator<Item = &'a i32>) -> i32 {
+    let (sum, count) = prices.fold((0i128, 0i128), |(sum, count), price| {
+        (sum + *price as i128, count + 1)
+    });
+    if count == 0 {
+        return 0;
+    }
+    ((2 * sum + sum.signum() * count) / (2 * count)) as i32
+}
+
+// Whether an insert is kept, given the bounds in force
+fn admits(timestamp: i32, price: i32, bounds: Option<Bounds>) -> bool {
+    let admitted = bounds.is_none_or(|bounds| bounds.accepts(timestamp, price));
+    if !admitted {
+        common::debug!(
+            "Dropping out of bounds insert: timestamp {}, price {}",
+            timestamp,
+            price
+        );
+    }
+    admitted
+}
+
+fn mean(prices: &BTreeMap<i32, i32>, beginning: i32, end: i32, bounds: Option<Bounds>) -> i32 {
+    match bounds {
+        Some(bounds) => {
+            let beginning = beginning.max(bounds.min_timestamp);
+            let end = end.min(bounds.max_timestamp);
+            if beginning <= end {
+                exact_mean(prices.range(beginning..=end).map(|(_k, v)| v))
+            } else {
+                0
+            }
+        }
+        None => {
+            let mean = if beginning <= end {
+                prices
+                    .range((Included(beginning), Included(end)))
+                    .map(|(_k, v)| v)
+                    .zip(1..)
+                    .fold(0., |s, (e, i)| (*e as f64 + s * (i - 1) as f64) / i as f64)
+            } else {
+                0f64
+            };
+            mean.round().clamp(i32::MIN as f64, i32::MAX as f64) as i32
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    inserts: Counter,
+    queries: Counter,
+    malformed: Counter,
+    offloaded: Counter,
+    memory: Ledger,
+}
+
+impl Metrics {
+    fn new(scope: &Scope) -> Self {
+        let requests = |kind| {
+            scope.counter_with(
+                "means_requests_total",
+                "Requests by message type",
+                &[("type", kind)],
+            )
+        };
+        Metrics {
+            inserts: requests("insert"),
+            queries: requests("query"),
+            malformed: requests("malformed"),
+            offloaded: scope.counter(
+                "means_offloaded_queries_total",
+                "Queries averaged on the blocking pool",
+            ),
+            memory: Ledger::new(scope),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Prices {
+    bounds: Option<Bounds>,
+    offload_store_len: usize,
+    quarantine: Option<Quarantine>,
+    metrics: Metrics,
+}
+
+impl ConnectionHandler for Prices {
+    async fn handle<S: ByteStream>(&self, socket: S, peer: Option<SocketAddr>, ctx: Context) {
+        let session = Session {
+            peer,
+            store: Store::new(
+                self.bounds,
+                self.offload_store_len,
+                self.metrics.offloaded.clone(),
+            ),
+            account: self.metrics.memory.open(peer),
+            quarantine: self.quarantine.clone(),
+            metrics: self.metrics.clone(),
+        };
+        let codec = AssetProtoCodec::default;
+        serve_framed(socket, codec(), codec(), session, &ctx).await
+    }
+}
+
+// One client's prices, and the answers still to send it
+struct Session {
+    peer: Option<SocketAddr>,
+    store: Store,
+    account: Account,
+    quarantine: Option<Quarantine>,
+    metrics: Metrics,
+}
+
+impl Session {
+    // Queue every answer still pending
+    async fn drain(&mut self, out: &mut Vec<AssetProtoResponse>) {
+        while let Some(mean) = self.store.next_answer().await {
+            out.push(AssetProtoResponse::PeriodMean(mean));
+        }
+    }
+}
+
+impl FramedHandler<AssetProtoCodec> for Session {
+    type Response = AssetProtoResponse;
+
+    async fn request(
+        &mut self,
+        value: AssetProtoRequest,
+        out: &mut Vec<AssetProtoResponse>,
+    ) -> Flow {
+        common::debug!("Starting service iteration for value: {:?}", value);
+        match value {
+            AssetProtoRequest::Insert { timestamp, price } => {
+                self.metrics.inserts.inc();
+                if self.store.insert(timestamp, price) && !self.account.charge(PRICE_ENTRY_BYTES) {
+                    common::info!("{:?} stored too many prices, closing", self.peer);
+                    self.drain(out).await;
+                    out.push(AssetProtoResponse::ErrorResponse(
+                        "Memory limit exceeded".to_owned(),
+                    ));
+                    return Flow::Close;
+                }
+            }
+            AssetProtoRequest::Query { beginning, end } => {
+                self.metrics.queries.inc();
+                // Held inserts go in before this query runs
+                if self.store.holding() {
+                    self.drain(out).await;
+                }
+                if let Some(mean) = self.store.query(beginning, end) {
+                    out.push(AssetProtoResponse::PeriodMean(mean));
+                }
+            }
+        }
+        Flow::Continue
+    }
+
+    async fn malformed(
+        &mut self,
+        error: AssetProtoError,
+        codec: &mut AssetProtoCodec,
+        unread: &[u8],
+        out: &mut Vec<AssetProtoResponse>,
+    ) {
+        common::debug!("Error parsing value: {:?}", error);
+        self.metrics.malformed.inc();
+        if let (Some(quarantine), Some(AssetProtoViolation::WrongMessageType(_))) =
+            (&self.quarantine, error.protocol())
+        {
+            let offset = codec.last_frame_offset();
+            quarantine
+                .capture(self.peer, offset, codec.recent(), unread)
+                .await;
+        }
+        self.drain(out).await;
+        out.push(AssetProtoResponse::ErrorResponse(
+            "Malformed request (error parsing value)".to_owned(),
+        ));
+    }
+
+    async fn finish(&mut self, out: &mut Vec<AssetProtoResponse>) {
+        self.drain(out).await;
+    }
+
+    async fn answer(&mut self) -> Option<AssetProtoResponse> {
+        self.store
+            .next_answer()
+            .await
+            .map(AssetProtoResponse::PeriodMean)
+    }
+
+    fn backlogged(&self) -> bool {
+        self.store.backlogged()
+    }
+
+    fn account(&self) -> Option<&Account> {
+        Some(&self.account)
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+    // Validated arithmetic mode, if set
+    pub bounds: Option<Bounds>,
+    // Stored prices past which a session's queries run on the blocking pool
+    pub offload_store_len: usize,
+    pub quarantine: Option<Quarantine>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env(),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+            bounds: Bounds::from_env(),
+            offload_store_len: common::env::var_or(
+                "OFFLOAD_STORE_LEN",
+                store::DEFAULT_OFFLOAD_STORE_LEN,
+            ),
+            quarantine: Quarantine::from_env(),
+        }
+    }
+}
+
+// The handler `run` serves, for a server that accepts connections itself
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    let metrics = Metrics::new(scope);
+    common::report::watch_decode_errors(scope, "malformed messages", metrics.malformed.clone());
+    Prices {
+        bounds: config.bounds,
+        offload_store_len: config.offload_store_len,
+        quarantine: config.quarantine.clone(),
+        metrics,
+    }
+}
+
+// Track asset prices on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem2", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let prices = handler(&scope, &config);
+
+    #[cfg(feature = "middleware")]
+    let prices = config.middleware.wrap(prices, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, prices).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codecs::testkit;
+
+    const FULL: Bounds = Bounds {
+        min_timestamp: i32::MIN,
+        max_timestamp: i32::MAX,
+        min_price: i32::MIN,
+        max_price: i32::MAX,
+    };
+
+    fn prices(pairs: &[(i32, i32)]) -> BTreeMap<i32, i32> {
+        pairs.iter().copied().collect()
+    }
+
+    #[test]
+    fn exact_mean_of_extreme_prices() {
+        assert_eq!(exact_mean([i32::MAX; 3].iter()), i32::MAX);
+        assert_eq!(exact_mean([i32::MIN; 3].iter()), i32::MIN);
+        // -0.5, rounded away from zero
+        assert_eq!(exact_mean([i32::MIN, i32::MAX].iter()), -1);
+        assert_eq!(exact_mean([].iter()), 0);
+    }
+
+    #[test]
+    fn exact_mean_rounds_half_away_from_zero() {
+        assert_eq!(exact_mean([1, 2].iter()), 2);
+        assert_eq!(exact_mean([-1, -2].iter()), -2);
+        assert_eq!(exact_mean([-1, 0].iter()), -1);
+        assert_eq!(exact_mean([-3, 0, 0, 0].iter()), -1);
+        assert_eq!(exact_mean([-1, 0, 0, 0].iter()), 0);
+        assert_eq!(exact_mean([-5, -4, -4].iter()), -4);
+    }
+
+    #[test]
+    fn float_and_exact_means_agree_on_halves() {
+        for pairs in [
+            &[(1, -1), (2, -2)][..],
+            &[(1, -1), (2, 0)],
+            &[(1, 1), (2, 2)],
+            &[(1, -7), (2, 2)],
+        ] {
+            let prices = prices(pairs);
+            assert_eq!(
+                mean(&prices, 0, 10, None),
+                mean(&prices, 0, 10, Some(FULL)),
+                "{:?}",
+                pairs
+            );
+        }
+    }
+
+    #[test]
+    fn means_over_the_full_range() {
+        let prices = prices(&[(i32::MIN, i32::MIN), (0, 0), (i32::MAX, i32::MAX)]);
+        for bounds in [None, Some(FULL)] {
+            assert_eq!(mean(&prices, i32::MIN, i32::MAX, bounds), 0);
+            assert_eq!(mean(&prices, i32::MIN, i32::MIN, bounds), i32::MIN);
+            assert_eq!(mean(&prices, i32::MAX, i32::MAX, bounds), i32::MAX);
+        }
+    }
+
+    #[test]
+    fn inverted_queries_are_zero() {
+        let prices = prices(&[(i32::MIN, 5), (0, 5), (i32::MAX, 5)]);
+        for bounds in [None, Some(FULL)] {
+            assert_eq!(mean(&prices, i32::MAX, i32::MIN, bounds), 0);
+            assert_eq!(mean(&prices, 1, 0, bounds), 0);
+        }
+    }
+
+    #[test]
+    fn bounds_clip_queries_and_drop_inserts() {
+        let bounds = Bounds {
+            min_timestamp: 0,
+            max_timestamp: 100,
+            min_price: -10,
+            max_price: 10,
+        };
+        assert!(admits(0, -10, Some(bounds)));
+        assert!(admits(100, 10, Some(bounds)));
+        assert!(!admits(-1, 0, Some(bounds)));
+        assert!(!admits(101, 0, Some(bounds)));
+        assert!(!admits(50, 11, Some(bounds)));
+        assert!(admits(i32::MIN, i32::MIN, None));
+
+        let prices = prices(&[(-5, 100), (50, 4), (150, 100)]);
+        assert_eq!(mean(&prices, i32::MIN, i32::MAX, Some(bounds)), 4);
+        // Clipping leaves nothing in between
+        assert_eq!(mean(&prices, 101, i32::MAX, Some(bounds)), 0);
+        assert_eq!(mean(&prices, i32::MIN, -1, Some(bounds)), 0);
+    }
+
+    fn frame(kind: u8, first: i32, second: i32) -> Vec<u8> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&first.to_be_bytes());
+        frame.extend_from_slice(&second.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn requests_decode_however_they_arrive() {
+        let mut input = frame(b'I', 12345, 101);
+        input.extend(frame(b'Q', i32::MIN, i32::MAX));
+        let requests = testkit::assert_splits_agree(AssetProtoCodec::default, &input).unwrap();
+        let expected = [
+            AssetProtoRequest::Insert {
+                timestamp: 12345,
+                price: 101,
+            },
+            AssetProtoRequest::Query {
+                beginning: i32::MIN,
+                end: i32::MAX,
+            },
+        ];
+        assert_eq!(requests, expected);
+        let mut bad = frame(b'I', 1, 2);
+        bad.extend(frame(b'X', 1, 2));
+        assert!(matches!(
+            testkit::assert_splits_agree(AssetProtoCodec::default, &bad),
+            Err(ProtocolError::Protocol(
+                AssetProtoViolation::WrongMessageType(b'X')
+            ))
+        ));
+    }
+
+    #[test]
+    fn a_request_cut_off_by_the_end_of_the_stream_is_an_error() {
+        testkit::assert_truncations_fail(AssetProtoCodec::default, &frame(b'Q', 0, 100));
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let insert = AssetProtoRequest::Insert {
+            timestamp: -1,
+            price: 0,
+        };
+        let encoded = frame(b'I', -1, 0);
+        assert_eq!(
+            testkit::round_trip(AssetProtoCodec::default, &encoded),
+            insert
+        );
+        let query = AssetProtoRequest::Query {
+            beginning: 7,
+            end: -7,
+        };
+        let encoded = frame(b'Q', 7, -7);
+        assert_eq!(
+            testkit::round_trip(AssetProtoCodec::default, &encoded),
+            query
+        );
+    }
+
+    #[test]
+    fn responses_encode() {
+        let mut codec = AssetProtoCodec::default();
+        let mut out = BytesMut::new();
+        codec
+            .encode(AssetProtoResponse::PeriodMean(-2), &mut out)
+            .unwrap();
+        assert_eq!(&out[..], (-2i32).to_be_bytes());
+        let rejected = codec.encode(AssetProtoResponse::ErrorResponse("bad".into()), &mut out);
+        assert!(matches!(
+            rejected,
+            Err(ProtocolError::Protocol(AssetProtoViolation::Rejected(s))) if s == "bad"
+        ));
+        assert_eq!(&out[4..], b"Error: bad");
+    }
+
+    #[tokio::test]
+    async fn quarantines_an_unknown_frame_with_its_offset_and_context() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quarantine");
+        let config = Config {
+            limits: AcceptLimits::from_env(),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+            bounds: None,
+            offload_store_len: store::DEFAULT_OFFLOAD_STORE_LEN,
+            quarantine: Some(Quarantine::new(path.clone(), 4096)),
+        };
+        let prices = handler(&Scope::new("problem2", 2001), &config);
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move { prices.handle(server, None, Context::new("test")).await });
+
+        let mut input = [frame(b'I', 1, 10), frame(b'I', 2, 20), frame(b'X', 3, 30)].concat();
+        input.extend_from_slice(b"tail");
+        client.write_all(&input).await.unwrap();
+        // The session answers with an error and hangs up once the capture is done
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        assert!(answer.starts_with(b"Error: "));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let record: Vec<_> = contents.trim_end().split(' ').skip(1).collect();
+        let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(
+            record,
+            [
+                "peer=unknown".to_owned(),
+                "offset=18".to_owned(),
+                format!("before={}", hex(&input[..27])),
+                format!("after={}", hex(b"tail")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+// Capture of frames the decoder couldn't make sense of.
+//
+// With QUARANTINE_FILE set, every unknown message type is appended to that
+// file together with the peer, its offset in the stream and the raw bytes
+// around it, so framing disagreements with the checker can be looked at after
+// the fact. The file stops growing at QUARANTINE_MAX_BYTES. Appends run on
+// the blocking pool, off the connection's worker.
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_QUARANTINE_MAX_BYTES: u64 = 1024 * 1024;
+// Bytes kept from after the bad frame
+pub const CONTEXT_AFTER: usize = 64;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Clone)]
+pub struct Quarantine {
+    path: PathBuf,
+    max_bytes: u64,
+    // Serializes the size check and the append
+    lock: Arc<Mutex<()>>,
+}
+
+impl Quarantine {
+    pub fn new(path: PathBuf, max_bytes: u64) -> Self {
+        Quarantine {
+            path,
+            max_bytes,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        let path = common::env::var::<PathBuf>("QUARANTINE_FILE")?;
+        common::dry_run::writable("QUARANTINE_FILE", &path);
+        Some(Quarantine::new(
+            path,
+            common::env::var_or("QUARANTINE_MAX_BYTES", DEFAULT_QUARANTINE_MAX_BYTES),
+        ))
+    }
+
+    // `before` ends with the offending frame, which started at `offset`
+    pub async fn capture(
+        &self,
+        peer: Option<SocketAddr>,
+        offset: u64,
+        before: &[u8],
+        after: &[u8],
+    ) {
+        let after = &after[..after.len().min(CONTEXT_AFTER)];
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let peer = peer.map_or("unknown".to_owned(), |p| p.to_string());
+        let record = format!(
+            "time={} peer={} offset={} before={} after={}\n",
+            timestamp,
+            peer,
+            offset,
+            hex(before),
+            hex(after)
+        );
+        let quarantine = self.clone();
+        let appended = tokio::task::spawn_blocking(move || quarantine.append(&record, &peer));
+        if let Err(e) = appended.await {
+            common::error!("Quarantine append failed: {}", e);
+        }
+    }
+
+    fn append(&self, record: &str, peer: &str) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let size = std::fs::metadata(&self.path).map_or(0, |m| m.len());
+        if size + record.len() as u64 > self.max_bytes {
+            common::warn!("Quarantine file full, not capturing frame from {}", peer);
+            return;
+        }
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(record.as_bytes()));
+        if let Err(e) = written {
+            common::error!("Couldn't write to quarantine file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(record: &str) -> Vec<(&str, &str)> {
+        record
+            .split_whitespace()
+            .map(|field| field.split_once('=').unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn appends_a_line_per_capture() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quarantine");
+        let quarantine = Quarantine::new(path.clone(), 1024);
+        let peer = "127.0.0.1:4000".parse().ok();
+        quarantine.capture(peer, 9, &[0x49, 0xff], &[0x00]).await;
+        quarantine.capture(None, 0, &[], &[]).await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<_> = contents.lines().collect();
+        assert_eq!(records.len(), 2);
+        let first = fields(records[0]);
+        assert_eq!(first[0].0, "time");
+        assert!(first[0].1.parse::<u64>().is_ok());
+        assert_eq!(
+            &first[1..],
+            [
+                ("peer", "127.0.0.1:4000"),
+                ("offset", "9"),
+                ("before", "49ff"),
+                ("after", "00")
+            ]
+        );
+        assert_eq!(
+            &fields(records[1])[1..],
+            [
+                ("peer", "unknown"),
+                ("offset", "0"),
+                ("before", ""),
+                ("after", "")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_only_the_start_of_what_follows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quarantine");
+        let quarantine = Quarantine::new(path.clone(), 1024);
+        quarantine.capture(None, 0, &[1], &[0xab; 100]).await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let after = fields(contents.trim_end())[4].1.to_owned();
+        assert_eq!(after, "ab".repeat(CONTEXT_AFTER));
+    }
+
+    #[tokio::test]
+    async fn stops_growing_at_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quarantine");
+        let quarantine = Quarantine::new(path.clone(), 1024);
+        quarantine.capture(None, 0, &[1; 9], &[]).await;
+        let one = std::fs::metadata(&path).unwrap().len();
+
+        // Room for two records, but not a third
+        let quarantine = Quarantine::new(path.clone(), 2 * one + one / 2);
+        quarantine.capture(None, 0, &[1; 9], &[]).await;
+        quarantine.capture(None, 0, &[1; 9], &[]).await;
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * one);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+    }
+}
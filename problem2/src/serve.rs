@@ -0,0 +1,61 @@
+// Everything the problem2 binary does, callable so that the protohackers
+// binary can run the problem too.
+use crate::Config;
+use common::config::Checker;
+use common::listeners::Listeners;
+use protolib::{Problem, Transport};
+use std::io;
+use std::net::SocketAddr;
+use tokio_util::sync::CancellationToken;
+
+// This problem's own checks, on top of those `checker` makes
+pub fn check_config(checker: Checker) -> Checker {
+    checker
+        .parse::<bool>("VALIDATED_ARITHMETIC")
+        .ordered("MIN_TIMESTAMP", "MAX_TIMESTAMP", i32::MIN, i32::MAX)
+        .ordered("MIN_PRICE", "MAX_PRICE", i32::MIN, i32::MAX)
+        .parent_dir("QUARANTINE_FILE")
+        .parse::<u64>("QUARANTINE_MAX_BYTES")
+        .parse::<usize>("OFFLOAD_STORE_LEN")
+}
+
+// Check the configuration, then serve on `addrs` until the process is stopped
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub fn serve(addrs: &[SocketAddr]) {
+    protolib::check::<Config>(addrs);
+    common::dry_run::finish();
+    common::env::print_config();
+    crate::uring::serve(common::cli::only_addr(addrs, "with uring"));
+}
+
+#[cfg(not(all(target_os = "linux", feature = "uring")))]
+pub fn serve(addrs: &[SocketAddr]) {
+    protolib::main::<Config>(addrs)
+}
+
+impl Problem for Config {
+    const TRANSPORT: Transport = Transport::Tcp;
+    type Listener = Listeners;
+
+    fn name() -> &'static str {
+        "problem2"
+    }
+
+    fn from_env() -> Self {
+        Config::from_env()
+    }
+
+    fn check_config(checker: Checker) -> Checker {
+        check_config(checker)
+    }
+
+    // Served by tokio even when built with uring, as it shares the caller's
+    // runtime
+    async fn bind(&self, addrs: &[SocketAddr]) -> io::Result<Listeners> {
+        common::listeners::bind(addrs).await
+    }
+
+    async fn serve(&self, listener: Listeners, shutdown: CancellationToken) {
+        crate::run(listener, shutdown, self.clone()).await
+    }
+}
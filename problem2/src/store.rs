@@ -0,0 +1,110 @@
+// One session's prices, with wide queries answered off the connection task.
+//
+// Once a session has stored OFFLOAD_STORE_LEN prices (default 100000), each
+// query is averaged on the blocking pool over a snapshot of the store, so the
+// connection keeps decoding while it runs. Answers are handed back in the
+// order the queries came in, whether they were offloaded or not. Inserts that
+// arrive while queries are running are held back and applied once they have
+// all finished, and a query that comes after held inserts waits for that, so
+// every query sees exactly the inserts sent before it.
+use common::metrics::Counter;
+use futures::stream::FuturesOrdered;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+
+use crate::Bounds;
+
+pub const DEFAULT_OFFLOAD_STORE_LEN: usize = 100_000;
+// Queries waiting to be answered before no more requests are read
+const MAX_PENDING_ANSWERS: usize = 16;
+
+type Answer = Pin<Box<dyn Future<Output = i32> + Send>>;
+
+pub(crate) struct Store {
+    prices: Arc<BTreeMap<i32, i32>>,
+    held: Vec<(i32, i32)>,
+    // Answers not sent yet, in request order
+    pending: FuturesOrdered<Answer>,
+    bounds: Option<Bounds>,
+    offload_len: usize,
+    offloaded: Counter,
+}
+
+impl Store {
+    pub(crate) fn new(bounds: Option<Bounds>, offload_len: usize, offloaded: Counter) -> Self {
+        Store {
+            prices: Arc::new(BTreeMap::new()),
+            held: Vec::new(),
+            pending: FuturesOrdered::new(),
+            bounds,
+            offload_len,
+            offloaded,
+        }
+    }
+
+    // Whether enough answers are pending that reading more requests should wait
+    pub(crate) fn backlogged(&self) -> bool {
+        self.pending.len() >= MAX_PENDING_ANSWERS
+    }
+
+    // Whether a query has to wait for pending answers before it can run
+    pub(crate) fn holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    // Store a price, returning whether it may take up a new entry
+    pub(crate) fn insert(&mut self, timestamp: i32, price: i32) -> bool {
+        if !crate::admits(timestamp, price, self.bounds) {
+            return false;
+        }
+        let new = !self.prices.contains_key(&timestamp);
+        if self.pending.is_empty() {
+            Arc::make_mut(&mut self.prices).insert(timestamp, price);
+        } else {
+            self.held.push((timestamp, price));
+        }
+        new
+    }
+
+    // The mean for a query, if it can be answered right away. Otherwise the
+    // answer comes out of `next_answer` after the ones already pending. Must
+    // not be called while `holding`.
+    pub(crate) fn query(&mut self, beginning: i32, end: i32) -> Option<i32> {
+        let bounds = self.bounds;
+        if self.prices.len() < self.offload_len {
+            let mean = crate::mean(&self.prices, beginning, end, bounds);
+            if self.pending.is_empty() {
+                return Some(mean);
+            }
+            self.pending.push_back(Box::pin(std::future::ready(mean)));
+            return None;
+        }
+        self.offloaded.inc();
+        let prices = self.prices.clone();
+        let mean =
+            tokio::task::spawn_blocking(move || crate::mean(&prices, beginning, end, bounds));
+        self.pending.push_back(Box::pin(async move {
+            match mean.await {
+                Ok(mean) => mean,
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                // Only when the runtime is shutting down
+                Err(_) => 0,
+            }
+        }));
+        None
+    }
+
+    // The next pending answer, or None if there are none. Held inserts are
+    // applied once the last one is out.
+    pub(crate) async fn next_answer(&mut self) -> Option<i32> {
+        let mean = self.pending.next().await?;
+        if self.pending.is_empty() {
+            let prices = Arc::make_mut(&mut self.prices);
+            prices.extend(self.held.drain(..));
+        }
+        Some(mean)
+    }
+}
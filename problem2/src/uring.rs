@@ -0,0 +1,115 @@
+// io_uring backend for the asset price server, enabled with the `uring`
+// feature. tokio-uring sockets don't implement AsyncRead/AsyncWrite, so the
+// codec is driven by hand: read into an owned buffer, decode every complete
+// frame, and write all the responses for that read in a single operation.
+use crate::quarantine::Quarantine;
+use crate::{AssetProtoCodec, AssetProtoRequest, AssetProtoResponse, AssetProtoViolation, Bounds};
+use bytes::BytesMut;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tokio_uring::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Decoder, Encoder};
+
+// Queries are answered inline here; offloading needs the tokio runtime
+fn handle_request(
+    prices: &mut BTreeMap<i32, i32>,
+    request: AssetProtoRequest,
+    bounds: Option<Bounds>,
+) -> Option<AssetProtoResponse> {
+    match request {
+        AssetProtoRequest::Insert { timestamp, price } => {
+            if crate::admits(timestamp, price, bounds) {
+                prices.insert(timestamp, price);
+            }
+            None
+        }
+        AssetProtoRequest::Query { beginning, end } => Some(AssetProtoResponse::PeriodMean(
+            crate::mean(prices, beginning, end, bounds),
+        )),
+    }
+}
+
+async fn process_socket(
+    socket: TcpStream,
+    peer: SocketAddr,
+    bounds: Option<Bounds>,
+    quarantine: Option<Quarantine>,
+) {
+    let mut prices = BTreeMap::new();
+    let mut codec = AssetProtoCodec::default();
+    let mut read_buf = BytesMut::new();
+    let mut buf = vec![0u8; 4096];
+
+    loop {
+        let (res, b) = socket.read(buf).await;
+        buf = b;
+        match res {
+            Ok(0) => return,
+            Ok(n) => read_buf.extend_from_slice(&buf[..n]),
+            Err(e) => {
+                common::debug!("Error reading socket: {:?}", e);
+                return;
+            }
+        }
+
+        let mut write_buf = BytesMut::new();
+        let mut closing = false;
+        loop {
+            let response = match codec.decode(&mut read_buf) {
+                Ok(Some(value)) => handle_request(&mut prices, value, bounds),
+                Ok(None) => break,
+                Err(e) => {
+                    common::debug!("Error parsing value: {:?}", e);
+                    if let (Some(quarantine), Some(AssetProtoViolation::WrongMessageType(_))) =
+                        (&quarantine, e.protocol())
+                    {
+                        let offset = codec.last_frame_offset();
+                        quarantine
+                            .capture(Some(peer), offset, codec.recent(), &read_buf)
+                            .await;
+                    }
+                    closing = true;
+                    Some(AssetProtoResponse::ErrorResponse(
+                        "Malformed request (error parsing value)".to_owned(),
+                    ))
+                }
+            };
+            if let Some(response) = response {
+                codec.encode(response, &mut write_buf).unwrap_or(());
+            }
+            if closing {
+                break;
+            }
+        }
+
+        if !write_buf.is_empty() {
+            let (res, _) = socket.write_all(write_buf).await;
+            if let Err(e) = res {
+                common::debug!("Couldn't write to socket: {:?}", e);
+                return;
+            }
+        }
+        if closing {
+            return;
+        }
+    }
+}
+
+pub fn serve(addr: SocketAddr) {
+    tokio_uring::start(async {
+        let listener = TcpListener::bind(addr).unwrap();
+        common::alloc::spawn_stats_reporter();
+        let bounds = Bounds::from_env();
+        let quarantine = Quarantine::from_env();
+
+        loop {
+            match listener.accept().await {
+                Ok((socket, addr)) => {
+                    common::debug!("Accepted connection from {:?}", addr);
+                    tokio_uring::spawn(process_socket(socket, addr, bounds, quarantine.clone()));
+                }
+                Err(e) => common::warn!("Couldn't accept connection: {:?}", e),
+            }
+        }
+    })
+}
@@ -0,0 +1,94 @@
+// Fan-out benchmark for the chat server. Start the server with FAN_OUT set to
+// the strategy under test and run:
+//
+//     cargo run --release --example chat_bench -- [addr] [clients] [messages]
+//
+// Every client joins the room, then the first one sends the given number of
+// messages and the benchmark waits until all the others have received them.
+// Pass 0 clients to run the 10/100/1000 client series.
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+async fn join(addr: &str, name: &str) -> (BufReader<OwnedReadHalf>, OwnedWriteHalf) {
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (rd, mut wr) = socket.into_split();
+    let mut rd = BufReader::new(rd);
+    let mut line = String::new();
+
+    // Greeting, then the room listing after sending the name
+    rd.read_line(&mut line).await.unwrap();
+    wr.write_all(format!("{}\n", name).as_bytes())
+        .await
+        .unwrap();
+    rd.read_line(&mut line).await.unwrap();
+    (rd, wr)
+}
+
+async fn receive(mut rd: BufReader<OwnedReadHalf>, messages: usize) {
+    let mut line = String::new();
+    let mut received = 0;
+    while received < messages {
+        line.clear();
+        if rd.read_line(&mut line).await.unwrap() == 0 {
+            panic!("server closed the connection after {} messages", received);
+        }
+        if line.starts_with("[c0]") {
+            received += 1;
+        }
+    }
+}
+
+async fn run(addr: &str, clients: usize, messages: usize) -> Duration {
+    let mut members = Vec::with_capacity(clients);
+    for i in 0..clients {
+        members.push(join(addr, &format!("c{}", i)).await);
+    }
+
+    let (_, mut sender) = members.remove(0);
+    // Dropping a write half shuts it down, which the server takes as leaving
+    let mut writers = Vec::with_capacity(members.len());
+    let mut receivers = Vec::with_capacity(members.len());
+    for (rd, wr) in members {
+        writers.push(wr);
+        receivers.push(tokio::spawn(receive(rd, messages)));
+    }
+
+    let start = Instant::now();
+    for i in 0..messages {
+        sender
+            .write_all(format!("message number {}\n", i).as_bytes())
+            .await
+            .unwrap();
+    }
+    for r in receivers {
+        r.await.unwrap();
+    }
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:39456".to_owned());
+    let clients: usize = args.next().map(|a| a.parse().unwrap()).unwrap_or(0);
+    let messages: usize = args.next().map(|a| a.parse().unwrap()).unwrap_or(100);
+
+    let series = if clients == 0 {
+        vec![10, 100, 1000]
+    } else {
+        vec![clients]
+    };
+    for clients in series {
+        let elapsed = run(&addr, clients, messages).await;
+        let delivered = ((clients - 1) * messages) as f64;
+        println!(
+            "{} clients: delivered {:.0} messages in {:.2?} ({:.0} msg/s)",
+            clients,
+            delivered,
+            elapsed,
+            delivered / elapsed.as_secs_f64()
+        );
+    }
+}
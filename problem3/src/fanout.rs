@@ -0,0 +1,298 @@
+// Strategies for delivering room events to every connected client.
+//
+// `broadcast` shares one tokio broadcast channel between all clients, while
+// `mpsc` keeps a bounded queue per client and copies each event into all of
+// them. Run examples/chat_bench.rs against both, and watch the fan-out
+// latency histogram (see latency.rs), to compare them. Either one can be
+// wrapped in `Sequenced` to number events in the order they go out, which
+// tests/chat_order.rs checks every client agrees on.
+//
+// Either way, each event queued for a client is charged to its memory
+// account, through the tab it subscribed with, until it takes the event.
+use crate::Event;
+use common::memory::Tab;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+
+pub trait FanOut: Send + Sync + 'static {
+    type Subscriber: Subscriber;
+
+    // Start receiving every event published from now on, charging `tab` for
+    // those waiting to be received
+    fn subscribe(&self, tab: Tab) -> Self::Subscriber;
+    // Queue `ev` for every subscriber, returning how many it was queued for
+    fn publish(&self, ev: Event) -> usize;
+}
+
+pub trait Subscriber: Send + 'static {
+    // Wait for the next event. None means this client fell too far behind (or
+    // the room is gone) and should be disconnected.
+    fn recv(&mut self) -> impl Future<Output = Option<Event>> + Send;
+    // Take an event only if one is already queued
+    fn try_recv(&mut self) -> Option<Event>;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FanOutStrategy {
+    Broadcast,
+    Mpsc,
+}
+
+impl fmt::Display for FanOutStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Broadcast => write!(f, "broadcast"),
+            Self::Mpsc => write!(f, "mpsc"),
+        }
+    }
+}
+
+impl FromStr for FanOutStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "broadcast" => Ok(Self::Broadcast),
+            "mpsc" => Ok(Self::Mpsc),
+            _ => Err(format!("unknown fan-out strategy {:?}", s)),
+        }
+    }
+}
+
+// Subscribers' tabs by id, to charge for each event sent
+type Tabs = Arc<Mutex<BTreeMap<u64, Tab>>>;
+
+fn lock(tabs: &Tabs) -> std::sync::MutexGuard<'_, BTreeMap<u64, Tab>> {
+    tabs.lock()
+        .unwrap_or_else(|e| panic!("Error locking subscriber tabs: {}", e))
+}
+
+pub struct BroadcastFanOut {
+    tx: broadcast::Sender<Event>,
+    tabs: Tabs,
+    next_id: Mutex<u64>,
+}
+
+impl BroadcastFanOut {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _rx) = broadcast::channel(capacity);
+        BroadcastFanOut {
+            tx,
+            tabs: Arc::default(),
+            next_id: Mutex::new(0),
+        }
+    }
+}
+
+impl FanOut for BroadcastFanOut {
+    type Subscriber = BroadcastSubscriber;
+
+    fn subscribe(&self, tab: Tab) -> Self::Subscriber {
+        let id = {
+            let mut next_id = self
+                .next_id
+                .lock()
+                .unwrap_or_else(|e| panic!("Error locking subscriber ids: {}", e));
+            *next_id += 1;
+            *next_id
+        };
+        // Under the lock, so that every event it gets is charged
+        let mut tabs = lock(&self.tabs);
+        tabs.insert(id, tab.clone());
+        BroadcastSubscriber {
+            rx: self.tx.subscribe(),
+            tab,
+            id,
+            tabs: self.tabs.clone(),
+        }
+    }
+
+    fn publish(&self, ev: Event) -> usize {
+        let tabs = lock(&self.tabs);
+        let size = ev.size();
+        // Only fails when nobody is listening
+        let sent = self.tx.send(ev).unwrap_or(0);
+        if sent > 0 {
+            for tab in tabs.values() {
+                tab.charge(size);
+            }
+        }
+        sent
+    }
+}
+
+pub struct BroadcastSubscriber {
+    rx: broadcast::Receiver<Event>,
+    tab: Tab,
+    id: u64,
+    tabs: Tabs,
+}
+
+impl BroadcastSubscriber {
+    fn received(&self, ev: Event) -> Event {
+        self.tab.credit(ev.size());
+        ev
+    }
+}
+
+impl Drop for BroadcastSubscriber {
+    fn drop(&mut self) {
+        lock(&self.tabs).remove(&self.id);
+    }
+}
+
+impl Subscriber for BroadcastSubscriber {
+    async fn recv(&mut self) -> Option<Event> {
+        let ev = self.rx.recv().await.ok()?;
+        Some(self.received(ev))
+    }
+
+    fn try_recv(&mut self) -> Option<Event> {
+        let ev = self.rx.try_recv().ok()?;
+        Some(self.received(ev))
+    }
+}
+
+pub struct MpscFanOut {
+    capacity: usize,
+    clients: Mutex<Vec<(mpsc::Sender<Event>, Tab)>>,
+}
+
+impl MpscFanOut {
+    pub fn new(capacity: usize) -> Self {
+        MpscFanOut {
+            capacity,
+            clients: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl FanOut for MpscFanOut {
+    type Subscriber = MpscSubscriber;
+
+    fn subscribe(&self, tab: Tab) -> Self::Subscriber {
+        let (tx, rx) = mpsc::channel(self.capacity);
+        self.clients
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking client list: {}", e))
+            .push((tx, tab.clone()));
+        MpscSubscriber { rx, tab }
+    }
+
+    fn publish(&self, ev: Event) -> usize {
+        // A client whose queue is full, or that is over its memory limit,
+        // gets dropped, which closes its receiver and disconnects it, the
+        // same way a lagging broadcast receiver is. It leaves the room on its
+        // way out, freeing its name.
+        let size = ev.size();
+        let mut clients = self
+            .clients
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking client list: {}", e));
+        clients.retain(|(tx, tab)| tx.try_send(ev.clone()).is_ok() && tab.charge(size));
+        clients.len()
+    }
+}
+
+pub struct MpscSubscriber {
+    rx: mpsc::Receiver<Event>,
+    tab: Tab,
+}
+
+impl Subscriber for MpscSubscriber {
+    async fn recv(&mut self) -> Option<Event> {
+        let ev = self.rx.recv().await?;
+        self.tab.credit(ev.size());
+        Some(ev)
+    }
+
+    fn try_recv(&mut self) -> Option<Event> {
+        let ev = self.rx.try_recv().ok()?;
+        self.tab.credit(ev.size());
+        Some(ev)
+    }
+}
+
+// Numbers events in publish order, if enabled. Publishing is serialized while
+// numbering, so the numbers follow the order the inner strategy queues events
+// in.
+pub struct Sequenced<F> {
+    inner: F,
+    next: Option<Mutex<u64>>,
+}
+
+impl<F> Sequenced<F> {
+    pub fn new(inner: F, enabled: bool) -> Self {
+        Sequenced {
+            inner,
+            next: enabled.then(|| Mutex::new(0)),
+        }
+    }
+}
+
+impl<F: FanOut> FanOut for Sequenced<F> {
+    type Subscriber = F::Subscriber;
+
+    fn subscribe(&self, tab: Tab) -> Self::Subscriber {
+        self.inner.subscribe(tab)
+    }
+
+    fn publish(&self, mut ev: Event) -> usize {
+        let Some(next) = &self.next else {
+            return self.inner.publish(ev);
+        };
+        let mut next = next
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking sequence number: {}", e));
+        ev.seq = Some(*next);
+        *next += 1;
+        self.inner.publish(ev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EventKind;
+    use common::memory::Ledger;
+    use common::metrics::Scope;
+
+    fn message(text: &str) -> Event {
+        EventKind::Msg {
+            user: "alice".parse().unwrap(),
+            msg: text.parse().unwrap(),
+        }
+        .into()
+    }
+
+    // Queued events are charged to the subscriber until it takes them
+    async fn charges_queued_events<F: FanOut>(fan_out: F, port: u16) {
+        let ledger = Ledger::new(&Scope::new("problem3", port));
+        let account = ledger.open(None);
+        let mut rx = fan_out.subscribe(account.tab());
+        let (short, long) = (message("hi"), message("a longer message"));
+        let sizes = (short.size(), long.size());
+        assert_eq!(fan_out.publish(short), 1);
+        assert_eq!(fan_out.publish(long), 1);
+        assert_eq!(account.used(), sizes.0 + sizes.1);
+
+        rx.recv().await.unwrap();
+        assert_eq!(account.used(), sizes.1);
+        rx.try_recv().unwrap();
+        assert_eq!(account.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn broadcast_charges_queued_events() {
+        charges_queued_events(BroadcastFanOut::new(16), 1).await;
+    }
+
+    #[tokio::test]
+    async fn mpsc_charges_queued_events() {
+        charges_queued_events(MpscFanOut::new(16), 2).await;
+    }
+}
@@ -0,0 +1,103 @@
+// Fan-out latency: how long a chat line takes from being read off its
+// sender's socket to being written to the last client it goes to.
+//
+// Every message is published with a `Trace` counting the clients still to
+// write it. Publishing adds the number of clients the fan-out handed it to,
+// and each of them takes one off once its write has completed, so whichever
+// brings the count to zero (it only can after both) records the latency in
+// the chat_fanout_latency_micros histogram. A message some client never
+// writes, because it left or fell behind, isn't recorded. With FANOUT_TRACE
+// set, each recorded message is also logged with its timings. p50 and p99
+// are served at /fanout on the metrics endpoint.
+use common::metrics::{Histogram, Scope};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const BOUNDS_MICROS: &[u64] = &[
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+
+#[derive(Debug)]
+pub(crate) struct Tracer {
+    latency: Histogram,
+    spans: bool,
+}
+
+impl Tracer {
+    pub(crate) fn new(scope: &Scope, spans: bool) -> Arc<Self> {
+        let latency = scope.histogram(
+            "chat_fanout_latency_micros",
+            "Time from reading a chat message to the last client's write of it",
+            BOUNDS_MICROS,
+        );
+        let page = latency.clone();
+        common::metrics::register_page("/fanout", move || {
+            let quantile = |q| match page.quantile(q) {
+                Some(bound) => format!("<= {}us", bound),
+                None if page.count() == 0 => "-".to_owned(),
+                None => format!("> {}us", BOUNDS_MICROS[BOUNDS_MICROS.len() - 1]),
+            };
+            format!(
+                "messages: {}\np50: {}\np99: {}\n",
+                page.count(),
+                quantile(0.5),
+                quantile(0.99)
+            )
+        });
+        Arc::new(Tracer { latency, spans })
+    }
+
+    // Start timing a message read at `received`
+    pub(crate) fn start(self: &Arc<Self>, received: Instant) -> Arc<Trace> {
+        Arc::new(Trace {
+            tracer: self.clone(),
+            received,
+            published: Default::default(),
+            pending: AtomicI64::new(0),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    tracer: Arc<Tracer>,
+    received: Instant,
+    published: std::sync::OnceLock<(Duration, usize)>,
+    // Recipients yet to write the message, minus those not counted yet
+    pending: AtomicI64,
+}
+
+impl Trace {
+    // The message was handed to `recipients` clients
+    pub(crate) fn published(&self, recipients: usize) {
+        let published = (self.received.elapsed(), recipients);
+        self.published.set(published).unwrap_or(());
+        let recipients = recipients as i64;
+        if self.pending.fetch_add(recipients, Ordering::AcqRel) + recipients == 0 {
+            self.finish();
+        }
+    }
+
+    // One recipient has finished writing the message
+    pub(crate) fn delivered(&self) {
+        if self.pending.fetch_sub(1, Ordering::AcqRel) - 1 == 0 {
+            self.finish();
+        }
+    }
+
+    fn finish(&self) {
+        let elapsed = self.received.elapsed();
+        self.tracer.latency.observe(elapsed.as_micros() as u64);
+        if self.tracer.spans {
+            let (published, recipients) = self.published.get().copied().unwrap_or_default();
+            common::info!(
+                "Fan-out trace: published after {:?}, written to all {} clients after {:?}",
+                published,
+                recipients,
+                elapsed
+            );
+        }
+    }
+}
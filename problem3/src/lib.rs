@@ -0,0 +1,468 @@
+use ascii::AsciiString;
+use codecs::AsciiLinesCodec;
+use common::accept::AcceptLimits;
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::memory::Ledger;
+use common::metrics::{Counter, Scope};
+use fanout::{BroadcastFanOut, FanOut, MpscFanOut, Sequenced, Subscriber};
+use latency::{Trace, Tracer};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tokio_util::sync::CancellationToken;
+
+mod fanout;
+mod latency;
+mod serve;
+mod users;
+
+pub use fanout::FanOutStrategy;
+pub use serve::serve;
+use users::{Join, Users};
+
+// Longer lines are discarded instead of buffered indefinitely
+const DEFAULT_MAX_LINE_LENGTH: usize = 16 * 1024;
+
+// Events a client can fall behind by before it is disconnected
+const EVENT_QUEUE_LEN: usize = 1000;
+
+// Maximum number of queued events written to a client in a single call
+const MAX_WRITE_BATCH: usize = 64;
+
+#[derive(Clone, Debug)]
+pub enum EventKind {
+    Msg { user: AsciiString, msg: AsciiString },
+    NewUser { user: AsciiString },
+    UserLeft { user: AsciiString },
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    // Position in the room's history, in sequence numbers mode
+    pub seq: Option<u64>,
+    pub kind: EventKind,
+    // For timing how long a message takes to reach everyone
+    pub trace: Option<Arc<Trace>>,
+}
+
+impl Event {
+    // Roughly what it takes up queued
+    pub(crate) fn size(&self) -> usize {
+        let text = match &self.kind {
+            EventKind::Msg { user, msg } => user.len() + msg.len(),
+            EventKind::NewUser { user } | EventKind::UserLeft { user } => user.len(),
+        };
+        std::mem::size_of::<Event>() + text
+    }
+}
+
+impl From<EventKind> for Event {
+    fn from(kind: EventKind) -> Self {
+        Event {
+            seq: None,
+            kind,
+            trace: None,
+        }
+    }
+}
+
+// Append the line sent to the given user for an event, if it should be sent at
+// all. In sequence numbers mode the line starts with "#<seq> ".
+fn render_event(ev: &Event, name: &AsciiString, out: &mut Vec<u8>) {
+    let start = out.len();
+    match &ev.kind {
+        EventKind::Msg { user: u, msg: m } if u != name => {
+            out.push(b'[');
+            out.extend_from_slice(u.as_bytes());
+            out.extend_from_slice(b"] ");
+            out.extend_from_slice(m.as_bytes());
+            out.push(b'\n');
+        }
+        EventKind::NewUser { user: u } if u != name => {
+            out.extend_from_slice(b"* ");
+            out.extend_from_slice(u.as_bytes());
+            out.extend_from_slice(b" has entered the room\n");
+        }
+        EventKind::UserLeft { user: u } if u != name => {
+            out.extend_from_slice(b"* ");
+            out.extend_from_slice(u.as_bytes());
+            out.extend_from_slice(b" has left the room\n");
+        }
+        _ => {}
+    }
+    if let Some(seq) = ev.seq.filter(|_| out.len() > start) {
+        out.splice(start..start, format!("#{} ", seq).into_bytes());
+    }
+}
+
+fn valid_name(name: &AsciiString) -> bool {
+    !name.is_empty() && name.chars().all(|ch| ch.is_ascii_alphanumeric())
+}
+
+#[derive(Clone)]
+struct Metrics {
+    messages: Counter,
+    fan_out: Arc<Tracer>,
+    memory: Ledger,
+}
+
+impl Metrics {
+    fn new(scope: &Scope, config: &Config) -> Self {
+        Metrics {
+            messages: scope.counter("chat_messages_total", "Chat messages sent to the room"),
+            fan_out: Tracer::new(scope, config.fan_out_trace),
+            memory: Ledger::new(scope),
+        }
+    }
+}
+
+// A name in the room, which leaves it when this is dropped, however the
+// session ends
+struct Member<F: FanOut> {
+    users: Arc<Users>,
+    fan_out: Arc<F>,
+    name: AsciiString,
+    from: Option<IpAddr>,
+}
+
+impl<F: FanOut> Drop for Member<F> {
+    fn drop(&mut self) {
+        self.users.leave(&self.name, self.from, &self.fan_out);
+    }
+}
+
+async fn process_socket<F: FanOut, S: AsyncRead + AsyncWrite>(
+    socket: S,
+    peer: Option<SocketAddr>,
+    users: Arc<Users>,
+    fan_out: Arc<F>,
+    max_line_length: usize,
+    metrics: Metrics,
+    ctx: Context,
+) {
+    let cancel = ctx.cancel;
+    let (rd, mut wr) = tokio::io::split(socket);
+    let mut line_delimited = FramedRead::new(rd, AsciiLinesCodec::new(max_line_length));
+    // Queued events are charged by the room, the buffers here
+    let account = metrics.memory.open(peer);
+    let mut buffers = account.hold();
+
+    // Read username
+    ctx.task.phase("reading name");
+    wr.write_all(b"Welcome to budgetchat! What shall I call you?\n")
+        .await
+        .unwrap_or(());
+    let name = tokio::select! {
+        name = line_delimited.next() => name,
+        _ = cancel.cancelled() => return,
+    };
+    let name = match name {
+        Some(Ok(n)) => n,
+        None => {
+            common::debug!("Connection closed while reading username");
+            return;
+        }
+        Some(Err(e)) => {
+            common::debug!("Error reading username: {}", e);
+            return;
+        }
+    };
+
+    let from = peer.map(|p| p.ip());
+    let mut rx = match users.join(&name, from) {
+        Join::Entered(user_list) => {
+            fan_out.publish(EventKind::NewUser { user: name.clone() }.into());
+            let rx = fan_out.subscribe(account.tab());
+            wr.write_all(format!("* The room contains: {}\n", user_list).as_bytes())
+                .await
+                .unwrap_or(());
+            rx
+        }
+        // Nobody saw them leave, so don't announce them again
+        Join::Returned(user_list) => {
+            let rx = fan_out.subscribe(account.tab());
+            wr.write_all(format!("* The room contains: {}\n", user_list).as_bytes())
+                .await
+                .unwrap_or(());
+            rx
+        }
+        Join::Rejected => {
+            wr.write_all(b"Illegal username\n").await.unwrap_or(());
+            return;
+        }
+    };
+
+    let member = Member {
+        users,
+        fan_out,
+        name,
+        from,
+    };
+    let (fan_out, name) = (&member.fan_out, &member.name);
+
+    // Outgoing lines are assembled here, reusing the allocation across events,
+    // with where each one ends
+    let mut out = Vec::new();
+    let mut ends = Vec::new();
+    // Traces of the events in `out`, to mark delivered once it is written
+    let mut traces = Vec::new();
+
+    // Main event loop
+    loop {
+        let held = line_delimited.read_buffer().capacity() + out.capacity();
+        if !buffers.set(held) || !account.within_limit() {
+            common::info!("{} is over the memory limit, disconnecting", name);
+            return;
+        }
+        ctx.task.phase("waiting for events");
+        tokio::select! {
+            ev = rx.recv() => {
+                let Some(ev) = ev else {
+                    // Fell behind, or was dropped by the room
+                    common::debug!("{} stopped getting events, disconnecting", name);
+                    return;
+                };
+                // Drain whatever else is already queued so a burst of events
+                // goes out in a single vectored write
+                out.clear();
+                ends.clear();
+                let mut next = Some(ev);
+                for _ in 0..MAX_WRITE_BATCH {
+                    let Some(ev) = next.take().or_else(|| rx.try_recv()) else {
+                        break;
+                    };
+                    render_event(&ev, name, &mut out);
+                    if out.len() > ends.last().copied().unwrap_or(0) {
+                        ends.push(out.len());
+                    }
+                    traces.extend(ev.trace);
+                }
+                if !out.is_empty() {
+                    ctx.task.phase("writing events");
+                    tokio::select! {
+                        written = common::framed::write_frames(&mut wr, &out, &ends) => {
+                            written.unwrap_or(())
+                        }
+                        _ = cancel.cancelled() => return,
+                    }
+                }
+                for trace in traces.drain(..) {
+                    trace.delivered();
+                }
+            },
+            m = line_delimited.next() => {
+                if let Some(m) = m {
+                    match m {
+                        Ok(m) => {
+                            metrics.messages.inc();
+                            let trace = metrics.fan_out.start(Instant::now());
+                            let recipients = fan_out.publish(Event {
+                                trace: Some(trace.clone()),
+                                ..EventKind::Msg{ user: name.clone(), msg: m}.into()
+                            });
+                            trace.published(recipients);
+                        },
+                        Err(e) => {
+                            common::debug!("Error reading message: {}", e);
+                        }
+                    }
+                } else {
+                    return;
+                }
+            },
+            _ = cancel.cancelled() => return,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+    pub max_line_length: usize,
+    pub fan_out: FanOutStrategy,
+    // How long the name of someone who disconnected stays reserved for them
+    pub name_grace: Duration,
+    // Prefix every event line with its room-wide sequence number, to check
+    // that all clients see events in the same order. Breaks the protocol.
+    pub sequence_numbers: bool,
+    // Log the timings of every message's fan-out, not just the histogram
+    pub fan_out_trace: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env(),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+            max_line_length: common::env::var_or("MAX_LINE_LENGTH", DEFAULT_MAX_LINE_LENGTH),
+            fan_out: common::env::var_or("FAN_OUT", FanOutStrategy::Broadcast),
+            name_grace: Duration::from_millis(common::env::var_or("NAME_GRACE_MILLIS", 0)),
+            sequence_numbers: common::env::var_or("SEQUENCE_NUMBERS", false),
+            fan_out_trace: common::env::var_or("FANOUT_TRACE", false),
+        }
+    }
+}
+
+struct Chat<F> {
+    users: Arc<Users>,
+    fan_out: Arc<F>,
+    max_line_length: usize,
+    metrics: Metrics,
+}
+
+// Derived Clone would want F: Clone, though only the Arc is cloned
+impl<F> Clone for Chat<F> {
+    fn clone(&self) -> Self {
+        Chat {
+            users: self.users.clone(),
+            fan_out: self.fan_out.clone(),
+            max_line_length: self.max_line_length,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<F: FanOut> ConnectionHandler for Chat<F> {
+    async fn handle<S: ByteStream>(&self, socket: S, peer: Option<SocketAddr>, ctx: Context) {
+        process_socket(
+            socket,
+            peer,
+            self.users.clone(),
+            self.fan_out.clone(),
+            self.max_line_length,
+            self.metrics.clone(),
+            ctx,
+        )
+        .await
+    }
+}
+
+fn chat<F>(scope: &Scope, config: &Config, fan_out: F) -> Chat<Sequenced<F>> {
+    if config.sequence_numbers {
+        common::info!("Sequence numbers mode: event lines are prefixed with \"#<seq> \"");
+    }
+    let fan_out = Sequenced::new(fan_out, config.sequence_numbers);
+    Chat {
+        users: Arc::new(Users::new(
+            config.name_grace,
+            scope.gauge("chat_users", "Users in the room"),
+        )),
+        fan_out: Arc::new(fan_out),
+        max_line_length: config.max_line_length,
+        metrics: Metrics::new(scope, config),
+    }
+}
+
+// The room with whichever fan-out strategy was configured
+#[derive(Clone)]
+enum Room {
+    Broadcast(Chat<Sequenced<BroadcastFanOut>>),
+    Mpsc(Chat<Sequenced<MpscFanOut>>),
+}
+
+impl ConnectionHandler for Room {
+    async fn handle<S: ByteStream>(&self, socket: S, peer: Option<SocketAddr>, ctx: Context) {
+        match self {
+            Room::Broadcast(chat) => chat.handle(socket, peer, ctx).await,
+            Room::Mpsc(chat) => chat.handle(socket, peer, ctx).await,
+        }
+    }
+}
+
+// The handler `run` serves, for a server that accepts connections itself
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    match config.fan_out {
+        FanOutStrategy::Broadcast => {
+            Room::Broadcast(chat(scope, config, BroadcastFanOut::new(EVENT_QUEUE_LEN)))
+        }
+        FanOutStrategy::Mpsc => Room::Mpsc(chat(scope, config, MpscFanOut::new(EVENT_QUEUE_LEN))),
+    }
+}
+
+// Run the chat room on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem3", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let chat = handler(&scope, &config);
+
+    #[cfg(feature = "middleware")]
+    let chat = config.middleware.wrap(chat, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(chat.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(chat.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "isl")]
+    isl::spawn_from_env(chat.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, chat).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncBufReadExt, AsyncReadExt, BufReader, DuplexStream};
+
+    // A client of `chat` that has joined as `name`, with the greeting read
+    async fn join<F: FanOut>(chat: &Chat<F>, name: &str) -> BufReader<DuplexStream> {
+        let (client, server) = duplex(256);
+        let chat = chat.clone();
+        tokio::spawn(async move { chat.handle(server, None, Context::new("test")).await });
+        let mut client = BufReader::new(client);
+        let mut greeting = String::new();
+        client.read_line(&mut greeting).await.unwrap();
+        client
+            .get_mut()
+            .write_all(format!("{}\n", name).as_bytes())
+            .await
+            .unwrap();
+        client
+    }
+
+    async fn read_line(client: &mut BufReader<DuplexStream>) -> String {
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        line
+    }
+
+    #[tokio::test]
+    async fn a_client_dropped_for_memory_leaves_and_frees_its_name() {
+        let config = Config {
+            fan_out: FanOutStrategy::Mpsc,
+            ..Config::from_env()
+        };
+        let scope = Scope::new("problem3", 1);
+        let mut chat = chat(&scope, &config, MpscFanOut::new(EVENT_QUEUE_LEN));
+        chat.metrics.memory = Ledger::new(&scope).with_limit(Some(32 * 1024));
+
+        // Doesn't read until everything is sent, so its events pile up
+        let mut bob = join(&chat, "bob").await;
+        read_line(&mut bob).await;
+        let mut alice = join(&chat, "alice").await;
+        read_line(&mut alice).await;
+        let line = format!("{}\n", "x".repeat(100));
+        for _ in 0..500 {
+            alice.get_mut().write_all(line.as_bytes()).await.unwrap();
+        }
+
+        // Bob gets what was queued before he was dropped, then is disconnected
+        let mut rest = Vec::new();
+        bob.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.len() < 500 * line.len());
+
+        // Alice's own messages aren't echoed, so the next line is bob leaving
+        assert_eq!(read_line(&mut alice).await, "* bob has left the room\n");
+        let mut bob = join(&chat, "bob").await;
+        assert_eq!(read_line(&mut bob).await, "* The room contains: alice\n");
+    }
+}
@@ -0,0 +1,240 @@
+// Names in the room.
+//
+// With NAME_GRACE_MILLIS set, a user whose connection drops keeps their name
+// reserved for that long. Reconnecting from the same IP address with the same
+// name within the window picks up where they left off without anyone seeing
+// them leave and re-enter; anyone else asking for the name meanwhile is
+// refused. Otherwise the name is released and the leave notice goes out late.
+// A client whose address isn't known has nothing to reclaim a name by, so
+// its name is released as soon as it leaves.
+use crate::fanout::FanOut;
+use crate::EventKind;
+use ascii::AsciiString;
+use common::metrics::Gauge;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+enum Presence {
+    Connected,
+    Reserved {
+        // Identifies the disconnection, so a timer from an earlier one can't
+        // release a name that was reclaimed and dropped again since
+        disconnection: u64,
+        // Where the user connected from, and may reclaim the name from
+        from: IpAddr,
+    },
+}
+
+pub enum Join {
+    // New to the room, with everyone else already in it
+    Entered(AsciiString),
+    // Reclaimed a reserved name, with everyone else already in it
+    Returned(AsciiString),
+    Rejected,
+}
+
+pub struct Users {
+    names: Mutex<BTreeMap<AsciiString, Presence>>,
+    grace: Duration,
+    disconnections: AtomicU64,
+    gauge: Gauge,
+}
+
+impl Users {
+    pub fn new(grace: Duration, gauge: Gauge) -> Self {
+        Users {
+            names: Mutex::new(BTreeMap::new()),
+            grace,
+            disconnections: AtomicU64::new(0),
+            gauge,
+        }
+    }
+
+    // Join as `name`, for a client connecting from `from`
+    pub fn join(&self, name: &AsciiString, from: Option<IpAddr>) -> Join {
+        let mut names = self
+            .names
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking user list: {}", e));
+        if !crate::valid_name(name) {
+            return Join::Rejected;
+        }
+        let joined = match names.get(name) {
+            Some(Presence::Connected) => return Join::Rejected,
+            Some(Presence::Reserved { from: held_for, .. }) if Some(*held_for) == from => {
+                Join::Returned
+            }
+            Some(Presence::Reserved { .. }) => return Join::Rejected,
+            None => Join::Entered,
+        };
+        names.insert(name.clone(), Presence::Connected);
+        self.gauge.set(names.len() as i64);
+
+        let user_list = names
+            .keys()
+            .filter(|x| *x != name)
+            .cloned()
+            .reduce(|a, b| a.clone() + &AsciiString::from_ascii(", ").unwrap() + &b)
+            .unwrap_or(AsciiString::from_ascii("").unwrap());
+        joined(user_list)
+    }
+
+    // Leave, as the client that joined as `name` from `from`
+    pub fn leave<F: FanOut>(
+        self: &Arc<Self>,
+        name: &AsciiString,
+        from: Option<IpAddr>,
+        fan_out: &Arc<F>,
+    ) {
+        let Some(from) = from.filter(|_| !self.grace.is_zero()) else {
+            self.release(name, None);
+            fan_out.publish(EventKind::UserLeft { user: name.clone() }.into());
+            return;
+        };
+
+        let disconnection = self.disconnections.fetch_add(1, Ordering::Relaxed);
+        self.names
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking user list: {}", e))
+            .insert(
+                name.clone(),
+                Presence::Reserved {
+                    disconnection,
+                    from,
+                },
+            );
+
+        let (users, name, fan_out) = (self.clone(), name.clone(), fan_out.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(users.grace).await;
+            if users.release(&name, Some(disconnection)) {
+                fan_out.publish(EventKind::UserLeft { user: name }.into());
+            }
+        });
+    }
+
+    // Drop a name, only if it's still reserved for the given disconnection
+    // when one is given. Returns whether it was dropped.
+    fn release(&self, name: &AsciiString, disconnection: Option<u64>) -> bool {
+        let mut names = self
+            .names
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking user list: {}", e));
+        match (names.get(name), disconnection) {
+            (Some(Presence::Reserved { disconnection, .. }), Some(expected))
+                if *disconnection == expected => {}
+            (Some(_), None) => {}
+            _ => return false,
+        }
+        names.remove(name);
+        self.gauge.set(names.len() as i64);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fanout::{BroadcastFanOut, Subscriber};
+    use common::memory::Ledger;
+    use common::metrics::Scope;
+
+    const GRACE: Duration = Duration::from_secs(5);
+
+    fn users() -> Arc<Users> {
+        let scope = Scope::new("problem3", 3);
+        Arc::new(Users::new(
+            GRACE,
+            scope.gauge("chat_users", "Users in the room"),
+        ))
+    }
+
+    fn name(name: &str) -> AsciiString {
+        name.parse().unwrap()
+    }
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([127, 0, 0, last]))
+    }
+
+    fn entered(join: Join) -> bool {
+        matches!(join, Join::Entered(_))
+    }
+
+    fn returned(join: Join) -> bool {
+        matches!(join, Join::Returned(_))
+    }
+
+    fn rejected(join: Join) -> bool {
+        matches!(join, Join::Rejected)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_a_dropped_name_for_the_grace_period() {
+        let (users, fan_out) = (users(), Arc::new(BroadcastFanOut::new(16)));
+        assert!(entered(users.join(&name("alice"), ip(1))));
+        users.leave(&name("alice"), ip(1), &fan_out);
+
+        tokio::time::sleep(GRACE - Duration::from_millis(1)).await;
+        assert!(rejected(users.join(&name("alice"), ip(2))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_same_client_reclaims_its_name_without_leaving() {
+        let (users, fan_out) = (users(), Arc::new(BroadcastFanOut::new(16)));
+        let ledger = Ledger::new(&Scope::new("problem3", 3));
+        let account = ledger.open(None);
+        let mut rx = fan_out.subscribe(account.tab());
+        assert!(entered(users.join(&name("alice"), ip(1))));
+        users.leave(&name("alice"), ip(1), &fan_out);
+
+        tokio::time::sleep(GRACE / 2).await;
+        assert!(returned(users.join(&name("alice"), ip(1))));
+        // The timer from the dropped connection doesn't release the name
+        tokio::time::sleep(GRACE).await;
+        assert!(rejected(users.join(&name("alice"), ip(1))));
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn releases_the_name_once_the_grace_period_is_over() {
+        let (users, fan_out) = (users(), Arc::new(BroadcastFanOut::new(16)));
+        let ledger = Ledger::new(&Scope::new("problem3", 3));
+        let account = ledger.open(None);
+        let mut rx = fan_out.subscribe(account.tab());
+        assert!(entered(users.join(&name("alice"), ip(1))));
+        users.leave(&name("alice"), ip(1), &fan_out);
+
+        tokio::time::sleep(GRACE + Duration::from_millis(1)).await;
+        let left = rx.try_recv().unwrap();
+        assert!(matches!(left.kind, EventKind::UserLeft { user } if user == "alice"));
+        assert!(entered(users.join(&name("alice"), ip(2))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refuses_a_reserved_name_to_a_different_client() {
+        let (users, fan_out) = (users(), Arc::new(BroadcastFanOut::new(16)));
+        assert!(entered(users.join(&name("alice"), ip(1))));
+        users.leave(&name("alice"), ip(1), &fan_out);
+
+        assert!(rejected(users.join(&name("alice"), ip(2))));
+        assert!(rejected(users.join(&name("alice"), None)));
+        // and the refusal leaves the reservation with its owner
+        assert!(returned(users.join(&name("alice"), ip(1))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn releases_the_name_of_a_client_without_an_address_at_once() {
+        let (users, fan_out) = (users(), Arc::new(BroadcastFanOut::new(16)));
+        assert!(entered(users.join(&name("alice"), None)));
+        users.leave(&name("alice"), None, &fan_out);
+
+        assert!(entered(users.join(&name("alice"), None)));
+        users.leave(&name("alice"), None, &fan_out);
+        // and nobody is kept waiting for it
+        assert!(entered(users.join(&name("alice"), ip(1))));
+    }
+}
@@ -0,0 +1,186 @@
+// Ordering checks for the chat server, against a server on an ephemeral port.
+//
+// Every client joins the room, then all of them send their messages at once,
+// and each one records what it receives until it has seen everyone else's
+// messages. A check fails if two clients saw the messages neither of them
+// sent in different orders. When the lines carry sequence numbers, it also
+// fails if a client's numbers don't go up, or if two clients got different
+// lines under the same number.
+//
+// The server disconnects clients that fall 1000 events behind, so clients
+// times messages has to stay under that.
+use problem3::{Config, FanOutStrategy};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+const CLIENTS: usize = 10;
+const MESSAGES: usize = 50;
+
+async fn server(fan_out: FanOutStrategy, sequence_numbers: bool) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Config {
+        fan_out,
+        sequence_numbers,
+        ..Config::from_env()
+    };
+    tokio::spawn(problem3::run(listener, CancellationToken::new(), config));
+    addr
+}
+
+async fn join(addr: SocketAddr, name: &str) -> (BufReader<OwnedReadHalf>, OwnedWriteHalf) {
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (rd, mut wr) = socket.into_split();
+    let mut rd = BufReader::new(rd);
+    let mut line = String::new();
+
+    // Greeting, then the room listing after sending the name
+    rd.read_line(&mut line).await.unwrap();
+    wr.write_all(format!("{}\n", name).as_bytes())
+        .await
+        .unwrap();
+    rd.read_line(&mut line).await.unwrap();
+    (rd, wr)
+}
+
+// A received line split into its sequence number, if any, and the rest
+fn split_seq(line: &str) -> (Option<u64>, &str) {
+    let numbered = line
+        .strip_prefix('#')
+        .and_then(|rest| rest.split_once(' '))
+        .and_then(|(seq, rest)| Some((seq.parse().ok()?, rest)));
+    match numbered {
+        Some((seq, rest)) => (Some(seq), rest),
+        None => (None, line),
+    }
+}
+
+// The sender of a chat message line
+fn sender(line: &str) -> Option<&str> {
+    line.strip_prefix('[')?
+        .split_once(']')
+        .map(|(name, _)| name)
+}
+
+// Every line a client receives until it has all the others' messages
+async fn transcript(mut rd: BufReader<OwnedReadHalf>, expected: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut messages = 0;
+    while messages < expected {
+        let mut line = String::new();
+        if rd.read_line(&mut line).await.unwrap() == 0 {
+            panic!("server closed the connection after {} messages", messages);
+        }
+        let line = line.trim_end().to_owned();
+        if sender(split_seq(&line).1).is_some() {
+            messages += 1;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn check(transcripts: &[Vec<String>]) -> Result<usize, String> {
+    let mut numbered = BTreeMap::new();
+    for (client, lines) in transcripts.iter().enumerate() {
+        let mut last = None;
+        for line in lines {
+            let (seq, rest) = split_seq(line);
+            let Some(seq) = seq else { continue };
+            if last.is_some_and(|last| seq <= last) {
+                return Err(format!("c{} got #{} after #{}", client, seq, last.unwrap()));
+            }
+            last = Some(seq);
+            if let Some(other) = numbered.insert(seq, rest) {
+                if other != rest {
+                    return Err(format!("#{} is both {:?} and {:?}", seq, other, rest));
+                }
+            }
+        }
+    }
+
+    let messages = |client: usize, other: usize| -> Vec<&str> {
+        let (them, me) = (format!("c{}", other), format!("c{}", client));
+        transcripts[client]
+            .iter()
+            .map(|line| split_seq(line).1)
+            .filter(|line| sender(line).is_some_and(|s| s != them && s != me))
+            .collect()
+    };
+    for a in 0..transcripts.len() {
+        for b in a + 1..transcripts.len() {
+            let (seen_by_a, seen_by_b) = (messages(a, b), messages(b, a));
+            if let Some(i) = (0..seen_by_a.len()).find(|&i| seen_by_a.get(i) != seen_by_b.get(i)) {
+                return Err(format!(
+                    "c{} and c{} disagree at message {}: {:?} vs {:?}",
+                    a,
+                    b,
+                    i,
+                    seen_by_a[i],
+                    seen_by_b.get(i)
+                ));
+            }
+        }
+    }
+    Ok(numbered.len())
+}
+
+// Run a room on the server, returning how many numbered events the clients
+// saw
+async fn room(addr: SocketAddr) -> usize {
+    let mut members = Vec::with_capacity(CLIENTS);
+    for i in 0..CLIENTS {
+        members.push(join(addr, &format!("c{}", i)).await);
+    }
+
+    // Dropping a write half shuts it down, which the server takes as leaving
+    let mut writers = Vec::with_capacity(CLIENTS);
+    let mut readers = Vec::with_capacity(CLIENTS);
+    for (i, (rd, mut wr)) in members.into_iter().enumerate() {
+        readers.push(tokio::spawn(transcript(rd, (CLIENTS - 1) * MESSAGES)));
+        writers.push(tokio::spawn(async move {
+            for n in 0..MESSAGES {
+                wr.write_all(format!("message {} from c{}\n", n, i).as_bytes())
+                    .await
+                    .unwrap();
+            }
+            wr
+        }));
+    }
+    let mut transcripts = Vec::with_capacity(CLIENTS);
+    for r in readers {
+        transcripts.push(r.await.unwrap());
+    }
+    drop(writers);
+
+    check(&transcripts).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn broadcast_clients_agree_on_the_order() {
+    let addr = server(FanOutStrategy::Broadcast, false).await;
+    assert_eq!(room(addr).await, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mpsc_clients_agree_on_the_order() {
+    let addr = server(FanOutStrategy::Mpsc, false).await;
+    assert_eq!(room(addr).await, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn broadcast_sequence_numbers_are_consistent() {
+    let addr = server(FanOutStrategy::Broadcast, true).await;
+    // At least every message, besides joins and leaves
+    assert!(room(addr).await >= CLIENTS * MESSAGES);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mpsc_sequence_numbers_are_consistent() {
+    let addr = server(FanOutStrategy::Mpsc, true).await;
+    assert!(room(addr).await >= CLIENTS * MESSAGES);
+}
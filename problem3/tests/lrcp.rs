@@ -0,0 +1,59 @@
+// The chat server served over LRCP instead of TCP, as it is with the lrcp
+// feature, against a listener on an ephemeral port.
+#![cfg(feature = "lrcp")]
+use common::accept::AcceptLimits;
+use common::admission::Admission;
+use common::metrics::Scope;
+use lrcp::testkit::Peer;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const WELCOME: &str = "Welcome to budgetchat! What shall I call you?\n";
+
+async fn server() -> SocketAddr {
+    let config = lrcp::Config {
+        retransmit_interval: Duration::from_millis(100),
+        ..lrcp::Config::default()
+    };
+    let listener = lrcp::Listener::bind_with("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = listener.local_addr();
+    let scope = Scope::new("problem3", addr.port());
+    let chat = problem3::handler(&scope, &problem3::Config::from_env());
+    let admission = Admission::new(&scope, AcceptLimits::from_env());
+    tokio::spawn(lrcp::serve(
+        listener,
+        chat,
+        admission,
+        CancellationToken::new(),
+    ));
+    addr
+}
+
+#[tokio::test]
+async fn runs_budget_chat() {
+    let server = server().await;
+    let mut alice = Peer::connect(server, 1).await;
+    assert_eq!(alice.line().await, WELCOME);
+    alice.send("alice\n");
+    assert_eq!(alice.line().await, "* The room contains: \n");
+
+    let mut bob = Peer::connect(server, 2).await;
+    assert_eq!(bob.line().await, WELCOME);
+    bob.send("bob\n");
+    assert_eq!(bob.line().await, "* The room contains: alice\n");
+    assert_eq!(alice.line().await, "* bob has entered the room\n");
+
+    // Sent in pieces, which LRCP reassembles into the one line
+    alice.send("hi ");
+    alice.send("bob\n");
+    assert_eq!(bob.line().await, "[alice] hi bob\n");
+    let long = format!("[bob] {}\n", "x".repeat(900));
+    bob.send(&long[6..]);
+    assert_eq!(alice.line().await, long);
+
+    drop(bob);
+    assert_eq!(alice.line().await, "* bob has left the room\n");
+}
@@ -0,0 +1,451 @@
+// Where the key-value pairs are kept.
+//
+// KV_BACKEND picks the backend: "memory" (the default) keeps them in a map
+// owned by the socket task and loses them on restart, "redis" keeps them in
+// the Redis server at KV_REDIS_URL (built with the `redis` feature), under
+// keys prefixed with KV_REDIS_PREFIX so several stores can share a server.
+// With Redis the pairs survive restarts and instances pointed at the same
+// server share them. The reserved "version" key never reaches a backend.
+//
+// The memory backend can be capped: KV_MAX_KEYS bounds how many keys it
+// holds and KV_MAX_BYTES how many bytes of keys and values. An insert that
+// would go over either is handled as KV_EVICTION says: "reject" (the default)
+// drops the insert, "lru" evicts the least recently inserted or retrieved
+// keys until it fits and "random" evicts keys picked at random. A pair
+// bigger than KV_MAX_BYTES on its own is always rejected. Redis has its own
+// limits (maxmemory and maxmemory-policy), so these don't apply to it.
+use common::metrics::Counter;
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    #[default]
+    Memory,
+    Redis,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "redis" if cfg!(feature = "redis") => Ok(Self::Redis),
+            "redis" => Err("the redis backend needs the `redis` feature".to_owned()),
+            _ => Err(format!("unknown backend {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory => write!(f, "memory"),
+            Self::Redis => write!(f, "redis"),
+        }
+    }
+}
+
+pub trait Backend: Send {
+    fn get(&mut self, key: &[u8]) -> impl Future<Output = io::Result<Option<Vec<u8>>>> + Send;
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    // How many keys are stored, if the backend keeps count
+    fn len(&self) -> Option<usize> {
+        None
+    }
+
+    // Every pair, if the backend can snapshot them
+    #[cfg(feature = "snapshot")]
+    fn pairs(&self) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Eviction {
+    #[default]
+    Reject,
+    Lru,
+    Random,
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "lru" => Ok(Self::Lru),
+            "random" => Ok(Self::Random),
+            _ => Err(format!("unknown eviction policy {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Eviction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reject => write!(f, "reject"),
+            Self::Lru => write!(f, "lru"),
+            Self::Random => write!(f, "random"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub eviction: Eviction,
+}
+
+struct Entry {
+    value: Vec<u8>,
+    // When it was last used, for LRU
+    used: u64,
+    // Where its key is in `keys`, for random eviction
+    slot: usize,
+}
+
+#[derive(Default)]
+pub struct Memory {
+    entries: HashMap<Vec<u8>, Entry>,
+    limits: Limits,
+    // Bytes of keys and values stored
+    bytes: usize,
+    // Keys by when they were last used, kept only for LRU
+    by_use: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+    // Every key, kept only for random eviction
+    keys: Vec<Vec<u8>>,
+    evicted: Option<Counter>,
+}
+
+impl Memory {
+    pub fn new(limits: Limits, evicted: Counter) -> Self {
+        Memory {
+            limits,
+            evicted: Some(evicted),
+            ..Memory::default()
+        }
+    }
+
+    fn over(&self, keys: usize, bytes: usize) -> bool {
+        self.limits.max_keys.is_some_and(|max| keys > max)
+            || self.limits.max_bytes.is_some_and(|max| bytes > max)
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        if self.limits.eviction != Eviction::Lru {
+            return;
+        }
+        self.clock += 1;
+        let now = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            let key = self
+                .by_use
+                .remove(&entry.used)
+                .unwrap_or_else(|| key.to_vec());
+            entry.used = now;
+            self.by_use.insert(now, key);
+        }
+    }
+
+    // A key to evict to make room for `keep`, if there's any other
+    fn victim(&self, keep: &[u8]) -> Option<Vec<u8>> {
+        match self.limits.eviction {
+            Eviction::Reject => None,
+            Eviction::Lru => self
+                .by_use
+                .values()
+                .find(|key| key.as_slice() != keep)
+                .cloned(),
+            Eviction::Random => {
+                let others = self.keys.len() - usize::from(self.entries.contains_key(keep));
+                if others == 0 {
+                    return None;
+                }
+                let mut rng = rand::thread_rng();
+                loop {
+                    let key = &self.keys[rng.gen_range(0..self.keys.len())];
+                    if key.as_slice() != keep {
+                        return Some(key.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        let Some(entry) = self.entries.remove(key) else {
+            return;
+        };
+        self.bytes -= key.len() + entry.value.len();
+        match self.limits.eviction {
+            Eviction::Reject => {}
+            Eviction::Lru => {
+                self.by_use.remove(&entry.used);
+            }
+            Eviction::Random => {
+                self.keys.swap_remove(entry.slot);
+                if let Some(moved) = self.keys.get(entry.slot) {
+                    if let Some(moved) = self.entries.get_mut(moved) {
+                        moved.slot = entry.slot;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Backend for Memory {
+    async fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.touch(key);
+        Ok(self.entries.get(key).map(|entry| entry.value.clone()))
+    }
+
+    async fn insert(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let size = key.len() + value.len();
+        if self.limits.max_bytes.is_some_and(|max| size > max) {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "pair is bigger than KV_MAX_BYTES",
+            ));
+        }
+        let old = self.entries.get(key).map(|entry| entry.value.len());
+        loop {
+            let keys = self.entries.len() + usize::from(old.is_none());
+            let bytes = self.bytes + size - old.map_or(0, |len| key.len() + len);
+            if !self.over(keys, bytes) {
+                break;
+            }
+            let Some(victim) = self.victim(key) else {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, "store is full"));
+            };
+            self.remove(&victim);
+            if let Some(evicted) = &self.evicted {
+                evicted.inc();
+            }
+        }
+
+        self.bytes += size;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.bytes -= key.len() + entry.value.len();
+                entry.value = value.to_vec();
+            }
+            None => {
+                let slot = self.keys.len();
+                if self.limits.eviction == Eviction::Random {
+                    self.keys.push(key.to_vec());
+                }
+                let entry = Entry {
+                    value: value.to_vec(),
+                    used: 0,
+                    slot,
+                };
+                self.entries.insert(key.to_vec(), entry);
+            }
+        }
+        self.touch(key);
+        Ok(())
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn pairs(&self) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        let pairs = self.entries.iter();
+        Some(
+            pairs
+                .map(|(key, entry)| (key.clone(), entry.value.clone()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(feature = "redis")]
+pub struct Redis {
+    conn: redis::aio::MultiplexedConnection,
+    prefix: Vec<u8>,
+}
+
+#[cfg(feature = "redis")]
+impl Redis {
+    pub async fn connect(url: &str, prefix: &str) -> io::Result<Self> {
+        let client = redis::Client::open(url).map_err(io::Error::other)?;
+        let conn = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(io::Error::other)?;
+        Ok(Redis {
+            conn,
+            prefix: prefix.as_bytes().to_vec(),
+        })
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [&self.prefix[..], key].concat()
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Backend for Redis {
+    async fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        redis::cmd("GET")
+            .arg(self.key(key))
+            .query_async(&mut self.conn)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn insert(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(value)
+            .query_async(&mut self.conn)
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_overwrites_and_counts() {
+        let mut memory = Memory::default();
+        assert_eq!(memory.get(b"k").await.unwrap(), None);
+        memory.insert(b"k", b"1").await.unwrap();
+        memory.insert(b"k", b"2").await.unwrap();
+        memory.insert(b"", b"empty").await.unwrap();
+        assert_eq!(memory.get(b"k").await.unwrap().as_deref(), Some(&b"2"[..]));
+        assert_eq!(
+            memory.get(b"").await.unwrap().as_deref(),
+            Some(&b"empty"[..])
+        );
+        assert_eq!(memory.len(), Some(2));
+    }
+
+    fn capped(max_keys: Option<usize>, max_bytes: Option<usize>, eviction: Eviction) -> Memory {
+        Memory {
+            limits: Limits {
+                max_keys,
+                max_bytes,
+                eviction,
+            },
+            ..Memory::default()
+        }
+    }
+
+    async fn has(memory: &mut Memory, key: &[u8]) -> bool {
+        memory.get(key).await.unwrap().is_some()
+    }
+
+    #[tokio::test]
+    async fn reject_refuses_new_keys_once_full() {
+        let mut memory = capped(Some(2), None, Eviction::Reject);
+        memory.insert(b"a", b"1").await.unwrap();
+        memory.insert(b"b", b"2").await.unwrap();
+        let e = memory.insert(b"c", b"3").await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+        // Overwriting doesn't add a key
+        memory.insert(b"a", b"11").await.unwrap();
+        assert!(!has(&mut memory, b"c").await);
+        assert_eq!(memory.len(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn reject_counts_bytes_of_keys_and_values() {
+        let mut memory = capped(None, Some(6), Eviction::Reject);
+        memory.insert(b"a", b"12").await.unwrap();
+        memory.insert(b"b", b"12").await.unwrap();
+        assert!(memory.insert(b"c", b"").await.is_err());
+        // Shrinking a value makes room
+        memory.insert(b"a", b"").await.unwrap();
+        memory.insert(b"c", b"1").await.unwrap();
+        assert_eq!(memory.bytes, 6);
+        assert!(memory.insert(b"d", b"1234567").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lru_evicts_the_least_recently_used() {
+        let mut memory = capped(Some(2), None, Eviction::Lru);
+        memory.insert(b"a", b"1").await.unwrap();
+        memory.insert(b"b", b"2").await.unwrap();
+        // Retrieving "a" makes "b" the least recently used
+        assert!(has(&mut memory, b"a").await);
+        memory.insert(b"c", b"3").await.unwrap();
+        assert!(!has(&mut memory, b"b").await);
+        assert!(has(&mut memory, b"c").await);
+        // Then "a", since "c" was just retrieved
+        memory.insert(b"d", b"4").await.unwrap();
+        assert!(!has(&mut memory, b"a").await);
+        assert_eq!(memory.len(), Some(2));
+        assert_eq!(memory.by_use.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn lru_evicts_as_many_as_it_takes_but_never_the_key_itself() {
+        let mut memory = capped(None, Some(10), Eviction::Lru);
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            memory.insert(key, b"1").await.unwrap();
+        }
+        memory.insert(b"e", b"123456789").await.unwrap();
+        assert_eq!(memory.len(), Some(1));
+        assert_eq!(memory.bytes, 10);
+        assert!(memory.insert(b"e", b"1234567890").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn random_keeps_within_the_limits() {
+        let mut memory = capped(Some(10), Some(60), Eviction::Random);
+        for i in 0..1000u32 {
+            let key = (i % 37).to_string();
+            memory
+                .insert(key.as_bytes(), &vec![b'x'; (i % 7) as usize])
+                .await
+                .unwrap();
+            assert!(memory.entries.len() <= 10 && memory.bytes <= 60);
+            assert!(has(&mut memory, key.as_bytes()).await);
+        }
+        assert_eq!(memory.keys.len(), memory.entries.len());
+        for (slot, key) in memory.keys.iter().enumerate() {
+            assert_eq!(memory.entries[key].slot, slot);
+        }
+        let bytes: usize = memory
+            .entries
+            .iter()
+            .map(|(k, e)| k.len() + e.value.len())
+            .sum();
+        assert_eq!(bytes, memory.bytes);
+    }
+
+    #[test]
+    fn parses_eviction_policies() {
+        for eviction in [Eviction::Reject, Eviction::Lru, Eviction::Random] {
+            assert_eq!(eviction.to_string().parse(), Ok(eviction));
+        }
+        assert!("lfu".parse::<Eviction>().is_err());
+    }
+
+    #[test]
+    fn parses_backend_names() {
+        assert_eq!("memory".parse(), Ok(BackendKind::Memory));
+        assert!("disk".parse::<BackendKind>().is_err());
+        assert_eq!(
+            "redis".parse::<BackendKind>().is_ok(),
+            cfg!(feature = "redis")
+        );
+    }
+}
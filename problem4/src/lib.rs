@@ -0,0 +1,476 @@
+// Unusual Database Program: a key-value store over UDP.
+//
+// Every datagram is one request and gets at most one datagram back. A request
+// containing '=' inserts: the key is everything before the first '=' and the
+// value everything after it, which may contain more '='. Any other request
+// retrieves that key, answered with "key=value", or not at all when the key
+// was never inserted. The "version" key is reserved: it retrieves the server's
+// identifier (KV_VERSION) and inserts into it are ignored. Requests of 1000
+// bytes or more aren't part of the protocol and are ignored too.
+//
+// There's a single socket task, so it owns the store and requests from every
+// client see each other's inserts in the order they arrived. Where the pairs
+// are kept is up to the backend; see backend.rs, and snapshot.rs for keeping
+// the memory backend's across restarts. Responses go through a
+// common::udp_guard::Guard; the protocol has no way to verify a
+// source, so each one is held to its amplification cap.
+use backend::Backend;
+use common::metrics::{Counter, Gauge, Scope};
+use common::udp_guard::Guard;
+use std::net::SocketAddr;
+#[cfg(feature = "snapshot")]
+use std::path::PathBuf;
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+mod backend;
+mod serve;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+
+pub use backend::{BackendKind, Eviction};
+pub use serve::serve;
+
+const MAX_REQUEST_LEN: usize = 999;
+const VERSION_KEY: &[u8] = b"version";
+const DEFAULT_VERSION: &str = concat!("protohackers key-value store ", env!("CARGO_PKG_VERSION"));
+#[cfg(feature = "redis")]
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1/";
+#[cfg(feature = "redis")]
+const DEFAULT_REDIS_PREFIX: &str = "problem4:";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // What retrieving "version" answers
+    pub version: String,
+    pub backend: BackendKind,
+    // Caps on the memory backend and what to do when they're reached
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub eviction: Eviction,
+    #[cfg(feature = "snapshot")]
+    pub snapshot_path: Option<PathBuf>,
+    #[cfg(feature = "snapshot")]
+    pub snapshot_secs: u64,
+    #[cfg(feature = "redis")]
+    pub redis_url: String,
+    #[cfg(feature = "redis")]
+    pub redis_prefix: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            version: common::env::var_or("KV_VERSION", DEFAULT_VERSION.to_owned()),
+            backend: common::env::var_or("KV_BACKEND", BackendKind::Memory),
+            max_keys: common::env::var("KV_MAX_KEYS"),
+            max_bytes: common::env::var("KV_MAX_BYTES"),
+            eviction: common::env::var_or("KV_EVICTION", Eviction::Reject),
+            #[cfg(feature = "snapshot")]
+            snapshot_path: common::env::var("KV_SNAPSHOT_PATH"),
+            #[cfg(feature = "snapshot")]
+            snapshot_secs: common::env::var_or("KV_SNAPSHOT_SECS", snapshot::DEFAULT_INTERVAL_SECS),
+            #[cfg(feature = "redis")]
+            redis_url: common::env::var_or("KV_REDIS_URL", DEFAULT_REDIS_URL.to_owned()),
+            #[cfg(feature = "redis")]
+            redis_prefix: common::env::var_or("KV_REDIS_PREFIX", DEFAULT_REDIS_PREFIX.to_owned()),
+        }
+    }
+}
+
+struct Metrics {
+    inserts: Counter,
+    retrieves: Counter,
+    misses: Counter,
+    ignored: Counter,
+    backend_errors: Counter,
+    rejected: Counter,
+    evicted: Counter,
+    keys: Gauge,
+}
+
+impl Metrics {
+    fn new(scope: &Scope) -> Self {
+        Metrics {
+            inserts: scope.counter("kv_inserts_total", "Insert requests"),
+            retrieves: scope.counter("kv_retrieves_total", "Retrieve requests"),
+            misses: scope.counter(
+                "kv_retrieve_misses_total",
+                "Retrieve requests for keys never inserted",
+            ),
+            ignored: scope.counter(
+                "kv_ignored_total",
+                "Requests ignored for being too long or inserting into version",
+            ),
+            backend_errors: scope.counter(
+                "kv_backend_errors_total",
+                "Requests the backend failed to serve",
+            ),
+            rejected: scope.counter(
+                "kv_rejected_total",
+                "Inserts dropped for going over KV_MAX_KEYS or KV_MAX_BYTES",
+            ),
+            evicted: scope.counter("kv_evicted_total", "Keys evicted to make room for inserts"),
+            keys: scope.gauge("kv_keys", "Keys stored"),
+        }
+    }
+}
+
+enum Request<'a> {
+    Insert { key: &'a [u8], value: &'a [u8] },
+    Retrieve { key: &'a [u8] },
+}
+
+impl<'a> Request<'a> {
+    fn parse(datagram: &'a [u8]) -> Self {
+        match datagram.iter().position(|&b| b == b'=') {
+            Some(i) => Request::Insert {
+                key: &datagram[..i],
+                value: &datagram[i + 1..],
+            },
+            None => Request::Retrieve { key: datagram },
+        }
+    }
+}
+
+struct Store<B> {
+    backend: B,
+    version: Vec<u8>,
+    #[cfg(feature = "snapshot")]
+    snapshots: Option<snapshot::Snapshots>,
+    // KV_VERSION, before the snapshot lineage is added to it
+    #[cfg(feature = "snapshot")]
+    base_version: String,
+    // Whether anything was inserted since the last snapshot was taken
+    #[cfg(feature = "snapshot")]
+    changed: bool,
+}
+
+impl<B: Backend> Store<B> {
+    fn new(backend: B, version: String) -> Self {
+        Store {
+            backend,
+            version: version.clone().into_bytes(),
+            #[cfg(feature = "snapshot")]
+            snapshots: None,
+            #[cfg(feature = "snapshot")]
+            base_version: version,
+            #[cfg(feature = "snapshot")]
+            changed: false,
+        }
+    }
+
+    // Insert the latest snapshot at `path` and keep taking them every
+    // `interval_secs` seconds
+    #[cfg(feature = "snapshot")]
+    async fn restore(&mut self, path: &std::path::Path, interval_secs: u64) {
+        let opened = snapshot::Snapshots::open(path, interval_secs).await;
+        let (snapshots, pairs) = common::report::startup("open snapshot", opened);
+        let count = pairs.len();
+        let mut failed = 0;
+        for (key, value) in pairs {
+            if self.backend.insert(&key, &value).await.is_err() {
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            common::warn!("Couldn't restore {} of {} pairs", failed, count);
+        }
+        self.snapshots = Some(snapshots);
+        self.describe_snapshots();
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn describe_snapshots(&mut self) {
+        if let Some(snapshots) = &self.snapshots {
+            let version = format!("{} ({})", self.base_version, snapshots.lineage());
+            self.version = version.into_bytes();
+        }
+    }
+
+    // Take snapshots when they're due. Never returns when there aren't any
+    // to take; cancel-safe.
+    #[cfg(feature = "snapshot")]
+    async fn snapshot(&mut self) {
+        let Some(snapshots) = &mut self.snapshots else {
+            return std::future::pending().await;
+        };
+        match snapshots.next().await {
+            snapshot::Event::Due => {
+                if self.changed {
+                    if let Some(pairs) = self.backend.pairs() {
+                        self.changed = !snapshots.take(pairs);
+                    }
+                }
+            }
+            snapshot::Event::Written => self.describe_snapshots(),
+        }
+    }
+
+    #[cfg(not(feature = "snapshot"))]
+    async fn snapshot(&mut self) {
+        std::future::pending().await
+    }
+
+    // Take a last snapshot before shutting down, if anything changed
+    async fn finish(&mut self) {
+        #[cfg(feature = "snapshot")]
+        if let Some(snapshots) = &mut self.snapshots {
+            snapshots.finish().await;
+            if self.changed {
+                if let Some(pairs) = self.backend.pairs() {
+                    snapshots.take(pairs);
+                    snapshots.finish().await;
+                }
+            }
+        }
+    }
+
+    // Apply `request`, returning what to answer with, if anything
+    async fn handle(&mut self, request: Request<'_>, metrics: &Metrics) -> Option<Vec<u8>> {
+        match request {
+            Request::Insert { key, .. } if key == VERSION_KEY => {
+                metrics.ignored.inc();
+                None
+            }
+            Request::Insert { key, value } => {
+                metrics.inserts.inc();
+                match self.backend.insert(key, value).await {
+                    Ok(()) => {
+                        #[cfg(feature = "snapshot")]
+                        {
+                            self.changed = true;
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+                        metrics.rejected.inc();
+                    }
+                    Err(e) => {
+                        common::error!("Couldn't insert: {}", e);
+                        metrics.backend_errors.inc();
+                    }
+                }
+                if let Some(len) = self.backend.len() {
+                    metrics.keys.set(len as i64);
+                }
+                None
+            }
+            Request::Retrieve { key } => {
+                metrics.retrieves.inc();
+                let value = if key == VERSION_KEY {
+                    self.version.clone()
+                } else {
+                    match self.backend.get(key).await {
+                        Ok(Some(value)) => value,
+                        Ok(None) => {
+                            metrics.misses.inc();
+                            return None;
+                        }
+                        Err(e) => {
+                            common::error!("Couldn't retrieve: {}", e);
+                            metrics.backend_errors.inc();
+                            return None;
+                        }
+                    }
+                };
+                let mut response = Vec::with_capacity(key.len() + 1 + value.len());
+                response.extend_from_slice(key);
+                response.push(b'=');
+                response.extend_from_slice(&value);
+                // Only a long version could make it too long to send
+                (response.len() <= MAX_REQUEST_LEN).then_some(response)
+            }
+        }
+    }
+}
+
+// Answer requests on `socket` until `shutdown` is cancelled
+pub async fn run(socket: UdpSocket, shutdown: CancellationToken, config: Config) {
+    let local_addr = socket.local_addr().unwrap();
+    let metrics = Metrics::new(&Scope::new("problem4", local_addr.port()));
+    match config.backend {
+        BackendKind::Memory => {
+            let limits = backend::Limits {
+                max_keys: config.max_keys,
+                max_bytes: config.max_bytes,
+                eviction: config.eviction,
+            };
+            let memory = backend::Memory::new(limits, metrics.evicted.clone());
+            #[allow(unused_mut)]
+            let mut store = Store::new(memory, config.version);
+            #[cfg(feature = "snapshot")]
+            if let Some(path) = &config.snapshot_path {
+                store.restore(path, config.snapshot_secs).await;
+            }
+            serve_store(socket, shutdown, store, metrics).await
+        }
+        #[cfg(feature = "redis")]
+        BackendKind::Redis => {
+            let redis = backend::Redis::connect(&config.redis_url, &config.redis_prefix).await;
+            let redis = common::report::startup("connect to Redis", redis);
+            #[cfg(feature = "snapshot")]
+            if config.snapshot_path.is_some() {
+                common::warn!("KV_SNAPSHOT_PATH only applies to the memory backend");
+            }
+            let store = Store::new(redis, config.version);
+            serve_store(socket, shutdown, store, metrics).await
+        }
+        #[cfg(not(feature = "redis"))]
+        BackendKind::Redis => unreachable!("parsing KV_BACKEND refuses redis"),
+    }
+}
+
+async fn serve_store<B: Backend>(
+    socket: UdpSocket,
+    shutdown: CancellationToken,
+    mut store: Store<B>,
+    metrics: Metrics,
+) {
+    common::tuning::tune_udp(&socket);
+    let local_addr = socket.local_addr().unwrap();
+    let guard = Guard::from_env();
+    common::dry_run::finish();
+    common::env::print_config();
+    common::info!("Listening for UDP requests on {}", local_addr);
+
+    // One byte more than a request may have, to tell when it's too long
+    let mut buf = vec![0u8; MAX_REQUEST_LEN + 1];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = store.snapshot() => continue,
+            _ = shutdown.cancelled() => return store.finish().await,
+        };
+        let (n, from): (usize, SocketAddr) = match received {
+            Ok(r) => r,
+            Err(e) => {
+                common::warn!("Couldn't receive request: {:?}", e);
+                continue;
+            }
+        };
+        guard.received(from, n);
+        if n > MAX_REQUEST_LEN {
+            metrics.ignored.inc();
+            continue;
+        }
+        let Some(response) = store.handle(Request::parse(&buf[..n]), &metrics).await else {
+            continue;
+        };
+        if guard.allow(from, response.len()) {
+            if let Err(e) = socket.send_to(&response, from).await {
+                common::warn!("Couldn't send response to {}: {:?}", from, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // The address of a store with the memory backend, answering on loopback
+    async fn server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let metrics = Metrics::new(&Scope::new("problem4", addr.port()));
+        let store = Store::new(backend::Memory::default(), "test version".to_owned());
+        tokio::spawn(serve_store(
+            socket,
+            CancellationToken::new(),
+            store,
+            metrics,
+        ));
+        addr
+    }
+
+    async fn client(server: SocketAddr) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        socket
+    }
+
+    // Send `request` and wait for the answer, if there is one
+    async fn ask(socket: &UdpSocket, request: &[u8]) -> Option<Vec<u8>> {
+        socket.send(request).await.unwrap();
+        let mut buf = vec![0u8; MAX_REQUEST_LEN + 1];
+        let n = tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some(buf[..n].to_vec())
+    }
+
+    fn parsed(datagram: &[u8]) -> (Option<&[u8]>, &[u8]) {
+        match Request::parse(datagram) {
+            Request::Insert { key, value } => (Some(key), value),
+            Request::Retrieve { key } => (None, key),
+        }
+    }
+
+    #[test]
+    fn splits_inserts_at_the_first_equals_sign() {
+        assert_eq!(parsed(b"foo=bar"), (Some(&b"foo"[..]), &b"bar"[..]));
+        assert_eq!(parsed(b"foo=bar=baz"), (Some(&b"foo"[..]), &b"bar=baz"[..]));
+        assert_eq!(parsed(b"foo="), (Some(&b"foo"[..]), &b""[..]));
+        assert_eq!(parsed(b"=foo"), (Some(&b""[..]), &b"foo"[..]));
+        assert_eq!(parsed(b"==="), (Some(&b""[..]), &b"=="[..]));
+        assert_eq!(parsed(b"foo"), (None, &b"foo"[..]));
+        assert_eq!(parsed(b""), (None, &b""[..]));
+    }
+
+    #[tokio::test]
+    async fn inserts_and_retrieves() {
+        let socket = client(server().await).await;
+        assert_eq!(ask(&socket, b"foo").await, None);
+        assert_eq!(ask(&socket, b"foo=bar=baz").await, None);
+        assert_eq!(ask(&socket, b"foo").await, Some(b"foo=bar=baz".to_vec()));
+        socket.send(b"foo=").await.unwrap();
+        assert_eq!(ask(&socket, b"foo").await, Some(b"foo=".to_vec()));
+        socket.send(b"=empty").await.unwrap();
+        assert_eq!(ask(&socket, b"").await, Some(b"=empty".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn version_is_reserved() {
+        let socket = client(server().await).await;
+        let version = Some(b"version=test version".to_vec());
+        assert_eq!(ask(&socket, b"version").await, version);
+        socket.send(b"version=mine").await.unwrap();
+        assert_eq!(ask(&socket, b"version").await, version);
+    }
+
+    #[tokio::test]
+    async fn ignores_requests_that_are_too_long() {
+        let socket = client(server().await).await;
+        let mut insert = b"k=".to_vec();
+        insert.resize(MAX_REQUEST_LEN, b'v');
+        socket.send(&insert).await.unwrap();
+        assert_eq!(ask(&socket, b"k").await, Some(insert.clone()));
+
+        let mut too_long = b"k=".to_vec();
+        too_long.resize(MAX_REQUEST_LEN + 1, b'w');
+        socket.send(&too_long).await.unwrap();
+        assert_eq!(ask(&socket, b"k").await, Some(insert));
+        let key = vec![b'k'; MAX_REQUEST_LEN + 1];
+        assert_eq!(ask(&socket, &key).await, None);
+    }
+
+    // An unverified source is sent back three times what it sent, plus 1000
+    // bytes once, so short retrieves of a large value stop being answered
+    #[tokio::test]
+    async fn answers_retrieves_of_a_large_value_only_as_far_as_requests_pay() {
+        let socket = client(server().await).await;
+        let mut insert = b"k=".to_vec();
+        insert.extend_from_slice(&[b'v'; 990]);
+        socket.send(&insert).await.unwrap();
+        // 1000 + 3 * 992 bytes, with 3 more for each retrieve, pays for four
+        for _ in 0..4 {
+            assert_eq!(ask(&socket, b"k").await, Some(insert.clone()));
+        }
+        assert_eq!(ask(&socket, b"k").await, None);
+        // A large request pays for a large answer again
+        socket.send(&insert).await.unwrap();
+        assert_eq!(ask(&socket, b"k").await, Some(insert));
+    }
+}
@@ -0,0 +1,53 @@
+// Everything the problem4 binary does, callable so that the protohackers
+// binary can run the problem too.
+use crate::Config;
+use common::config::Checker;
+use protolib::{Problem, Transport};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+// This problem's own checks, on top of those `checker` makes
+pub fn check_config(checker: Checker) -> Checker {
+    let checker = checker
+        .parse::<crate::BackendKind>("KV_BACKEND")
+        .positive("KV_MAX_KEYS")
+        .positive("KV_MAX_BYTES")
+        .parse::<crate::Eviction>("KV_EVICTION");
+    #[cfg(feature = "snapshot")]
+    let checker = checker
+        .parent_dir("KV_SNAPSHOT_PATH")
+        .positive("KV_SNAPSHOT_SECS");
+    checker
+}
+
+// Check the configuration, then serve on `addrs` until the process is stopped
+pub fn serve(addrs: &[SocketAddr]) {
+    protolib::main::<Config>(addrs)
+}
+
+impl Problem for Config {
+    const TRANSPORT: Transport = Transport::Udp;
+    type Listener = UdpSocket;
+
+    fn name() -> &'static str {
+        "problem4"
+    }
+
+    fn from_env() -> Self {
+        Config::from_env()
+    }
+
+    fn check_config(checker: Checker) -> Checker {
+        check_config(checker)
+    }
+
+    async fn bind(&self, addrs: &[SocketAddr]) -> io::Result<UdpSocket> {
+        UdpSocket::bind(common::cli::only_addr(addrs, "for UDP")).await
+    }
+
+    async fn serve(&self, listener: UdpSocket, shutdown: CancellationToken) {
+        crate::run(listener, shutdown, self.clone()).await
+    }
+}
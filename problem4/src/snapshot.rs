@@ -0,0 +1,188 @@
+// Periodic snapshots of the memory backend, restored on startup.
+//
+// With KV_SNAPSHOT_PATH set (and the `snapshot` feature), the memory backend's
+// pairs are written every KV_SNAPSHOT_SECS seconds, if anything was inserted
+// since the last time, to the "problem4" namespace of a storage database at
+// that path, and once more on shutdown. Each snapshot replaces the previous one
+// in a single transaction, so a crash leaves one or the other. On startup the
+// latest snapshot is inserted back, subject to KV_MAX_KEYS and KV_MAX_BYTES
+// like any other insert.
+//
+// Snapshots are numbered, the number stored alongside the pairs under a key
+// no request can insert (keys end at the first '='). Retrieving "version"
+// reports the lineage: the snapshot the store was restored from and the
+// latest one written since. Redis keeps its own pairs, so snapshots only apply
+// to the memory backend. Under the sandbox, the database's directory has to be
+// in SANDBOX_WRITE_PATHS.
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use storage::{KvStore, Storage};
+use tokio::task::JoinHandle;
+use tokio::time::{Interval, MissedTickBehavior};
+
+const NAMESPACE: &str = "problem4";
+const GENERATION_KEY: &[u8] = b"=generation";
+pub(crate) const DEFAULT_INTERVAL_SECS: u64 = 60;
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+pub(crate) enum Event {
+    // Time to take a snapshot
+    Due,
+    // A snapshot finished writing
+    Written,
+}
+
+pub(crate) struct Snapshots {
+    kv: KvStore,
+    interval: Interval,
+    // The snapshot the store started from and the latest written since
+    restored: Option<u64>,
+    latest: Option<u64>,
+    writing: Option<JoinHandle<io::Result<u64>>>,
+}
+
+impl Snapshots {
+    // Open the snapshot database at `path`, returning it with the pairs of
+    // the latest snapshot
+    pub(crate) async fn open(path: &Path, interval_secs: u64) -> io::Result<(Snapshots, Pairs)> {
+        let kv = Storage::open(path).await?.kv(NAMESPACE);
+        let mut pairs = kv.entries().await?;
+        let mut restored = None;
+        pairs.retain(|(key, value)| {
+            if key != GENERATION_KEY {
+                return true;
+            }
+            restored = std::str::from_utf8(value).ok().and_then(|g| g.parse().ok());
+            false
+        });
+        if let Some(generation) = restored {
+            common::info!(
+                "Restoring {} pairs from snapshot {} in {:?}",
+                pairs.len(),
+                generation,
+                path
+            );
+        }
+
+        let period = Duration::from_secs(interval_secs);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let snapshots = Snapshots {
+            kv,
+            interval,
+            restored,
+            latest: None,
+            writing: None,
+        };
+        Ok((snapshots, pairs))
+    }
+
+    pub(crate) fn lineage(&self) -> String {
+        match (self.restored, self.latest) {
+            (None, None) => "no snapshot yet".to_owned(),
+            (Some(restored), None) => format!("restored from snapshot {}", restored),
+            (None, Some(latest)) => format!("snapshot {}", latest),
+            (Some(restored), Some(latest)) => {
+                format!("snapshot {}, restored from snapshot {}", latest, restored)
+            }
+        }
+    }
+
+    // Wait for the next thing to do. Cancel-safe.
+    pub(crate) async fn next(&mut self) -> Event {
+        let writing = async {
+            match &mut self.writing {
+                Some(handle) => handle.await,
+                None => std::future::pending().await,
+            }
+        };
+        let written = tokio::select! {
+            _ = self.interval.tick() => return Event::Due,
+            written = writing => written,
+        };
+        self.writing = None;
+        match written.map_err(io::Error::from).and_then(|r| r) {
+            Ok(generation) => self.latest = Some(generation),
+            Err(e) => common::error!("Couldn't write snapshot: {}", e),
+        }
+        Event::Written
+    }
+
+    // Start writing `pairs` as the next snapshot, unless the last one is
+    // still being written. Returns whether it started.
+    pub(crate) fn take(&mut self, mut pairs: Pairs) -> bool {
+        if self.writing.is_some() {
+            return false;
+        }
+        let generation = self.latest.or(self.restored).map_or(1, |g| g + 1);
+        pairs.push((GENERATION_KEY.to_vec(), generation.to_string().into_bytes()));
+        let kv = self.kv.clone();
+        self.writing = Some(tokio::spawn(async move {
+            kv.replace_all(pairs).await.map(|()| generation)
+        }));
+        true
+    }
+
+    // Wait for the snapshot being written, if there is one
+    pub(crate) async fn finish(&mut self) {
+        while self.writing.is_some() {
+            self.next().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(n: u8) -> Pairs {
+        (0..n)
+            .map(|i| (vec![b'k', i], vec![i; i as usize]))
+            .collect()
+    }
+
+    async fn written(snapshots: &mut Snapshots) {
+        while !matches!(snapshots.next().await, Event::Written) {}
+    }
+
+    #[tokio::test]
+    async fn restores_the_latest_snapshot_and_counts_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.db");
+        let (mut snapshots, restored) = Snapshots::open(&path, 60).await.unwrap();
+        assert!(restored.is_empty());
+        assert_eq!(snapshots.lineage(), "no snapshot yet");
+
+        assert!(snapshots.take(pairs(3)));
+        assert!(!snapshots.take(pairs(4)));
+        written(&mut snapshots).await;
+        assert!(snapshots.take(pairs(5)));
+        snapshots.finish().await;
+        assert_eq!(snapshots.lineage(), "snapshot 2");
+        drop(snapshots);
+
+        let (mut snapshots, restored) = Snapshots::open(&path, 60).await.unwrap();
+        assert_eq!(restored, pairs(5));
+        assert_eq!(snapshots.lineage(), "restored from snapshot 2");
+        assert!(snapshots.take(Vec::new()));
+        snapshots.finish().await;
+        assert_eq!(snapshots.lineage(), "snapshot 3, restored from snapshot 2");
+        drop(snapshots);
+
+        let (_, restored) = Snapshots::open(&path, 60).await.unwrap();
+        assert!(restored.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn comes_due_every_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut snapshots, _) = Snapshots::open(&dir.path().join("kv.db"), 5).await.unwrap();
+        let start = tokio::time::Instant::now();
+        for n in 1..=3 {
+            assert!(matches!(snapshots.next().await, Event::Due));
+            assert_eq!(start.elapsed(), Duration::from_secs(5 * n));
+        }
+    }
+}
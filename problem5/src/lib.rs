@@ -0,0 +1,588 @@
+// Mob in the Middle: a budget chat proxy that steals Boguscoin.
+//
+// Every client gets its own connection to the chat server at UPSTREAM
+// (default chat.protohackers.com:16963), and lines are relayed both ways with
+// every Boguscoin address replaced by Tony's (see common::boguscoin).
+//
+// Each direction runs until its side stops sending. A partial line left then
+// is rewritten as if the line ended there and relayed without a newline, and
+// the other side's connection is shut down for writing, so it sees the same
+// half-close; the session ends once both directions have. An error on either
+// side, or a line longer than MAX_LINE_LENGTH, ends the session at once,
+// closing both connections.
+//
+// Connecting upstream is tried UPSTREAM_CONNECT_ATTEMPTS times (default 3),
+// each given UPSTREAM_CONNECT_TIMEOUT_MILLIS (default 5000), with a short
+// backoff in between. The client is disconnected if none of them succeed.
+//
+// With UPSTREAM_SOCKS5 set to a proxy's host:port, the chat server is dialed
+// through that SOCKS5 proxy, authenticating with UPSTREAM_SOCKS5_USERNAME and
+// UPSTREAM_SOCKS5_PASSWORD if a username is set (see socks5.rs).
+//
+// With the `tls` feature and UPSTREAM_TLS=true, the connection upstream is
+// made over TLS while clients keep talking plain text to the proxy. The
+// server is asked for UPSTREAM_TLS_SERVER_NAME (default UPSTREAM's host)
+// through SNI, and its certificate checked against the system's roots, or
+// only the PEM certificates in UPSTREAM_TLS_CA if set. UPSTREAM_TLS_VERIFY=
+// false skips the check, for chat servers with self-signed certificates.
+use common::accept::AcceptLimits;
+use common::boguscoin::{self, TONY_ADDRESS};
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::metrics::{Counter, Scope};
+use common::retry::{Backoff, Stopped};
+use std::borrow::Cow;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+mod serve;
+mod socks5;
+
+pub use serve::serve;
+pub use socks5::Socks5;
+
+const DEFAULT_UPSTREAM: &str = "chat.protohackers.com:16963";
+// Longer lines end the session instead of being buffered indefinitely
+const DEFAULT_MAX_LINE_LENGTH: usize = 16 * 1024;
+const DEFAULT_CONNECT_TIMEOUT_MILLIS: u64 = 5000;
+const DEFAULT_CONNECT_ATTEMPTS: u32 = 3;
+const CONNECT_BACKOFF_INITIAL: Duration = Duration::from_millis(100);
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+    // The chat server, as host:port
+    pub upstream: String,
+    pub max_line_length: usize,
+    pub connect_timeout: Duration,
+    pub connect_attempts: u32,
+    pub upstream_socks5: Option<Socks5>,
+    #[cfg(feature = "tls")]
+    pub upstream_tls: Option<UpstreamTls>,
+}
+
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug)]
+pub struct UpstreamTls {
+    pub server_name: String,
+    pub verify: common::tls::Verify,
+}
+
+#[cfg(feature = "tls")]
+impl UpstreamTls {
+    fn from_env(upstream: &str) -> Option<Self> {
+        if !common::env::var_or("UPSTREAM_TLS", false) {
+            return None;
+        }
+        // The host part of host:port, without an IPv6 address's brackets
+        let host = upstream.rsplit_once(':').map_or(upstream, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let verify = match common::env::var::<String>("UPSTREAM_TLS_CA") {
+            _ if !common::env::var_or("UPSTREAM_TLS_VERIFY", true) => common::tls::Verify::Nothing,
+            Some(path) => common::tls::Verify::Ca(path),
+            None => common::tls::Verify::System,
+        };
+        Some(UpstreamTls {
+            server_name: common::env::var_or("UPSTREAM_TLS_SERVER_NAME", host.to_owned()),
+            verify,
+        })
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env(),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+            upstream: common::env::var_or("UPSTREAM", DEFAULT_UPSTREAM.to_owned()),
+            max_line_length: common::env::var_or("MAX_LINE_LENGTH", DEFAULT_MAX_LINE_LENGTH),
+            connect_timeout: Duration::from_millis(common::env::var_or(
+                "UPSTREAM_CONNECT_TIMEOUT_MILLIS",
+                DEFAULT_CONNECT_TIMEOUT_MILLIS,
+            )),
+            connect_attempts: common::env::var_or(
+                "UPSTREAM_CONNECT_ATTEMPTS",
+                DEFAULT_CONNECT_ATTEMPTS,
+            ),
+            upstream_socks5: Socks5::from_env(),
+            #[cfg(feature = "tls")]
+            upstream_tls: UpstreamTls::from_env(&common::env::var_or(
+                "UPSTREAM",
+                DEFAULT_UPSTREAM.to_owned(),
+            )),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    rewritten: Counter,
+    upstream_failures: Counter,
+}
+
+impl Metrics {
+    fn new(scope: &Scope) -> Self {
+        Metrics {
+            rewritten: scope.counter(
+                "proxy_lines_rewritten_total",
+                "Lines relayed with a Boguscoin address replaced",
+            ),
+            upstream_failures: scope.counter(
+                "proxy_upstream_failures_total",
+                "Clients disconnected because the chat server couldn't be reached",
+            ),
+        }
+    }
+}
+
+// Copy lines from `rd` to `wr`, rewriting addresses, until `rd` ends, then
+// shut `wr` down. Fails if a line is too long.
+async fn relay<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    rd: R,
+    mut wr: W,
+    max_line_length: usize,
+    rewritten: &Counter,
+) -> io::Result<()> {
+    let mut rd = BufReader::new(rd);
+    let mut line = Vec::new();
+    let mut out = Vec::new();
+    loop {
+        line.clear();
+        let n = (&mut rd)
+            .take(max_line_length as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await?;
+        let text = match line.strip_suffix(b"\n") {
+            Some(text) => text,
+            None if n > max_line_length => return Err(io::Error::other("line too long")),
+            None if n == 0 => break,
+            // Closed in the middle of a line, which ends there
+            None => &line[..],
+        };
+        out.clear();
+        match std::str::from_utf8(text).map(|text| boguscoin::rewrite(text, TONY_ADDRESS)) {
+            Ok(Cow::Owned(text)) => {
+                rewritten.inc();
+                out.extend_from_slice(text.as_bytes());
+            }
+            Ok(Cow::Borrowed(text)) => out.extend_from_slice(text.as_bytes()),
+            // Not text, so no addresses either
+            Err(_) => out.extend_from_slice(text),
+        }
+        if text.len() < line.len() {
+            out.push(b'\n');
+        }
+        wr.write_all(&out).await?;
+    }
+    wr.shutdown().await
+}
+
+// How a session ended: an error says which side it came from
+type Relayed = Result<(), (&'static str, io::Error)>;
+
+// Relay both ways between `client` and `upstream` until both have stopped
+// sending, or either fails
+async fn pair<C: ByteStream, U: ByteStream>(
+    client: C,
+    upstream: U,
+    max_line_length: usize,
+    rewritten: &Counter,
+) -> Relayed {
+    let (client_rd, client_wr) = tokio::io::split(client);
+    let (upstream_rd, upstream_wr) = tokio::io::split(upstream);
+    let from_client = async {
+        let relayed = relay(client_rd, upstream_wr, max_line_length, rewritten).await;
+        relayed.map_err(|e| ("client", e))
+    };
+    let from_upstream = async {
+        let relayed = relay(upstream_rd, client_wr, max_line_length, rewritten).await;
+        relayed.map_err(|e| ("upstream", e))
+    };
+    // The first error drops the other direction, and with it both connections
+    tokio::try_join!(from_client, from_upstream).map(|_| ())
+}
+
+#[derive(Clone)]
+struct Proxy {
+    upstream: Arc<str>,
+    max_line_length: usize,
+    connect_timeout: Duration,
+    backoff: Backoff,
+    socks5: Option<Socks5>,
+    metrics: Metrics,
+    // What to dial upstream with and the name to ask for, over TLS
+    #[cfg(feature = "tls")]
+    tls: Option<(common::tls::TlsConnector, Arc<str>)>,
+}
+
+impl Proxy {
+    async fn connect(&self, ctx: &Context) -> Option<TcpStream> {
+        let attempt = |_| async {
+            let connect = async {
+                match &self.socks5 {
+                    Some(socks5) => socks5.connect(&self.upstream).await,
+                    None => common::resolve::connect(&self.upstream).await,
+                }
+            };
+            tokio::time::timeout(self.connect_timeout, connect)
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))
+        };
+        let what = match &self.socks5 {
+            Some(socks5) => format!("connect to {} through {}", self.upstream, socks5.proxy),
+            None => format!("connect to {}", self.upstream),
+        };
+        match common::retry::retry(&self.backoff, &what, &ctx.cancel, attempt).await {
+            Ok(upstream) => Some(upstream),
+            Err(Stopped::Cancelled) => None,
+            Err(Stopped::GaveUp(_)) => {
+                self.metrics.upstream_failures.inc();
+                None
+            }
+        }
+    }
+}
+
+impl Proxy {
+    // Relay between `client` and `upstream` until the session ends
+    async fn relay<C: ByteStream, U: ByteStream>(
+        &self,
+        client: C,
+        upstream: U,
+        peer: Option<SocketAddr>,
+        ctx: Context,
+    ) {
+        ctx.task.phase("relaying");
+        let rewritten = &self.metrics.rewritten;
+        let result = tokio::select! {
+            result = pair(client, upstream, self.max_line_length, rewritten) => result,
+            _ = ctx.cancel.cancelled() => return,
+        };
+        match result {
+            Ok(()) => common::debug!("Both sides closed, ending session for {:?}", peer),
+            Err((side, e)) => common::debug!("Error relaying from {} for {:?}: {}", side, peer, e),
+        }
+    }
+}
+
+impl ConnectionHandler for Proxy {
+    async fn handle<S: ByteStream>(&self, client: S, peer: Option<SocketAddr>, ctx: Context) {
+        ctx.task.phase("connecting upstream");
+        let Some(upstream) = self.connect(&ctx).await else {
+            return;
+        };
+
+        #[cfg(feature = "tls")]
+        if let Some((connector, server_name)) = &self.tls {
+            let handshake = tokio::select! {
+                handshake = common::tls::connect(connector, server_name, upstream) => handshake,
+                _ = ctx.cancel.cancelled() => return,
+            };
+            match handshake {
+                Ok(upstream) => self.relay(client, upstream, peer, ctx).await,
+                Err(e) => {
+                    common::warn!("Couldn't set up TLS to {}: {}", self.upstream, e);
+                    self.metrics.upstream_failures.inc();
+                }
+            }
+            return;
+        }
+        self.relay(client, upstream, peer, ctx).await
+    }
+}
+
+// The handler `run` serves, for a server that accepts connections itself
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    Proxy {
+        upstream: config.upstream.as_str().into(),
+        max_line_length: config.max_line_length,
+        connect_timeout: config.connect_timeout,
+        backoff: Backoff::new(CONNECT_BACKOFF_INITIAL, CONNECT_BACKOFF_MAX)
+            .max_attempts(config.connect_attempts),
+        socks5: config.upstream_socks5.clone(),
+        metrics: Metrics::new(scope),
+        #[cfg(feature = "tls")]
+        tls: config.upstream_tls.as_ref().map(|tls| {
+            let connector = common::tls::connector(&tls.verify);
+            let connector = common::report::startup("set up TLS to UPSTREAM", connector);
+            (connector, tls.server_name.as_str().into())
+        }),
+    }
+}
+
+// Proxy chat sessions from `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem5", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let proxy = handler(&scope, &config);
+
+    #[cfg(feature = "middleware")]
+    let proxy = config.middleware.wrap(proxy, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(proxy.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(proxy.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "isl")]
+    isl::spawn_from_env(proxy.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, proxy).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    const ADDRESS: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+    const MAX_LINE_LENGTH: usize = 64;
+
+    // A session between two in-memory connections, returning the client's
+    // and the chat server's ends of them
+    fn session() -> (DuplexStream, DuplexStream, JoinHandle<Relayed>) {
+        let (client, client_end) = duplex(1024);
+        let (upstream, upstream_end) = duplex(1024);
+        let rewritten = Metrics::new(&Scope::new("problem5", 0)).rewritten;
+        let session = tokio::spawn(async move {
+            pair(client_end, upstream_end, MAX_LINE_LENGTH, &rewritten).await
+        });
+        (client, upstream, session)
+    }
+
+    async fn read_all(stream: &mut DuplexStream) -> String {
+        let mut read = String::new();
+        stream.read_to_string(&mut read).await.unwrap();
+        read
+    }
+
+    async fn read_line(stream: &mut DuplexStream) -> String {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while stream.read(&mut byte).await.unwrap() == 1 {
+            line.push(byte[0]);
+            if byte[0] == b'\n' {
+                break;
+            }
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_half_close_reaches_upstream_which_can_still_answer() {
+        let (mut client, mut upstream, session) = session();
+        client
+            .write_all(format!("hi\npay {}", ADDRESS).as_bytes())
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        // The partial line is rewritten and flushed, then upstream sees EOF
+        assert_eq!(
+            read_all(&mut upstream).await,
+            format!("hi\npay {}", TONY_ADDRESS)
+        );
+
+        upstream.write_all(b"bye\n").await.unwrap();
+        assert_eq!(read_line(&mut client).await, "bye\n");
+        assert!(!session.is_finished());
+        upstream.shutdown().await.unwrap();
+        assert_eq!(read_all(&mut client).await, "");
+        assert!(session.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn upstream_half_close_reaches_the_client_which_can_still_send() {
+        let (mut client, mut upstream, session) = session();
+        upstream
+            .write_all(format!("Welcome\n{} is mine", ADDRESS).as_bytes())
+            .await
+            .unwrap();
+        upstream.shutdown().await.unwrap();
+        assert_eq!(
+            read_all(&mut client).await,
+            format!("Welcome\n{} is mine", TONY_ADDRESS)
+        );
+
+        client.write_all(b"still here\n").await.unwrap();
+        assert_eq!(read_line(&mut upstream).await, "still here\n");
+        client.shutdown().await.unwrap();
+        assert_eq!(read_all(&mut upstream).await, "");
+        assert!(session.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn closing_both_at_once_ends_the_session() {
+        let (mut client, mut upstream, session) = session();
+        client.shutdown().await.unwrap();
+        upstream.shutdown().await.unwrap();
+        assert!(session.await.unwrap().is_ok());
+        assert_eq!(read_all(&mut client).await, "");
+        assert_eq!(read_all(&mut upstream).await, "");
+    }
+
+    #[tokio::test]
+    async fn a_client_error_tears_down_upstream() {
+        let (mut client, mut upstream, session) = session();
+        client.write_all(b"fine\n").await.unwrap();
+        assert_eq!(read_line(&mut upstream).await, "fine\n");
+        client
+            .write_all(&[b'x'; MAX_LINE_LENGTH + 1])
+            .await
+            .unwrap();
+        let (side, _) = session.await.unwrap().unwrap_err();
+        assert_eq!(side, "client");
+        // Neither side half-closed, but both connections are gone
+        assert_eq!(read_all(&mut upstream).await, "");
+        assert_eq!(read_all(&mut client).await, "");
+    }
+
+    #[tokio::test]
+    async fn an_upstream_error_tears_down_the_client() {
+        let (mut client, mut upstream, session) = session();
+        upstream
+            .write_all(&[b'x'; MAX_LINE_LENGTH + 1])
+            .await
+            .unwrap();
+        let (side, _) = session.await.unwrap().unwrap_err();
+        assert_eq!(side, "upstream");
+        assert_eq!(read_all(&mut client).await, "");
+        assert!(client.write_all(b"hello?\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_vanished_upstream_fails_the_next_write() {
+        let (mut client, upstream, session) = session();
+        drop(upstream);
+        // Upstream's EOF is passed on to the client first
+        assert_eq!(read_all(&mut client).await, "");
+        client.write_all(b"anyone?\n").await.unwrap();
+        let (side, e) = session.await.unwrap().unwrap_err();
+        assert_eq!((side, e.kind()), ("client", io::ErrorKind::BrokenPipe));
+    }
+
+    #[tokio::test]
+    async fn relays_lines_split_across_writes() {
+        let (mut client, mut upstream, _session) = session();
+        let line = format!("to {} now\n", ADDRESS);
+        for chunk in line.as_bytes().chunks(3) {
+            client.write_all(chunk).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            read_line(&mut upstream).await,
+            format!("to {} now\n", TONY_ADDRESS)
+        );
+    }
+
+    // Proxy to `upstream` from an ephemeral port, returning that port's address
+    async fn proxy(config: Config) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(listener, CancellationToken::new(), config));
+        addr
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn dials_upstream_over_tls() {
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let acceptor = common::tls::acceptor(&[]).unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = upstream.accept().await.unwrap();
+            let stream = common::tls::accept(&acceptor, socket).await.unwrap();
+            let (rd, mut wr) = tokio::io::split(stream);
+            wr.write_all(b"Send to 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX\n")
+                .await
+                .unwrap();
+            let mut line = String::new();
+            BufReader::new(rd).read_line(&mut line).await.unwrap();
+            wr.write_all(line.as_bytes()).await.unwrap();
+        });
+
+        let config = Config {
+            upstream: upstream_addr.to_string(),
+            upstream_tls: Some(UpstreamTls {
+                server_name: "localhost".to_owned(),
+                verify: common::tls::Verify::Nothing,
+            }),
+            ..Config::from_env()
+        };
+        let client = TcpStream::connect(proxy(config).await).await.unwrap();
+        let (rd, mut wr) = client.into_split();
+        let mut rd = BufReader::new(rd);
+        let mut line = String::new();
+        rd.read_line(&mut line).await.unwrap();
+        assert_eq!(line, format!("Send to {}\n", TONY_ADDRESS));
+
+        wr.write_all(b"pay 7YWHMfk9JZe0LM0g1ZauHuiSxhI please\n")
+            .await
+            .unwrap();
+        line.clear();
+        rd.read_line(&mut line).await.unwrap();
+        assert_eq!(line, format!("pay {} please\n", TONY_ADDRESS));
+    }
+
+    #[tokio::test]
+    async fn dials_upstream_through_socks5() {
+        // A SOCKS5 proxy that is also the chat server it's asked for
+        let socks = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_addr = socks.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = socks.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            socket.write_all(&[5, 0]).await.unwrap();
+            let target = b"chat.invalid";
+            let mut request = vec![0; 5 + target.len() + 2];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..5], [5, 1, 0, 3, target.len() as u8]);
+            assert_eq!(&request[5..5 + target.len()], target);
+            assert_eq!(request[5 + target.len()..], 16963u16.to_be_bytes());
+            socket
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x9c, 0x40])
+                .await
+                .unwrap();
+
+            socket
+                .write_all(format!("Send to {}\n", ADDRESS).as_bytes())
+                .await
+                .unwrap();
+            let mut line = String::new();
+            BufReader::new(socket).read_line(&mut line).await.unwrap();
+            assert_eq!(line, "hello\n");
+        });
+
+        let config = Config {
+            upstream: "chat.invalid:16963".to_owned(),
+            upstream_socks5: Some(Socks5 {
+                proxy: socks_addr.to_string(),
+                credentials: None,
+            }),
+            ..Config::from_env()
+        };
+        let client = TcpStream::connect(proxy(config).await).await.unwrap();
+        let (rd, mut wr) = client.into_split();
+        let mut rd = BufReader::new(rd);
+        let mut line = String::new();
+        rd.read_line(&mut line).await.unwrap();
+        assert_eq!(line, format!("Send to {}\n", TONY_ADDRESS));
+        wr.write_all(b"hello\n").await.unwrap();
+        // The stub closes once it has checked the line
+        line.clear();
+        assert_eq!(rd.read_line(&mut line).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,59 @@
+// Everything the problem5 binary does, callable so that the protohackers
+// binary can run the problem too.
+use crate::Config;
+use common::config::Checker;
+use common::listeners::Listeners;
+use protolib::{Problem, Transport};
+use std::io;
+use std::net::SocketAddr;
+use tokio_util::sync::CancellationToken;
+
+// This problem's own checks, on top of those `checker` makes, and in a dry
+// run a check that UPSTREAM, or the SOCKS5 proxy to it, can be reached
+pub fn check_config(checker: Checker) -> Checker {
+    let checker = checker
+        .positive("MAX_LINE_LENGTH")
+        .positive("UPSTREAM_CONNECT_TIMEOUT_MILLIS")
+        .positive("UPSTREAM_CONNECT_ATTEMPTS");
+    #[cfg(feature = "tls")]
+    let checker = checker
+        .parse::<bool>("UPSTREAM_TLS")
+        .parse::<bool>("UPSTREAM_TLS_VERIFY")
+        .file("UPSTREAM_TLS_CA");
+    let config = Config::from_env();
+    match &config.upstream_socks5 {
+        Some(socks5) => common::dry_run::reachable("UPSTREAM_SOCKS5", &socks5.proxy),
+        None => common::dry_run::reachable("UPSTREAM", &config.upstream),
+    }
+    checker
+}
+
+// Check the configuration, then serve on `addrs` until the process is stopped
+pub fn serve(addrs: &[SocketAddr]) {
+    protolib::main::<Config>(addrs)
+}
+
+impl Problem for Config {
+    const TRANSPORT: Transport = Transport::Tcp;
+    type Listener = Listeners;
+
+    fn name() -> &'static str {
+        "problem5"
+    }
+
+    fn from_env() -> Self {
+        Config::from_env()
+    }
+
+    fn check_config(checker: Checker) -> Checker {
+        check_config(checker)
+    }
+
+    async fn bind(&self, addrs: &[SocketAddr]) -> io::Result<Listeners> {
+        common::listeners::bind(addrs).await
+    }
+
+    async fn serve(&self, listener: Listeners, shutdown: CancellationToken) {
+        crate::run(listener, shutdown, self.clone()).await
+    }
+}
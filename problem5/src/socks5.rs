@@ -0,0 +1,167 @@
+// Dialing the chat server through a SOCKS5 proxy (RFC 1928).
+//
+// The target's host name is passed to the proxy as it is, for the proxy to
+// resolve, so a proxy on a restricted network can reach names the proxy host
+// itself can't look up. IP literals are sent as addresses. With a username
+// the proxy is asked for username/password authentication (RFC 1929), and
+// without one for none at all.
+use std::io;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+#[derive(Clone, Debug)]
+pub struct Socks5 {
+    // The proxy, as host:port
+    pub proxy: String,
+    pub credentials: Option<(String, String)>,
+}
+
+fn protocol_error(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+// What a CONNECT reply's status byte means
+fn reply_error(status: u8) -> io::Error {
+    let what = match status {
+        1 => "general failure",
+        2 => "connection not allowed",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    };
+    io::Error::other(format!("SOCKS5 proxy couldn't connect: {}", what))
+}
+
+impl Socks5 {
+    pub fn from_env() -> Option<Self> {
+        let proxy = common::env::var::<String>("UPSTREAM_SOCKS5")?;
+        let credentials = common::env::var::<String>("UPSTREAM_SOCKS5_USERNAME").map(|user| {
+            let password = common::env::var_or("UPSTREAM_SOCKS5_PASSWORD", String::new());
+            (user, password)
+        });
+        Some(Socks5 { proxy, credentials })
+    }
+
+    // A connection to `target`, a host:port, through the proxy
+    pub async fn connect(&self, target: &str) -> io::Result<TcpStream> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} isn't host:port", target),
+                )
+            })?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let mut stream = common::resolve::connect(&self.proxy).await?;
+        self.authenticate(&mut stream).await?;
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let len = u8::try_from(host.len()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "host name too long for SOCKS5")
+                })?;
+                request.push(DOMAIN);
+                request.push(len);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(protocol_error(format!(
+                "SOCKS version {} in reply",
+                reply[0]
+            )));
+        }
+        if reply[1] != 0 {
+            return Err(reply_error(reply[1]));
+        }
+        // The address the proxy connected from, which isn't needed
+        let len = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN => stream.read_u8().await? as usize,
+            other => return Err(protocol_error(format!("address type {} in reply", other))),
+        };
+        let mut bound = vec![0; len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(stream)
+    }
+
+    // Agree on an authentication method with the proxy, and authenticate
+    async fn authenticate(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let method = match self.credentials {
+            Some(_) => USERNAME_PASSWORD,
+            None => NO_AUTH,
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut chosen = [0; 2];
+        stream.read_exact(&mut chosen).await?;
+        if chosen[0] != VERSION {
+            return Err(protocol_error(format!(
+                "SOCKS version {} in reply",
+                chosen[0]
+            )));
+        }
+        if chosen[1] == NO_ACCEPTABLE_METHODS {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy refused our authentication method",
+            ));
+        }
+        if chosen[1] != method {
+            return Err(protocol_error(format!(
+                "SOCKS5 proxy chose method {}",
+                chosen[1]
+            )));
+        }
+        let Some((user, password)) = &self.credentials else {
+            return Ok(());
+        };
+        let too_long =
+            || io::Error::new(io::ErrorKind::InvalidInput, "SOCKS5 credentials too long");
+        let mut request = vec![1, u8::try_from(user.len()).map_err(|_| too_long())?];
+        request.extend_from_slice(user.as_bytes());
+        request.push(u8::try_from(password.len()).map_err(|_| too_long())?);
+        request.extend_from_slice(password.as_bytes());
+        stream.write_all(&request).await?;
+        let mut status = [0; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy rejected the username and password",
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+// Simulated traffic for a Speed Daemon server.
+//
+// Every road gets a camera every few miles, each on a connection of its own,
+// and one dispatcher takes the tickets for all of them. Cars each drive the
+// length of one road at a steady speed, some of them over the limit, and the
+// cameras they pass report their plates in a shuffled order, so the server
+// sees observations out of order as it would from real cameras. Trips all
+// start and end on day 0, so each speeding car should get exactly one
+// ticket.
+//
+// The tickets the dispatcher gets are printed as they arrive, then how many
+// of the speeding cars were ticketed. The exit status is 0 when that was all
+// of them and no car within the limit was, 1 when not, and 2 when the server
+// couldn't be reached or sent an error.
+use clap::Parser;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+#[command(about = "Drive simulated traffic past a Speed Daemon server's cameras")]
+struct Args {
+    /// The server, as host:port
+    #[arg(long, default_value = "127.0.0.1:39456")]
+    addr: String,
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u16).range(1..=255))]
+    roads: u16,
+    /// Cameras on each road
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(2..))]
+    cameras: u16,
+    /// Miles between neighbouring cameras
+    #[arg(long, default_value_t = 10)]
+    spacing: u16,
+    /// The speed limit on every road, in miles per hour
+    #[arg(long, default_value_t = 60)]
+    limit: u16,
+    #[arg(long, default_value_t = 50)]
+    cars: u32,
+    /// The share of cars that drive over the limit
+    #[arg(long, default_value_t = 0.2)]
+    speeders: f64,
+    /// Seed for the traffic, to repeat a run
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Seconds to wait for tickets once every plate is sent
+    #[arg(long, default_value_t = 2)]
+    wait: u64,
+}
+
+struct Car {
+    plate: String,
+    road: u16,
+    // Miles per hour
+    speed: u16,
+    start: u32,
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.push(s.len() as u8);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn connect_camera(addr: &str, road: u16, mile: u16, limit: u16) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut message = vec![0x80];
+    for n in [road, mile, limit] {
+        message.extend_from_slice(&n.to_be_bytes());
+    }
+    stream.write_all(&message)?;
+    Ok(stream)
+}
+
+fn connect_dispatcher(addr: &str, roads: u16) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut message = vec![0x81, roads as u8];
+    for road in 0..roads {
+        message.extend_from_slice(&road.to_be_bytes());
+    }
+    stream.write_all(&message)?;
+    Ok(stream)
+}
+
+fn read_u16(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut TcpStream) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_str(stream: &mut TcpStream) -> io::Result<String> {
+    let mut len = [0; 1];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0; len[0] as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+// Read tickets from `dispatcher` until `deadline`, printing each and
+// returning the plates ticketed
+fn read_tickets(dispatcher: &mut TcpStream, deadline: Instant) -> io::Result<Vec<String>> {
+    let mut plates = Vec::new();
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(plates);
+        }
+        dispatcher.set_read_timeout(Some(left))?;
+        let mut kind = [0; 1];
+        match dispatcher.read_exact(&mut kind) {
+            Ok(()) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(plates);
+            }
+            Err(e) => return Err(e),
+        }
+        dispatcher.set_read_timeout(None)?;
+        match kind[0] {
+            0x21 => {
+                let plate = read_str(dispatcher)?;
+                let road = read_u16(dispatcher)?;
+                let (mile1, timestamp1) = (read_u16(dispatcher)?, read_u32(dispatcher)?);
+                let (mile2, timestamp2) = (read_u16(dispatcher)?, read_u32(dispatcher)?);
+                let speed = read_u16(dispatcher)?;
+                println!(
+                    "{} on road {}: mile {} at {} to mile {} at {}, {} mph",
+                    plate,
+                    road,
+                    mile1,
+                    timestamp1,
+                    mile2,
+                    timestamp2,
+                    speed as f64 / 100.0
+                );
+                plates.push(plate);
+            }
+            0x10 => {
+                let error = read_str(dispatcher)?;
+                return Err(io::Error::other(format!("server error: {}", error)));
+            }
+            other => {
+                return Err(io::Error::other(format!(
+                    "unexpected message type {:#x}",
+                    other
+                )));
+            }
+        }
+    }
+}
+
+fn simulate(args: &Args) -> io::Result<bool> {
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let length = (args.cameras - 1) as u32 * args.spacing as u32;
+    let slowest = args.limit.saturating_sub(20).max(1);
+    let longest_trip = length * 3600 / slowest as u32 + 1;
+    let cars: Vec<Car> = (0..args.cars)
+        .map(|i| {
+            let speed = if rng.gen_bool(args.speeders.clamp(0.0, 1.0)) {
+                args.limit + rng.gen_range(5..=30)
+            } else {
+                args.limit.saturating_sub(rng.gen_range(0..=20)).max(1)
+            };
+            Car {
+                plate: format!("SIM{:04}", i),
+                road: rng.gen_range(0..args.roads),
+                speed,
+                start: rng.gen_range(0..86400u32.saturating_sub(longest_trip).max(1)),
+            }
+        })
+        .collect();
+
+    let mut dispatcher = connect_dispatcher(&args.addr, args.roads)?;
+    let mut cameras = Vec::new();
+    for road in 0..args.roads {
+        for camera in 0..args.cameras {
+            let mile = camera * args.spacing;
+            cameras.push((
+                (road, mile),
+                connect_camera(&args.addr, road, mile, args.limit)?,
+            ));
+        }
+    }
+
+    // Every sighting, in no particular order
+    let mut sightings: Vec<(usize, &Car, u32)> = Vec::new();
+    for car in &cars {
+        for (i, ((road, mile), _)) in cameras.iter().enumerate() {
+            if *road == car.road {
+                let hours = *mile as f64 / car.speed as f64;
+                sightings.push((i, car, car.start + (hours * 3600.0).round() as u32));
+            }
+        }
+    }
+    sightings.shuffle(&mut rng);
+    for (i, car, timestamp) in sightings {
+        let mut message = vec![0x20];
+        put_str(&mut message, &car.plate);
+        message.extend_from_slice(&timestamp.to_be_bytes());
+        cameras[i].1.write_all(&message)?;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(args.wait);
+    let ticketed: HashSet<String> = read_tickets(&mut dispatcher, deadline)?
+        .into_iter()
+        .collect();
+    let speeding: HashSet<&str> = cars
+        .iter()
+        .filter(|car| car.speed > args.limit)
+        .map(|car| car.plate.as_str())
+        .collect();
+    let caught = ticketed
+        .iter()
+        .filter(|p| speeding.contains(p.as_str()))
+        .count();
+    let wrong = ticketed.len() - caught;
+    println!(
+        "{} of {} speeding cars ticketed, {} others",
+        caught,
+        speeding.len(),
+        wrong
+    );
+    Ok(caught == speeding.len() && wrong == 0)
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match simulate(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("Couldn't simulate traffic on {}: {}", args.addr, e);
+            ExitCode::from(2)
+        }
+    }
+}
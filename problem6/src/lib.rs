@@ -0,0 +1,360 @@
+// Speed Daemon: cameras report the plates they see, dispatchers get tickets
+// for the cars that sped between two of them.
+//
+// A client first says what it is, with IAmCamera or IAmDispatcher, and can
+// ask for heartbeats once, at any point. Anything out of place (a plate from
+// something that isn't a camera, identifying twice, asking for heartbeats
+// twice, an unknown message type) gets an Error message and the connection is
+// closed. See ticketing.rs for who gets a ticket, and roads.rs for how
+// tickets reach dispatchers.
+use common::accept::AcceptLimits;
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::memory::Ledger;
+use common::metrics::{Counter, Gauge, Scope};
+use message::{Request, RequestCodec, Ticket};
+use roads::{Outbox, Roads};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use ticketing::Daily;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Interval};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tokio_util::sync::CancellationToken;
+
+mod message;
+mod roads;
+mod serve;
+mod ticketing;
+
+pub use serve::serve;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env().busy_message(message::BUSY),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    pub(crate) observed: Counter,
+    pub(crate) issued: Counter,
+    // Tickets put aside for lack of a dispatcher, and those written to one
+    pub(crate) stored: Counter,
+    delivered: Counter,
+    pub(crate) pending: Gauge,
+    cameras: Gauge,
+    dispatchers: Gauge,
+    errors: Counter,
+    memory: Ledger,
+}
+
+impl Metrics {
+    fn new(scope: &Scope) -> Self {
+        Metrics {
+            observed: scope.counter("speed_observations_total", "Plates reported by cameras"),
+            issued: scope.counter("speed_tickets_total", "Tickets issued"),
+            stored: scope.counter(
+                "speed_tickets_stored_total",
+                "Tickets stored until a dispatcher for their road connects",
+            ),
+            delivered: scope.counter(
+                "speed_tickets_delivered_total",
+                "Tickets written to a dispatcher",
+            ),
+            pending: scope.gauge(
+                "speed_tickets_waiting",
+                "Tickets waiting for a dispatcher for their road",
+            ),
+            cameras: scope.gauge("speed_cameras_connected", "Clients identified as cameras"),
+            dispatchers: scope.gauge(
+                "speed_dispatchers_connected",
+                "Clients identified as dispatchers",
+            ),
+            errors: scope.counter(
+                "speed_client_errors_total",
+                "Clients disconnected for sending something out of place",
+            ),
+            memory: Ledger::new(scope),
+        }
+    }
+}
+
+// Counts a client in one of the connected gauges for as long as it's kept
+struct Connected(Gauge);
+
+impl Connected {
+    fn new(gauge: &Gauge) -> Self {
+        gauge.inc();
+        Connected(gauge.clone())
+    }
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+enum Role {
+    Unidentified,
+    Camera { road: u16, mile: u16, limit: u16 },
+    // With its id in Roads
+    Dispatcher(u64),
+}
+
+// Wait for the next heartbeat, or forever if there are none
+async fn next_heartbeat(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn process_socket<S: AsyncRead + AsyncWrite>(
+    socket: S,
+    peer: Option<SocketAddr>,
+    roads: Arc<Roads>,
+    metrics: Metrics,
+    ctx: Context,
+) {
+    let (rd, mut wr) = tokio::io::split(socket);
+    let mut requests = FramedRead::new(rd, RequestCodec);
+    let mut role = Role::Unidentified;
+    let mut heartbeat = None;
+    let mut wants_heartbeat = false;
+    let mut _connected = None;
+    // Tickets queued are charged by Roads, the buffers here
+    let account = metrics.memory.open(peer);
+    let mut buffers = account.hold();
+    // Only used once identified as a dispatcher
+    let (outbox, mut tickets) = mpsc::unbounded_channel();
+    let outbox = Outbox::new(outbox, account.tab());
+    // A ticket being written, to hand back if that fails
+    let mut sending: Option<Ticket> = None;
+    let mut out = Vec::new();
+
+    let error = loop {
+        out.clear();
+        if !buffers.set(requests.read_buffer().capacity() + out.capacity())
+            || !account.within_limit()
+        {
+            break Some("memory limit exceeded");
+        }
+        ctx.task.phase("waiting for messages");
+        tokio::select! {
+            request = requests.next() => {
+                let request = match request {
+                    None => break None,
+                    Some(Ok(request)) => request,
+                    Some(Err(e)) => {
+                        common::debug!("Error reading message: {}", e);
+                        break Some("illegal msg");
+                    }
+                };
+                match (request, &role) {
+                    (Request::Plate { plate, timestamp }, &Role::Camera { road, mile, limit }) => {
+                        roads.observe(plate, road, mile, limit, timestamp);
+                    }
+                    (Request::Plate { .. }, _) => break Some("only cameras report plates"),
+                    (Request::WantHeartbeat { .. }, _) if wants_heartbeat => {
+                        break Some("heartbeats were already requested");
+                    }
+                    (Request::WantHeartbeat { interval }, _) => {
+                        wants_heartbeat = true;
+                        if interval > 0 {
+                            let period = Duration::from_millis(interval as u64 * 100);
+                            heartbeat = Some(tokio::time::interval_at(Instant::now() + period, period));
+                        }
+                    }
+                    (Request::IAmCamera { road, mile, limit }, Role::Unidentified) => {
+                        role = Role::Camera { road, mile, limit };
+                        _connected = Some(Connected::new(&metrics.cameras));
+                    }
+                    (Request::IAmDispatcher { roads: dispatching }, Role::Unidentified) => {
+                        role = Role::Dispatcher(roads.add_dispatcher(&dispatching, outbox.clone()));
+                        _connected = Some(Connected::new(&metrics.dispatchers));
+                    }
+                    (Request::IAmCamera { .. } | Request::IAmDispatcher { .. }, _) => {
+                        break Some("already identified");
+                    }
+                }
+            }
+            ticket = tickets.recv() => {
+                // Never None, as `outbox` is still here
+                if let Some(ticket) = ticket {
+                    account.credit(ticket.size());
+                    message::encode_ticket(&mut out, &ticket);
+                    sending = Some(ticket);
+                }
+            }
+            _ = next_heartbeat(&mut heartbeat) => message::encode_heartbeat(&mut out),
+            _ = ctx.cancel.cancelled() => break None,
+        }
+        if out.is_empty() {
+            continue;
+        }
+        ctx.task.phase("writing");
+        tokio::select! {
+            written = wr.write_all(&out) => if written.is_err() { break None },
+            _ = ctx.cancel.cancelled() => break None,
+        }
+        if sending.take().is_some() {
+            metrics.delivered.inc();
+        }
+    };
+
+    if let Some(error) = error {
+        metrics.errors.inc();
+        out.clear();
+        message::encode_error(&mut out, error);
+        wr.write_all(&out).await.unwrap_or(());
+    }
+    if let Role::Dispatcher(id) = role {
+        tickets.close();
+        let mut unsent: Vec<Ticket> = sending.into_iter().collect();
+        while let Ok(ticket) = tickets.try_recv() {
+            unsent.push(ticket);
+        }
+        roads.remove_dispatcher(id, unsent);
+    }
+}
+
+#[derive(Clone)]
+struct SpeedDaemon {
+    roads: Arc<Roads>,
+    metrics: Metrics,
+}
+
+impl ConnectionHandler for SpeedDaemon {
+    async fn handle<S: ByteStream>(&self, socket: S, peer: Option<SocketAddr>, ctx: Context) {
+        process_socket(socket, peer, self.roads.clone(), self.metrics.clone(), ctx).await
+    }
+}
+
+// The handler `run` serves, for a server that accepts connections itself
+pub fn handler(scope: &Scope, _config: &Config) -> impl ConnectionHandler {
+    let metrics = Metrics::new(scope);
+    SpeedDaemon {
+        roads: Arc::new(Roads::new(Box::new(Daily::default()), metrics.clone())),
+        metrics,
+    }
+}
+
+// Run the ticketing system on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem6", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let speed = handler(&scope, &config);
+
+    #[cfg(feature = "middleware")]
+    let speed = config.middleware.wrap(speed, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(speed.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(speed.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "isl")]
+    isl::spawn_from_env(speed.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, speed).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+
+    fn camera(road: u16, mile: u16, limit: u16) -> Vec<u8> {
+        let mut message = vec![0x80];
+        for n in [road, mile, limit] {
+            message.extend_from_slice(&n.to_be_bytes());
+        }
+        message
+    }
+
+    fn plate(plate: &str, timestamp: u32) -> Vec<u8> {
+        let mut message = vec![0x20, plate.len() as u8];
+        message.extend_from_slice(plate.as_bytes());
+        message.extend_from_slice(&timestamp.to_be_bytes());
+        message
+    }
+
+    // A client of `speed` that has sent `messages`
+    async fn client(speed: &SpeedDaemon, messages: &[Vec<u8>]) -> DuplexStream {
+        let (mut client, server) = duplex(1024);
+        let speed = speed.clone();
+        tokio::spawn(async move { speed.handle(server, None, Context::new("test")).await });
+        client.write_all(&messages.concat()).await.unwrap();
+        client
+    }
+
+    async fn until(what: impl Fn() -> bool) {
+        while !what() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_clients_and_where_tickets_went() {
+        let metrics = Metrics::new(&Scope::new("problem6", 6001));
+        let speed = SpeedDaemon {
+            roads: Arc::new(Roads::new(Box::new(Daily::default()), metrics.clone())),
+            metrics: metrics.clone(),
+        };
+        let first = client(&speed, &[camera(7, 8, 60), plate("UN1X", 0)]).await;
+        let second = client(&speed, &[camera(7, 9, 60), plate("UN1X", 45)]).await;
+        until(|| metrics.issued.get() == 1).await;
+        assert_eq!(metrics.cameras.get(), 2);
+        assert_eq!(metrics.observed.get(), 2);
+        assert_eq!((metrics.stored.get(), metrics.pending.get()), (1, 1));
+        assert_eq!(metrics.delivered.get(), 0);
+
+        let mut dispatcher = client(&speed, &[vec![0x81, 1, 0, 7]]).await;
+        let mut ticket = [0; 1 + 1 + 4 + 2 + 2 + 4 + 2 + 4 + 2];
+        dispatcher.read_exact(&mut ticket).await.unwrap();
+        assert_eq!(ticket[0], 0x21);
+        until(|| metrics.delivered.get() == 1).await;
+        assert_eq!(metrics.dispatchers.get(), 1);
+        assert_eq!((metrics.stored.get(), metrics.pending.get()), (1, 0));
+
+        drop((first, second, dispatcher));
+        until(|| metrics.cameras.get() == 0 && metrics.dispatchers.get() == 0).await;
+        assert_eq!(metrics.errors.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn clients_that_never_identify_count_as_neither() {
+        let metrics = Metrics::new(&Scope::new("problem6", 6002));
+        let speed = SpeedDaemon {
+            roads: Arc::new(Roads::new(Box::new(Daily::default()), metrics.clone())),
+            metrics: metrics.clone(),
+        };
+        // A plate from something that isn't a camera
+        let mut client = client(&speed, &[plate("UN1X", 0)]).await;
+        let mut error = Vec::new();
+        client.read_to_end(&mut error).await.unwrap();
+        assert_eq!(error[0], 0x10);
+        assert_eq!(metrics.errors.get(), 1);
+        assert_eq!((metrics.cameras.get(), metrics.dispatchers.get()), (0, 0));
+    }
+}
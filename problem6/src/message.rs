@@ -0,0 +1,249 @@
+// The Speed Daemon wire format.
+//
+// Every message is a type byte followed by its fields: big-endian u8, u16 and
+// u32 integers, and strings as a length byte followed by that many bytes.
+// Clients send Plate, WantHeartbeat, IAmCamera and IAmDispatcher; the server
+// sends Error, Ticket and Heartbeat.
+use bytes::{Buf, BytesMut};
+use std::io;
+use tokio_util::codec::Decoder;
+
+const ERROR: u8 = 0x10;
+const PLATE: u8 = 0x20;
+const TICKET: u8 = 0x21;
+const WANT_HEARTBEAT: u8 = 0x40;
+const HEARTBEAT: u8 = 0x41;
+const I_AM_CAMERA: u8 = 0x80;
+const I_AM_DISPATCHER: u8 = 0x81;
+
+// An Error telling a client the server is too busy to take it
+pub(crate) const BUSY: &[u8] = b"\x10\x0bserver busy";
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Request {
+    Plate { plate: String, timestamp: u32 },
+    // Deciseconds between heartbeats, 0 for none
+    WantHeartbeat { interval: u32 },
+    IAmCamera { road: u16, mile: u16, limit: u16 },
+    IAmDispatcher { roads: Vec<u16> },
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    // Hundredths of a mile per hour
+    pub speed: u16,
+}
+
+impl Ticket {
+    // Roughly what it takes up queued
+    pub(crate) fn size(&self) -> usize {
+        std::mem::size_of::<Ticket>() + self.plate.len()
+    }
+}
+
+// Why a request couldn't be read yet
+enum Short {
+    Incomplete,
+    UnknownType(u8),
+}
+
+// Reads fields off the front of a buffer without consuming it, so nothing is
+// lost when the message turns out to be incomplete
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn u8(&mut self) -> Result<u8, Short> {
+        let (&first, rest) = self.0.split_first().ok_or(Short::Incomplete)?;
+        self.0 = rest;
+        Ok(first)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&[u8], Short> {
+        if self.0.len() < n {
+            return Err(Short::Incomplete);
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, Short> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Short> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, Short> {
+        let len = self.u8()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+fn parse(fields: &mut Fields) -> Result<Request, Short> {
+    match fields.u8()? {
+        PLATE => Ok(Request::Plate {
+            plate: fields.str()?,
+            timestamp: fields.u32()?,
+        }),
+        WANT_HEARTBEAT => Ok(Request::WantHeartbeat {
+            interval: fields.u32()?,
+        }),
+        I_AM_CAMERA => Ok(Request::IAmCamera {
+            road: fields.u16()?,
+            mile: fields.u16()?,
+            limit: fields.u16()?,
+        }),
+        I_AM_DISPATCHER => {
+            let count = fields.u8()?;
+            let roads = (0..count).map(|_| fields.u16()).collect::<Result<_, _>>()?;
+            Ok(Request::IAmDispatcher { roads })
+        }
+        other => Err(Short::UnknownType(other)),
+    }
+}
+
+pub(crate) struct RequestCodec;
+
+impl Decoder for RequestCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut fields = Fields(&buf[..]);
+        match parse(&mut fields) {
+            Ok(request) => {
+                let used = buf.len() - fields.0.len();
+                buf.advance(used);
+                Ok(Some(request))
+            }
+            Err(Short::Incomplete) => Ok(None),
+            Err(Short::UnknownType(t)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message type 0x{:02x}", t),
+            )),
+        }
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    // Longer strings can't be represented; only error messages could be
+    let s = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+    out.push(s.len() as u8);
+    out.extend_from_slice(s);
+}
+
+pub(crate) fn encode_error(out: &mut Vec<u8>, msg: &str) {
+    out.push(ERROR);
+    put_str(out, msg);
+}
+
+pub(crate) fn encode_ticket(out: &mut Vec<u8>, ticket: &Ticket) {
+    out.push(TICKET);
+    put_str(out, &ticket.plate);
+    out.extend_from_slice(&ticket.road.to_be_bytes());
+    out.extend_from_slice(&ticket.mile1.to_be_bytes());
+    out.extend_from_slice(&ticket.timestamp1.to_be_bytes());
+    out.extend_from_slice(&ticket.mile2.to_be_bytes());
+    out.extend_from_slice(&ticket.timestamp2.to_be_bytes());
+    out.extend_from_slice(&ticket.speed.to_be_bytes());
+}
+
+pub(crate) fn encode_heartbeat(out: &mut Vec<u8>) {
+    out.push(HEARTBEAT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codecs::testkit;
+
+    // What a client would send
+    fn encode(request: &Request) -> Vec<u8> {
+        let mut out = Vec::new();
+        match request {
+            Request::Plate { plate, timestamp } => {
+                out.push(PLATE);
+                put_str(&mut out, plate);
+                out.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            Request::WantHeartbeat { interval } => {
+                out.push(WANT_HEARTBEAT);
+                out.extend_from_slice(&interval.to_be_bytes());
+            }
+            Request::IAmCamera { road, mile, limit } => {
+                out.push(I_AM_CAMERA);
+                for n in [road, mile, limit] {
+                    out.extend_from_slice(&n.to_be_bytes());
+                }
+            }
+            Request::IAmDispatcher { roads } => {
+                out.push(I_AM_DISPATCHER);
+                out.push(roads.len() as u8);
+                for road in roads {
+                    out.extend_from_slice(&road.to_be_bytes());
+                }
+            }
+        }
+        out
+    }
+
+    fn requests() -> Vec<Request> {
+        vec![
+            Request::IAmCamera {
+                road: 66,
+                mile: 100,
+                limit: 60,
+            },
+            Request::Plate {
+                plate: "UN1X".into(),
+                timestamp: 1000,
+            },
+            Request::Plate {
+                plate: String::new(),
+                timestamp: u32::MAX,
+            },
+            Request::WantHeartbeat { interval: 10 },
+            Request::IAmDispatcher {
+                roads: vec![66, 368, 5000],
+            },
+            Request::IAmDispatcher { roads: vec![] },
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            assert_eq!(
+                testkit::round_trip(|| RequestCodec, &encode(&request)),
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn requests_decode_however_they_arrive() {
+        let input: Vec<u8> = requests().iter().flat_map(encode).collect();
+        let decoded = testkit::assert_splits_agree(|| RequestCodec, &input).unwrap();
+        assert_eq!(decoded, requests());
+
+        let mut bad = encode(&Request::WantHeartbeat { interval: 0 });
+        bad.extend_from_slice(&[TICKET, 0, 0]);
+        let error = testkit::assert_splits_agree(|| RequestCodec, &bad).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_request_cut_off_by_the_end_of_the_stream_is_an_error() {
+        for request in requests() {
+            testkit::assert_truncations_fail(|| RequestCodec, &encode(&request));
+        }
+    }
+}
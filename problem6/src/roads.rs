@@ -0,0 +1,225 @@
+// The tickets issued, and where they go.
+//
+// Every observation goes to the ticketing policy (see ticketing.rs), and the
+// tickets it issues go to a dispatcher for their road, or wait for one to
+// connect.
+use crate::message::Ticket;
+use crate::ticketing::Policy;
+use crate::Metrics;
+use common::memory::Tab;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+// A dispatcher's queue of tickets to send, each charged to its memory account
+// until it takes it. Unbounded, as tickets it can't take would only pile up
+// in `pending` instead.
+#[derive(Clone)]
+pub(crate) struct Outbox {
+    tx: mpsc::UnboundedSender<Ticket>,
+    tab: Tab,
+}
+
+impl Outbox {
+    pub(crate) fn new(tx: mpsc::UnboundedSender<Ticket>, tab: Tab) -> Self {
+        Outbox { tx, tab }
+    }
+
+    // Queue `ticket`, or hand it back if the dispatcher is leaving
+    fn send(&self, ticket: Ticket) -> Result<(), Ticket> {
+        let size = ticket.size();
+        self.tx.send(ticket).map_err(|e| e.0)?;
+        self.tab.charge(size);
+        Ok(())
+    }
+}
+
+struct State {
+    policy: Box<dyn Policy>,
+    // Tickets for roads nobody is dispatching for yet
+    pending: HashMap<u16, VecDeque<Ticket>>,
+    dispatchers: HashMap<u16, Vec<(u64, Outbox)>>,
+    next_dispatcher: u64,
+}
+
+pub(crate) struct Roads {
+    state: Mutex<State>,
+    metrics: Metrics,
+}
+
+impl Roads {
+    pub(crate) fn new(policy: Box<dyn Policy>, metrics: Metrics) -> Self {
+        Roads {
+            state: Mutex::new(State {
+                policy,
+                pending: HashMap::new(),
+                dispatchers: HashMap::new(),
+                next_dispatcher: 0,
+            }),
+            metrics,
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking road state: {}", e))
+    }
+
+    // Record `plate` seen by a camera at `mile` on `road` at `timestamp`,
+    // issuing whatever tickets that calls for
+    pub(crate) fn observe(&self, plate: String, road: u16, mile: u16, limit: u16, timestamp: u32) {
+        self.metrics.observed.inc();
+        let mut state = self.state();
+        let tickets = state.policy.observe(&plate, road, mile, limit, timestamp);
+        for ticket in tickets {
+            self.metrics.issued.inc();
+            common::info!(
+                "Ticket for {} on road {}: {} mph",
+                ticket.plate,
+                road,
+                ticket.speed as f64 / 100.0
+            );
+            self.dispatch(&mut state, ticket);
+        }
+    }
+
+    // Hand `ticket` to a dispatcher for its road, or keep it until one connects
+    fn dispatch(&self, state: &mut State, mut ticket: Ticket) {
+        if let Some(outboxes) = state.dispatchers.get(&ticket.road) {
+            for (_, outbox) in outboxes.iter() {
+                match outbox.send(ticket) {
+                    Ok(()) => return,
+                    // Its dispatcher is leaving and will be removed
+                    Err(unsent) => ticket = unsent,
+                }
+            }
+        }
+        self.metrics.stored.inc();
+        self.metrics.pending.inc();
+        state
+            .pending
+            .entry(ticket.road)
+            .or_default()
+            .push_back(ticket);
+    }
+
+    // Start sending tickets for `roads` to `outbox`, beginning with those
+    // waiting for a dispatcher. Returns an id to remove it by.
+    pub(crate) fn add_dispatcher(&self, roads: &[u16], outbox: Outbox) -> u64 {
+        let mut state = self.state();
+        let id = state.next_dispatcher;
+        state.next_dispatcher += 1;
+        for &road in roads {
+            state
+                .dispatchers
+                .entry(road)
+                .or_default()
+                .push((id, outbox.clone()));
+        }
+        for &road in roads {
+            let waiting = state.pending.remove(&road).unwrap_or_default();
+            self.metrics.pending.add(-(waiting.len() as i64));
+            for ticket in waiting {
+                self.dispatch(&mut state, ticket);
+            }
+        }
+        id
+    }
+
+    // Stop sending to dispatcher `id`, handing back the tickets it didn't send.
+    // Its outbox must be closed first, so none can arrive after they're taken.
+    pub(crate) fn remove_dispatcher(&self, id: u64, unsent: Vec<Ticket>) {
+        let mut state = self.state();
+        for outboxes in state.dispatchers.values_mut() {
+            outboxes.retain(|(other, _)| *other != id);
+        }
+        for ticket in unsent {
+            self.dispatch(&mut state, ticket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ticketing::{Daily, SECS_PER_DAY};
+    use common::memory::Ledger;
+    use common::metrics::Scope;
+
+    // Roads with a dispatcher for `road`, and what that dispatcher gets
+    fn roads(port: u16, road: u16) -> (Roads, mpsc::UnboundedReceiver<Ticket>) {
+        let scope = Scope::new("problem6", port);
+        let roads = Roads::new(Box::new(Daily::default()), Metrics::new(&scope));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let tab = Ledger::new(&scope).open(None).tab();
+        roads.add_dispatcher(&[road], Outbox::new(tx, tab));
+        (roads, rx)
+    }
+
+    fn issued(rx: &mut mpsc::UnboundedReceiver<Ticket>) -> Vec<(u32, u32, u16)> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|t| (t.timestamp1, t.timestamp2, t.speed))
+            .collect()
+    }
+
+    #[test]
+    fn tickets_from_half_a_mile_per_hour_over_the_limit() {
+        let (roads, mut rx) = roads(6101, 1);
+        // Exactly 60 mph, then 60.5 mph
+        roads.observe("AT".to_owned(), 1, 0, 60, 0);
+        roads.observe("AT".to_owned(), 1, 60, 60, 3600);
+        roads.observe("OVER".to_owned(), 1, 0, 60, 0);
+        roads.observe("OVER".to_owned(), 1, 121, 60, 7200);
+        assert_eq!(issued(&mut rx), [(0, 7200, 6050)]);
+    }
+
+    #[test]
+    fn a_ticket_counts_for_every_day_it_spans() {
+        let (roads, mut rx) = roads(6102, 1);
+        let day = SECS_PER_DAY;
+        // From the last hour of day 0 to late on day 2, at 120 mph
+        roads.observe("SPAN".to_owned(), 1, 0, 60, day - 3600);
+        roads.observe("SPAN".to_owned(), 1, 5520, 60, 2 * day + 75600);
+        // Speeding again within day 2, which is taken
+        roads.observe("SPAN".to_owned(), 1, 5720, 60, 2 * day + 79200);
+        // And on day 3, which isn't
+        roads.observe("SPAN".to_owned(), 1, 5920, 60, 3 * day + 100);
+        roads.observe("SPAN".to_owned(), 1, 6120, 60, 3 * day + 3700);
+        assert_eq!(
+            issued(&mut rx),
+            [
+                (day - 3600, 2 * day + 75600, 12000),
+                (3 * day + 100, 3 * day + 3700, 20000)
+            ]
+        );
+    }
+
+    #[test]
+    fn one_ticket_per_car_per_day() {
+        let (roads, mut rx) = roads(6103, 1);
+        for hour in 0..4 {
+            roads.observe("DAILY".to_owned(), 1, hour as u16 * 100, 60, hour * 3600);
+        }
+        // Another car speeding the same day still gets its own
+        roads.observe("OTHER".to_owned(), 1, 0, 60, 0);
+        roads.observe("OTHER".to_owned(), 1, 100, 60, 3600);
+        assert_eq!(issued(&mut rx), [(0, 3600, 10000), (0, 3600, 10000)]);
+    }
+
+    #[test]
+    fn observations_out_of_order_compare_with_their_neighbours() {
+        let (roads, mut rx) = roads(6104, 1);
+        // 50 mph from first to last, but not in between
+        roads.observe("LATE".to_owned(), 1, 100, 60, 7200);
+        roads.observe("LATE".to_owned(), 1, 0, 60, 0);
+        assert_eq!(issued(&mut rx), []);
+        roads.observe("LATE".to_owned(), 1, 40, 60, 1200);
+        let tickets: Vec<Ticket> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(tickets.len(), 1);
+        let ticket = &tickets[0];
+        assert_eq!((ticket.mile1, ticket.timestamp1), (0, 0));
+        assert_eq!((ticket.mile2, ticket.timestamp2), (40, 1200));
+        assert_eq!(ticket.speed, 12000);
+    }
+}
@@ -0,0 +1,162 @@
+// Who gets a ticket, decided apart from the connections so that it can be
+// tested, or swapped for another policy, on its own.
+//
+// `Daily`, the policy the server uses, keeps observations per plate and road,
+// ordered by time, so a new one only needs comparing with the observations
+// just before and after it: if the car averaged too much between any two, it
+// did between two neighbouring ones too. Averaging at least half a mile per
+// hour over the limit gets a ticket, unless the car already had one on any of
+// the days the two observations span (days start every 86400 seconds).
+use crate::message::Ticket;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::ops::RangeInclusive;
+
+pub(crate) const SECS_PER_DAY: u32 = 86400;
+
+pub(crate) trait Policy: Send {
+    // Record `plate` seen by a camera at `mile` on `road` at `timestamp`,
+    // returning the tickets that calls for
+    fn observe(
+        &mut self,
+        plate: &str,
+        road: u16,
+        mile: u16,
+        limit: u16,
+        timestamp: u32,
+    ) -> Vec<Ticket>;
+}
+
+// The average speed in miles per hour between the (timestamp, mile)
+// positions `a` and `b`, or None if they are at the same time
+pub(crate) fn speed(a: (u32, u16), b: (u32, u16)) -> Option<f64> {
+    if a.0 == b.0 {
+        return None;
+    }
+    let miles = a.1.abs_diff(b.1) as f64;
+    let hours = a.0.abs_diff(b.0) as f64 / 3600.0;
+    Some(miles / hours)
+}
+
+// The days a ticket from `timestamp1` to `timestamp2` counts for
+pub(crate) fn days(timestamp1: u32, timestamp2: u32) -> RangeInclusive<u32> {
+    timestamp1 / SECS_PER_DAY..=timestamp2 / SECS_PER_DAY
+}
+
+// The ticket for being at the (timestamp, mile) positions `a` and `b`, if
+// getting from one to the other took speeding
+fn check(plate: &str, road: u16, limit: u16, a: (u32, u16), b: (u32, u16)) -> Option<Ticket> {
+    let ((timestamp1, mile1), (timestamp2, mile2)) = if a.0 <= b.0 { (a, b) } else { (b, a) };
+    let speed = speed(a, b)?;
+    (speed >= limit as f64 + 0.5).then(|| Ticket {
+        plate: plate.to_owned(),
+        road,
+        mile1,
+        timestamp1,
+        mile2,
+        timestamp2,
+        speed: (speed * 100.0).round().min(u16::MAX as f64) as u16,
+    })
+}
+
+// At most one ticket per car per day
+#[derive(Default)]
+pub(crate) struct Daily {
+    // (plate, road) -> timestamp -> mile
+    observations: HashMap<(String, u16), BTreeMap<u32, u16>>,
+    ticketed_days: HashMap<String, HashSet<u32>>,
+}
+
+impl Policy for Daily {
+    fn observe(
+        &mut self,
+        plate: &str,
+        road: u16,
+        mile: u16,
+        limit: u16,
+        timestamp: u32,
+    ) -> Vec<Ticket> {
+        let seen = self
+            .observations
+            .entry((plate.to_owned(), road))
+            .or_default();
+        if seen.insert(timestamp, mile).is_some() {
+            // Seen at the same time already, so nothing new to compare
+            return Vec::new();
+        }
+        let before = seen.range(..timestamp).next_back();
+        let after = seen.range((Excluded(timestamp), Unbounded)).next();
+        let candidates = [before, after]
+            .into_iter()
+            .flatten()
+            .filter_map(|(&t, &m)| check(plate, road, limit, (t, m), (timestamp, mile)));
+
+        let mut tickets = Vec::new();
+        for ticket in candidates {
+            let days = days(ticket.timestamp1, ticket.timestamp2);
+            let ticketed = self.ticketed_days.entry(plate.to_owned()).or_default();
+            if days.clone().any(|day| ticketed.contains(&day)) {
+                continue;
+            }
+            ticketed.extend(days);
+            tickets.push(ticket);
+        }
+        tickets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observe(policy: &mut Daily, plate: &str, mile: u16, timestamp: u32) -> Vec<(u32, u32)> {
+        policy
+            .observe(plate, 1, mile, 60, timestamp)
+            .iter()
+            .map(|t| (t.timestamp1, t.timestamp2))
+            .collect()
+    }
+
+    #[test]
+    fn speed_is_miles_over_hours_either_way_round() {
+        assert_eq!(speed((0, 0), (3600, 60)), Some(60.0));
+        assert_eq!(speed((3600, 60), (0, 0)), Some(60.0));
+        assert_eq!(speed((1800, 10), (0, 40)), Some(60.0));
+        assert_eq!(speed((7200, 0), (0, 121)), Some(60.5));
+        assert_eq!(speed((10, 5), (10, 9)), None);
+    }
+
+    #[test]
+    fn tickets_carry_hundredths_of_a_mile_per_hour() {
+        assert!(check("A", 1, 60, (0, 0), (3600, 60)).is_none());
+        let ticket = check("A", 1, 60, (7200, 121), (0, 0)).unwrap();
+        assert_eq!((ticket.timestamp1, ticket.mile1), (0, 0));
+        assert_eq!((ticket.timestamp2, ticket.mile2), (7200, 121));
+        assert_eq!(ticket.speed, 6050);
+    }
+
+    #[test]
+    fn days_start_every_86400_seconds() {
+        assert_eq!(days(0, SECS_PER_DAY - 1), 0..=0);
+        assert_eq!(days(SECS_PER_DAY - 1, SECS_PER_DAY), 0..=1);
+        assert_eq!(days(SECS_PER_DAY, 3 * SECS_PER_DAY + 5), 1..=3);
+    }
+
+    #[test]
+    fn one_ticket_per_day_whichever_road() {
+        let mut policy = Daily::default();
+        assert_eq!(observe(&mut policy, "CAR", 0, 0), []);
+        assert_eq!(observe(&mut policy, "CAR", 100, 3600), [(0, 3600)]);
+        assert_eq!(observe(&mut policy, "CAR", 200, 7200), []);
+        // Another road, the same day
+        assert!(policy.observe("CAR", 2, 0, 60, 10000).is_empty());
+        assert!(policy.observe("CAR", 2, 100, 60, 13600).is_empty());
+        // The next day
+        let day = SECS_PER_DAY;
+        assert_eq!(observe(&mut policy, "CAR", 300, day), []);
+        assert_eq!(
+            observe(&mut policy, "CAR", 400, day + 3600),
+            [(day, day + 3600)]
+        );
+    }
+}
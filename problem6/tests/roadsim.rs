@@ -0,0 +1,35 @@
+// The roadsim binary against a server on an ephemeral port
+use std::process::Command;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn every_speeding_car_gets_a_ticket() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = problem6::Config::from_env();
+    tokio::spawn(problem6::run(listener, CancellationToken::new(), config));
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_roadsim"));
+    command.arg("--addr").arg(addr.to_string()).args([
+        "--cars",
+        "40",
+        "--speeders",
+        "0.5",
+        "--seed",
+        "7",
+        "--wait",
+        "1",
+    ]);
+    let output = tokio::task::spawn_blocking(move || command.output().unwrap())
+        .await
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    let summary = stdout.lines().last().unwrap();
+    assert!(
+        summary.ends_with("speeding cars ticketed, 0 others"),
+        "{}",
+        summary
+    );
+}
@@ -0,0 +1,229 @@
+// Line Reversal: every line a client sends comes back reversed.
+//
+// Sessions arrive over LRCP (see the lrcp crate, which takes care of
+// acknowledgements, retransmission and expiry), so the application itself
+// only sees a byte stream. Only complete lines are answered: whatever follows
+// the last newline when the client closes is dropped. A line longer than
+// MAX_LINE_LENGTH (default 10000, the longest the protocol promises to handle)
+// ends the session instead of being buffered indefinitely.
+use common::accept::AcceptLimits;
+use common::admission::Admission;
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::metrics::{Counter, Scope};
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio_util::sync::CancellationToken;
+
+mod serve;
+
+pub use serve::serve;
+
+const DEFAULT_MAX_LINE_LENGTH: usize = 10_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub lrcp: lrcp::Config,
+    pub max_line_length: usize,
+    // Only MAX_CONNECTIONS and MAX_CONNECTIONS_PER_IP apply to sessions
+    pub limits: AcceptLimits,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            lrcp: lrcp::Config::from_env(),
+            max_line_length: common::env::var_or("MAX_LINE_LENGTH", DEFAULT_MAX_LINE_LENGTH),
+            limits: AcceptLimits::from_env(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Reverser {
+    max_line_length: usize,
+    sessions: Counter,
+    lines: Counter,
+}
+
+impl Reverser {
+    async fn serve<S: ByteStream>(&self, stream: S, ctx: &Context) {
+        self.sessions.inc();
+        let (rd, mut wr) = tokio::io::split(stream);
+        let mut rd = BufReader::new(rd);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            ctx.task.phase("reading line");
+            let mut limited = (&mut rd).take(self.max_line_length as u64 + 1);
+            let n = tokio::select! {
+                n = limited.read_until(b'\n', &mut line) => n,
+                _ = ctx.cancel.cancelled() => return,
+            };
+            let n = match n {
+                Ok(n) => n,
+                Err(e) => {
+                    common::debug!("Error reading line: {:?}", e);
+                    return;
+                }
+            };
+            if line.pop() != Some(b'\n') {
+                if n > self.max_line_length {
+                    common::debug!("Line longer than {} bytes, closing", self.max_line_length);
+                }
+                return;
+            }
+            line.reverse();
+            line.push(b'\n');
+            self.lines.inc();
+            ctx.task.phase("writing line");
+            tokio::select! {
+                written = wr.write_all(&line) => if written.is_err() { return },
+                _ = ctx.cancel.cancelled() => return,
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for Reverser {
+    async fn handle<S: ByteStream>(&self, stream: S, _peer: Option<SocketAddr>, ctx: Context) {
+        self.serve(stream, &ctx).await
+    }
+}
+
+// The handler `run` serves, for sessions accepted some other way
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    Reverser {
+        max_line_length: config.max_line_length,
+        sessions: scope.counter("reversal_sessions_total", "LRCP sessions served"),
+        lines: scope.counter("reversal_lines_total", "Lines reversed"),
+    }
+}
+
+// Reverse lines for every session on `listener` until `shutdown` is cancelled
+pub async fn run(listener: lrcp::Listener, shutdown: CancellationToken, config: Config) {
+    let scope = Scope::new("problem7", listener.local_addr().port());
+    let reverser = handler(&scope, &config);
+    let admission = Admission::new(&scope, config.limits);
+    common::dry_run::finish();
+    common::env::print_config();
+    common::info!("Listening for LRCP sessions on {}", listener.local_addr());
+    lrcp::serve(listener, reverser, admission, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    async fn server(max_line_length: usize) -> SocketAddr {
+        let lrcp = lrcp::Config {
+            retransmit_interval: Duration::from_millis(100),
+            ..lrcp::Config::default()
+        };
+        let config = Config {
+            lrcp,
+            max_line_length,
+            limits: AcceptLimits::from_env(),
+        };
+        let listener = lrcp::Listener::bind_with("127.0.0.1:0", lrcp)
+            .await
+            .unwrap();
+        let addr = listener.local_addr();
+        tokio::spawn(run(listener, CancellationToken::new(), config));
+        addr
+    }
+
+    // A client of a single session, speaking LRCP by hand. None of what the
+    // tests send needs escaping.
+    struct Client {
+        socket: UdpSocket,
+        // Bytes received in order so far, and those not read yet
+        length: usize,
+        received: Vec<u8>,
+    }
+
+    impl Client {
+        async fn connect(server: SocketAddr) -> Client {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(server).await.unwrap();
+            let client = Client {
+                socket,
+                length: 0,
+                received: Vec::new(),
+            };
+            client.send("/connect/1/").await;
+            assert_eq!(client.recv().await, ["ack", "1", "0"]);
+            client
+        }
+
+        async fn send(&self, message: &str) {
+            self.socket.send(message.as_bytes()).await.unwrap();
+        }
+
+        // The fields of the next message from the server
+        async fn recv(&self) -> Vec<String> {
+            let mut buf = [0u8; 1000];
+            let n = tokio::time::timeout(Duration::from_secs(5), self.socket.recv(&mut buf))
+                .await
+                .expect("server sent nothing")
+                .unwrap();
+            let message = std::str::from_utf8(&buf[..n]).unwrap();
+            let fields = message
+                .strip_prefix('/')
+                .unwrap()
+                .strip_suffix('/')
+                .unwrap();
+            fields.split('/').map(str::to_owned).collect()
+        }
+
+        // Acknowledge data from the server until `len` bytes have come, then
+        // return them
+        async fn read(&mut self, len: usize) -> String {
+            while self.received.len() < len {
+                let message = self.recv().await;
+                if message[0] != "data" {
+                    continue;
+                }
+                if message[2].parse::<usize>().unwrap() == self.length {
+                    self.length += message[3].len();
+                    self.received.extend_from_slice(message[3].as_bytes());
+                }
+                self.send(&format!("/ack/1/{}/", self.length)).await;
+            }
+            String::from_utf8(self.received.drain(..len).collect()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn reverses_lines() {
+        let mut client = Client::connect(server(DEFAULT_MAX_LINE_LENGTH).await).await;
+        client.send("/data/1/0/hello\nwor/").await;
+        assert_eq!(client.recv().await, ["ack", "1", "9"]);
+        assert_eq!(client.read(6).await, "olleh\n");
+        client.send("/data/1/9/ld\n\n/").await;
+        assert_eq!(client.read(7).await, "dlrow\n\n");
+
+        client.send("/close/1/").await;
+        loop {
+            if client.recv().await == ["close", "1"] {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_the_session_on_a_line_too_long() {
+        let mut client = Client::connect(server(10).await).await;
+        // Exactly the limit is fine
+        client.send("/data/1/0/0123456789\n/").await;
+        assert_eq!(client.read(11).await, "9876543210\n");
+        client.send("/data/1/11/0123456789a/").await;
+        loop {
+            match client.recv().await {
+                message if message == ["close", "1"] => break,
+                message => assert_eq!(message[0], "ack", "{:?}", message),
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// Everything the problem7 binary does, callable so that the protohackers
+// binary can run the problem too.
+use crate::Config;
+use common::config::Checker;
+use protolib::{Problem, Transport};
+use std::io;
+use std::net::SocketAddr;
+use tokio_util::sync::CancellationToken;
+
+// This problem's own checks, on top of those `checker` makes
+pub fn check_config(checker: Checker) -> Checker {
+    checker.positive("MAX_LINE_LENGTH")
+}
+
+// Check the configuration, then serve on `addrs` until the process is stopped
+pub fn serve(addrs: &[SocketAddr]) {
+    protolib::main::<Config>(addrs)
+}
+
+impl Problem for Config {
+    const TRANSPORT: Transport = Transport::Udp;
+    type Listener = lrcp::Listener;
+
+    fn name() -> &'static str {
+        "problem7"
+    }
+
+    fn from_env() -> Self {
+        Config::from_env()
+    }
+
+    fn check_config(checker: Checker) -> Checker {
+        check_config(checker)
+    }
+
+    async fn bind(&self, addrs: &[SocketAddr]) -> io::Result<lrcp::Listener> {
+        let addr = common::cli::only_addr(addrs, "for UDP");
+        lrcp::Listener::bind_with(addr, self.lrcp).await
+    }
+
+    async fn serve(&self, listener: lrcp::Listener, shutdown: CancellationToken) {
+        crate::run(listener, shutdown, *self).await
+    }
+}
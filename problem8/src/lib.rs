@@ -0,0 +1,148 @@
+// Insecure Sockets Layer: a toy workshop's job queue, behind the ISL cipher.
+//
+// Every connection starts with a cipher spec and is encrypted from then on;
+// the isl crate takes care of that (and of dropping clients whose spec
+// changes nothing), so the application only sees plain lines. Each line is a
+// comma-separated list of toys with how many of them to make, like
+// "10x toy car,15x dog on a string", and is answered with the toy to make
+// most of, as written. A malformed line, or one longer than MAX_LINE_LENGTH
+// (default 5000, the longest the protocol promises to handle), ends the
+// connection.
+use common::accept::AcceptLimits;
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::metrics::{Counter, Scope};
+use isl::Encrypted;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio_util::sync::CancellationToken;
+
+mod serve;
+
+pub use serve::serve;
+
+const DEFAULT_MAX_LINE_LENGTH: usize = 5000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    pub max_line_length: usize,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env(),
+            max_line_length: common::env::var_or("MAX_LINE_LENGTH", DEFAULT_MAX_LINE_LENGTH),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+        }
+    }
+}
+
+// The toy in `line` with the highest count, the first of them on a tie
+fn most_wanted(line: &str) -> Option<&str> {
+    let mut best: Option<(u64, &str)> = None;
+    for toy in line.split(',') {
+        let (count, _) = toy.split_once("x ")?;
+        let count: u64 = count.parse().ok()?;
+        if best.is_none_or(|(most, _)| count > most) {
+            best = Some((count, toy));
+        }
+    }
+    best.map(|(_, toy)| toy)
+}
+
+#[derive(Clone)]
+struct Workshop {
+    max_line_length: usize,
+    requests: Counter,
+    malformed: Counter,
+}
+
+impl Workshop {
+    async fn serve<S: ByteStream>(&self, stream: S, ctx: &Context) {
+        let (rd, mut wr) = tokio::io::split(stream);
+        let mut rd = BufReader::new(rd);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            ctx.task.phase("reading request");
+            let mut limited = (&mut rd).take(self.max_line_length as u64 + 1);
+            let n = tokio::select! {
+                n = limited.read_until(b'\n', &mut line) => n,
+                _ = ctx.cancel.cancelled() => return,
+            };
+            let n = match n {
+                Ok(n) => n,
+                Err(e) => {
+                    common::debug!("Error reading request: {:?}", e);
+                    return;
+                }
+            };
+            if line.pop() != Some(b'\n') {
+                if n > self.max_line_length {
+                    common::debug!(
+                        "Request longer than {} bytes, closing",
+                        self.max_line_length
+                    );
+                }
+                return;
+            }
+            let request = String::from_utf8_lossy(&line);
+            let Some(toy) = most_wanted(&request) else {
+                self.malformed.inc();
+                common::debug!("Malformed request {:?}, closing", request);
+                return;
+            };
+            let answer = format!("{}\n", toy);
+            self.requests.inc();
+            ctx.task.phase("writing answer");
+            tokio::select! {
+                written = wr.write_all(answer.as_bytes()) => if written.is_err() { return },
+                _ = ctx.cancel.cancelled() => return,
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for Workshop {
+    async fn handle<S: ByteStream>(&self, stream: S, _peer: Option<SocketAddr>, ctx: Context) {
+        self.serve(stream, &ctx).await
+    }
+}
+
+// The handler `run` serves, cipher negotiation included, for a server that
+// accepts connections itself
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    Encrypted(Workshop {
+        max_line_length: config.max_line_length,
+        requests: scope.counter("toy_requests_total", "Requests answered"),
+        malformed: scope.counter(
+            "toy_malformed_requests_total",
+            "Connections closed over a request that wasn't a list of toys",
+        ),
+    })
+}
+
+// Serve the job queue on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem8", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let workshop = handler(&scope, &config);
+
+    #[cfg(feature = "middleware")]
+    let workshop = config.middleware.wrap(workshop, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(workshop.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(workshop.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, workshop).await;
+}
@@ -0,0 +1,150 @@
+// Command-line client for the Job Centre.
+//
+// Each command is one request on a connection of its own, and prints the
+// server's response line. Job payloads are JSON objects read from a file, or
+// from stdin when no file (or "-") is given. Aborting only works for a job
+// the same connection got, so `abort` on its own always fails; `run` sends
+// every request in a file (or stdin), one JSON object per line, over a single
+// connection, for sequences like get then abort.
+//
+// The exit status is 0 when every response was "ok", 1 when one was "no-job"
+// and 2 when one was an error or the server couldn't be reached.
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(about = "Send requests to a Job Centre server")]
+struct Args {
+    /// The server, as host:port
+    #[arg(long, default_value = "127.0.0.1:39456")]
+    addr: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Add a job to a queue
+    Put {
+        queue: String,
+        #[arg(long, default_value_t = 0)]
+        pri: u64,
+        /// The job's JSON object; stdin if missing or "-"
+        file: Option<PathBuf>,
+    },
+    /// Take the highest-priority job from the queues, if there is one
+    Get {
+        #[arg(required = true)]
+        queues: Vec<String>,
+        /// Wait for a job instead of answering "no-job"
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Take the highest-priority job from the queues, waiting for one
+    Wait {
+        #[arg(required = true)]
+        queues: Vec<String>,
+    },
+    /// Put a job this connection got back in its queue
+    Abort { id: u64 },
+    /// Delete a job wherever it is
+    Delete { id: u64 },
+    /// Send each request in a file, one JSON object per line, over one
+    /// connection
+    Run {
+        /// The requests; stdin if missing or "-"
+        file: Option<PathBuf>,
+    },
+}
+
+fn read_input(file: Option<PathBuf>) -> io::Result<String> {
+    let mut input = String::new();
+    match file {
+        Some(path) if path.as_os_str() != "-" => input = std::fs::read_to_string(path)?,
+        _ => {
+            io::stdin().read_to_string(&mut input)?;
+        }
+    }
+    Ok(input)
+}
+
+// The requests `command` stands for
+fn requests(command: Command) -> Result<Vec<Value>, String> {
+    let request = match command {
+        Command::Put { queue, pri, file } => {
+            let input = read_input(file).map_err(|e| format!("Couldn't read job: {}", e))?;
+            let job: Value =
+                serde_json::from_str(&input).map_err(|e| format!("Job isn't JSON: {}", e))?;
+            if !job.is_object() {
+                return Err("The job must be a JSON object".to_owned());
+            }
+            json!({"request": "put", "queue": queue, "pri": pri, "job": job})
+        }
+        Command::Get { queues, wait } => json!({"request": "get", "queues": queues, "wait": wait}),
+        Command::Wait { queues } => json!({"request": "get", "queues": queues, "wait": true}),
+        Command::Abort { id } => json!({"request": "abort", "id": id}),
+        Command::Delete { id } => json!({"request": "delete", "id": id}),
+        Command::Run { file } => {
+            let input = read_input(file).map_err(|e| format!("Couldn't read requests: {}", e))?;
+            return input
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    serde_json::from_str(line)
+                        .map_err(|e| format!("Request isn't JSON: {}: {}", e, line))
+                })
+                .collect();
+        }
+    };
+    Ok(vec![request])
+}
+
+// Send `requests` in turn over one connection to `addr`, printing each
+// response. Returns the exit status they add up to.
+fn send(addr: &str, requests: &[Value]) -> io::Result<u8> {
+    let stream = TcpStream::connect(addr)?;
+    let mut responses = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    let mut status = 0;
+    let mut line = String::new();
+    for request in requests {
+        writeln!(stream, "{}", request)?;
+        line.clear();
+        if responses.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "server closed the connection",
+            ));
+        }
+        print!("{}", line);
+        let response: Value = serde_json::from_str(&line).unwrap_or(Value::Null);
+        status = status.max(match response.get("status").and_then(Value::as_str) {
+            Some("ok") => 0,
+            Some("no-job") => 1,
+            _ => 2,
+        });
+    }
+    Ok(status)
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let requests = match requests(args.command) {
+        Ok(requests) => requests,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+    match send(&args.addr, &requests) {
+        Ok(status) => ExitCode::from(status),
+        Err(e) => {
+            eprintln!("Couldn't talk to {}: {}", args.addr, e);
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,513 @@
+// The job store every client shares.
+//
+// Waiting jobs are kept per queue in a BTreeSet ordered by rank (oldest first
+// among equals), so the best job across several queues is the greatest of
+// their last elements. A job's rank is its priority, less one point for each
+// aging interval (JOBS_AGING_MILLIS, off when 0) between the store starting
+// and the job being put. Comparing those is the same as adding a point to
+// every waiting job each interval, so a low priority job overtakes newer ones
+// the longer it waits, yet ranks never change once set. A job a client takes
+// is held by it until deleted, aborted, or the client leaves, which aborts
+// everything it still holds. A get that waits registers a `Waiter`, and a job
+// becoming available goes straight to the longest-waiting client that wants
+// its queue instead of into the queue. Puts and deletes also go to the
+// write-ahead log, if there is one (see wal.rs).
+#[cfg(feature = "wal")]
+use crate::wal::{Record, Wal};
+use common::metrics::{Gauge, Histogram, Scope};
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+// Queues with their own depth and wait time series; the rest share one
+const MAX_QUEUE_METRICS: usize = 100;
+const OTHER_QUEUES: &str = "(other)";
+const WAIT_BOUNDS_MILLIS: &[u64] = &[1, 10, 100, 1_000, 10_000, 60_000, 600_000, 3_600_000];
+
+// A job as handed to the client that takes it
+#[derive(Debug)]
+pub(crate) struct Assigned {
+    pub id: u64,
+    pub queue: String,
+    pub pri: u64,
+    pub job: Value,
+}
+
+pub(crate) enum Got {
+    Job(Assigned),
+    NoJob,
+    // Nothing yet; the job arrives here once there is one
+    Waiting(oneshot::Receiver<Assigned>),
+}
+
+// Why an abort was refused
+#[derive(Debug)]
+pub(crate) struct NotHeld;
+
+struct Job {
+    queue: String,
+    pri: u64,
+    rank: i128,
+    job: Value,
+    // The client working on it
+    holder: Option<u64>,
+    // When it last became available
+    queued_at: Instant,
+}
+
+impl Job {
+    fn assign(&self, id: u64) -> Assigned {
+        Assigned {
+            id,
+            queue: self.queue.clone(),
+            pri: self.pri,
+            job: self.job.clone(),
+        }
+    }
+
+    #[cfg(feature = "wal")]
+    fn record(&self, id: u64) -> Record {
+        Record::Put {
+            id,
+            queue: self.queue.clone(),
+            pri: self.pri,
+            job: self.job.clone(),
+        }
+    }
+}
+
+struct Waiter {
+    client: u64,
+    queues: Vec<String>,
+    sender: oneshot::Sender<Assigned>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    next_client: u64,
+    jobs: HashMap<u64, Job>,
+    // queue -> (rank, id) of the jobs waiting in it
+    queues: HashMap<String, BTreeSet<(i128, Reverse<u64>)>>,
+    queue_metrics: HashMap<String, QueueMetrics>,
+    // client -> ids of the jobs it holds
+    held: HashMap<u64, HashSet<u64>>,
+    waiters: VecDeque<Waiter>,
+    #[cfg(feature = "wal")]
+    wal: Option<Wal>,
+}
+
+#[cfg(feature = "wal")]
+impl State {
+    fn record(&mut self, record: Record) {
+        let State { jobs, wal, .. } = self;
+        let Some(wal) = wal else {
+            return;
+        };
+        wal.record(record);
+        if wal.wants_compaction(jobs.len()) {
+            wal.compact(jobs.iter().map(|(id, job)| job.record(*id)).collect());
+        }
+    }
+}
+
+struct QueueMetrics {
+    depth: Gauge,
+    wait: Histogram,
+}
+
+impl QueueMetrics {
+    fn new(scope: &Scope, queue: &str) -> Self {
+        let labels = [("queue", queue)];
+        QueueMetrics {
+            depth: scope.gauge_with("job_queue_depth", "Jobs waiting in a queue", &labels),
+            wait: scope.histogram_with(
+                "job_queue_wait_millis",
+                "Time from a job becoming available to a client taking it",
+                WAIT_BOUNDS_MILLIS,
+                &labels,
+            ),
+        }
+    }
+}
+
+pub(crate) struct Jobs {
+    state: Mutex<State>,
+    waiting: Gauge,
+    working: Gauge,
+    scope: Scope,
+    other_queues: QueueMetrics,
+    aging_millis: u64,
+    started: Instant,
+}
+
+impl Jobs {
+    pub(crate) fn new(scope: &Scope, aging_millis: u64) -> Self {
+        Jobs {
+            state: Mutex::new(State::default()),
+            waiting: scope.gauge("jobs_waiting", "Jobs in a queue, waiting for a client"),
+            working: scope.gauge("jobs_working", "Jobs a client is working on"),
+            scope: scope.clone(),
+            other_queues: QueueMetrics::new(scope, OTHER_QUEUES),
+            aging_millis,
+            started: Instant::now(),
+        }
+    }
+
+    // The rank of a job with priority `pri` put now
+    fn rank(&self, pri: u64) -> i128 {
+        if self.aging_millis == 0 {
+            return pri as i128;
+        }
+        let age = self.started.elapsed().as_millis() as i128;
+        pri as i128 * self.aging_millis as i128 - age
+    }
+
+    fn queue_metrics<'a>(
+        &'a self,
+        metrics: &'a mut HashMap<String, QueueMetrics>,
+        queue: &str,
+    ) -> &'a QueueMetrics {
+        if !metrics.contains_key(queue) && metrics.len() < MAX_QUEUE_METRICS {
+            metrics.insert(queue.to_owned(), QueueMetrics::new(&self.scope, queue));
+        }
+        metrics.get(queue).unwrap_or(&self.other_queues)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| panic!("Error locking job state: {}", e))
+    }
+
+    // Bring back the jobs in `records`, replayed from `wal`, and record in
+    // it from now on
+    #[cfg(feature = "wal")]
+    pub(crate) fn recover(&self, mut wal: Wal, records: Vec<Record>) {
+        let mut state = self.state();
+        for record in records {
+            match record {
+                Record::Put {
+                    id,
+                    queue,
+                    pri,
+                    job,
+                } => {
+                    let job = Job {
+                        queue,
+                        pri,
+                        rank: self.rank(pri),
+                        job,
+                        holder: None,
+                        queued_at: Instant::now(),
+                    };
+                    state.jobs.insert(id, job);
+                    state.next_id = state.next_id.max(id);
+                }
+                Record::Delete { id } => {
+                    state.jobs.remove(&id);
+                    state.next_id = state.next_id.max(id);
+                }
+            }
+        }
+        let ids: Vec<u64> = state.jobs.keys().copied().collect();
+        for id in ids {
+            self.offer(&mut state, id);
+        }
+        common::info!("Recovered {} jobs", state.jobs.len());
+        wal.compact(state.jobs.iter().map(|(id, job)| job.record(*id)).collect());
+        state.wal = Some(wal);
+    }
+
+    // Wait until everything done so far is in the write-ahead log, failing
+    // if any of what was logged after `since` couldn't be written
+    #[cfg(feature = "wal")]
+    pub(crate) fn synced(
+        &self,
+        since: u64,
+    ) -> impl std::future::Future<Output = std::io::Result<()>> + Send + 'static {
+        let synced = self.state().wal.as_ref().map(|wal| wal.synced(since));
+        async move {
+            match synced {
+                Some(synced) => synced.await,
+                None => Ok(()),
+            }
+        }
+    }
+
+    // Changes sent to the log so far, to pass to `synced` later
+    #[cfg(feature = "wal")]
+    pub(crate) fn logged(&self) -> u64 {
+        self.state().wal.as_ref().map_or(0, Wal::sent)
+    }
+
+    // An id for a new client to do everything else as
+    pub(crate) fn connect(&self) -> u64 {
+        let mut state = self.state();
+        state.next_client += 1;
+        state.next_client
+    }
+
+    // Abort every job `client` still holds
+    pub(crate) fn disconnect(&self, client: u64) {
+        let mut state = self.state();
+        for id in state.held.remove(&client).unwrap_or_default() {
+            if let Some(job) = state.jobs.get_mut(&id) {
+                job.holder = None;
+            }
+            self.working.dec();
+            self.offer(&mut state, id);
+        }
+    }
+
+    // Make job `id`, held by nobody, available again: to a waiting client
+    // if there is one for its queue, to its queue otherwise
+    fn offer(&self, state: &mut State, id: u64) {
+        let State {
+            jobs,
+            queues,
+            queue_metrics,
+            held,
+            waiters,
+            ..
+        } = state;
+        let Some(job) = jobs.get_mut(&id) else {
+            return;
+        };
+        job.queued_at = Instant::now();
+        let metrics = self.queue_metrics(queue_metrics, &job.queue);
+        waiters.retain(|waiter| !waiter.sender.is_closed());
+        while let Some(i) = waiters.iter().position(|w| w.queues.contains(&job.queue)) {
+            let waiter = waiters.remove(i).unwrap();
+            // Can still fail if the client gave up just now
+            if waiter.sender.send(job.assign(id)).is_ok() {
+                job.holder = Some(waiter.client);
+                held.entry(waiter.client).or_default().insert(id);
+                self.working.inc();
+                metrics.wait.observe(0);
+                return;
+            }
+        }
+        queues
+            .entry(job.queue.clone())
+            .or_default()
+            .insert((job.rank, Reverse(id)));
+        self.waiting.inc();
+        metrics.depth.inc();
+    }
+
+    pub(crate) fn put(&self, queue: String, job: Value, pri: u64) -> u64 {
+        let mut state = self.state();
+        state.next_id += 1;
+        let id = state.next_id;
+        let job = Job {
+            queue,
+            pri,
+            rank: self.rank(pri),
+            job,
+            holder: None,
+            queued_at: Instant::now(),
+        };
+        // Recorded once it's in, so a rewrite the record sets off includes it
+        #[cfg(feature = "wal")]
+        let record = job.record(id);
+        state.jobs.insert(id, job);
+        #[cfg(feature = "wal")]
+        state.record(record);
+        self.offer(&mut state, id);
+        id
+    }
+
+    // The highest priority job in any of `queues`, for `client` to work on.
+    // With `wait`, an empty answer becomes a wait for the next such job.
+    pub(crate) fn get(&self, client: u64, queues: &[String], wait: bool) -> Got {
+        let mut state = self.state();
+        let best = queues
+            .iter()
+            .filter_map(|queue| Some((*state.queues.get(queue)?.last()?, queue)))
+            .max_by_key(|&(key, _)| key);
+        let Some((key, queue)) = best else {
+            if !wait {
+                return Got::NoJob;
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.push_back(Waiter {
+                client,
+                queues: queues.to_vec(),
+                sender,
+            });
+            return Got::Waiting(receiver);
+        };
+
+        let waiting = state.queues.get_mut(queue).unwrap();
+        waiting.remove(&key);
+        if waiting.is_empty() {
+            state.queues.remove(queue);
+        }
+        self.waiting.dec();
+        let Reverse(id) = key.1;
+        state.held.entry(client).or_default().insert(id);
+        self.working.inc();
+        let State {
+            jobs,
+            queue_metrics,
+            ..
+        } = &mut *state;
+        let job = jobs.get_mut(&id).unwrap();
+        job.holder = Some(client);
+        let metrics = self.queue_metrics(queue_metrics, &job.queue);
+        metrics.depth.dec();
+        metrics
+            .wait
+            .observe(job.queued_at.elapsed().as_millis() as u64);
+        Got::Job(job.assign(id))
+    }
+
+    // Delete job `id`, whoever holds it. Returns whether it existed.
+    pub(crate) fn delete(&self, id: u64) -> bool {
+        let mut state = self.state();
+        let Some(job) = state.jobs.remove(&id) else {
+            return false;
+        };
+        #[cfg(feature = "wal")]
+        state.record(Record::Delete { id });
+        match job.holder {
+            Some(client) => {
+                if let Some(held) = state.held.get_mut(&client) {
+                    held.remove(&id);
+                }
+                self.working.dec();
+            }
+            None => {
+                if let Some(waiting) = state.queues.get_mut(&job.queue) {
+                    waiting.remove(&(job.rank, Reverse(id)));
+                    if waiting.is_empty() {
+                        state.queues.remove(&job.queue);
+                    }
+                }
+                self.waiting.dec();
+                self.queue_metrics(&mut state.queue_metrics, &job.queue)
+                    .depth
+                    .dec();
+            }
+        }
+        true
+    }
+
+    // Put job `id` back, if it's `client`'s to give up. Returns whether it
+    // existed.
+    pub(crate) fn abort(&self, client: u64, id: u64) -> Result<bool, NotHeld> {
+        let mut state = self.state();
+        let Some(job) = state.jobs.get_mut(&id) else {
+            return Ok(false);
+        };
+        if job.holder != Some(client) {
+            return Err(NotHeld);
+        }
+        job.holder = None;
+        if let Some(held) = state.held.get_mut(&client) {
+            held.remove(&id);
+        }
+        self.working.dec();
+        self.offer(&mut state, id);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn jobs(aging_millis: u64) -> Jobs {
+        Jobs::new(&Scope::new("problem9", 0), aging_millis)
+    }
+
+    fn got(jobs: &Jobs, client: u64, queues: &[&str]) -> Option<u64> {
+        let queues: Vec<String> = queues.iter().map(|q| q.to_string()).collect();
+        match jobs.get(client, &queues, false) {
+            Got::Job(job) => Some(job.id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn strict_priority_without_aging() {
+        let jobs = jobs(0);
+        let client = jobs.connect();
+        let low = jobs.put("q".to_owned(), json!(null), 1);
+        std::thread::sleep(Duration::from_millis(30));
+        let high = jobs.put("q".to_owned(), json!(null), 2);
+        let other = jobs.put("r".to_owned(), json!(null), 2);
+        assert_eq!(got(&jobs, client, &["q", "r"]), Some(high));
+        assert_eq!(got(&jobs, client, &["q", "r"]), Some(other));
+        assert_eq!(got(&jobs, client, &["q", "r"]), Some(low));
+        assert_eq!(got(&jobs, client, &["q", "r"]), None);
+    }
+
+    #[test]
+    fn waiting_jobs_age_past_newer_ones() {
+        let jobs = jobs(10);
+        let client = jobs.connect();
+        let old = jobs.put("q".to_owned(), json!(null), 1);
+        std::thread::sleep(Duration::from_millis(50));
+        // Five points of waiting put the old job ahead of this one, but not
+        // of one with a far higher priority
+        let newer = jobs.put("r".to_owned(), json!(null), 3);
+        let newest = jobs.put("q".to_owned(), json!(null), 20);
+        assert_eq!(got(&jobs, client, &["q", "r"]), Some(newest));
+        assert_eq!(got(&jobs, client, &["q", "r"]), Some(old));
+        assert_eq!(got(&jobs, client, &["q", "r"]), Some(newer));
+    }
+
+    #[test]
+    fn aborted_jobs_keep_their_rank() {
+        let jobs = jobs(10);
+        let client = jobs.connect();
+        let old = jobs.put("q".to_owned(), json!(null), 1);
+        assert_eq!(got(&jobs, client, &["q"]), Some(old));
+        std::thread::sleep(Duration::from_millis(50));
+        let newer = jobs.put("q".to_owned(), json!(null), 3);
+        jobs.abort(client, old).unwrap();
+        assert_eq!(got(&jobs, client, &["q"]), Some(old));
+        assert_eq!(got(&jobs, client, &["q"]), Some(newer));
+    }
+
+    #[test]
+    fn tracks_depth_and_wait_per_queue() {
+        let jobs = jobs(0);
+        let client = jobs.connect();
+        let depth = |queue: &str| jobs.state().queue_metrics[queue].depth.get();
+        let waits = |queue: &str| jobs.state().queue_metrics[queue].wait.count();
+        let first = jobs.put("depth-a".to_owned(), json!(null), 1);
+        jobs.put("depth-a".to_owned(), json!(null), 1);
+        jobs.put("depth-b".to_owned(), json!(null), 1);
+        assert_eq!((depth("depth-a"), depth("depth-b")), (2, 1));
+        assert_eq!(got(&jobs, client, &["depth-a"]), Some(first));
+        assert!(jobs.delete(first + 2));
+        assert_eq!((depth("depth-a"), depth("depth-b")), (1, 0));
+        assert_eq!((waits("depth-a"), waits("depth-b")), (1, 0));
+
+        // A waiting client takes the job without it ever being queued
+        let queues = vec!["depth-c".to_owned()];
+        let Got::Waiting(_receiver) = jobs.get(client, &queues, true) else {
+            panic!("expected to wait");
+        };
+        jobs.put("depth-c".to_owned(), json!(null), 1);
+        assert_eq!((depth("depth-c"), waits("depth-c")), (0, 1));
+    }
+
+    #[test]
+    fn lumps_together_queues_past_the_limit() {
+        let jobs = jobs(0);
+        for i in 0..MAX_QUEUE_METRICS + 5 {
+            jobs.put(format!("many-{}", i), json!(null), 1);
+        }
+        assert_eq!(jobs.state().queue_metrics.len(), MAX_QUEUE_METRICS);
+        assert_eq!(jobs.other_queues.depth.get(), 5);
+    }
+}
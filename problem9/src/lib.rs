@@ -0,0 +1,308 @@
+// Job Centre: clients put jobs into named queues and take them out again by
+// priority.
+//
+// Requests and responses are JSON objects, one per line (see request.rs for
+// what's accepted, jobs.rs for how jobs are kept). A request that can't be
+// understood gets an error response and the connection carries on; a line
+// longer than MAX_LINE_LENGTH ends it. A get with "wait" holds up the rest of
+// the connection's requests until a job turns up, though the connection is
+// still read meanwhile to notice the client leaving, which aborts whatever
+// jobs it was working on. With a write-ahead log (see wal.rs) jobs also
+// outlive the process.
+use common::accept::AcceptLimits;
+use common::handler::{ByteStream, ConnectionHandler, Context};
+use common::listeners::Listeners;
+use common::metrics::{Counter, Scope};
+use jobs::{Assigned, Got, Jobs, NotHeld};
+use request::Request;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+#[cfg(feature = "wal")]
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LinesCodec};
+use tokio_util::sync::CancellationToken;
+
+mod jobs;
+mod request;
+mod serve;
+#[cfg(feature = "wal")]
+mod wal;
+
+pub use serve::serve;
+
+// Jobs are arbitrary JSON, so this is more generous than in other problems
+const DEFAULT_MAX_LINE_LENGTH: usize = 1024 * 1024;
+// Requests read ahead while waiting for a job, before reading stops until it
+// arrives
+const MAX_BACKLOG: usize = 16;
+// The error response for a client turned away because the server is full
+const BUSY: &[u8] = b"{\"status\":\"error\",\"error\":\"server busy\"}\n";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub limits: AcceptLimits,
+    pub max_line_length: usize,
+    // Waiting that counts as one priority point, or 0 for strict priority
+    pub aging_millis: u64,
+    // Where to keep the write-ahead log, if anywhere
+    #[cfg(feature = "wal")]
+    pub wal_path: Option<PathBuf>,
+    #[cfg(feature = "wal")]
+    pub wal_compact_after: usize,
+    #[cfg(feature = "middleware")]
+    pub middleware: common::middleware::Stack,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            limits: AcceptLimits::from_env().busy_message(BUSY),
+            max_line_length: common::env::var_or("MAX_LINE_LENGTH", DEFAULT_MAX_LINE_LENGTH),
+            aging_millis: common::env::var_or("JOBS_AGING_MILLIS", 0),
+            #[cfg(feature = "wal")]
+            wal_path: common::env::var("JOBS_WAL_PATH"),
+            #[cfg(feature = "wal")]
+            wal_compact_after: common::env::var_or(
+                "JOBS_WAL_COMPACT_AFTER",
+                wal::DEFAULT_COMPACT_AFTER,
+            ),
+            #[cfg(feature = "middleware")]
+            middleware: common::middleware::Stack::from_env(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    put: Counter,
+    get: Counter,
+    delete: Counter,
+    abort: Counter,
+    invalid: Counter,
+}
+
+impl Metrics {
+    fn new(scope: &Scope) -> Self {
+        let requests = |request| {
+            scope.counter_with(
+                "job_requests_total",
+                "Job Centre requests by type",
+                &[("request", request)],
+            )
+        };
+        Metrics {
+            put: requests("put"),
+            get: requests("get"),
+            delete: requests("delete"),
+            abort: requests("abort"),
+            invalid: requests("invalid"),
+        }
+    }
+}
+
+enum Answer {
+    Now(Value),
+    Wait(oneshot::Receiver<Assigned>),
+}
+
+fn error(error: &str) -> Value {
+    json!({"status": "error", "error": error})
+}
+
+fn found(assigned: Assigned) -> Value {
+    json!({
+        "status": "ok",
+        "id": assigned.id,
+        "job": assigned.job,
+        "pri": assigned.pri,
+        "queue": assigned.queue,
+    })
+}
+
+fn ok_or_no_job(existed: bool) -> Value {
+    if existed {
+        json!({"status": "ok"})
+    } else {
+        json!({"status": "no-job"})
+    }
+}
+
+// Aborts the client's jobs however its connection ends, even if aborted
+struct Client {
+    id: u64,
+    jobs: Arc<Jobs>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.jobs.disconnect(self.id);
+    }
+}
+
+#[derive(Clone)]
+struct JobCentre {
+    jobs: Arc<Jobs>,
+    max_line_length: usize,
+    metrics: Metrics,
+}
+
+impl JobCentre {
+    fn new(scope: &Scope, config: &Config) -> Self {
+        JobCentre {
+            jobs: Arc::new(Jobs::new(scope, config.aging_millis)),
+            max_line_length: config.max_line_length,
+            metrics: Metrics::new(scope),
+        }
+    }
+
+    fn answer(&self, request: Request, client: u64) -> Answer {
+        let response = match request {
+            Request::Put { queue, job, pri } => {
+                self.metrics.put.inc();
+                json!({"status": "ok", "id": self.jobs.put(queue, job, pri)})
+            }
+            Request::Get { queues, wait } => {
+                self.metrics.get.inc();
+                match self.jobs.get(client, &queues, wait) {
+                    Got::Job(assigned) => found(assigned),
+                    Got::NoJob => json!({"status": "no-job"}),
+                    Got::Waiting(receiver) => return Answer::Wait(receiver),
+                }
+            }
+            Request::Delete { id } => {
+                self.metrics.delete.inc();
+                ok_or_no_job(self.jobs.delete(id))
+            }
+            Request::Abort { id } => {
+                self.metrics.abort.inc();
+                match self.jobs.abort(client, id) {
+                    Ok(existed) => ok_or_no_job(existed),
+                    Err(NotHeld) => error("not working on that job"),
+                }
+            }
+        };
+        Answer::Now(response)
+    }
+
+    async fn serve<S: ByteStream>(&self, stream: S, ctx: &Context) {
+        let client = Client {
+            id: self.jobs.connect(),
+            jobs: self.jobs.clone(),
+        };
+        let (rd, mut wr) = tokio::io::split(stream);
+        let mut lines = FramedRead::new(rd, LinesCodec::new_with_max_length(self.max_line_length));
+        // Requests read while waiting for a job, to answer after it
+        let mut backlog = VecDeque::new();
+        loop {
+            let line = match backlog.pop_front() {
+                Some(line) => Some(line),
+                None => {
+                    ctx.task.phase("reading request");
+                    tokio::select! {
+                        line = lines.next() => line,
+                        _ = ctx.cancel.cancelled() => return,
+                    }
+                }
+            };
+            let line = match line {
+                None => return,
+                Some(Ok(line)) => line,
+                Some(Err(e)) => {
+                    common::debug!("Error reading request: {}", e);
+                    return;
+                }
+            };
+
+            #[cfg(feature = "wal")]
+            let logged = self.jobs.logged();
+            let answer = match Request::parse(&line) {
+                Ok(request) => self.answer(request, client.id),
+                Err(reason) => {
+                    self.metrics.invalid.inc();
+                    Answer::Now(error(reason))
+                }
+            };
+            let response = match answer {
+                Answer::Now(response) => response,
+                Answer::Wait(mut receiver) => {
+                    ctx.task.phase("waiting for a job");
+                    loop {
+                        tokio::select! {
+                            assigned = &mut receiver => match assigned {
+                                Ok(assigned) => break found(assigned),
+                                Err(_) => return,
+                            },
+                            line = lines.next(), if backlog.len() < MAX_BACKLOG => match line {
+                                Some(line) => backlog.push_back(line),
+                                // Gone, so it can't be given anything
+                                None => return,
+                            },
+                            _ = ctx.cancel.cancelled() => return,
+                        }
+                    }
+                }
+            };
+
+            // Nothing is acknowledged before it's in the log
+            #[cfg(feature = "wal")]
+            let response = match self.jobs.synced(logged).await {
+                Ok(()) => response,
+                Err(e) => {
+                    common::warn!("Answering with an error: {}", e);
+                    error("couldn't record the change")
+                }
+            };
+            let mut out = response.to_string();
+            out.push('\n');
+            ctx.task.phase("writing response");
+            tokio::select! {
+                written = wr.write_all(out.as_bytes()) => if written.is_err() { return },
+                _ = ctx.cancel.cancelled() => return,
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for JobCentre {
+    async fn handle<S: ByteStream>(&self, stream: S, _peer: Option<SocketAddr>, ctx: Context) {
+        self.serve(stream, &ctx).await
+    }
+}
+
+// The handler `run` serves, for a server that accepts connections itself
+pub fn handler(scope: &Scope, config: &Config) -> impl ConnectionHandler {
+    JobCentre::new(scope, config)
+}
+
+// Run the Job Centre on `listener` until `shutdown` is cancelled
+pub async fn run(listener: impl Into<Listeners>, shutdown: CancellationToken, config: Config) {
+    let listener = listener.into();
+    let scope = Scope::new("problem9", listener.local_addr().unwrap().port());
+    #[cfg(feature = "mdns")]
+    common::mdns::advertise(scope.problem(), listener.local_addr().unwrap().port());
+    let centre = JobCentre::new(&scope, &config);
+    #[cfg(feature = "wal")]
+    if let Some(path) = &config.wal_path {
+        let opened = wal::Wal::open(path, config.wal_compact_after).await;
+        let (wal, records) = common::report::startup("open job log", opened);
+        centre.jobs.recover(wal, records);
+    }
+
+    #[cfg(feature = "middleware")]
+    let centre = config.middleware.wrap(centre, &scope);
+
+    let admission = common::admission::Admission::new(&scope, config.limits);
+    #[cfg(feature = "quic")]
+    common::quic::spawn_from_env(centre.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "lrcp")]
+    lrcp::spawn_from_env(centre.clone(), admission.clone(), shutdown.clone());
+    #[cfg(feature = "isl")]
+    isl::spawn_from_env(centre.clone(), admission.clone(), shutdown.clone());
+
+    common::accept::run_acceptor(listener, &scope, admission, shutdown, centre).await;
+}
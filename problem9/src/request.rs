@@ -0,0 +1,66 @@
+// Job Centre requests, read out of their JSON.
+//
+// A request is a JSON object with a "request" field naming one of put, get,
+// delete and abort, and the fields that one needs. Extra fields are ignored.
+// Anything else is reported with a reason to send back in the error response.
+use serde_json::{Map, Value};
+
+#[derive(Debug)]
+pub(crate) enum Request {
+    Put { queue: String, job: Value, pri: u64 },
+    Get { queues: Vec<String>, wait: bool },
+    Delete { id: u64 },
+    Abort { id: u64 },
+}
+
+impl Request {
+    pub(crate) fn parse(line: &str) -> Result<Request, &'static str> {
+        let Ok(Value::Object(mut fields)) = serde_json::from_str(line) else {
+            return Err("request is not a JSON object");
+        };
+        match fields.get("request").and_then(Value::as_str) {
+            Some("put") => Ok(Request::Put {
+                queue: fields
+                    .get("queue")
+                    .and_then(Value::as_str)
+                    .ok_or("put needs a queue name")?
+                    .to_owned(),
+                pri: fields
+                    .get("pri")
+                    .and_then(Value::as_u64)
+                    .ok_or("put needs a non-negative integer pri")?,
+                job: match fields.remove("job") {
+                    Some(job @ Value::Object(_)) => job,
+                    _ => return Err("put needs a job object"),
+                },
+            }),
+            Some("get") => Ok(Request::Get {
+                queues: fields
+                    .get("queues")
+                    .and_then(Value::as_array)
+                    .and_then(|queues| {
+                        queues
+                            .iter()
+                            .map(|queue| queue.as_str().map(str::to_owned))
+                            .collect()
+                    })
+                    .ok_or("get needs an array of queue names")?,
+                wait: match fields.get("wait") {
+                    None => false,
+                    Some(wait) => wait.as_bool().ok_or("wait must be true or false")?,
+                },
+            }),
+            Some("delete") => Ok(Request::Delete { id: id(&fields)? }),
+            Some("abort") => Ok(Request::Abort { id: id(&fields)? }),
+            Some(_) => Err("unknown request type"),
+            None => Err("request type missing"),
+        }
+    }
+}
+
+fn id(fields: &Map<String, Value>) -> Result<u64, &'static str> {
+    fields
+        .get("id")
+        .and_then(Value::as_u64)
+        .ok_or("job id missing")
+}
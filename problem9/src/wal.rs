@@ -0,0 +1,415 @@
+// Write-ahead log of the job store, so jobs survive a restart.
+//
+// With JOBS_WAL_PATH set (and the `wal` feature), every put and delete is
+// recorded in the "jobs" log of a storage database at that path before the
+// client gets its answer. On startup the log is replayed: jobs put and not
+// deleted come back waiting in their queues, including those a client was
+// working on, since that client is gone. Ids carry on from the highest one
+// recorded. Once the log holds more than JOBS_WAL_COMPACT_AFTER records
+// beyond the jobs still alive, it is rewritten as a single put per job. A
+// rewrite appends the puts before dropping what they replace, and replaying
+// a put twice changes nothing, so stopping halfway loses nothing. Under the
+// sandbox, the log's directory has to be in SANDBOX_WRITE_PATHS.
+//
+// Entries that can't be written aren't retried. Whoever waits for them is told
+// they failed, so the client gets an error instead of an answer for a change
+// that never made it to the log.
+use serde_json::{json, Value};
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use storage::{AppendLog, Storage};
+use tokio::sync::{mpsc, watch};
+
+const LOG_NAME: &str = "jobs";
+pub(crate) const DEFAULT_COMPACT_AFTER: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Record {
+    Put {
+        id: u64,
+        queue: String,
+        pri: u64,
+        job: Value,
+    },
+    Delete {
+        id: u64,
+    },
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let value = match self {
+            Record::Put {
+                id,
+                queue,
+                pri,
+                job,
+            } => json!({"op": "put", "id": id, "queue": queue, "pri": pri, "job": job}),
+            Record::Delete { id } => json!({"op": "delete", "id": id}),
+        };
+        value.to_string().into_bytes()
+    }
+
+    fn decode(entry: &[u8]) -> Option<Record> {
+        let Value::Object(mut fields) = serde_json::from_slice(entry).ok()? else {
+            return None;
+        };
+        let id = fields.get("id")?.as_u64()?;
+        match fields.get("op")?.as_str()? {
+            "put" => Some(Record::Put {
+                id,
+                queue: fields.get("queue")?.as_str()?.to_owned(),
+                pri: fields.get("pri")?.as_u64()?,
+                job: fields.remove("job")?,
+            }),
+            "delete" => Some(Record::Delete { id }),
+            _ => None,
+        }
+    }
+}
+
+enum Entry {
+    Record(Record),
+    // Every live job, to replace everything recorded before
+    Compact(Vec<Record>),
+}
+
+// How far the writer has got, counting entries in the order they were sent
+#[derive(Default)]
+struct Progress {
+    // Entries written, or given up on
+    done: u64,
+    // Those given up on, in order, with adjacent ranges merged
+    failed: Vec<Range<u64>>,
+}
+
+// The job store's end of the log. It lives under the store's lock, so
+// entries reach the writer in the order they happened.
+pub(crate) struct Wal {
+    entries: mpsc::UnboundedSender<Entry>,
+    // Entries handed to the writer, and how far it has got with them
+    sent: u64,
+    progress: watch::Receiver<Progress>,
+    compact_after: usize,
+    // Records in the log beyond the one put per live job a rewrite leaves
+    stale: usize,
+}
+
+impl Wal {
+    // Open the log at `path`, returning it with the records to replay
+    pub(crate) async fn open(path: &Path, compact_after: usize) -> io::Result<(Wal, Vec<Record>)> {
+        let log = Storage::open(path).await?.log(LOG_NAME);
+        let entries = log.read_after(0).await?;
+        let last_seq = entries.last().map_or(0, |(seq, _)| *seq);
+        let records: Vec<Record> = entries
+            .iter()
+            .filter_map(|(seq, entry)| {
+                let record = Record::decode(entry);
+                if record.is_none() {
+                    common::warn!("Skipping unreadable job log entry {}", seq);
+                }
+                record
+            })
+            .collect();
+        common::info!(
+            "Replaying {} job log records from {:?}",
+            records.len(),
+            path
+        );
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (progress_tx, progress) = watch::channel(Progress::default());
+        tokio::spawn(write(log, receiver, progress_tx, last_seq));
+        let wal = Wal {
+            entries: sender,
+            sent: 0,
+            progress,
+            compact_after,
+            stale: records.len(),
+        };
+        Ok((wal, records))
+    }
+
+    fn send(&mut self, entry: Entry) {
+        // The writer only stops once every sender is gone
+        self.entries.send(entry).unwrap_or(());
+        self.sent += 1;
+    }
+
+    pub(crate) fn record(&mut self, record: Record) {
+        // A delete makes its job's put stale as well
+        self.stale += match record {
+            Record::Put { .. } => 0,
+            Record::Delete { .. } => 2,
+        };
+        self.send(Entry::Record(record));
+    }
+
+    // Whether enough of the log is stale that it should be rewritten
+    pub(crate) fn wants_compaction(&self, live: usize) -> bool {
+        self.stale > self.compact_after && self.stale > live
+    }
+
+    // Rewrite the log as `live`, a put for each job still around
+    pub(crate) fn compact(&mut self, live: Vec<Record>) {
+        self.stale = 0;
+        self.send(Entry::Compact(live));
+    }
+
+    // Entries sent so far, to pass to `synced` later
+    pub(crate) fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    // Wait for everything sent so far to be written, failing if any of what
+    // was sent after the first `since` entries couldn't be
+    pub(crate) fn synced(
+        &self,
+        since: u64,
+    ) -> impl std::future::Future<Output = io::Result<()>> + Send + 'static {
+        let target = self.sent;
+        let mut progress = self.progress.clone();
+        async move {
+            let progress = progress
+                .wait_for(|progress| progress.done >= target)
+                .await
+                .map_err(|_| io::Error::other("job log writer stopped"))?;
+            let lost = progress
+                .failed
+                .iter()
+                .any(|failed| failed.start < target && since < failed.end);
+            if lost {
+                return Err(io::Error::other("couldn't write to job log"));
+            }
+            Ok(())
+        }
+    }
+}
+
+// Write entries to `log` as they come, batching whatever is waiting.
+// `last_seq` is the last entry already in the log.
+async fn write(
+    log: AppendLog,
+    mut entries: mpsc::UnboundedReceiver<Entry>,
+    progress: watch::Sender<Progress>,
+    mut last_seq: i64,
+) {
+    let mut count = 0;
+    while let Some(entry) = entries.recv().await {
+        let mut batch = vec![entry];
+        while let Ok(entry) = entries.try_recv() {
+            batch.push(entry);
+        }
+        let start = count;
+        count += batch.len() as u64;
+
+        let mut records = Vec::new();
+        let mut written = true;
+        for entry in batch {
+            match entry {
+                Entry::Record(record) => records.push(record.encode()),
+                Entry::Compact(live) => {
+                    // What came before it, then the rewrite
+                    let before = std::mem::take(&mut records);
+                    written &= append(&log, before, &mut last_seq).await;
+                    let replaced = last_seq;
+                    let live = live.iter().map(Record::encode).collect();
+                    if append(&log, live, &mut last_seq).await {
+                        if let Err(e) = log.truncate(replaced).await {
+                            common::error!("Couldn't compact job log: {}", e);
+                        }
+                    } else {
+                        written = false;
+                    }
+                }
+            }
+        }
+        written &= append(&log, records, &mut last_seq).await;
+        // Failures are reported, not retried, so nobody waits for them forever
+        progress.send_modify(|progress| {
+            if !written {
+                match progress.failed.last_mut() {
+                    Some(failed) if failed.end == start => failed.end = count,
+                    _ => progress.failed.push(start..count),
+                }
+            }
+            progress.done = count;
+        });
+    }
+}
+
+// Returns whether the entries were written
+async fn append(log: &AppendLog, entries: Vec<Vec<u8>>, last_seq: &mut i64) -> bool {
+    if entries.is_empty() {
+        return true;
+    }
+    match log.append_all(entries).await {
+        Ok(last) => {
+            *last_seq = last.unwrap_or(*last_seq);
+            true
+        }
+        Err(e) => {
+            common::error!("Couldn't write to job log: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::{Got, Jobs};
+    use common::metrics::Scope;
+
+    fn put(id: u64) -> Record {
+        Record::Put {
+            id,
+            queue: "q".to_owned(),
+            pri: id,
+            job: json!({"n": id}),
+        }
+    }
+
+    fn jobs() -> Jobs {
+        Jobs::new(&Scope::new("problem9", 0), 0)
+    }
+
+    async fn log_len(path: &Path) -> usize {
+        let log = Storage::open(path).await.unwrap().log(LOG_NAME);
+        log.read_after(0).await.unwrap().len()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        for record in [
+            Record::Put {
+                id: 7,
+                queue: "q1".to_owned(),
+                pri: 123,
+                job: json!({"title": "x", "nested": [1, 2, {"a": null}]}),
+            },
+            Record::Delete { id: 7 },
+        ] {
+            assert_eq!(Record::decode(&record.encode()), Some(record));
+        }
+        assert_eq!(Record::decode(b"{\"op\":\"get\",\"id\":1}"), None);
+        assert_eq!(Record::decode(b"not json"), None);
+    }
+
+    #[tokio::test]
+    async fn replays_what_was_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.db");
+        let (mut wal, records) = Wal::open(&path, 100).await.unwrap();
+        assert!(records.is_empty());
+        wal.record(put(1));
+        wal.record(put(2));
+        wal.record(Record::Delete { id: 1 });
+        wal.synced(0).await.unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, 100).await.unwrap();
+        assert_eq!(records, vec![put(1), put(2), Record::Delete { id: 1 }]);
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_only_live_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.db");
+        let (mut wal, _) = Wal::open(&path, 3).await.unwrap();
+        for id in 1..=4 {
+            wal.record(put(id));
+            wal.record(Record::Delete { id });
+        }
+        wal.record(put(5));
+        assert!(wal.wants_compaction(1));
+        wal.compact(vec![put(5)]);
+        assert!(!wal.wants_compaction(1));
+        wal.synced(0).await.unwrap();
+        drop(wal);
+
+        assert_eq!(log_len(&path).await, 1);
+        let (_, records) = Wal::open(&path, 3).await.unwrap();
+        assert_eq!(records, vec![put(5)]);
+    }
+
+    // Make every later write to the log at `path` fail
+    fn break_log(path: &Path) {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch(
+            "CREATE TRIGGER broken BEFORE INSERT ON log BEGIN SELECT RAISE(FAIL, 'disk full'); END;",
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_entries_that_could_not_be_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.db");
+        let (mut wal, _) = Wal::open(&path, 100).await.unwrap();
+        wal.record(put(1));
+        wal.synced(0).await.unwrap();
+
+        break_log(&path);
+        let since = wal.sent();
+        wal.record(put(2));
+        assert!(wal.synced(since).await.is_err());
+        // Waiting on everything since the start takes in the failure too,
+        // though what came before it was written
+        assert!(wal.synced(0).await.is_err());
+        let (_, records) = Wal::open(&path, 100).await.unwrap();
+        assert_eq!(records, vec![put(1)]);
+    }
+
+    #[tokio::test]
+    async fn changes_are_not_acknowledged_when_the_log_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.db");
+        let jobs = jobs();
+        let (wal, records) = Wal::open(&path, 100).await.unwrap();
+        jobs.recover(wal, records);
+        jobs.synced(0).await.unwrap();
+
+        break_log(&path);
+        let since = jobs.logged();
+        let id = jobs.put("q".to_owned(), json!(1), 10);
+        assert!(jobs.synced(since).await.is_err());
+        let since = jobs.logged();
+        jobs.delete(id);
+        assert!(jobs.synced(since).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn recovered_jobs_wait_again_and_ids_carry_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.db");
+        let queues = ["q".to_owned()];
+
+        let before = jobs();
+        let (wal, records) = Wal::open(&path, 100).await.unwrap();
+        before.recover(wal, records);
+        let client = before.connect();
+        let first = before.put("q".to_owned(), json!(1), 10);
+        let second = before.put("q".to_owned(), json!(2), 20);
+        let third = before.put("q".to_owned(), json!(3), 30);
+        assert!(before.delete(first));
+        // Taken by a client, which won't be around after the restart
+        assert!(matches!(before.get(client, &queues, false), Got::Job(job) if job.id == third));
+        before.synced(0).await.unwrap();
+        drop(before);
+
+        let after = jobs();
+        let (wal, records) = Wal::open(&path, 100).await.unwrap();
+        after.recover(wal, records);
+        let client = after.connect();
+        for id in [third, second] {
+            assert!(matches!(after.get(client, &queues, false), Got::Job(job) if job.id == id));
+        }
+        assert!(matches!(after.get(client, &queues, false), Got::NoJob));
+        assert!(after.put("q".to_owned(), json!(4), 40) > third);
+        // Startup rewrote the log down to the two jobs that were left
+        after.synced(0).await.unwrap();
+        assert_eq!(log_len(&path).await, 3);
+    }
+}
@@ -0,0 +1,132 @@
+// The jobctl binary against a server on an ephemeral port
+use serde_json::Value;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+async fn server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = problem9::Config::from_env();
+    tokio::spawn(problem9::run(listener, CancellationToken::new(), config));
+    addr
+}
+
+// Run jobctl with `args` and `stdin`, returning its exit status and the
+// responses it printed
+async fn jobctl(addr: SocketAddr, args: &[&str], stdin: &str) -> (i32, Vec<Value>) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_jobctl"));
+    command.arg("--addr").arg(addr.to_string()).args(args);
+    let stdin = stdin.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(stdin.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        let responses = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (output.status.code().unwrap(), responses)
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn puts_gets_and_deletes() {
+    let addr = server().await;
+    let (status, put) = jobctl(addr, &["put", "q1", "--pri", "5"], r#"{"title": "x"}"#).await;
+    assert_eq!(status, 0);
+    let id = put[0]["id"].as_u64().unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let job = dir.path().join("job.json");
+    std::fs::write(&job, r#"{"title": "y"}"#).unwrap();
+    let (_, put) = jobctl(
+        addr,
+        &["put", "q1", "--pri", "9", job.to_str().unwrap()],
+        "",
+    )
+    .await;
+    let higher = put[0]["id"].as_u64().unwrap();
+
+    let (status, got) = jobctl(addr, &["get", "q1", "q2"], "").await;
+    assert_eq!(status, 0);
+    assert_eq!(got[0]["id"].as_u64(), Some(higher));
+    assert_eq!(got[0]["job"]["title"], "y");
+    assert_eq!(got[0]["queue"], "q1");
+
+    // jobctl's connection closed after the get, so that job is back
+    let (_, got) = jobctl(addr, &["get", "q1"], "").await;
+    assert_eq!(got[0]["id"].as_u64(), Some(higher));
+    for deleted in [higher, id] {
+        let (status, _) = jobctl(addr, &["delete", &deleted.to_string()], "").await;
+        assert_eq!(status, 0);
+    }
+    let (status, got) = jobctl(addr, &["get", "q1"], "").await;
+    assert_eq!((status, got[0]["status"].as_str()), (1, Some("no-job")));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn waits_for_a_job_another_client_puts() {
+    let addr = server().await;
+    let waiting = tokio::spawn(jobctl(addr, &["wait", "later"], ""));
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(!waiting.is_finished());
+    jobctl(addr, &["put", "later"], r#"{"n": 1}"#).await;
+    let (status, got) = waiting.await.unwrap();
+    assert_eq!(status, 0);
+    assert_eq!(got[0]["job"]["n"], 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn runs_requests_over_one_connection() {
+    let addr = server().await;
+    jobctl(addr, &["put", "q"], r#"{"n": 1}"#).await;
+    let requests = concat!(
+        r#"{"request": "get", "queues": ["q"]}"#,
+        "\n",
+        r#"{"request": "abort", "id": 1}"#,
+        "\n\n",
+        r#"{"request": "get", "queues": ["q"]}"#,
+        "\n",
+    );
+    let (status, responses) = jobctl(addr, &["run"], requests).await;
+    assert_eq!(status, 0);
+    let statuses: Vec<_> = responses.iter().map(|r| r["status"].clone()).collect();
+    assert_eq!(statuses, ["ok", "ok", "ok"]);
+    assert_eq!(responses[0]["id"], responses[2]["id"]);
+
+    // Another connection can't abort it
+    let (status, responses) = jobctl(addr, &["abort", "1"], "").await;
+    assert_eq!(
+        (status, responses[0]["status"].as_str()),
+        (2, Some("error"))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refuses_bad_input() {
+    let addr = server().await;
+    for job in ["not json", "[1, 2]"] {
+        let (status, responses) = jobctl(addr, &["put", "q"], job).await;
+        assert_eq!((status, responses.len()), (2, 0));
+    }
+    let (status, responses) = jobctl(addr, &["run"], "{\"request\": \"what\"}\n").await;
+    assert_eq!(
+        (status, responses[0]["status"].as_str()),
+        (2, Some("error"))
+    );
+}
@@ -0,0 +1,172 @@
+// `protohackers all`: every problem served by one process at once.
+//
+// Problem N listens on PROBLEMN_PORT, or 39456 + N when that isn't set, on
+// the address given with --bind or BIND_ADDR (every interface unless given);
+// PORT is ignored, as it can only name one of them. The rest of the
+// configuration is shared, so MAX_LINE_LENGTH, say, applies to
+// every problem that reads it, and side listeners with a port of their own
+// (QUIC_PORT and the like) only come up for the first problem to start them.
+// Each problem runs under a supervisor: if its listener can't be bound, or
+// its accept loop returns or panics, it is started again after a backoff
+// that resets once it has stayed up for STABLE_AFTER. A restarted problem
+// binds its port afresh, so HANDOVER_SOCKET is refused: use
+// `protohackers supervise`, where each problem's own process hands over its
+// listener.
+use crate::{Problem, DEFAULT_PORT, PROBLEMS};
+use common::config::Checker;
+use common::metrics::Scope;
+use common::retry::Backoff;
+use protolib::Transport;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+pub(crate) const RESTART_BACKOFF_INITIAL: Duration = Duration::from_millis(100);
+pub(crate) const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+pub(crate) const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+fn port_variable(problem: &Problem) -> String {
+    format!("{}_PORT", (problem.name)().to_uppercase())
+}
+
+// N, for problemN
+pub(crate) fn number(problem: &Problem) -> u16 {
+    (problem.name)()["problem".len()..].parse().unwrap()
+}
+
+fn default_port(problem: &Problem) -> u16 {
+    DEFAULT_PORT + number(problem)
+}
+
+// Serve `problem` on `addr` until `shutdown` is cancelled, starting it again
+// whenever it stops
+async fn supervise(problem: &'static Problem, addr: SocketAddr, shutdown: CancellationToken) {
+    let backoff = Backoff::new(RESTART_BACKOFF_INITIAL, RESTART_BACKOFF_MAX);
+    let restarts = Scope::new((problem.name)(), addr.port()).counter(
+        "problem_restarts_total",
+        "Times the problem was started again after stopping",
+    );
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let stopped = match tokio::spawn((problem.listen)(addr, shutdown.clone())).await {
+            Ok(Ok(())) => "accept loop stopped".to_owned(),
+            Ok(Err(e)) => format!("couldn't bind {}: {}", addr, e),
+            Err(e) => e.to_string(),
+        };
+        if shutdown.is_cancelled() {
+            return;
+        }
+        if started.elapsed() >= STABLE_AFTER {
+            failures = 0;
+        }
+        failures += 1;
+        let delay = backoff.delay(failures);
+        common::warn!(
+            "{} on {}: {}, restarting in {:?}",
+            (problem.name)(),
+            addr,
+            stopped,
+            delay
+        );
+        restarts.inc();
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+#[tokio::main]
+async fn supervise_all(addrs: Vec<(&'static Problem, SocketAddr)>) {
+    #[cfg(feature = "console")]
+    common::console::init();
+    common::alloc::spawn_stats_reporter();
+    common::metrics::spawn_endpoint_from_env();
+    #[cfg(feature = "pprof")]
+    common::profile::spawn_endpoint_from_env();
+    #[cfg(feature = "sandbox")]
+    common::sandbox::restrict_syscalls_from_env();
+    let shutdown = common::shutdown::on_signal();
+    let supervisors: Vec<_> = addrs
+        .into_iter()
+        .map(|(problem, addr)| {
+            (
+                problem,
+                tokio::spawn(supervise(problem, addr, shutdown.clone())),
+            )
+        })
+        .collect();
+    for (problem, supervisor) in supervisors {
+        if let Err(e) = supervisor.await {
+            common::error!("Supervisor for {} stopped: {}", (problem.name)(), e);
+        }
+    }
+}
+
+// Each problem with the port it listens on
+pub(crate) fn ports() -> Vec<(&'static Problem, u16)> {
+    PROBLEMS
+        .iter()
+        .map(|problem| {
+            let port = common::env::var_or(&port_variable(problem), default_port(problem));
+            (problem, port)
+        })
+        .collect()
+}
+
+// Check the configuration of every problem listening on its port of `ports`
+// at `bind`, exiting if it's wrong
+pub(crate) fn check_config(ports: &[(&'static Problem, u16)], bind: IpAddr) {
+    let main = |transport| -> Vec<(&str, u16)> {
+        ports
+            .iter()
+            .filter(|(problem, _)| problem.transport == transport)
+            .map(|&(problem, port)| ((problem.name)(), port))
+            .collect()
+    };
+    let (tcp, udp) = (main(Transport::Tcp), main(Transport::Udp));
+    let on_bind = |main: &[(&'static str, u16)]| -> Vec<(&'static str, SocketAddr)> {
+        main.iter()
+            .map(|&(name, port)| (name, SocketAddr::new(bind, port)))
+            .collect()
+    };
+
+    let mut checker = Checker::new_many(&tcp, &udp);
+    for (problem, _) in ports {
+        checker = (problem.check_config)(checker.parse::<u16>(&port_variable(problem)));
+    }
+    checker.finish();
+    common::dry_run::bind_many_listeners(&on_bind(&tcp), &on_bind(&udp));
+}
+
+// Check the configuration, then serve every problem until the process is
+// stopped
+pub(crate) fn run() -> Result<(), String> {
+    if !common::cli::extra_addrs().is_empty() {
+        return Err("--listen isn't supported by protohackers all".to_owned());
+    }
+    // Every problem would listen on the same path
+    if common::cli::uds_path().is_some() {
+        return Err("--uds and UDS_PATH aren't supported by protohackers all".to_owned());
+    }
+    if common::env::var::<String>("HANDOVER_SOCKET").is_some() {
+        return Err(
+            "HANDOVER_SOCKET isn't supported by protohackers all; use protohackers supervise"
+                .to_owned(),
+        );
+    }
+    let bind = common::cli::bind_addr();
+    let ports = ports();
+    check_config(&ports, bind);
+    // Landlock only covers threads started after it, so it goes before the runtime
+    #[cfg(feature = "sandbox")]
+    common::sandbox::restrict_paths_from_env();
+    supervise_all(
+        ports
+            .into_iter()
+            .map(|(problem, port)| (problem, SocketAddr::new(bind, port)))
+            .collect(),
+    );
+    Ok(())
+}
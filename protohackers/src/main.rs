@@ -0,0 +1,122 @@
+// Every problem in one binary.
+//
+// `protohackers run problemN [--bind ADDR] [--port P] [--listen ADDR:PORT]...`
+// serves problem N on ADDR and port P (0.0.0.0 and 39456 unless given), and
+// on each --listen address too, exactly as problemN's own binary would.
+// `protohackers all` serves every problem at once, each on a port of its own;
+// see all.rs. `protohackers supervise` does the same with a child process per
+// problem; see supervise.rs. The other options (--set, --config,
+// --print-config, --check-config, --dry-run) are read by the problems
+// themselves, so they can go anywhere after the command, and --help after a
+// command lists them.
+use clap::builder::PossibleValuesParser;
+use clap::Parser;
+use common::cli::Args;
+use common::config::Checker;
+use protolib::Transport;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio_util::sync::CancellationToken;
+
+mod all;
+mod supervise;
+
+const DEFAULT_PORT: u16 = 39456;
+
+// A problem's listen(), boxed to fit in the table
+type Listen =
+    fn(SocketAddr, CancellationToken) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+struct Problem {
+    name: fn() -> &'static str,
+    // What its main socket is
+    transport: Transport,
+    serve: fn(&[SocketAddr]),
+    check_config: fn(Checker) -> Checker,
+    listen: Listen,
+}
+
+// The table entry for a problem crate, from its `protolib::Problem`
+macro_rules! problem {
+    ($name:ident) => {
+        Problem {
+            name: <$name::Config as protolib::Problem>::name,
+            transport: <$name::Config as protolib::Problem>::TRANSPORT,
+            serve: $name::serve,
+            check_config: <$name::Config as protolib::Problem>::check_config,
+            listen: |addr, shutdown| Box::pin(protolib::listen::<$name::Config>(addr, shutdown)),
+        }
+    };
+}
+
+const PROBLEMS: &[Problem] = &[
+    problem!(problem0),
+    problem!(problem1),
+    problem!(problem2),
+    problem!(problem3),
+    problem!(problem4),
+    problem!(problem5),
+    problem!(problem6),
+    problem!(problem7),
+    problem!(problem8),
+    problem!(problem9),
+    problem!(problem10),
+    problem!(problem11),
+];
+
+#[derive(Parser)]
+#[command(about = "Every Protohackers problem in one binary")]
+enum Cli {
+    /// Serve one problem, exactly as its own binary would
+    #[command(after_help = common::cli::AFTER_HELP, args_override_self = true)]
+    Run {
+        #[arg(value_parser = PossibleValuesParser::new(PROBLEMS.iter().map(|p| (p.name)())))]
+        problem: String,
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Serve every problem at once, each on a port of its own
+    #[command(after_help = common::cli::AFTER_HELP, args_override_self = true)]
+    All {
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Serve every problem from a child process of its own
+    #[command(after_help = common::cli::AFTER_HELP, args_override_self = true)]
+    Supervise {
+        #[command(flatten)]
+        args: Args,
+    },
+}
+
+// Serve what the command line selects, which only returns if it stops
+// serving
+fn run() -> Result<(), String> {
+    match Cli::parse() {
+        Cli::Run { problem, args } => {
+            common::cli::init(args);
+            let problem = PROBLEMS.iter().find(|p| (p.name)() == problem).unwrap();
+            (problem.serve)(&common::cli::listen_addrs(DEFAULT_PORT));
+        }
+        Cli::All { args } => {
+            common::cli::init(args);
+            all::run()?;
+        }
+        Cli::Supervise { args } => {
+            common::cli::init(args);
+            // Everything after `supervise`, for the children
+            let rest: Vec<String> = std::env::args().skip(2).collect();
+            supervise::run(&rest)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
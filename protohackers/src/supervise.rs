@@ -0,0 +1,265 @@
+// `protohackers supervise`: every problem at once, each in a process of its
+// own.
+//
+// Problems listen where `protohackers all` would have them (PROBLEMN_PORT, or
+// 39456 + N, on the address given with --bind or BIND_ADDR), but each is
+// served by a child process running `protohackers run problemN`, so a problem
+// that panics, aborts or runs out of memory only takes itself down. A child
+// that exits is started again after a backoff that resets once it has stayed
+// up for STABLE_AFTER, as in all.rs. Children inherit the environment and the
+// rest of the command line (--set, --config and the like).
+//
+// Every line a child writes is passed on to the same stream of the
+// supervisor, prefixed with the problem's name. With METRICS_PORT set, the
+// child for problem N serves its metrics on METRICS_PORT + 1 + N, and the
+// supervisor's own endpoint merges them all (see metrics.rs) with its
+// problem_restarts_total counters. On SIGINT or SIGTERM the supervisor sends
+// the children SIGTERM and waits for them to finish draining. Children run in
+// process groups of their own, so a Ctrl-C at the terminal doesn't reach them
+// as well; that would be their second signal, which exits without draining.
+use crate::all::{self, RESTART_BACKOFF_INITIAL, RESTART_BACKOFF_MAX, STABLE_AFTER};
+use crate::Problem;
+use common::metrics::Scope;
+use common::retry::Backoff;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+// Pass each line of `output` on, prefixed with `name`, to stderr if `stderr`
+// or stdout otherwise
+fn forward<R>(name: &'static str, output: R, stderr: bool) -> JoinHandle<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(output).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match stderr {
+                true => eprintln!("{} | {}", name, line),
+                false => println!("{} | {}", name, line),
+            }
+        }
+    })
+}
+
+// Run `command` as `name` until it exits, or until `shutdown` is cancelled
+// and it has finished after being sent SIGTERM
+async fn run_child(
+    name: &'static str,
+    mut command: Command,
+    shutdown: &CancellationToken,
+) -> io::Result<ExitStatus> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = forward(name, child.stdout.take().unwrap(), false);
+    let stderr = forward(name, child.stderr.take().unwrap(), true);
+    let status = tokio::select! {
+        status = child.wait() => status,
+        _ = shutdown.cancelled() => {
+            if let Some(pid) = child.id() {
+                // The child's shutdown handler drains its connections
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            }
+            child.wait().await
+        }
+    };
+    // What it wrote last, which may well say why it stopped, unless whatever
+    // it left running holds its output open
+    let flushed = async { tokio::join!(stdout, stderr) };
+    tokio::time::timeout(OUTPUT_GRACE, flushed).await.ok();
+    status
+}
+
+// Keep a child made by `command` running as `name` until `shutdown` is
+// cancelled, starting another whenever one exits. `restarts` counts them.
+async fn supervise<F>(
+    name: &'static str,
+    command: F,
+    restarts: common::metrics::Counter,
+    shutdown: CancellationToken,
+) where
+    F: Fn() -> io::Result<Command>,
+{
+    let backoff = Backoff::new(RESTART_BACKOFF_INITIAL, RESTART_BACKOFF_MAX);
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let stopped = match command() {
+            Ok(command) => match run_child(name, command, &shutdown).await {
+                Ok(status) => format!("exited with {}", status),
+                Err(e) => format!("couldn't start: {}", e),
+            },
+            Err(e) => format!("couldn't start: {}", e),
+        };
+        if shutdown.is_cancelled() {
+            return;
+        }
+        if started.elapsed() >= STABLE_AFTER {
+            failures = 0;
+        }
+        failures += 1;
+        let delay = backoff.delay(failures);
+        common::warn!("{} {}, restarting in {:?}", name, stopped, delay);
+        restarts.inc();
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+// The command serving `problem` on `addr`, passing on `args`, with its
+// metrics on `metrics_port` if given
+fn command(
+    problem: &Problem,
+    addr: SocketAddr,
+    args: &[String],
+    metrics_port: Option<u16>,
+) -> io::Result<Command> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("run").arg((problem.name)()).args(args);
+    // The last --bind and --port win
+    command.arg("--bind").arg(addr.ip().to_string());
+    command.arg("--port").arg(addr.port().to_string());
+    match metrics_port {
+        Some(port) => command.env("METRICS_PORT", port.to_string()),
+        None => command.env_remove("METRICS_PORT"),
+    };
+    Ok(command)
+}
+
+#[tokio::main]
+async fn supervise_all(bind: IpAddr, ports: Vec<(&'static Problem, u16)>, args: Vec<String>) {
+    let metrics_port = common::env::var::<u16>("METRICS_PORT");
+    common::metrics::spawn_endpoint_from_env();
+    let shutdown = common::shutdown::on_signal();
+    let mut supervisors = Vec::new();
+    for (problem, port) in ports {
+        let addr = SocketAddr::new(bind, port);
+        let child_metrics = metrics_port.map(|port| port + 1 + all::number(problem));
+        if let Some(port) = child_metrics {
+            common::metrics::add_upstream(
+                (problem.name)(),
+                SocketAddr::from(([127, 0, 0, 1], port)),
+            );
+        }
+        let restarts = Scope::new((problem.name)(), port).counter(
+            "problem_restarts_total",
+            "Times the problem was started again after stopping",
+        );
+        let args = args.clone();
+        let command = move || command(problem, addr, &args, child_metrics);
+        supervisors.push(tokio::spawn(supervise(
+            (problem.name)(),
+            command,
+            restarts,
+            shutdown.clone(),
+        )));
+    }
+    for supervisor in supervisors {
+        supervisor.await.unwrap_or(());
+    }
+}
+
+// Check the configuration, then run every problem in a child process until
+// the supervisor is stopped. `args` are passed on to the children.
+pub(crate) fn run(args: &[String]) -> Result<(), String> {
+    if !common::cli::extra_addrs().is_empty() {
+        return Err("--listen isn't supported by protohackers supervise".to_owned());
+    }
+    // Every child would listen on the same path
+    if common::cli::uds_path().is_some() {
+        return Err("--uds and UDS_PATH aren't supported by protohackers supervise".to_owned());
+    }
+    let bind = common::cli::bind_addr();
+    let ports = all::ports();
+    all::check_config(&ports, bind);
+    supervise_all(bind, ports, args.to_vec());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(script: &'static str) -> impl Fn() -> io::Result<Command> {
+        move || {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            Ok(command)
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_a_child_that_exits() {
+        let restarts = Scope::new("supervised", 1).counter("restarts", "");
+        let shutdown = CancellationToken::new();
+        let supervisor = tokio::spawn(supervise(
+            "crashing",
+            shell("echo starting; exit 3"),
+            restarts.clone(),
+            shutdown.clone(),
+        ));
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while restarts.get() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        shutdown.cancel();
+        supervisor.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_children_on_shutdown() {
+        let restarts = Scope::new("supervised", 2).counter("restarts", "");
+        let shutdown = CancellationToken::new();
+        let supervisor = tokio::spawn(supervise(
+            "sleeping",
+            shell("trap 'echo draining; exit 0' TERM; sleep 30 & wait"),
+            restarts.clone(),
+            shutdown.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), supervisor)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(restarts.get(), 0);
+    }
+
+    // A Ctrl-C at the terminal goes to the supervisor's process group, so
+    // children must not be in it
+    #[tokio::test]
+    async fn children_have_process_groups_of_their_own() {
+        let path = std::env::temp_dir().join(format!("supervise-pgid-{}", std::process::id()));
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("ps -o pgid= -p $$ > \"$0\"")
+            .arg(&path);
+        let status = run_child("grouped", command, &CancellationToken::new()).await;
+        assert!(status.unwrap().success());
+        let pgid: libc::pid_t = std::fs::read_to_string(&path)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(pgid, unsafe { libc::getpgrp() });
+    }
+}
@@ -0,0 +1,105 @@
+// A problem server as a whole, for tooling that treats every problem alike.
+//
+// Where common's `ConnectionHandler` is what a problem does with one
+// connection, `Problem` is the whole server: something with a name that can
+// read and check its configuration, bind what it listens on and serve that
+// until told to stop. A problem crate implements it on its `Config`, as
+// everything it needs to serve has been read into that, so the protohackers
+// binary, `protohackers all` and the multiplexer can start any problem the
+// same way: check the configuration, read it, bind and serve. `main` is that
+// sequence for a process serving just the one problem, as its binary does.
+use common::config::Checker;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tokio_util::sync::CancellationToken;
+
+// What a problem's main socket is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+pub trait Problem: Clone + Send + Sync + 'static {
+    const TRANSPORT: Transport;
+
+    // What `bind` gives `serve`: TCP listeners, or a socket for the UDP
+    // problems
+    type Listener: Send;
+
+    // The problem's crate name, as its metrics and binary are named
+    fn name() -> &'static str;
+
+    fn from_env() -> Self;
+
+    // This problem's own checks, on top of those `checker` makes
+    fn check_config(checker: Checker) -> Checker;
+
+    // Bind every one of `addrs`; the UDP problems take only one
+    fn bind(&self, addrs: &[SocketAddr])
+        -> impl Future<Output = io::Result<Self::Listener>> + Send;
+
+    // Serve `listener` until `shutdown` is cancelled, then wait for the
+    // connections already accepted
+    fn serve(
+        &self,
+        listener: Self::Listener,
+        shutdown: CancellationToken,
+    ) -> impl Future<Output = ()> + Send;
+}
+
+// Serve `P` on `addr` until `shutdown` is cancelled or the accept loop stops,
+// in a process the caller has already set up (as `protohackers all` does)
+pub async fn listen<P: Problem>(addr: SocketAddr, shutdown: CancellationToken) -> io::Result<()> {
+    let problem = P::from_env();
+    let listener = problem.bind(&[addr]).await?;
+    problem.serve(listener, shutdown).await;
+    Ok(())
+}
+
+// Check the configuration of `P` for serving on `addrs`, and in a dry run
+// that they can be bound, exiting if anything is wrong
+pub fn check<P: Problem>(addrs: &[SocketAddr]) {
+    match P::TRANSPORT {
+        Transport::Tcp => {
+            P::check_config(Checker::new(addrs[0].port())).finish();
+            common::dry_run::bind_listeners(addrs);
+        }
+        Transport::Udp => {
+            let addr = common::cli::only_addr(addrs, "for UDP");
+            P::check_config(Checker::new_udp(addr.port())).finish();
+            common::dry_run::bind_udp_listeners(addr);
+        }
+    }
+}
+
+// Everything a problem's binary does: check the configuration, then serve
+// `P` on `addrs` until the process is stopped
+pub fn main<P: Problem>(addrs: &[SocketAddr]) {
+    check::<P>(addrs);
+    // Read before the sandbox, as it may read files the sandbox won't allow
+    let problem = P::from_env();
+    // Landlock only covers threads started after it, so it goes before the runtime
+    #[cfg(feature = "sandbox")]
+    common::sandbox::restrict_paths_from_env();
+    run(problem, addrs);
+}
+
+#[tokio::main]
+async fn run<P: Problem>(problem: P, addrs: &[SocketAddr]) {
+    #[cfg(feature = "console")]
+    common::console::init();
+    let what = match P::TRANSPORT {
+        Transport::Tcp => "bind listener",
+        Transport::Udp => "bind socket",
+    };
+    let listener = common::report::startup(what, problem.bind(addrs).await);
+    common::alloc::spawn_stats_reporter();
+    common::metrics::spawn_endpoint_from_env();
+    #[cfg(feature = "pprof")]
+    common::profile::spawn_endpoint_from_env();
+    #[cfg(feature = "sandbox")]
+    common::sandbox::restrict_syscalls_from_env();
+    problem.serve(listener, common::shutdown::on_signal()).await;
+}
@@ -0,0 +1,390 @@
+// SQLite-backed persistence shared by the problem servers.
+//
+// A `Storage` is a single database file holding any number of key-value
+// namespaces and append-only logs, so features that need to survive restarts
+// don't each have to come up with their own file format. SQLite calls are
+// blocking, so every operation runs on tokio's blocking pool.
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS kv (
+        namespace TEXT NOT NULL,
+        key BLOB NOT NULL,
+        value BLOB NOT NULL,
+        PRIMARY KEY (namespace, key)
+    );
+    CREATE TABLE IF NOT EXISTS log (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        entry BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS log_by_name ON log (name, seq);
+";
+
+fn std_error_from_sqlite_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[derive(Clone)]
+pub struct Storage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Storage {
+    // Open (creating if needed) the database at `path`
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<Storage> {
+        let path = path.as_ref().to_owned();
+        let conn = tokio::task::spawn_blocking(move || -> rusqlite::Result<Connection> {
+            let conn = Connection::open(path)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.execute_batch(SCHEMA)?;
+            Ok(conn)
+        })
+        .await?
+        .map_err(std_error_from_sqlite_error)?;
+
+        Ok(Storage {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // A database that lives only as long as this process, for tests and
+    // for running without a configured path
+    pub fn in_memory() -> io::Result<Storage> {
+        let conn = Connection::open_in_memory().map_err(std_error_from_sqlite_error)?;
+        conn.execute_batch(SCHEMA)
+            .map_err(std_error_from_sqlite_error)?;
+
+        Ok(Storage {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub fn kv(&self, namespace: &str) -> KvStore {
+        KvStore {
+            storage: self.clone(),
+            namespace: namespace.to_owned(),
+        }
+    }
+
+    pub fn log(&self, name: &str) -> AppendLog {
+        AppendLog {
+            storage: self.clone(),
+            name: name.to_owned(),
+        }
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            // A panic while holding the lock can't leave SQLite itself in a bad
+            // state, so keep using the connection
+            let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&conn)
+        })
+        .await?
+        .map_err(std_error_from_sqlite_error)
+    }
+}
+
+// Key-value pairs within one namespace of a `Storage`
+#[derive(Clone)]
+pub struct KvStore {
+    storage: Storage,
+    namespace: String,
+}
+
+impl KvStore {
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let namespace = self.namespace.clone();
+        let key = key.to_vec();
+        self.storage
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT value FROM kv WHERE namespace = ?1 AND key = ?2",
+                    params![namespace, key],
+                    |row| row.get(0),
+                )
+                .optional()
+            })
+            .await
+    }
+
+    pub async fn set(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let namespace = self.namespace.clone();
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.storage
+            .with_conn(move |conn| {
+                conn.execute(
+                    "INSERT INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (namespace, key) DO UPDATE SET value = excluded.value",
+                    params![namespace, key, value],
+                )
+                .map(|_| ())
+            })
+            .await
+    }
+
+    // Returns whether the key was present
+    pub async fn delete(&self, key: &[u8]) -> io::Result<bool> {
+        let namespace = self.namespace.clone();
+        let key = key.to_vec();
+        self.storage
+            .with_conn(move |conn| {
+                conn.execute(
+                    "DELETE FROM kv WHERE namespace = ?1 AND key = ?2",
+                    params![namespace, key],
+                )
+                .map(|n| n > 0)
+            })
+            .await
+    }
+
+    // Replace every pair in the namespace with `entries`, all at once
+    pub async fn replace_all(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> io::Result<()> {
+        let namespace = self.namespace.clone();
+        self.storage
+            .with_conn(move |conn| {
+                let tx = conn.unchecked_transaction()?;
+                tx.execute("DELETE FROM kv WHERE namespace = ?1", params![namespace])?;
+                {
+                    let mut stmt =
+                        tx.prepare("INSERT INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)")?;
+                    for (key, value) in &entries {
+                        stmt.execute(params![namespace, key, value])?;
+                    }
+                }
+                tx.commit()
+            })
+            .await
+    }
+
+    // Every pair in the namespace, ordered by key
+    pub async fn entries(&self) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let namespace = self.namespace.clone();
+        self.storage
+            .with_conn(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT key, value FROM kv WHERE namespace = ?1 ORDER BY key")?;
+                let rows =
+                    stmt.query_map(params![namespace], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect()
+            })
+            .await
+    }
+}
+
+// An append-only sequence of entries within a `Storage`. Sequence numbers are
+// increasing but not contiguous, since all logs share one counter.
+#[derive(Clone)]
+pub struct AppendLog {
+    storage: Storage,
+    name: String,
+}
+
+impl AppendLog {
+    // Returns the sequence number of the new entry
+    pub async fn append(&self, entry: &[u8]) -> io::Result<i64> {
+        let name = self.name.clone();
+        let entry = entry.to_vec();
+        self.storage
+            .with_conn(move |conn| {
+                conn.execute(
+                    "INSERT INTO log (name, entry) VALUES (?1, ?2)",
+                    params![name, entry],
+                )?;
+                Ok(conn.last_insert_rowid())
+            })
+            .await
+    }
+
+    // Append `entries` in order, all or none of them. Returns the sequence
+    // number of the last one, if there were any.
+    pub async fn append_all(&self, entries: Vec<Vec<u8>>) -> io::Result<Option<i64>> {
+        let name = self.name.clone();
+        self.storage
+            .with_conn(move |conn| {
+                let tx = conn.unchecked_transaction()?;
+                {
+                    let mut stmt = tx.prepare("INSERT INTO log (name, entry) VALUES (?1, ?2)")?;
+                    for entry in &entries {
+                        stmt.execute(params![name, entry])?;
+                    }
+                }
+                let last = (!entries.is_empty()).then(|| tx.last_insert_rowid());
+                tx.commit()?;
+                Ok(last)
+            })
+            .await
+    }
+
+    // Entries with a sequence number greater than `after`, oldest first.
+    // Pass 0 to replay the whole log.
+    pub async fn read_after(&self, after: i64) -> io::Result<Vec<(i64, Vec<u8>)>> {
+        let name = self.name.clone();
+        self.storage
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT seq, entry FROM log WHERE name = ?1 AND seq > ?2 ORDER BY seq",
+                )?;
+                let rows =
+                    stmt.query_map(params![name, after], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect()
+            })
+            .await
+    }
+
+    // Drop entries up to and including `upto`, e.g. after writing a snapshot
+    pub async fn truncate(&self, upto: i64) -> io::Result<()> {
+        let name = self.name.clone();
+        self.storage
+            .with_conn(move |conn| {
+                conn.execute(
+                    "DELETE FROM log WHERE name = ?1 AND seq <= ?2",
+                    params![name, upto],
+                )
+                .map(|_| ())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> (Vec<u8>, Vec<u8>) {
+        (key.as_bytes().to_vec(), value.as_bytes().to_vec())
+    }
+
+    #[tokio::test]
+    async fn kv_puts_gets_overwrites_and_deletes() {
+        let kv = Storage::in_memory().unwrap().kv("test");
+        assert_eq!(kv.get(b"k").await.unwrap(), None);
+        kv.set(b"k", b"1").await.unwrap();
+        assert_eq!(kv.get(b"k").await.unwrap(), Some(b"1".to_vec()));
+        kv.set(b"k", b"2").await.unwrap();
+        assert_eq!(kv.get(b"k").await.unwrap(), Some(b"2".to_vec()));
+        // Empty keys and values are kept like any other
+        kv.set(b"", b"").await.unwrap();
+        assert_eq!(kv.get(b"").await.unwrap(), Some(Vec::new()));
+
+        assert!(kv.delete(b"k").await.unwrap());
+        assert!(!kv.delete(b"k").await.unwrap());
+        assert_eq!(kv.get(b"k").await.unwrap(), None);
+        assert_eq!(kv.entries().await.unwrap(), [pair("", "")]);
+    }
+
+    #[tokio::test]
+    async fn kv_namespaces_are_separate() {
+        let storage = Storage::in_memory().unwrap();
+        let (a, b) = (storage.kv("a"), storage.kv("b"));
+        a.set(b"k", b"in a").await.unwrap();
+        b.set(b"k", b"in b").await.unwrap();
+        assert_eq!(a.get(b"k").await.unwrap(), Some(b"in a".to_vec()));
+        assert!(b.delete(b"k").await.unwrap());
+        assert_eq!(a.get(b"k").await.unwrap(), Some(b"in a".to_vec()));
+        assert_eq!(b.entries().await.unwrap(), []);
+    }
+
+    #[tokio::test]
+    async fn kv_replaces_every_pair_at_once() {
+        let storage = Storage::in_memory().unwrap();
+        let kv = storage.kv("test");
+        kv.set(b"old", b"gone").await.unwrap();
+        kv.set(b"b", b"old").await.unwrap();
+        storage.kv("other").set(b"kept", b"1").await.unwrap();
+
+        kv.replace_all(vec![pair("b", "2"), pair("a", "1")])
+            .await
+            .unwrap();
+        assert_eq!(
+            kv.entries().await.unwrap(),
+            [pair("a", "1"), pair("b", "2")]
+        );
+        assert_eq!(
+            storage.kv("other").entries().await.unwrap(),
+            [pair("kept", "1")]
+        );
+        // A conflict rolls the whole replacement back
+        let duplicate = vec![pair("c", "3"), pair("c", "4")];
+        assert!(kv.replace_all(duplicate).await.is_err());
+        assert_eq!(
+            kv.entries().await.unwrap(),
+            [pair("a", "1"), pair("b", "2")]
+        );
+    }
+
+    #[tokio::test]
+    async fn logs_replay_in_append_order() {
+        let storage = Storage::in_memory().unwrap();
+        let (log, other) = (storage.log("test"), storage.log("other"));
+        let first = log.append(b"one").await.unwrap();
+        other.append(b"elsewhere").await.unwrap();
+        let last = log
+            .append_all(vec![b"two".to_vec(), b"three".to_vec()])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(log.append_all(Vec::new()).await.unwrap(), None);
+
+        let replayed = log.read_after(0).await.unwrap();
+        let entries: Vec<&[u8]> = replayed.iter().map(|(_, e)| &e[..]).collect();
+        assert_eq!(entries, [&b"one"[..], b"two", b"three"]);
+        let seqs: Vec<i64> = replayed.iter().map(|&(seq, _)| seq).collect();
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!((seqs[0], seqs[2]), (first, last));
+
+        assert_eq!(
+            log.read_after(seqs[1]).await.unwrap(),
+            [(last, b"three".to_vec())]
+        );
+        assert_eq!(log.read_after(last).await.unwrap(), []);
+    }
+
+    #[tokio::test]
+    async fn logs_truncate_up_to_a_sequence_number() {
+        let storage = Storage::in_memory().unwrap();
+        let (log, other) = (storage.log("test"), storage.log("other"));
+        let first = log.append(b"one").await.unwrap();
+        other.append(b"elsewhere").await.unwrap();
+        let second = log.append(b"two").await.unwrap();
+
+        log.truncate(first).await.unwrap();
+        assert_eq!(
+            log.read_after(0).await.unwrap(),
+            [(second, b"two".to_vec())]
+        );
+        assert_eq!(other.read_after(0).await.unwrap().len(), 1);
+        // New entries still come after everything truncated
+        let third = log.append(b"three").await.unwrap();
+        assert!(third > second);
+    }
+
+    #[tokio::test]
+    async fn files_keep_everything_across_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("storage.db");
+        {
+            let storage = Storage::open(&path).await.unwrap();
+            storage.kv("test").set(b"k", b"v").await.unwrap();
+            storage.log("test").append(b"entry").await.unwrap();
+        }
+        let storage = Storage::open(&path).await.unwrap();
+        assert_eq!(
+            storage.kv("test").get(b"k").await.unwrap(),
+            Some(b"v".to_vec())
+        );
+        let replayed = storage.log("test").read_after(0).await.unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].1, b"entry");
+    }
+}
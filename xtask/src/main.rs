@@ -0,0 +1,224 @@
+// Repository chores, run as `cargo xtask <task>` (the alias is in
+// .cargo/config.toml).
+//
+// new-problem N: create a problemN crate wired up like the others (the
+// accept loop, config checks, metrics scope, a line codec that echoes, to be
+// replaced, and an integration test stub), from the templates in
+// xtask/templates, add it to the workspace and make it one of the problems
+// the protohackers binary can run. Nothing is overwritten: an existing
+// problemN directory is an error.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage: cargo xtask new-problem N";
+
+// Path within the new crate -> template
+const TEMPLATES: &[(&str, &str)] = &[
+    ("Cargo.toml", include_str!("../templates/Cargo.toml.in")),
+    ("src/main.rs", include_str!("../templates/main.rs.in")),
+    ("src/lib.rs", include_str!("../templates/lib.rs.in")),
+    ("src/serve.rs", include_str!("../templates/serve.rs.in")),
+    ("src/codec.rs", include_str!("../templates/codec.rs.in")),
+    (
+        "tests/{{problem}}.rs",
+        include_str!("../templates/test.rs.in"),
+    ),
+];
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_owned()
+}
+
+fn fill(template: &str, n: u32) -> String {
+    template
+        .replace("{{problem}}", &format!("problem{}", n))
+        .replace("{{n}}", &n.to_string())
+}
+
+// Features the protohackers binary leaves to common rather than forwarding
+// to every problem
+const COMMON_FEATURES: &[&str] = &["jemalloc", "mimalloc"];
+
+// Longest line a feature list is written on before it's split into one
+// problem per line, as rustfmt would
+const MAX_WIDTH: usize = 100;
+
+// The N of the first "problemN" in `s`
+fn problem_number(s: &str) -> Option<u32> {
+    s.match_indices("problem").find_map(|(i, _)| {
+        let digits = &s[i + "problem".len()..];
+        let end = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        digits[..end].parse().ok()
+    })
+}
+
+// Where an entry for problem `n` goes in `entries`: after the last one for a
+// lower-numbered problem
+fn position<S: AsRef<str>>(entries: &[S], n: u32) -> usize {
+    entries
+        .iter()
+        .rposition(|e| problem_number(e.as_ref()).is_some_and(|other| other < n))
+        .map_or(0, |i| i + 1)
+}
+
+// Add `line` among the lines of `text` that `is_entry` picks out
+fn insert_line(text: &str, is_entry: impl Fn(&str) -> bool, line: &str) -> Option<String> {
+    let mut lines: Vec<&str> = text.lines().collect();
+    let entries: Vec<usize> = (0..lines.len()).filter(|&i| is_entry(lines[i])).collect();
+    let first = *entries.first()?;
+    let names: Vec<&str> = entries.iter().map(|&i| lines[i]).collect();
+    let at = match position(&names, problem_number(line)?) {
+        0 => first,
+        i => entries[i - 1] + 1,
+    };
+    lines.insert(at, line);
+    Some(lines.join("\n") + "\n")
+}
+
+// Add `entry` to the list of feature `feature` in `manifest`, or give the
+// feature a list of its own if it has none yet
+fn add_to_feature(manifest: &str, feature: &str, entry: &str) -> Option<String> {
+    let Some(start) = manifest.find(&format!("\n{} = [", feature)) else {
+        return Some(format!("{}{} = [{}]\n", manifest, feature, entry));
+    };
+    let start = start + 1;
+    let end = start + manifest[start..].find(']')? + 1;
+    let list_start = manifest[start..end].find('[')? + start + 1;
+    let mut entries: Vec<&str> = manifest[list_start..end - 1]
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .collect();
+    entries.insert(position(&entries, problem_number(entry)?), entry);
+    let mut list = format!("{} = [{}]", feature, entries.join(", "));
+    if list.len() > MAX_WIDTH {
+        list = format!("{} = [\n", feature);
+        for entry in entries {
+            list.push_str(&format!("    {},\n", entry));
+        }
+        list.push(']');
+    }
+    Some(format!(
+        "{}{}{}",
+        &manifest[..start],
+        list,
+        &manifest[end..]
+    ))
+}
+
+// Add `problem`, whose manifest is `crate_manifest`, to the protohackers
+// binary's manifest and to its table of problems in `selector`
+fn add_to_selector(
+    manifest: &str,
+    selector: &str,
+    problem: &str,
+    crate_manifest: &str,
+) -> Option<(String, String)> {
+    let dependency = format!("{} = {{ path = \"../{}\" }}", problem, problem);
+    let is_dependency = |line: &str| line.starts_with("problem") && line.contains("path =");
+    let mut manifest = insert_line(manifest, is_dependency, &dependency)?;
+    let features = &crate_manifest[crate_manifest.find("[features]")?..];
+    for line in features.lines().skip(1) {
+        let Some((feature, _)) = line.split_once(" = ") else {
+            continue;
+        };
+        if !COMMON_FEATURES.contains(&feature) {
+            let entry = format!("\"{}/{}\"", problem, feature);
+            manifest = add_to_feature(&manifest, feature, &entry)?;
+        }
+    }
+    let is_problem = |line: &str| line.trim_start().starts_with("problem!(");
+    let row = format!("    problem!({}),", problem);
+    Some((manifest, insert_line(selector, is_problem, &row)?))
+}
+
+// Add `member` to the workspace manifest, after the last problem crate
+fn add_member(manifest: &str, member: &str) -> Option<String> {
+    let start = manifest.find("members = [")? + "members = [".len();
+    let end = start + manifest[start..].find(']')?;
+    let mut members: Vec<&str> = manifest[start..end]
+        .split(',')
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .collect();
+    let entry = format!("\"{}\"", member);
+    members.insert(position(&members, problem_number(member)?), &entry);
+    Some(format!(
+        "{}{}{}",
+        &manifest[..start],
+        members.join(", "),
+        &manifest[end..]
+    ))
+}
+
+fn new_problem(n: &str) -> Result<(), String> {
+    let n: u32 = n
+        .parse()
+        .map_err(|_| format!("{:?} isn't a problem number\n{}", n, USAGE))?;
+    let problem = format!("problem{}", n);
+    let root = workspace_root();
+    let dir = root.join(&problem);
+    if dir.exists() {
+        return Err(format!("{} already exists", dir.display()));
+    }
+
+    let manifest_path = root.join("Cargo.toml");
+    let manifest = fs::read_to_string(&manifest_path)
+        .map_err(|e| format!("Couldn't read {}: {}", manifest_path.display(), e))?;
+    let manifest = add_member(&manifest, &problem)
+        .ok_or_else(|| format!("No workspace members in {}", manifest_path.display()))?;
+
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))
+    };
+    let selector_manifest_path = root.join("protohackers/Cargo.toml");
+    let selector_path = root.join("protohackers/src/main.rs");
+    let (selector_manifest, selector) = add_to_selector(
+        &read(&selector_manifest_path)?,
+        &read(&selector_path)?,
+        &problem,
+        &fill(TEMPLATES[0].1, n),
+    )
+    .ok_or_else(|| format!("No problems in {}", selector_path.display()))?;
+
+    let write = |path: &Path, contents: &str| -> io::Result<()> {
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)
+    };
+    for (path, template) in TEMPLATES {
+        let path = dir.join(fill(path, n));
+        write(&path, &fill(template, n))
+            .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+        println!("Created {}", path.strip_prefix(&root).unwrap().display());
+    }
+    write(&manifest_path, &manifest)
+        .map_err(|e| format!("Couldn't write {}: {}", manifest_path.display(), e))?;
+    println!("Added {} to the workspace", problem);
+    for (path, contents) in [
+        (&selector_manifest_path, &selector_manifest),
+        (&selector_path, &selector),
+    ] {
+        write(path, contents).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    }
+    println!("Added {} to the protohackers binary", problem);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["new-problem", n] => new_problem(n),
+        _ => Err(USAGE.to_owned()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}